@@ -0,0 +1,191 @@
+//! Throughput of concurrent state reads, run with `cargo bench`.
+//!
+//! The first part compares the lock types the shared desk state could be kept
+//! behind: many tasks reading a `SvenState` that is written now and then, each
+//! holding it across an await as a handler waiting on a publish would. A bare
+//! copy out of the lock costs about the same either way. The second serves the API on an ephemeral port and
+//! reports how many `GET /api/sven/state` and `GET /api/sven/status` requests
+//! concurrent clients get through while state reports keep arriving.
+//!
+//! `SVEN_BENCH_CLIENTS` and `SVEN_BENCH_SECS` change the load, 32 clients for
+//! 3 seconds by default.
+
+use rumqttc::AsyncClient;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use sven_api::config::Config;
+use sven_api::listen::ClientAddr;
+use sven_api::transport::TransportKind;
+use sven_api::{SvenPosition, SvenState};
+
+const LOCK_TASKS: usize = 64;
+const LOCK_READS: usize = 20_000;
+
+fn env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+    std::env::var(key)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+fn state(height_mm: u32) -> SvenState {
+    SvenState {
+        height_mm,
+        position: SvenPosition::Custom,
+        motor: None,
+    }
+}
+
+/// Reads per second through `read`, with one task writing every millisecond.
+async fn lock_throughput<L, R, W>(lock: Arc<L>, read: R, write: W) -> f64
+where
+    L: Send + Sync + 'static,
+    R: Fn(Arc<L>) -> tokio::task::JoinHandle<()> + Copy,
+    W: Fn(Arc<L>) -> tokio::task::JoinHandle<()>,
+{
+    let writer = write(lock.clone());
+    let started = Instant::now();
+    let readers: Vec<_> = (0..LOCK_TASKS).map(|_| read(lock.clone())).collect();
+    for reader in readers {
+        reader.await.unwrap();
+    }
+    let elapsed = started.elapsed();
+    writer.abort();
+    (LOCK_TASKS * LOCK_READS) as f64 / elapsed.as_secs_f64()
+}
+
+async fn compare_locks() {
+    let mutex = lock_throughput(
+        Arc::new(tokio::sync::Mutex::new(state(700))),
+        |lock| {
+            tokio::spawn(async move {
+                for _ in 0..LOCK_READS {
+                    let state = lock.lock().await;
+                    tokio::task::yield_now().await;
+                    std::hint::black_box(state.height_mm);
+                }
+            })
+        },
+        |lock| {
+            tokio::spawn(async move {
+                for height_mm in 700.. {
+                    *lock.lock().await = state(height_mm);
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+            })
+        },
+    )
+    .await;
+    let rwlock = lock_throughput(
+        Arc::new(tokio::sync::RwLock::new(state(700))),
+        |lock| {
+            tokio::spawn(async move {
+                for _ in 0..LOCK_READS {
+                    let state = lock.read().await;
+                    tokio::task::yield_now().await;
+                    std::hint::black_box(state.height_mm);
+                }
+            })
+        },
+        |lock| {
+            tokio::spawn(async move {
+                for height_mm in 700.. {
+                    *lock.write().await = state(height_mm);
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+            })
+        },
+    )
+    .await;
+    println!("state reads by {} tasks:", LOCK_TASKS);
+    println!("  tokio Mutex   {:>12.0} reads/s", mutex);
+    println!(
+        "  tokio RwLock  {:>12.0} reads/s ({:.1}x)",
+        rwlock,
+        rwlock / mutex
+    );
+}
+
+async fn load_test(clients: usize, duration: Duration) {
+    let mut config = Config::load().expect("default configuration");
+    config.transport = TransportKind::Http;
+    config.transport_url = Some("http://127.0.0.1:9".to_string());
+    config.rate_limit_per_minute = 0;
+    let (requests_tx, _requests) = flume::unbounded();
+    let app_state = sven_api::build_state(
+        config,
+        AsyncClient::from_senders(requests_tx),
+        "sven-bench".to_string(),
+    )
+    .unwrap();
+    app_state.on_broker_connected().await;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let app =
+        sven_api::build_app(app_state.clone()).into_make_service_with_connect_info::<ClientAddr>();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    // Reports keep coming in, so reads contend with writes as they would live
+    let reporter = {
+        let client = reqwest::Client::new();
+        let url = format!("{}/api/sven/transport/messages", url);
+        tokio::spawn(async move {
+            for height_mm in (700..1200).cycle() {
+                let report = serde_json::json!({
+                    "topic": "sven/state",
+                    "payload": {"height_mm": height_mm, "position": "Custom"},
+                });
+                let _ = client.post(&url).json(&report).send().await;
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+    };
+
+    for path in ["/api/sven/state", "/api/sven/status"] {
+        let completed = Arc::new(AtomicU64::new(0));
+        let failed = Arc::new(AtomicU64::new(0));
+        let deadline = Instant::now() + duration;
+        let workers: Vec<_> = (0..clients)
+            .map(|_| {
+                let client = reqwest::Client::new();
+                let url = format!("{}{}", url, path);
+                let completed = completed.clone();
+                let failed = failed.clone();
+                tokio::spawn(async move {
+                    while Instant::now() < deadline {
+                        match client.get(&url).send().await {
+                            Ok(response) if response.status().is_success() => {
+                                let _ = response.bytes().await;
+                                completed.fetch_add(1, Ordering::Relaxed);
+                            }
+                            _ => {
+                                failed.fetch_add(1, Ordering::Relaxed);
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.await.unwrap();
+        }
+        println!(
+            "GET {:<18} {:>9.0} requests/s with {} clients, {} failed",
+            path,
+            completed.load(Ordering::Relaxed) as f64 / duration.as_secs_f64(),
+            clients,
+            failed.load(Ordering::Relaxed)
+        );
+    }
+    reporter.abort();
+}
+
+#[tokio::main]
+async fn main() {
+    compare_locks().await;
+    let clients = env_or("SVEN_BENCH_CLIENTS", 32);
+    let duration = Duration::from_secs(env_or("SVEN_BENCH_SECS", 3));
+    load_test(clients, duration).await;
+}
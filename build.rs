@@ -0,0 +1,11 @@
+fn main() {
+    // The gRPC service is generated with protox, so building it needs no protoc
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/sven.proto");
+        let descriptors = protox::compile(["proto/sven.proto"], ["proto"]).unwrap();
+        tonic_prost_build::configure()
+            .compile_fds(descriptors)
+            .unwrap();
+    }
+}
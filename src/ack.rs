@@ -0,0 +1,80 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::{Mutex, oneshot};
+
+use crate::DeskCommand;
+
+/// What goes out on the command topic: the command plus the id the firmware
+/// echoes back in its acknowledgement.
+#[derive(Debug, Serialize)]
+pub struct CommandMessage<'a> {
+    #[serde(flatten)]
+    pub command: &'a DeskCommand,
+    pub id: &'a str,
+    /// Unix milliseconds after which the firmware should ignore the command
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AckStatus {
+    Ok,
+    Error,
+}
+
+/// Acknowledgement published by the firmware on the ack topic.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ack {
+    pub id: String,
+    pub status: AckStatus,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+/// Requests waiting for the firmware to acknowledge their command, by request id.
+#[derive(Default)]
+pub struct AckWaiters {
+    waiters: Mutex<HashMap<String, oneshot::Sender<Ack>>>,
+}
+
+impl AckWaiters {
+    /// Starts listening for the ack of `id`. Register before publishing so a
+    /// fast ack can't be missed.
+    pub async fn register(&self, id: &str) -> oneshot::Receiver<Ack> {
+        let (sender, receiver) = oneshot::channel();
+        self.waiters.lock().await.insert(id.to_string(), sender);
+        receiver
+    }
+
+    /// Hands an incoming ack to its waiter. Returns false if nobody was waiting,
+    /// e.g. because the request already timed out.
+    pub async fn resolve(&self, ack: Ack) -> bool {
+        match self.waiters.lock().await.remove(&ack.id) {
+            Some(sender) => sender.send(ack).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Stops listening for the ack of `id`, when its message never went out.
+    pub async fn forget(&self, id: &str) {
+        self.waiters.lock().await.remove(id);
+    }
+
+    /// Waits up to `timeout` for the ack registered as `id`; `None` on timeout.
+    pub async fn wait(
+        &self,
+        id: &str,
+        receiver: oneshot::Receiver<Ack>,
+        timeout: Duration,
+    ) -> Option<Ack> {
+        match tokio::time::timeout(timeout, receiver).await {
+            Ok(Ok(ack)) => Some(ack),
+            _ => {
+                self.waiters.lock().await.remove(id);
+                None
+            }
+        }
+    }
+}
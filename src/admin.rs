@@ -0,0 +1,52 @@
+use axum::{
+    Json,
+    http::{HeaderMap, StatusCode, header::AUTHORIZATION},
+};
+
+use crate::auth;
+use crate::config::Config;
+use crate::login::{self, Role};
+
+/// Checks that the request carries `Authorization: Bearer <SVEN_ADMIN_TOKEN>` or
+/// is logged in with an admin account. Admin routes are disabled entirely when
+/// there is neither an admin token nor an admin account.
+pub fn require_admin(
+    headers: &HeaderMap,
+    config: &Config,
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    let admin_accounts = config
+        .accounts
+        .values()
+        .any(|account| account.role == Role::Admin);
+    if config.admin_token.is_none() && !admin_accounts {
+        return Err((
+            StatusCode::FORBIDDEN,
+            Json(serde_json::json!({"error": "Admin routes are disabled, set SVEN_ADMIN_TOKEN"})),
+        ));
+    }
+
+    let provided = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match provided {
+        Some(token)
+            if config
+                .admin_token
+                .as_deref()
+                .is_some_and(|admin_token| auth::secrets_match(token, admin_token)) =>
+        {
+            Ok(())
+        }
+        None if login::session(headers, config)
+            .is_some_and(|session| session.role == Role::Admin) =>
+        {
+            Ok(())
+        }
+        _ => Err((
+            StatusCode::UNAUTHORIZED,
+            Json(serde_json::json!({"error": "Invalid or missing admin token"})),
+        )),
+    }
+}
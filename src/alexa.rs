@@ -0,0 +1,386 @@
+//! Alexa Smart Home skill endpoint. The skill's Lambda forwards each directive to
+//! `POST /api/alexa` unchanged and returns the answer, so the desk shows up in
+//! discovery as one endpoint with a height range controller. The range runs from
+//! 0 to 100 percent of the soft limits, and the shared presets are offered as
+//! named values, so "Alexa, set the desk to standing" moves to the `standing`
+//! preset. Directives carry the account-linking token, which has to be one of
+//! `SVEN_ALEXA_TOKENS` or be reported active by the OAuth server's introspection
+//! endpoint at `SVEN_ALEXA_INTROSPECTION_URL`. The route is only served when one
+//! of them is set.
+
+use axum::{Json, extract::Extension, response::IntoResponse};
+use chrono::Utc;
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::{AppState, DeskCommand, SvenCommand, auth, limits};
+
+const ENDPOINT_ID: &str = "sven-desk";
+const INSTANCE: &str = "Desk.Height";
+const INTROSPECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Deserialize)]
+pub struct Envelope {
+    directive: Directive,
+}
+
+#[derive(Debug, Deserialize)]
+struct Directive {
+    header: Header,
+    #[serde(default)]
+    endpoint: Option<Endpoint>,
+    #[serde(default)]
+    payload: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Header {
+    namespace: String,
+    name: String,
+    #[serde(default)]
+    correlation_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Endpoint {
+    #[serde(default)]
+    scope: Option<Scope>,
+    endpoint_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Scope {
+    token: String,
+}
+
+/// Errors answered with an `ErrorResponse` event, by Alexa error type.
+struct AlexaError(&'static str, String);
+
+impl Directive {
+    /// The account-linking token, which discovery carries in its payload and
+    /// other directives on their endpoint.
+    fn token(&self) -> Option<&str> {
+        self.endpoint
+            .as_ref()
+            .and_then(|endpoint| endpoint.scope.as_ref())
+            .map(|scope| scope.token.as_str())
+            .or_else(|| self.payload["scope"]["token"].as_str())
+    }
+}
+
+/// The range the percentages span, the soft limits within the travel range.
+fn range_mm(app_state: &AppState) -> (u32, u32) {
+    let limits = limits::limits_for(&app_state.config(), None);
+    (
+        limits
+            .min_height_mm
+            .unwrap_or(app_state.config().min_height_mm),
+        limits
+            .max_height_mm
+            .unwrap_or(app_state.config().max_height_mm),
+    )
+}
+
+fn to_percent((min_mm, max_mm): (u32, u32), height_mm: u32) -> u32 {
+    if max_mm <= min_mm {
+        return 0;
+    }
+    let height_mm = height_mm.clamp(min_mm, max_mm);
+    ((height_mm - min_mm) as f64 * 100.0 / (max_mm - min_mm) as f64).round() as u32
+}
+
+fn to_height_mm((min_mm, max_mm): (u32, u32), percent: u32) -> u32 {
+    let percent = percent.min(100);
+    min_mm + ((max_mm - min_mm) as f64 * percent as f64 / 100.0).round() as u32
+}
+
+/// Checks an account-linking token against the configured tokens, then against
+/// the introspection endpoint.
+async fn validate_token(app_state: &AppState, token: Option<&str>) -> Result<(), AlexaError> {
+    let invalid = |message: &str| AlexaError("INVALID_AUTHORIZATION_CREDENTIAL", message.into());
+    let Some(token) = token else {
+        return Err(invalid("The directive carries no token"));
+    };
+    if app_state
+        .config()
+        .alexa_tokens
+        .iter()
+        .any(|accepted| auth::secrets_match(token, accepted))
+    {
+        return Ok(());
+    }
+    let Some(url) = &app_state.config().alexa_introspection_url else {
+        return Err(invalid("Unknown token"));
+    };
+    let introspection = app_state
+        .http_client
+        .post(url)
+        .form(&[("token", token)])
+        .timeout(INTROSPECTION_TIMEOUT)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+    let body: serde_json::Value = match introspection {
+        Ok(response) => response.json().await.unwrap_or_default(),
+        Err(e) => {
+            warn!("Token introspection at {} failed: {}", url, e);
+            return Err(AlexaError(
+                "INTERNAL_ERROR",
+                "The token could not be checked".to_string(),
+            ));
+        }
+    };
+    if body["active"] == true {
+        Ok(())
+    } else {
+        Err(invalid("The token is not active"))
+    }
+}
+
+fn event(
+    namespace: &str,
+    name: &str,
+    correlation_token: Option<&str>,
+    endpoint: bool,
+    payload: serde_json::Value,
+) -> serde_json::Value {
+    let mut header = serde_json::json!({
+        "namespace": namespace,
+        "name": name,
+        "messageId": uuid::Uuid::new_v4().to_string(),
+        "payloadVersion": "3",
+    });
+    if let Some(token) = correlation_token {
+        header["correlationToken"] = token.into();
+    }
+    let mut event = serde_json::json!({"header": header, "payload": payload});
+    if endpoint {
+        event["endpoint"] = serde_json::json!({"endpointId": ENDPOINT_ID});
+    }
+    serde_json::json!({"event": event})
+}
+
+async fn context(app_state: &AppState) -> serde_json::Value {
+    let height_mm = app_state.sven_state.read().await.height_mm;
+    let connected = app_state.reconnect_monitor.lock().await.is_connected();
+    let now = Utc::now().to_rfc3339();
+    serde_json::json!({
+        "properties": [
+            {
+                "namespace": "Alexa.RangeController",
+                "instance": INSTANCE,
+                "name": "rangeValue",
+                "value": to_percent(range_mm(app_state), height_mm),
+                "timeOfSample": now,
+                "uncertaintyInMilliseconds": 500,
+            },
+            {
+                "namespace": "Alexa.EndpointHealth",
+                "name": "connectivity",
+                "value": {"value": if connected { "OK" } else { "UNREACHABLE" }},
+                "timeOfSample": now,
+                "uncertaintyInMilliseconds": 0,
+            },
+        ],
+    })
+}
+
+async fn discovery(app_state: &AppState) -> serde_json::Value {
+    let range = range_mm(app_state);
+    let text = |text: &str| serde_json::json!({"@type": "text", "value": {"text": text, "locale": "en-US"}});
+    let presets: Vec<serde_json::Value> = app_state
+        .presets
+        .lock()
+        .await
+        .0
+        .values()
+        .filter(|preset| preset.user.is_none())
+        .map(|preset| {
+            serde_json::json!({
+                "rangeValue": to_percent(range, preset.height_mm),
+                "presetResources": {"friendlyNames": [text(&preset.name)]},
+            })
+        })
+        .collect();
+    let endpoint = serde_json::json!({
+        "endpointId": ENDPOINT_ID,
+        "manufacturerName": "sven-api",
+        "friendlyName": app_state.config().alexa_device_name,
+        "description": "Standing desk",
+        "displayCategories": ["OTHER"],
+        "capabilities": [
+            {"type": "AlexaInterface", "interface": "Alexa", "version": "3"},
+            {
+                "type": "AlexaInterface",
+                "interface": "Alexa.RangeController",
+                "instance": INSTANCE,
+                "version": "3",
+                "properties": {
+                    "supported": [{"name": "rangeValue"}],
+                    "proactivelyReported": false,
+                    "retrievable": true,
+                },
+                "capabilityResources": {"friendlyNames": [text("height"), text("position")]},
+                "configuration": {
+                    "supportedRange": {"minimumValue": 0, "maximumValue": 100, "precision": 1},
+                    "unitOfMeasure": "Alexa.Unit.Percent",
+                    "presets": presets,
+                },
+            },
+            {
+                "type": "AlexaInterface",
+                "interface": "Alexa.EndpointHealth",
+                "version": "3",
+                "properties": {
+                    "supported": [{"name": "connectivity"}],
+                    "proactivelyReported": false,
+                    "retrievable": true,
+                },
+            },
+        ],
+    });
+    event(
+        "Alexa.Discovery",
+        "Discover.Response",
+        None,
+        false,
+        serde_json::json!({"endpoints": [endpoint]}),
+    )
+}
+
+/// Moves the desk to `percent` of the range.
+async fn set_percent(app_state: &Arc<AppState>, percent: u32) -> Result<(), AlexaError> {
+    let height_mm = to_height_mm(range_mm(app_state), percent);
+    info!("Alexa moves the desk to {}% ({} mm)", percent, height_mm);
+    app_state
+        .send_automated(DeskCommand {
+            command: SvenCommand::AbsoluteHeight,
+            value: height_mm,
+        })
+        .await
+        .map_err(|e| AlexaError("ENDPOINT_UNREACHABLE", e))
+}
+
+async fn handle(
+    app_state: &Arc<AppState>,
+    directive: &Directive,
+) -> Result<serde_json::Value, AlexaError> {
+    validate_token(app_state, directive.token()).await?;
+    let header = &directive.header;
+    if header.namespace == "Alexa.Discovery" && header.name == "Discover" {
+        return Ok(discovery(app_state).await);
+    }
+    if directive
+        .endpoint
+        .as_ref()
+        .is_some_and(|endpoint| endpoint.endpoint_id != ENDPOINT_ID)
+    {
+        return Err(AlexaError(
+            "NO_SUCH_ENDPOINT",
+            "Unknown endpoint".to_string(),
+        ));
+    }
+
+    let correlation_token = header.correlation_token.as_deref();
+    let name = match (header.namespace.as_str(), header.name.as_str()) {
+        ("Alexa", "ReportState") => "StateReport",
+        ("Alexa.RangeController", "SetRangeValue") => {
+            let Some(percent) = directive.payload["rangeValue"].as_f64() else {
+                return Err(AlexaError(
+                    "INVALID_VALUE",
+                    "rangeValue is missing".to_string(),
+                ));
+            };
+            if !(0.0..=100.0).contains(&percent) {
+                return Err(AlexaError(
+                    "VALUE_OUT_OF_RANGE",
+                    format!("{} is outside 0 to 100", percent),
+                ));
+            }
+            set_percent(app_state, percent.round() as u32).await?;
+            "Response"
+        }
+        ("Alexa.RangeController", "AdjustRangeValue") => {
+            let Some(delta) = directive.payload["rangeValueDelta"].as_f64() else {
+                return Err(AlexaError(
+                    "INVALID_VALUE",
+                    "rangeValueDelta is missing".to_string(),
+                ));
+            };
+            let height_mm = app_state.sven_state.read().await.height_mm;
+            let current = to_percent(range_mm(app_state), height_mm) as f64;
+            set_percent(
+                app_state,
+                (current + delta).clamp(0.0, 100.0).round() as u32,
+            )
+            .await?;
+            "Response"
+        }
+        (namespace, name) => {
+            return Err(AlexaError(
+                "INVALID_DIRECTIVE",
+                format!("{}.{} is not supported", namespace, name),
+            ));
+        }
+    };
+    let mut response = event(
+        "Alexa",
+        name,
+        correlation_token,
+        true,
+        serde_json::json!({}),
+    );
+    response["context"] = context(app_state).await;
+    Ok(response)
+}
+
+/// Answers one Smart Home directive. Alexa expects an event in every case, so
+/// failures are `ErrorResponse` events rather than HTTP errors.
+#[utoipa::path(
+    post,
+    path = "/api/alexa",
+    tag = "integrations",
+    request_body(content = serde_json::Value, description = "Alexa Smart Home directive, as the skill receives it"),
+    responses((status = 200, description = "Alexa event answering the directive", body = serde_json::Value)),
+)]
+pub async fn handle_directive(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Json(envelope): Json<Envelope>,
+) -> impl IntoResponse {
+    let directive = envelope.directive;
+    match handle(&app_state, &directive).await {
+        Ok(response) => Json(response),
+        Err(AlexaError(kind, message)) => {
+            warn!(
+                "Alexa {}.{} failed: {} ({})",
+                directive.header.namespace, directive.header.name, message, kind
+            );
+            Json(event(
+                "Alexa",
+                "ErrorResponse",
+                directive.header.correlation_token.as_deref(),
+                directive.endpoint.is_some(),
+                serde_json::json!({"type": kind, "message": message}),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentages_span_the_range() {
+        let range = (650, 1250);
+        assert_eq!(to_height_mm(range, 0), 650);
+        assert_eq!(to_height_mm(range, 50), 950);
+        assert_eq!(to_height_mm(range, 150), 1250);
+        assert_eq!(to_percent(range, 1100), 75);
+        assert_eq!(to_percent(range, 600), 0);
+    }
+}
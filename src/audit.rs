@@ -0,0 +1,278 @@
+//! Audit trail of every request that changes something: who made it (API key and
+//! user), what it asked for, when, from which address and how it ended. Requests
+//! refused by authentication are recorded too. Entries are kept in the history
+//! database when one is configured, otherwise the last `SVEN_AUDIT_CAPACITY` are
+//! kept in memory, and `GET /api/sven/audit/export` hands them out as CSV or JSON.
+
+use axum::{
+    Json,
+    body::Body,
+    extract::{ConnectInfo, Extension, Query, Request},
+    http::{HeaderValue, Method, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use tracing::error;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::listen::ClientAddr;
+use crate::validation::ErrorBody;
+use crate::{AppState, auth, request_id, users};
+
+/// JSON bodies up to this size are kept as the entry's `detail`.
+const MAX_DETAIL_BYTES: usize = 2048;
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct AuditEntry {
+    pub id: u64,
+    pub timestamp: DateTime<Utc>,
+    pub request_id: String,
+    /// Name of the API key the request authenticated with
+    pub key: Option<String>,
+    pub user: Option<String>,
+    /// Address the request came from
+    pub client: Option<String>,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    /// The request body, for JSON bodies small enough to keep
+    pub detail: Option<String>,
+}
+
+/// The most recent entries, when there is no history database to keep them in.
+pub struct AuditLog {
+    capacity: usize,
+    next_id: u64,
+    entries: VecDeque<AuditEntry>,
+}
+
+impl AuditLog {
+    pub fn new(capacity: usize) -> Self {
+        AuditLog {
+            capacity,
+            next_id: 1,
+            entries: VecDeque::new(),
+        }
+    }
+
+    fn record(&mut self, mut entry: AuditEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+        while self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        entry.id = self.next_id;
+        self.next_id += 1;
+        self.entries.push_back(entry);
+    }
+
+    fn query(&self, query: &ExportQuery) -> Vec<AuditEntry> {
+        self.entries
+            .iter()
+            .filter(|entry| query.matches(entry.timestamp))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Records requests that aren't plain reads, with the body when it is small JSON.
+pub async fn record(request: Request, next: Next) -> Response {
+    let Some(app_state) = request.extensions().get::<Arc<AppState>>().cloned() else {
+        return next.run(request).await;
+    };
+    if matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    ) {
+        return next.run(request).await;
+    }
+
+    let headers = request.headers().clone();
+    let client = request
+        .extensions()
+        .get::<ConnectInfo<ClientAddr>>()
+        .map(|ConnectInfo(remote)| remote.key());
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
+    let is_json = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("application/json"));
+    let small = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|length| length.parse::<usize>().ok())
+        .is_some_and(|length| length <= MAX_DETAIL_BYTES);
+
+    let (request, detail) = if is_json && small {
+        let (parts, body) = request.into_parts();
+        match axum::body::to_bytes(body, MAX_DETAIL_BYTES).await {
+            Ok(bytes) => {
+                let detail = String::from_utf8_lossy(&bytes).into_owned();
+                (Request::from_parts(parts, Body::from(bytes)), Some(detail))
+            }
+            Err(_) => return StatusCode::BAD_REQUEST.into_response(),
+        }
+    } else {
+        (request, None)
+    };
+
+    let response = next.run(request).await;
+    let entry = AuditEntry {
+        id: 0,
+        timestamp: Utc::now(),
+        request_id: request_id::from_headers(&headers),
+        key: auth::presented_key_name(&headers, &app_state.config()),
+        user: users::acting_user(&app_state, &headers).await,
+        client,
+        method,
+        path,
+        status: response.status().as_u16(),
+        detail,
+    };
+    match &app_state.history_db {
+        Some(db) => {
+            if let Err(e) = db.record_audit(&entry) {
+                error!("Failed to record audit entry: {}", e);
+            }
+        }
+        None => app_state.audit.lock().await.record(entry),
+    }
+    response
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct ExportQuery {
+    #[serde(default)]
+    pub format: ExportFormat,
+    /// Only entries at or after this time
+    pub since: Option<DateTime<Utc>>,
+    /// Only entries before this time
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl ExportQuery {
+    pub fn matches(&self, timestamp: DateTime<Utc>) -> bool {
+        self.since.is_none_or(|since| timestamp >= since)
+            && self.until.is_none_or(|until| timestamp < until)
+    }
+}
+
+/// Quotes a CSV field when it holds a separator, quote or line break.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn to_csv(entries: &[AuditEntry]) -> String {
+    let mut csv =
+        String::from("id,timestamp,request_id,key,user,client,method,path,status,detail\n");
+    for entry in entries {
+        let fields = [
+            entry.id.to_string(),
+            entry.timestamp.to_rfc3339(),
+            entry.request_id.clone(),
+            entry.key.clone().unwrap_or_default(),
+            entry.user.clone().unwrap_or_default(),
+            entry.client.clone().unwrap_or_default(),
+            entry.method.clone(),
+            entry.path.clone(),
+            entry.status.to_string(),
+            entry.detail.clone().unwrap_or_default(),
+        ];
+        let row: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+/// The audit trail, oldest first, as a JSON array or a CSV download.
+#[utoipa::path(
+    get,
+    path = "/api/sven/audit/export",
+    tag = "history",
+    params(ExportQuery),
+    responses(
+        (status = 200, description = "Audit entries as JSON or CSV", body = Vec<AuditEntry>),
+        (status = 500, description = "The history database could not be read", body = ErrorBody),
+    ),
+)]
+pub async fn export_audit(
+    Query(query): Query<ExportQuery>,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> Response {
+    let entries = match &app_state.history_db {
+        Some(db) => match db.audit_entries(query.since, query.until) {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("Failed to read the audit log: {}", e);
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(serde_json::json!({"error": "Failed to read the audit log"})),
+                )
+                    .into_response();
+            }
+        },
+        None => app_state.audit.lock().await.query(&query),
+    };
+    match query.format {
+        ExportFormat::Json => Json(entries).into_response(),
+        ExportFormat::Csv => (
+            [
+                (
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static("text/csv; charset=utf-8"),
+                ),
+                (
+                    header::CONTENT_DISPOSITION,
+                    HeaderValue::from_static("attachment; filename=\"sven-audit.csv\""),
+                ),
+            ],
+            to_csv(&entries),
+        )
+            .into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_quotes_fields_that_need_it() {
+        let entry = AuditEntry {
+            id: 1,
+            timestamp: "2026-03-02T08:15:00Z".parse().unwrap(),
+            request_id: "r1".to_string(),
+            key: Some("kitchen".to_string()),
+            user: None,
+            client: Some("192.168.1.20".to_string()),
+            method: "POST".to_string(),
+            path: "/api/sven/command".to_string(),
+            status: 200,
+            detail: Some(r#"{"command":"AbsoluteHeight","value":900}"#.to_string()),
+        };
+        let csv = to_csv(&[entry]);
+        assert_eq!(
+            csv.lines().nth(1).unwrap(),
+            r#"1,2026-03-02T08:15:00+00:00,r1,kitchen,,192.168.1.20,POST,/api/sven/command,200,"{""command"":""AbsoluteHeight"",""value"":900}""#
+        );
+    }
+}
@@ -0,0 +1,277 @@
+use axum::{
+    Json,
+    extract::Request,
+    http::{
+        HeaderMap, HeaderValue, Method, StatusCode,
+        header::{AUTHORIZATION, LOCATION},
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use ring::hmac;
+use ring::rand::SystemRandom;
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
+
+use crate::AppState;
+use crate::config::Config;
+use crate::login::{self, Role};
+
+/// Routes that stay reachable without a key: probes, the API description,
+/// discovery and everything needed to log in.
+const OPEN_PATHS: &[&str] = &[
+    "/healthz",
+    "/readyz",
+    "/api/openapi.json",
+    "/api/docs",
+    "/",
+    "/login",
+    "/ui/app.js",
+    "/ui/style.css",
+    "/api/login",
+    "/api/logout",
+    "/api/alexa",
+    "/api/discovery",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// GET and HEAD requests only
+    ReadOnly,
+    Full,
+}
+
+/// A static API key from `SVEN_API_KEYS`, written `token` or `token:read-only`.
+#[derive(Debug, Clone)]
+pub struct ApiKey {
+    pub token: String,
+    pub access: Access,
+}
+
+impl FromStr for ApiKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (token, access) = match s.rsplit_once(':') {
+            Some((token, "read-only" | "ro")) => (token, Access::ReadOnly),
+            Some((token, "full" | "rw")) => (token, Access::Full),
+            Some((_, other)) => return Err(format!("unknown access '{}'", other)),
+            None => (s, Access::Full),
+        };
+        if token.is_empty() {
+            return Err("API key token must not be empty".to_string());
+        }
+        Ok(ApiKey {
+            token: token.to_string(),
+            access,
+        })
+    }
+}
+
+/// Name of the API key a request authenticated with, added to the request's extensions.
+#[derive(Debug, Clone)]
+pub struct ApiKeyId(pub String);
+
+/// The key a request presents, as `Authorization: Bearer <token>` or `X-Api-Key: <token>`.
+fn presented_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or_else(|| {
+            headers
+                .get("x-api-key")
+                .and_then(|value| value.to_str().ok())
+        })
+}
+
+/// Whether a presented token is `secret`, without the time taken showing how much
+/// of it matched: both are MACed under a key of this process and the tags
+/// compared in constant time.
+pub fn secrets_match(presented: &str, secret: &str) -> bool {
+    static KEY: OnceLock<hmac::Key> = OnceLock::new();
+    let key = KEY.get_or_init(|| {
+        hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new())
+            .expect("Failed to generate a comparison key")
+    });
+    hmac::verify(
+        key,
+        presented.as_bytes(),
+        hmac::sign(key, secret.as_bytes()).as_ref(),
+    )
+    .is_ok()
+}
+
+/// Name and access of the key `token` belongs to, the admin token being named `admin`.
+fn key_for(config: &Config, token: &str) -> Option<(String, Access)> {
+    if config
+        .admin_token
+        .as_deref()
+        .is_some_and(|admin_token| secrets_match(token, admin_token))
+    {
+        return Some(("admin".to_string(), Access::Full));
+    }
+    config
+        .api_keys
+        .iter()
+        .find(|(_, key)| secrets_match(token, &key.token))
+        .map(|(name, key)| (name.clone(), key.access))
+}
+
+/// Name of the valid key a request presents, or of the account it is logged in as.
+pub fn presented_key_name(headers: &HeaderMap, config: &Config) -> Option<String> {
+    match presented_token(headers) {
+        Some(token) => key_for(config, token).map(|(name, _)| name),
+        None => login::session(headers, config).map(|session| session.name),
+    }
+}
+
+/// Whether the API needs a key or login at all.
+pub fn protected(config: &Config) -> bool {
+    !config.api_keys.is_empty() || !config.accounts.is_empty()
+}
+
+/// Checks the key or session `headers` present for a reading or, with `write`, a
+/// changing call: the key's or account's name, `None` while neither keys nor
+/// accounts are configured, or the status, code and message to refuse the call
+/// with.
+pub fn authorize(
+    headers: &HeaderMap,
+    config: &Config,
+    write: bool,
+) -> Result<Option<String>, (StatusCode, &'static str, String)> {
+    if !protected(config) {
+        return Ok(None);
+    }
+    let Some(token) = presented_token(headers) else {
+        let Some(session) = login::session(headers, config) else {
+            return Err((
+                StatusCode::UNAUTHORIZED,
+                "missing_api_key",
+                "An API key or login is required".to_string(),
+            ));
+        };
+        if session.role == Role::Viewer && write {
+            return Err((
+                StatusCode::FORBIDDEN,
+                "viewer_role",
+                format!("'{}' may only view", session.name),
+            ));
+        }
+        return Ok(Some(session.name));
+    };
+    let Some((name, access)) = key_for(config, token) else {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            "invalid_api_key",
+            "Invalid API key".to_string(),
+        ));
+    };
+    if access == Access::ReadOnly && write {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "read_only_key",
+            format!("API key '{}' is read-only", name),
+        ));
+    }
+    Ok(Some(name))
+}
+
+fn rejection(status: StatusCode, code: &str, message: String) -> Response {
+    let mut response = (
+        status,
+        Json(serde_json::json!({"error": message, "code": code})),
+    )
+        .into_response();
+    if status == StatusCode::UNAUTHORIZED {
+        response
+            .headers_mut()
+            .insert("www-authenticate", HeaderValue::from_static("Bearer"));
+    }
+    response
+}
+
+/// Requires a configured API key, an issued token or a login on every route but
+/// the open ones. Read-only keys and viewers may only read, issued tokens only use
+/// their scopes, see `tokens`. The admin token counts as a full access key, so
+/// admin routes keep working with it alone. Without any keys, tokens or accounts
+/// the API stays open. With accounts, the page at `/` sends whoever isn't logged
+/// in to `/login`.
+pub async fn require_api_key(mut request: Request, next: Next) -> Response {
+    let app_state = request
+        .extensions()
+        .get::<Arc<AppState>>()
+        .cloned()
+        .expect("AppState extension must be layered outside the auth middleware");
+    let config = &app_state.config();
+    if config.ui
+        && !config.accounts.is_empty()
+        && request.uri().path() == "/"
+        && authorize(request.headers(), config, false).is_err()
+    {
+        return (StatusCode::SEE_OTHER, [(LOCATION, "/login")]).into_response();
+    }
+    let tokens = app_state.tokens.lock().await;
+    let tokens_issued = !tokens.0.is_empty();
+    let scoped = presented_token(request.headers())
+        .and_then(|token| tokens.find(token))
+        .cloned();
+    drop(tokens);
+    if !(protected(config) || tokens_issued)
+        || request.method() == Method::OPTIONS
+        || OPEN_PATHS.contains(&request.uri().path())
+    {
+        return next.run(request).await;
+    }
+
+    if let Some(token) = scoped {
+        if !token.permits(request.method(), request.uri().path()) {
+            return rejection(
+                StatusCode::FORBIDDEN,
+                "out_of_scope",
+                format!(
+                    "Token '{}' doesn't allow {} {}",
+                    token.name,
+                    request.method(),
+                    request.uri().path()
+                ),
+            );
+        }
+        request.extensions_mut().insert(ApiKeyId(token.name));
+        return next.run(request).await;
+    }
+
+    let reads = matches!(*request.method(), Method::GET | Method::HEAD);
+    let name = if protected(config) {
+        match authorize(request.headers(), config, !reads) {
+            Ok(name) => name,
+            Err((status, code, message)) => return rejection(status, code, message),
+        }
+    } else {
+        // Only tokens are issued, the admin token is the one other key
+        match presented_token(request.headers()) {
+            Some(token) => match key_for(config, token) {
+                Some((name, _)) => Some(name),
+                None => {
+                    return rejection(
+                        StatusCode::UNAUTHORIZED,
+                        "invalid_api_key",
+                        "Invalid API key".to_string(),
+                    );
+                }
+            },
+            None => {
+                return rejection(
+                    StatusCode::UNAUTHORIZED,
+                    "missing_api_key",
+                    "An API key or login is required".to_string(),
+                );
+            }
+        }
+    };
+
+    if let Some(name) = name {
+        request.extensions_mut().insert(ApiKeyId(name));
+    }
+    next.run(request).await
+}
@@ -0,0 +1,345 @@
+//! Moving the bridge to another machine without setting it up again:
+//! `GET /api/admin/backup` answers one JSON archive of the presets, calibrated
+//! positions, schedules, rules, macros, users, reminder settings, webhooks, quick
+//! actions and the config file, and `POST /api/admin/restore` takes it back.
+//! Both need the admin token. A restore replaces what the archive contains and
+//! leaves everything else alone; a restored config file only takes effect after
+//! a restart.
+
+use axum::{
+    Json,
+    extract::{Extension, rejection::JsonRejection},
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::{error, info, warn};
+use utoipa::ToSchema;
+
+use crate::config::DEFAULT_CONFIG_FILE;
+use crate::macros::Macros;
+use crate::persistence::Artifact;
+use crate::positions::PositionHeights;
+use crate::presets::Presets;
+use crate::quick::QuickActions;
+use crate::reminders::ReminderSettings;
+use crate::rules::{self, Rules};
+use crate::schedules::Schedules;
+use crate::tokens::Tokens;
+use crate::users::Users;
+use crate::validation::ErrorBody;
+use crate::webhooks::Webhooks;
+use crate::{AppState, admin};
+
+/// The archive format written by this version; newer ones are refused.
+const FORMAT: u32 = 1;
+
+type Rejection = (StatusCode, Json<serde_json::Value>);
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct Backup {
+    pub format: u32,
+    pub created_at: DateTime<Utc>,
+    /// Version of the service that wrote it
+    pub version: String,
+    /// Stored data by key: `presets`, `positions`, `schedules`, `rules`, `macros`,
+    /// `users`, `reminders`, `webhooks`, `quick_actions` and `tokens`
+    #[serde(default)]
+    pub artifacts: BTreeMap<String, serde_json::Value>,
+    /// Contents of the config file, if the service was started with one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config_file: Option<String>,
+}
+
+fn invalid(message: String) -> Rejection {
+    (
+        StatusCode::UNPROCESSABLE_ENTITY,
+        Json(serde_json::json!(ErrorBody {
+            error: message,
+            code: Some("invalid_backup".to_string()),
+        })),
+    )
+}
+
+/// Takes the artifact `T` out of the archive, checking it reads as one.
+fn take<T: Artifact>(
+    artifacts: &mut BTreeMap<String, serde_json::Value>,
+) -> Result<Option<T>, Rejection> {
+    artifacts
+        .remove(T::KEY)
+        .map(|value| {
+            serde_json::from_value(value)
+                .map_err(|e| invalid(format!("The backup's {} can't be read: {}", T::KEY, e)))
+        })
+        .transpose()
+}
+
+/// The artifacts of an archive, read before any of them is restored.
+struct Archived {
+    presets: Option<Presets>,
+    positions: Option<PositionHeights>,
+    schedules: Option<Schedules>,
+    rules: Option<Rules>,
+    macros: Option<Macros>,
+    users: Option<Users>,
+    reminders: Option<ReminderSettings>,
+    webhooks: Option<Webhooks>,
+    quick_actions: Option<QuickActions>,
+    tokens: Option<Tokens>,
+}
+
+impl Archived {
+    /// Leaves the keys it doesn't know in `artifacts`.
+    fn read(artifacts: &mut BTreeMap<String, serde_json::Value>) -> Result<Archived, Rejection> {
+        Ok(Archived {
+            presets: take(artifacts)?,
+            positions: take(artifacts)?,
+            schedules: take(artifacts)?,
+            rules: take(artifacts)?,
+            macros: take(artifacts)?,
+            users: take(artifacts)?,
+            reminders: take(artifacts)?,
+            webhooks: take(artifacts)?,
+            quick_actions: take(artifacts)?,
+            tokens: take(artifacts)?,
+        })
+    }
+}
+
+fn persist<T: Artifact>(app_state: &AppState, value: &T) {
+    if let Some(store) = &app_state.store
+        && let Err(e) = store.put(value)
+    {
+        error!("Failed to persist restored {}: {}", T::KEY, e);
+    }
+}
+
+/// Where a restored config file goes: the file the service was started with, or
+/// `sven.toml` in the working directory, where it is looked for at startup.
+fn config_path(app_state: &AppState) -> PathBuf {
+    app_state
+        .config()
+        .config_file
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_FILE))
+}
+
+/// Everything set up on the bridge as one JSON file.
+#[utoipa::path(
+    get,
+    path = "/api/admin/backup",
+    tag = "admin",
+    responses(
+        (status = 200, description = "The archive, as an attachment", body = Backup),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+        (status = 403, description = "Admin routes are disabled", body = ErrorBody),
+    ),
+)]
+pub async fn get_backup(
+    headers: HeaderMap,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> Response {
+    if let Err(rejection) = admin::require_admin(&headers, &app_state.config()) {
+        return rejection.into_response();
+    }
+    let mut artifacts = BTreeMap::new();
+    artifacts.insert(
+        Presets::KEY.to_string(),
+        serde_json::json!(*app_state.presets.lock().await),
+    );
+    artifacts.insert(
+        PositionHeights::KEY.to_string(),
+        serde_json::json!(*app_state.position_heights.lock().await),
+    );
+    artifacts.insert(
+        Schedules::KEY.to_string(),
+        serde_json::json!(*app_state.schedules.lock().await),
+    );
+    artifacts.insert(
+        Rules::KEY.to_string(),
+        serde_json::json!(*app_state.rules.lock().await),
+    );
+    artifacts.insert(
+        Macros::KEY.to_string(),
+        serde_json::json!(*app_state.macros.lock().await),
+    );
+    artifacts.insert(
+        Users::KEY.to_string(),
+        serde_json::json!(*app_state.users.lock().await),
+    );
+    artifacts.insert(
+        ReminderSettings::KEY.to_string(),
+        serde_json::json!(app_state.reminders.lock().await.settings),
+    );
+    artifacts.insert(
+        Webhooks::KEY.to_string(),
+        serde_json::json!(*app_state.webhooks.lock().await),
+    );
+    artifacts.insert(
+        QuickActions::KEY.to_string(),
+        serde_json::json!(*app_state.quick_actions.lock().await),
+    );
+    artifacts.insert(
+        Tokens::KEY.to_string(),
+        serde_json::json!(*app_state.tokens.lock().await),
+    );
+    let config_file = app_state.config().config_file.as_ref().and_then(|path| {
+        std::fs::read_to_string(path)
+            .inspect_err(|e| warn!("Leaving {} out of the backup: {}", path.display(), e))
+            .ok()
+    });
+    let backup = Backup {
+        format: FORMAT,
+        created_at: Utc::now(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        artifacts,
+        config_file,
+    };
+    let filename = format!(
+        "attachment; filename=\"sven-backup-{}.json\"",
+        backup.created_at.format("%Y%m%d-%H%M%S")
+    );
+    (
+        StatusCode::OK,
+        [(header::CONTENT_DISPOSITION, filename)],
+        Json(backup),
+    )
+        .into_response()
+}
+
+/// Restores an archive from `GET /api/admin/backup`. Nothing is changed unless the
+/// whole archive reads.
+#[utoipa::path(
+    post,
+    path = "/api/admin/restore",
+    tag = "admin",
+    request_body = Backup,
+    responses(
+        (status = 200, description = "What was restored and whether a restart is needed", body = serde_json::Value),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+        (status = 403, description = "Admin routes are disabled", body = ErrorBody),
+        (status = 422, description = "Not a readable backup", body = ErrorBody),
+        (status = 500, description = "The config file couldn't be written", body = ErrorBody),
+    ),
+)]
+pub async fn handle_restore(
+    headers: HeaderMap,
+    Extension(app_state): Extension<Arc<AppState>>,
+    request: Result<Json<Backup>, JsonRejection>,
+) -> impl IntoResponse {
+    if let Err(rejection) = admin::require_admin(&headers, &app_state.config()) {
+        return rejection;
+    }
+    let mut backup = match request {
+        Ok(Json(backup)) => backup,
+        Err(rejection) => return invalid(rejection.body_text()),
+    };
+    if backup.format > FORMAT {
+        return invalid(format!(
+            "Backup format {} is newer than this version understands ({})",
+            backup.format, FORMAT
+        ));
+    }
+    let archived = match Archived::read(&mut backup.artifacts) {
+        Ok(archived) => archived,
+        Err(rejection) => return rejection,
+    };
+    if let Some(unknown) = backup.artifacts.keys().next() {
+        return invalid(format!("Unknown artifact '{}' in the backup", unknown));
+    }
+    if let Some(text) = &backup.config_file
+        && let Err(e) = text.parse::<toml::Table>()
+    {
+        return invalid(format!("The backup's config file can't be read: {}", e));
+    }
+
+    // The config file first, it is the only part that can still fail
+    let restart_required = backup.config_file.is_some();
+    if let Some(text) = &backup.config_file {
+        let path = config_path(&app_state);
+        if let Err(e) = std::fs::write(&path, text) {
+            error!("Failed to restore {}: {}", path.display(), e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({
+                    "error": format!("Failed to write {}: {}", path.display(), e),
+                    "code": "restore_failed",
+                })),
+            );
+        }
+    }
+
+    let mut restored = Vec::new();
+    if let Some(presets) = archived.presets {
+        persist(&app_state, &presets);
+        *app_state.presets.lock().await = presets;
+        restored.push(Presets::KEY);
+    }
+    if let Some(positions) = archived.positions {
+        persist(&app_state, &positions);
+        *app_state.position_heights.lock().await = positions;
+        restored.push(PositionHeights::KEY);
+    }
+    if let Some(schedules) = archived.schedules {
+        persist(&app_state, &schedules);
+        *app_state.schedules.lock().await = schedules;
+        restored.push(Schedules::KEY);
+    }
+    if let Some(restored_rules) = archived.rules {
+        persist(&app_state, &restored_rules);
+        for rule in restored_rules.0.values() {
+            rules::subscribe(&app_state, &rule.trigger).await;
+        }
+        *app_state.rules.lock().await = restored_rules;
+        restored.push(Rules::KEY);
+    }
+    if let Some(macros) = archived.macros {
+        persist(&app_state, &macros);
+        *app_state.macros.lock().await = macros;
+        restored.push(Macros::KEY);
+    }
+    if let Some(users) = archived.users {
+        let users = users.with_configured(&app_state.config());
+        persist(&app_state, &users);
+        *app_state.users.lock().await = users;
+        restored.push(Users::KEY);
+    }
+    if let Some(reminders) = archived.reminders {
+        persist(&app_state, &reminders);
+        app_state.reminders.lock().await.settings = reminders;
+        restored.push(ReminderSettings::KEY);
+    }
+    if let Some(webhooks) = archived.webhooks {
+        persist(&app_state, &webhooks);
+        *app_state.webhooks.lock().await = webhooks;
+        restored.push(Webhooks::KEY);
+    }
+    if let Some(quick_actions) = archived.quick_actions {
+        persist(&app_state, &quick_actions);
+        *app_state.quick_actions.lock().await = quick_actions;
+        restored.push(QuickActions::KEY);
+    }
+    if let Some(tokens) = archived.tokens {
+        persist(&app_state, &tokens);
+        *app_state.tokens.lock().await = tokens;
+        restored.push(Tokens::KEY);
+    }
+    info!(
+        "Restored a backup from {} (version {}): {}",
+        backup.created_at,
+        backup.version,
+        restored.join(", ")
+    );
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "status": "Backup restored",
+            "restored": restored,
+            "restart_required": restart_required,
+        })),
+    )
+}
@@ -0,0 +1,53 @@
+//! Request body limits for the endpoints that take a body from anyone holding a
+//! key: commands get `SVEN_MAX_COMMAND_BODY_BYTES`, firmware uploads
+//! `SVEN_OTA_MAX_BYTES`. A request announcing a larger body is refused with 413
+//! before any of it is read, and one that doesn't announce its length stops being
+//! read at the limit.
+
+use axum::{
+    Json,
+    extract::{DefaultBodyLimit, Request, State},
+    http::{StatusCode, header::CONTENT_LENGTH},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::MethodRouter,
+};
+
+use crate::validation::ErrorBody;
+
+/// The answer to a body over `max_bytes`.
+pub fn too_large(max_bytes: usize) -> (StatusCode, Json<serde_json::Value>) {
+    (
+        StatusCode::PAYLOAD_TOO_LARGE,
+        Json(serde_json::json!(ErrorBody {
+            error: format!("The request body is larger than {} bytes", max_bytes),
+            code: Some("payload_too_large".to_string()),
+        })),
+    )
+}
+
+async fn reject_announced(
+    State(max_bytes): State<usize>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let announced = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<usize>().ok());
+    if announced.is_some_and(|length| length > max_bytes) {
+        return too_large(max_bytes).into_response();
+    }
+    next.run(request).await
+}
+
+/// `route` reading at most `max_bytes` of a request body.
+pub fn limited<S>(route: MethodRouter<S>, max_bytes: usize) -> MethodRouter<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    route
+        .layer(DefaultBodyLimit::max(max_bytes))
+        .layer(middleware::from_fn_with_state(max_bytes, reject_announced))
+}
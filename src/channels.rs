@@ -0,0 +1,239 @@
+//! Channels notifications are forwarded to outside the API, for desk events like
+//! the controller going offline, a height limit being hit or a sitting reminder.
+//! Every notification goes to each configured channel: JSON webhooks
+//! (`SVEN_NOTIFY_WEBHOOKS`), an ntfy topic (`SVEN_NTFY_URL`) and a Telegram chat
+//! (`SVEN_TELEGRAM_BOT_TOKEN` and `SVEN_TELEGRAM_CHAT_ID`), optionally limited to
+//! the notification types in `SVEN_NOTIFY_EVENTS`, such as `reminder` or `goal_met`.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, warn};
+
+use crate::AppState;
+use crate::config::Config;
+use crate::notifications::Notification;
+
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+const TELEGRAM_API_URL: &str = "https://api.telegram.org";
+
+pub type ChannelFuture<'a> = Pin<Box<dyn Future<Output = Result<(), reqwest::Error>> + Send + 'a>>;
+
+pub trait NotificationChannel: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn send<'a>(&'a self, notification: &'a Notification) -> ChannelFuture<'a>;
+}
+
+/// Posts the notification as JSON, as it appears on the notification stream.
+pub struct WebhookChannel {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl WebhookChannel {
+    fn request(&self, notification: &Notification) -> reqwest::RequestBuilder {
+        self.client.post(&self.url).json(notification)
+    }
+}
+
+impl NotificationChannel for WebhookChannel {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    fn send<'a>(&'a self, notification: &'a Notification) -> ChannelFuture<'a> {
+        Box::pin(send(self.request(notification)))
+    }
+}
+
+/// Publishes the message to an ntfy topic, tagged with the notification type.
+pub struct NtfyChannel {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl NtfyChannel {
+    fn request(&self, notification: &Notification) -> reqwest::RequestBuilder {
+        self.client
+            .post(&self.url)
+            .header("Title", "Sven desk")
+            .header("Tags", notification.kind.name())
+            .body(notification.message.clone())
+    }
+}
+
+impl NotificationChannel for NtfyChannel {
+    fn name(&self) -> &'static str {
+        "ntfy"
+    }
+
+    fn send<'a>(&'a self, notification: &'a Notification) -> ChannelFuture<'a> {
+        Box::pin(send(self.request(notification)))
+    }
+}
+
+/// Sends the message to a Telegram chat through the Bot API.
+pub struct TelegramChannel {
+    client: reqwest::Client,
+    bot_token: String,
+    chat_id: String,
+}
+
+impl TelegramChannel {
+    fn request(&self, notification: &Notification) -> reqwest::RequestBuilder {
+        self.client
+            .post(format!(
+                "{}/bot{}/sendMessage",
+                TELEGRAM_API_URL, self.bot_token
+            ))
+            .json(&serde_json::json!({
+                "chat_id": self.chat_id,
+                "text": notification.message,
+            }))
+    }
+}
+
+impl NotificationChannel for TelegramChannel {
+    fn name(&self) -> &'static str {
+        "telegram"
+    }
+
+    fn send<'a>(&'a self, notification: &'a Notification) -> ChannelFuture<'a> {
+        Box::pin(send(self.request(notification)))
+    }
+}
+
+async fn send(request: reqwest::RequestBuilder) -> Result<(), reqwest::Error> {
+    request
+        .timeout(SEND_TIMEOUT)
+        .send()
+        .await?
+        .error_for_status()
+        .map(|_| ())
+}
+
+/// The channels `config` sets up, empty when notifications stay in the API.
+pub fn from_config(config: &Config, client: &reqwest::Client) -> Vec<Box<dyn NotificationChannel>> {
+    let mut channels: Vec<Box<dyn NotificationChannel>> = Vec::new();
+    for url in &config.notify_webhooks {
+        channels.push(Box::new(WebhookChannel {
+            client: client.clone(),
+            url: url.clone(),
+        }));
+    }
+    if let Some(url) = &config.ntfy_url {
+        channels.push(Box::new(NtfyChannel {
+            client: client.clone(),
+            url: url.clone(),
+        }));
+    }
+    if let (Some(bot_token), Some(chat_id)) = (&config.telegram_bot_token, &config.telegram_chat_id)
+    {
+        channels.push(Box::new(TelegramChannel {
+            client: client.clone(),
+            bot_token: bot_token.clone(),
+            chat_id: chat_id.clone(),
+        }));
+    }
+    channels
+}
+
+fn wanted(events: &[String], notification: &Notification) -> bool {
+    events.is_empty() || events.iter().any(|event| event == notification.kind.name())
+}
+
+/// Forwards notifications to the configured channels. A channel
+/// that fails is logged and skipped; the notification isn't retried.
+pub async fn run_channels(app_state: Arc<AppState>) {
+    let channels = from_config(&app_state.config(), &app_state.http_client);
+    if channels.is_empty() {
+        return;
+    }
+    let mut notifications = app_state.notifier.subscribe();
+    loop {
+        let notification = match notifications.recv().await {
+            Ok(notification) => notification,
+            Err(RecvError::Lagged(missed)) => {
+                warn!(
+                    "Notification channels fell behind, {} notifications were dropped",
+                    missed
+                );
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        if !wanted(&app_state.config().notify_events, &notification) {
+            continue;
+        }
+        for channel in &channels {
+            match channel.send(&notification).await {
+                Ok(()) => debug!(
+                    "Sent {} notification to {}",
+                    notification.kind.name(),
+                    channel.name()
+                ),
+                Err(e) => warn!("Failed to send notification to {}: {}", channel.name(), e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notifications::NotificationKind;
+
+    fn offline() -> Notification {
+        Notification {
+            kind: NotificationKind::Watchdog,
+            message: "Desk controller went offline".to_string(),
+            timestamp: "2026-03-02T08:15:00Z".parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn channels_shape_their_requests() {
+        let client = reqwest::Client::new();
+        let ntfy = NtfyChannel {
+            client: client.clone(),
+            url: "https://ntfy.sh/my-desk".to_string(),
+        }
+        .request(&offline())
+        .build()
+        .unwrap();
+        assert_eq!(ntfy.url().as_str(), "https://ntfy.sh/my-desk");
+        assert_eq!(ntfy.headers()["Tags"], "watchdog");
+        assert_eq!(
+            ntfy.body().unwrap().as_bytes().unwrap(),
+            b"Desk controller went offline"
+        );
+
+        let telegram = TelegramChannel {
+            client,
+            bot_token: "123:abc".to_string(),
+            chat_id: "42".to_string(),
+        }
+        .request(&offline())
+        .build()
+        .unwrap();
+        assert_eq!(
+            telegram.url().as_str(),
+            "https://api.telegram.org/bot123:abc/sendMessage"
+        );
+        let body: serde_json::Value =
+            serde_json::from_slice(telegram.body().unwrap().as_bytes().unwrap()).unwrap();
+        assert_eq!(body["chat_id"], "42");
+        assert_eq!(body["text"], "Desk controller went offline");
+    }
+
+    #[test]
+    fn events_filter_by_notification_type() {
+        assert!(wanted(&[], &offline()));
+        assert!(wanted(&["watchdog".to_string()], &offline()));
+        assert!(!wanted(&["height_limit".to_string()], &offline()));
+    }
+}
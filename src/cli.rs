@@ -0,0 +1,213 @@
+//! `sven-api client ...` controls a running instance over HTTP, for shell scripts:
+//!
+//! ```text
+//! sven-api client state
+//! sven-api client move --height 1100
+//! sven-api client move --up 50
+//! sven-api client preset standing
+//! sven-api client command SetSpeed 80
+//! sven-api client stop
+//! ```
+//!
+//! The instance is `--url` or `SVEN_URL`, `http://localhost:3001` by default, and
+//! the API key `--key` or `SVEN_API_KEY`. Calls go through `SvenClient`. The response body is printed as JSON; an
+//! error response exits with 1 after printing its message, bad usage with 2.
+//!
+//! `sven-api hash-password` reads a password from stdin and prints its hash for
+//! `SVEN_ACCOUNTS`.
+
+use std::process::ExitCode;
+
+use crate::{ClientError, DeskCommand, SvenClient, SvenCommand, login};
+
+const DEFAULT_URL: &str = "http://localhost:3001";
+
+const USAGE: &str = "\
+Usage: sven-api client [--url URL] [--key KEY] <action>
+
+Actions:
+  state                                the desk's current state
+  presets                              the saved presets
+  move --height MM | --up MM | --down MM
+  preset NAME                          moves to a saved preset
+  command COMMAND VALUE                sends any command, e.g. `command SetSpeed 80`
+  stop";
+
+#[derive(Debug, PartialEq)]
+enum Action {
+    State,
+    Presets,
+    Command(DeskCommand),
+    Preset(String),
+    Stop,
+}
+
+#[derive(Debug, PartialEq)]
+struct Invocation {
+    url: String,
+    key: Option<String>,
+    action: Action,
+}
+
+fn millimetres(flag: &str, value: &str) -> Result<u32, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{} needs a height in mm, got '{}'", flag, value))
+}
+
+/// Reads the arguments after `client`; `url` and `key` are the defaults from the
+/// environment.
+fn parse(args: &[String], url: Option<String>, key: Option<String>) -> Result<Invocation, String> {
+    let mut url = url.unwrap_or_else(|| DEFAULT_URL.to_string());
+    let mut key = key.filter(|key| !key.is_empty());
+    let mut words = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--url" => url = args.next().ok_or("--url needs a value")?.clone(),
+            "--key" => key = Some(args.next().ok_or("--key needs a value")?.clone()),
+            _ => words.push(arg),
+        }
+    }
+
+    let action = match words.as_slice() {
+        [action] if action.as_str() == "state" => Action::State,
+        [action] if action.as_str() == "presets" => Action::Presets,
+        [action] if action.as_str() == "stop" => Action::Stop,
+        [action, name] if action.as_str() == "preset" => Action::Preset(name.to_string()),
+        [action, flag, value] if action.as_str() == "move" => {
+            let command = match flag.as_str() {
+                "--height" => SvenCommand::AbsoluteHeight,
+                "--up" => SvenCommand::UpRelative,
+                "--down" => SvenCommand::DownRelative,
+                other => return Err(format!("Unknown move '{}'", other)),
+            };
+            Action::Command(DeskCommand {
+                command,
+                value: millimetres(flag, value)?,
+            })
+        }
+        [action, command, value] if action.as_str() == "command" => {
+            let command = serde_json::from_value(serde_json::json!(command))
+                .map_err(|_| format!("Unknown command '{}'", command))?;
+            let value = value
+                .parse()
+                .map_err(|_| format!("The value must be a number, got '{}'", value))?;
+            Action::Command(DeskCommand { command, value })
+        }
+        [action, ..] if action.as_str() == "move" => {
+            return Err("move needs --height, --up or --down and a height in mm".to_string());
+        }
+        _ => return Err(USAGE.to_string()),
+    };
+    Ok(Invocation {
+        url: url.trim_end_matches('/').to_string(),
+        key,
+        action,
+    })
+}
+
+async fn call(invocation: &Invocation) -> Result<serde_json::Value, ClientError> {
+    let client = SvenClient::new(&invocation.url);
+    let client = match &invocation.key {
+        Some(key) => client.with_key(key),
+        None => client,
+    };
+    match &invocation.action {
+        Action::State => Ok(serde_json::json!(client.state().await?)),
+        Action::Presets => Ok(serde_json::json!(client.presets().await?)),
+        Action::Command(command) => client.command(command).await,
+        Action::Preset(name) => client.apply_preset(name).await,
+        Action::Stop => client.stop().await,
+    }
+}
+
+/// Runs the client with the arguments after `client`.
+pub async fn run(args: &[String]) -> ExitCode {
+    let invocation = match parse(
+        args,
+        std::env::var("SVEN_URL").ok(),
+        std::env::var("SVEN_API_KEY").ok(),
+    ) {
+        Ok(invocation) => invocation,
+        Err(message) => {
+            eprintln!("{}", message);
+            return ExitCode::from(2);
+        }
+    };
+    match call(&invocation).await {
+        Ok(body) => {
+            println!("{}", serde_json::to_string_pretty(&body).unwrap());
+            ExitCode::SUCCESS
+        }
+        Err(message) => {
+            eprintln!("{}", message);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Prints the hash of the password on the first line of stdin.
+pub fn hash_password() -> ExitCode {
+    let mut password = String::new();
+    if let Err(e) = std::io::stdin().read_line(&mut password) {
+        eprintln!("Failed to read the password: {}", e);
+        return ExitCode::FAILURE;
+    }
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        eprintln!("The password must not be empty");
+        return ExitCode::from(2);
+    }
+    println!("{}", login::hash_password(password));
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn actions_and_options_are_parsed() {
+        let invocation = parse(
+            &args("move --height 1100 --url http://desk:3001/"),
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(invocation.url, "http://desk:3001");
+        assert_eq!(
+            invocation.action,
+            Action::Command(DeskCommand {
+                command: SvenCommand::AbsoluteHeight,
+                value: 1100,
+            })
+        );
+
+        let invocation = parse(&args("preset standing"), None, Some("k".to_string())).unwrap();
+        assert_eq!(invocation.url, DEFAULT_URL);
+        assert_eq!(invocation.key.as_deref(), Some("k"));
+        assert_eq!(invocation.action, Action::Preset("standing".to_string()));
+
+        let invocation = parse(&args("command SetSpeed 80"), None, None).unwrap();
+        assert_eq!(
+            invocation.action,
+            Action::Command(DeskCommand {
+                command: SvenCommand::SetSpeed,
+                value: 80,
+            })
+        );
+    }
+
+    #[test]
+    fn bad_usage_is_explained() {
+        assert!(parse(&args("move --height tall"), None, None).is_err());
+        assert!(parse(&args("move"), None, None).is_err());
+        assert!(parse(&args("command Jump 1"), None, None).is_err());
+        assert_eq!(parse(&args(""), None, None).unwrap_err(), USAGE);
+    }
+}
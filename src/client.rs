@@ -0,0 +1,139 @@
+//! A typed client for the HTTP API, for Rust programs that control the desk
+//! through a running instance, like `sven-api client` does.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), sven_api::ClientError> {
+//! let client = sven_api::SvenClient::new("http://localhost:3001").with_key("secret");
+//! client.move_to(1100).await?;
+//! println!("{} mm", client.state().await?.state.height_mm);
+//! # Ok(())
+//! # }
+//! ```
+
+use serde::de::DeserializeOwned;
+
+use crate::{DeskCommand, Preset, StateView, SvenCommand};
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// The instance couldn't be reached, or answered with something unreadable
+    Http(reqwest::Error),
+    /// The instance refused the call
+    Api {
+        status: u16,
+        /// The machine readable reason, when the API gives one
+        code: Option<String>,
+        message: String,
+    },
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::Http(e) => write!(f, "HTTP error: {}", e),
+            ClientError::Api {
+                status, message, ..
+            } => write!(f, "{} ({})", message, status),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(e: reqwest::Error) -> Self {
+        ClientError::Http(e)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SvenClient {
+    base_url: String,
+    key: Option<String>,
+    http: reqwest::Client,
+}
+
+impl SvenClient {
+    /// A client for the instance at `base_url`, e.g. `http://localhost:3001`.
+    pub fn new(base_url: &str) -> SvenClient {
+        SvenClient {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            key: None,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    /// Presents `key` as the API key on every call.
+    pub fn with_key(mut self, key: &str) -> SvenClient {
+        self.key = Some(key.to_string());
+        self
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    async fn send<T: DeserializeOwned>(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<T, ClientError> {
+        let request = match &self.key {
+            Some(key) => request.bearer_auth(key),
+            None => request,
+        };
+        let response = request.send().await?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response.json().await?);
+        }
+        let body: serde_json::Value = response.json().await.unwrap_or_default();
+        Err(ClientError::Api {
+            status: status.as_u16(),
+            code: body["code"].as_str().map(str::to_string),
+            message: body["error"]
+                .as_str()
+                .map(str::to_string)
+                .unwrap_or_else(|| status.to_string()),
+        })
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    /// The desk's current state.
+    pub async fn state(&self) -> Result<StateView, ClientError> {
+        self.send(self.http.get(self.url("/api/sven/state"))).await
+    }
+
+    /// Sends a command, answering with the API's response.
+    pub async fn command(&self, command: &DeskCommand) -> Result<serde_json::Value, ClientError> {
+        self.send(self.http.post(self.url("/api/sven/command")).json(command))
+            .await
+    }
+
+    /// Moves the desk to `height_mm`.
+    pub async fn move_to(&self, height_mm: u32) -> Result<serde_json::Value, ClientError> {
+        self.command(&DeskCommand {
+            command: SvenCommand::AbsoluteHeight,
+            value: height_mm,
+        })
+        .await
+    }
+
+    pub async fn stop(&self) -> Result<serde_json::Value, ClientError> {
+        self.send(self.http.post(self.url("/api/sven/stop"))).await
+    }
+
+    /// The saved presets.
+    pub async fn presets(&self) -> Result<Vec<Preset>, ClientError> {
+        self.send(self.http.get(self.url("/api/sven/presets")))
+            .await
+    }
+
+    /// Moves the desk to the preset `name`.
+    pub async fn apply_preset(&self, name: &str) -> Result<serde_json::Value, ClientError> {
+        let path = format!("/api/sven/presets/{}/apply", name);
+        self.send(self.http.post(self.url(&path))).await
+    }
+}
@@ -0,0 +1,128 @@
+//! Soft handling of obstructions. With `SVEN_COLLISION_REVERSE`, a fault reported
+//! mid-move, or the desk stopping short of where a job sends it, cancels the
+//! desk's unfinished jobs and backs off `SVEN_COLLISION_REVERSE_MM` against the
+//! direction of travel, so whatever got caught is freed. The back-off is sent even
+//! while a fault blocks other moves.
+
+use tracing::{error, warn};
+
+use crate::jobs::JobStatus;
+use crate::movement::ARRIVAL_TOLERANCE_MM;
+use crate::notifications::NotificationKind;
+use crate::status::DeskStatus;
+use crate::{AppState, DeskCommand, SvenCommand, request_id};
+
+/// The move backing off from an obstruction: against the way to the job's target,
+/// or the way the desk was seen moving. `None` when the desk wasn't going anywhere.
+fn back_off(
+    target_mm: Option<u32>,
+    height_mm: u32,
+    status: DeskStatus,
+    reverse_mm: u32,
+) -> Option<DeskCommand> {
+    let going_up = match target_mm {
+        Some(target_mm) if target_mm != height_mm => target_mm > height_mm,
+        _ => match status {
+            DeskStatus::MovingUp => true,
+            DeskStatus::MovingDown => false,
+            _ => return None,
+        },
+    };
+    Some(DeskCommand {
+        command: if going_up {
+            SvenCommand::DownRelative
+        } else {
+            SvenCommand::UpRelative
+        },
+        value: reverse_mm,
+    })
+}
+
+/// Cancels the primary desk's unfinished jobs and backs off, when the policy is on
+/// and the desk was moving.
+pub async fn handle_obstruction(app_state: &AppState, reason: &str, status: DeskStatus) {
+    if !app_state.config().collision_reverse {
+        return;
+    }
+    let height_mm = app_state.sven_state.read().await.height_mm;
+    let mut jobs = app_state.jobs.lock().await;
+    let active: Vec<_> = jobs
+        .list()
+        .into_iter()
+        .filter(|job| job.desk.is_none() && !job.status.is_finished())
+        .collect();
+    let target_mm = active.iter().find_map(|job| job.target_mm);
+    let Some(command) = back_off(
+        target_mm,
+        height_mm,
+        status,
+        app_state.config().collision_reverse_mm,
+    ) else {
+        return;
+    };
+    for job in &active {
+        jobs.finish(&job.id, JobStatus::Cancelled, Some(reason.to_string()));
+    }
+    drop(jobs);
+
+    warn!(
+        "{} at {} mm, backing off with {} {}",
+        reason, height_mm, command.command, command.value
+    );
+    if let Err(e) = app_state
+        .send_command(None, &command, &request_id::generate(), None)
+        .await
+    {
+        error!("Failed to back off from an obstruction: {}", e);
+    }
+    app_state.notifier.notify(
+        NotificationKind::Watchdog,
+        format!(
+            "{} at {} mm, backed off {} mm",
+            reason, height_mm, command.value
+        ),
+    );
+}
+
+/// Called when the desk stops reporting movement: a job still on its way that far
+/// from its target counts as stalled.
+pub async fn check_stall(app_state: &AppState) {
+    if !app_state.config().collision_reverse {
+        return;
+    }
+    let height_mm = app_state.sven_state.read().await.height_mm;
+    let stalled_target = app_state
+        .jobs
+        .lock()
+        .await
+        .list()
+        .into_iter()
+        .find_map(|job| {
+            let stalled = job.desk.is_none()
+                && job.status == JobStatus::Moving
+                && job
+                    .target_mm
+                    .is_some_and(|target_mm| target_mm.abs_diff(height_mm) > ARRIVAL_TOLERANCE_MM);
+            stalled.then_some(job.target_mm).flatten()
+        });
+    if let Some(target_mm) = stalled_target {
+        let reason = format!("Desk stalled on the way to {} mm", target_mm);
+        // The direction comes from the target, the desk has stopped by now
+        handle_obstruction(app_state, &reason, DeskStatus::Idle).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backs_off_against_the_direction_of_travel() {
+        let up = back_off(Some(1100), 900, DeskStatus::Idle, 20).unwrap();
+        assert_eq!(up.command, SvenCommand::DownRelative);
+        assert_eq!(up.value, 20);
+        let down = back_off(None, 900, DeskStatus::MovingDown, 20).unwrap();
+        assert_eq!(down.command, SvenCommand::UpRelative);
+        assert!(back_off(None, 900, DeskStatus::Idle, 20).is_none());
+    }
+}
@@ -0,0 +1,922 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::SvenPosition;
+use crate::auth::ApiKey;
+use crate::broker::BrokerUrl;
+use crate::cors::OriginPattern;
+use crate::groups::DeskGroup;
+use crate::limits::{HeightLimits, LimitPolicy};
+use crate::logging::LogFormat;
+use crate::login::{self, Account};
+use crate::persistence::StoreBackend;
+use crate::positions::DuplicatePolicy;
+use crate::queue::QueuePolicy;
+use crate::quiet_hours::QuietWindow;
+use crate::subscriptions::{SubscriptionSpec, TopicRouter};
+use crate::transport::{MessageClass, PublishClasses, PublishOptions, QosLevel, TransportKind};
+use crate::zones::{HeightRange, ProtectedZone};
+
+pub const DEFAULT_CONFIG_FILE: &str = "sven.toml";
+
+/// What a percentage command is relative to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PercentBasis {
+    /// Percentage of the current desk height
+    Height,
+    /// Percentage of the configured travel range (max - min)
+    Range,
+}
+
+impl FromStr for PercentBasis {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "height" => Ok(PercentBasis::Height),
+            "range" => Ok(PercentBasis::Range),
+            other => Err(format!("unknown percent basis '{}'", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub mqtt_host: String,
+    pub mqtt_port: u16,
+    pub mqtt_client_id: String,
+    pub mqtt_keep_alive_secs: u64,
+    pub mqtt_tls: bool,
+    /// CA to verify the broker with; the platform roots are used when unset
+    pub mqtt_ca_file: Option<PathBuf>,
+    pub mqtt_client_cert_file: Option<PathBuf>,
+    pub mqtt_client_key_file: Option<PathBuf>,
+    pub mqtt_username: Option<String>,
+    pub mqtt_password: Option<String>,
+    /// Shared with the firmware to sign commands and verify state reports, see `signing`
+    pub mqtt_signing_secret: Option<String>,
+    /// How far a signed report's timestamp may be from now
+    pub mqtt_signature_max_age_secs: u64,
+    /// `EnvFilter` directives, e.g. `debug` or `info,sven_api=debug`
+    pub log_level: String,
+    pub log_format: LogFormat,
+    pub http_host: String,
+    pub http_port: u16,
+    /// Serve the gRPC API on this port of `http_host`, needs the `grpc` feature
+    pub grpc_port: Option<u16>,
+    /// Serve Swagger UI for the OpenAPI document on `/api/docs`
+    pub swagger_ui: bool,
+    /// Serve the built-in control page on `/`
+    pub ui: bool,
+    pub min_height_mm: u32,
+    pub max_height_mm: u32,
+    /// Soft limits inside the mechanical range that moves may not cross
+    pub soft_height_limits: HeightLimits,
+    /// Per desk soft limits, `desk_id=min-max`, overriding `soft_height_limits`
+    pub desk_height_limits: HashMap<String, HeightLimits>,
+    pub height_limit_policy: LimitPolicy,
+    /// Longest UpDuration/DownDuration command accepted
+    pub max_duration_ms: u64,
+    pub percent_basis: PercentBasis,
+    /// Send UpRelative/DownRelative as AbsoluteHeight moves computed from the last
+    /// reported height, for firmware that can't be trusted to move relatively
+    pub resolve_relative_moves: bool,
+    /// Pulses a long timed move starts with, see `soft_start`; none to send it as is
+    pub soft_start_pulses_ms: Vec<u64>,
+    /// Timed moves up to this long are sent without pulses
+    pub soft_start_min_ms: u64,
+    /// Pause between the pulses
+    pub soft_start_gap_ms: u64,
+    pub admin_token: Option<String>,
+    /// `name=token[:read-only]` keys accepted by the auth middleware; the API is open when empty
+    pub api_keys: HashMap<String, ApiKey>,
+    /// `name=role:hash` accounts that may log in; the API also needs a login or key once set
+    pub accounts: HashMap<String, Account>,
+    /// Signs session cookies; random per start unless `SVEN_SESSION_SECRET` is set
+    pub session_secret: String,
+    pub session_ttl_secs: u64,
+    /// Failed logins allowed per client and per account, 0 for no limit
+    pub login_failures_per_minute: u32,
+    /// Origins browsers may call the API from
+    pub cors_origins: Vec<String>,
+    pub cors_origin_patterns: Vec<OriginPattern>,
+    /// Allow any origin; only set by `--insecure-cors`
+    pub insecure_cors: bool,
+    pub debug_events_capacity: usize,
+    pub store_backend: StoreBackend,
+    /// Directory (file store) or database file (sqlite store); persistence is off when unset
+    pub store_path: Option<PathBuf>,
+    /// The config file the settings were read from, if any
+    pub config_file: Option<PathBuf>,
+    /// The config file's entries by variable name, to tell what a reload changed
+    pub file_settings: HashMap<String, String>,
+    pub default_position: Option<SvenPosition>,
+    pub default_height_mm: Option<u32>,
+    pub event_log_file: Option<PathBuf>,
+    pub event_log_max_bytes: u64,
+    pub park_on_shutdown_mm: Option<u32>,
+    pub park_timeout_secs: u64,
+    pub mqtt_client_id_unique: bool,
+    /// How long confirmed commands wait for the desk when the request doesn't say
+    pub ack_timeout_ms: u64,
+    /// Largest per request timeout a client may ask for
+    pub ack_timeout_max_ms: u64,
+    /// How long a publish may wait for the MQTT client to take the message
+    pub publish_timeout_ms: u64,
+    /// Age after which unsent desk commands are dropped, `0` for never
+    pub command_ttl_secs: u64,
+    /// Keep moves while the broker is unreachable and send them once it's back, see `spool`
+    pub spool_commands: bool,
+    /// Spooled moves older than this are dropped instead of sent
+    pub spool_max_age_secs: u64,
+    pub spool_max_commands: usize,
+    /// Absolute moves closer than this to the current height are skipped, `0` for never
+    pub deadband_mm: u32,
+    /// Back off and cancel the move when the desk reports a fault or stalls, see `collision`
+    pub collision_reverse: bool,
+    /// How far to back off
+    pub collision_reverse_mm: u32,
+    /// QoS and retain flag per message class, `SVEN_PUBLISH_<CLASS>_QOS` and `_RETAIN`
+    pub publish_classes: PublishClasses,
+    /// First levels of the default topics, `sven` unless set
+    pub topic_prefix: String,
+    /// Topic commands for the primary desk are published on
+    pub command_topic: String,
+    pub status_topic: String,
+    /// Firmware faults, see `faults`
+    pub error_topic: String,
+    /// Retained `online`/`offline` status of the API itself, kept up to date with MQTT's last will
+    pub availability_topic: String,
+    /// MQTT filter for state reports, see `TopicPattern` for the desk id rules
+    pub state_topic_pattern: String,
+    /// Desk whose reports drive the main state endpoints when using a wildcard pattern
+    pub desk_id: Option<String>,
+    /// Incoming MQTT payloads larger than this are dropped without being parsed
+    pub max_payload_bytes: usize,
+    /// Largest request body the command endpoints read, larger ones get 413
+    pub max_command_body_bytes: usize,
+    /// Per preset minimum interval between activations, keyed by lowercase name
+    pub preset_cooldowns: HashMap<String, Duration>,
+    /// Desk travel speed used to predict arrival times, and to time moves with height estimation
+    pub travel_speed_mm_per_s: u32,
+    /// Work out the height from timed moves, for controllers without a sensor, see `estimation`
+    pub height_estimation: bool,
+    pub maint_topic: String,
+    /// Maintenance verbs that may be forwarded to the firmware
+    pub maint_commands: Vec<String>,
+    /// Topic firmware updates are sent to the controller on
+    pub ota_topic: String,
+    /// Topic motor settings are sent to the controller on
+    pub motor_topic: String,
+    /// Image bytes per OTA message, before base64
+    pub ota_chunk_bytes: usize,
+    /// Largest firmware image accepted for upload
+    pub ota_max_bytes: usize,
+    /// How long each OTA message waits for the controller's ack
+    pub ota_ack_timeout_ms: u64,
+    /// Resends of an OTA message that wasn't acked before the update fails
+    pub ota_retries: u32,
+    /// `max-age` for read endpoints whose data only changes with configuration
+    pub cache_max_age_secs: u64,
+    pub power_topic: String,
+    pub power_command_topic: String,
+    /// Presence sensor reports to read desk occupancy from, unset without a sensor
+    pub occupancy_topic: Option<String>,
+    /// Dot separated path to the occupancy value in JSON reports
+    pub occupancy_field: String,
+    /// Skip scheduled movements while the sensor reports the desk vacant
+    pub occupancy_suppresses_schedules: bool,
+    /// Smart plug power readings to monitor the desk's draw with, unset without a plug
+    pub power_meter_topic: Option<String>,
+    /// Dot separated path to the watts in JSON readings
+    pub power_meter_field: String,
+    /// Draw from which the motor counts as running
+    pub power_meter_moving_w: f64,
+    /// `HH:MM-HH:MM` local windows in which only forced commands move the desk
+    pub quiet_hours: Vec<QuietWindow>,
+    /// URLs every notification is posted to as JSON
+    pub notify_webhooks: Vec<String>,
+    /// ntfy topic URL notifications are published to, like `https://ntfy.sh/my-desk`
+    pub ntfy_url: Option<String>,
+    /// Bot token and chat for Telegram notifications, both needed to send them
+    pub telegram_bot_token: Option<String>,
+    pub telegram_chat_id: Option<String>,
+    /// Notification types sent to the channels, see `NotificationKind::name`, all
+    /// of them when empty
+    pub notify_events: Vec<String>,
+    /// Account-linking tokens accepted from the Alexa skill
+    pub alexa_tokens: Vec<String>,
+    /// OAuth token introspection endpoint (RFC 7662) for other Alexa tokens
+    pub alexa_introspection_url: Option<String>,
+    /// Name the desk is discovered under
+    pub alexa_device_name: String,
+    /// Wake the controller from standby instead of rejecting movement commands
+    pub auto_wake: bool,
+    /// How long shutdown waits for in-flight requests to finish, and then for
+    /// queued commands to be published
+    pub shutdown_drain_secs: u64,
+    /// Commands per minute allowed per client, 0 for unlimited
+    pub rate_limit_per_minute: u32,
+    /// Per client limits that replace `rate_limit_per_minute`, keyed by API key
+    /// name, `Origin` or address
+    pub rate_limit_overrides: HashMap<String, u32>,
+    /// How long a command's response is replayed for retries with the same
+    /// `Idempotency-Key`, zero to ignore the header
+    pub idempotency_window: Duration,
+    /// Audit entries kept in memory when there is no history database
+    pub audit_capacity: usize,
+    /// Commands kept in the in-memory history
+    pub history_capacity: usize,
+    /// SQLite database for the persistent command and state history
+    pub history_db: Option<PathBuf>,
+    /// Idle time after which commands are refused until unlocked, 0 to disable
+    pub inactivity_lock_secs: u64,
+    /// Serve over this Unix socket instead of TCP
+    pub bind_uds: Option<PathBuf>,
+    /// PEM certificate chain and private key to serve HTTPS with
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// Serve HTTPS with a generated self-signed certificate when no certificate is
+    /// configured, or create the configured files when they don't exist yet
+    pub tls_self_signed: bool,
+    /// Names the self-signed certificate is issued for, besides `localhost`
+    pub tls_hostnames: Vec<String>,
+    /// Users known from the start, besides those registered through the API
+    pub users: Vec<String>,
+    pub protected_zones: Vec<ProtectedZone>,
+    /// State older than this makes relative and percentage commands fail, unset to disable
+    pub stale_state_max_age: Option<Duration>,
+    /// Also refuse absolute and position commands while state is stale
+    pub stale_state_reject_absolute: bool,
+    /// Answer `GET /api/sven/state` with 503 while the state is stale
+    pub stale_state_unavailable: bool,
+    /// `/readyz` fails when the last state report is older than this, unset to only
+    /// require one report since startup
+    pub ready_state_max_age: Option<Duration>,
+    /// Moves ending this close to min/max approach the limit in small steps, 0 to disable
+    pub slow_zone_mm: u32,
+    /// Size of each relative step inside the slow zone
+    pub slow_zone_step_mm: u32,
+    /// Extra or overridden MQTT subscriptions, keyed by topic filter
+    pub subscriptions: HashMap<String, SubscriptionSpec>,
+    pub duplicate_preset_policy: DuplicatePolicy,
+    /// Position heights closer together than this count as duplicates
+    pub duplicate_preset_tolerance_mm: u32,
+    /// Exit instead of retrying forever when the broker can't be reached at startup
+    pub strict_startup: bool,
+    /// Connect/subscribe attempts before a strict startup gives up
+    pub startup_max_attempts: u32,
+    /// Upper bound for the in-memory buffers combined, see `memory` for how it is split
+    pub memory_budget_bytes: Option<usize>,
+    pub ack_topic: String,
+    /// Wait for the firmware's ack before answering a command, unless the request says otherwise
+    pub wait_for_ack: bool,
+    /// How a command is handled while an earlier one is still moving the desk
+    pub command_queue_policy: QueuePolicy,
+    /// Desks managed through `/api/desks/{id}`, besides any that report state on their own
+    pub desks: Vec<String>,
+    /// Groups of registry desks commanded together through `/api/groups/{name}`
+    pub desk_groups: HashMap<String, DeskGroup>,
+    /// Run against a virtual desk instead of an MQTT broker, also enabled by `--simulate`
+    pub simulate: bool,
+    /// Announce the desk to Home Assistant through MQTT discovery
+    pub ha_discovery: bool,
+    pub ha_discovery_prefix: String,
+    /// Prefix of the command topics the Home Assistant entities publish on
+    pub ha_topic_prefix: String,
+    /// Identifies the desk's device and entities in Home Assistant
+    pub ha_node_id: String,
+    /// Advertise the API over mDNS as `_sven-api._tcp`
+    pub mdns: bool,
+    /// Instance name the API is advertised under, distinct for every bridge on the network
+    pub mdns_name: String,
+    /// Command topic for registry desks, `{id}` is replaced with the desk id
+    pub desk_command_topic: String,
+    /// State topic registry desks report on, `{id}` standing for the desk id
+    pub desk_state_topic: String,
+    pub transport: TransportKind,
+    /// Where the `http` transport POSTs messages
+    pub transport_url: Option<String>,
+    /// Zigbee2MQTT's base topic and the desk's friendly name, for the `zigbee2mqtt` transport
+    pub z2m_base_topic: String,
+    pub z2m_device: String,
+}
+
+impl Config {
+    /// Loads the configuration from the environment and the optional config file,
+    /// `SVEN_CONFIG_FILE` or `sven.toml` in the working directory if it exists.
+    /// Environment variables take precedence over the file.
+    pub fn load() -> Result<Self, String> {
+        let path = match std::env::var("SVEN_CONFIG_FILE") {
+            Ok(path) => Some(PathBuf::from(path)),
+            Err(_) => Some(PathBuf::from(DEFAULT_CONFIG_FILE)).filter(|path| path.exists()),
+        };
+        Config::load_from(path)
+    }
+
+    /// Loads the configuration from the environment and the config file at `path`.
+    pub fn load_from(path: Option<PathBuf>) -> Result<Self, String> {
+        let settings = Settings::load(path.as_deref())?;
+        // Topic settings may use `{prefix}`, which their defaults all start with
+        let topic_prefix = settings
+            .or("SVEN_TOPIC_PREFIX", "sven".to_string())
+            .trim_end_matches('/')
+            .to_string();
+        let topic = |key: &str, default: &str| {
+            settings
+                .or(key, default.to_string())
+                .replace("{prefix}", &topic_prefix)
+        };
+
+        let mut config = Config {
+            mqtt_host: settings.or("SVEN_MQTT_HOST", "localhost".to_string()),
+            mqtt_port: settings.or("SVEN_MQTT_PORT", 1883),
+            mqtt_client_id: settings.or("SVEN_MQTT_CLIENT_ID", "sven-client".to_string()),
+            mqtt_keep_alive_secs: settings.or("SVEN_MQTT_KEEP_ALIVE_SECS", 5),
+            mqtt_tls: settings.or("SVEN_MQTT_TLS", false),
+            mqtt_ca_file: settings.opt("SVEN_MQTT_CA_FILE"),
+            mqtt_client_cert_file: settings.opt("SVEN_MQTT_CLIENT_CERT_FILE"),
+            mqtt_client_key_file: settings.opt("SVEN_MQTT_CLIENT_KEY_FILE"),
+            mqtt_username: settings.raw("SVEN_MQTT_USERNAME"),
+            mqtt_password: settings.raw("SVEN_MQTT_PASSWORD"),
+            mqtt_signing_secret: settings
+                .raw("SVEN_MQTT_SIGNING_SECRET")
+                .filter(|secret| !secret.is_empty()),
+            mqtt_signature_max_age_secs: settings.or("SVEN_MQTT_SIGNATURE_MAX_AGE_SECS", 30),
+            log_level: settings.or("SVEN_LOG_LEVEL", "info".to_string()),
+            log_format: settings.or("SVEN_LOG_FORMAT", LogFormat::Text),
+            http_host: settings.or("SVEN_HTTP_HOST", "0.0.0.0".to_string()),
+            http_port: settings.or("SVEN_HTTP_PORT", 3001),
+            grpc_port: settings.opt("SVEN_GRPC_PORT"),
+            swagger_ui: settings.or("SVEN_SWAGGER_UI", false),
+            ui: settings.or("SVEN_UI", true),
+            min_height_mm: settings.or("SVEN_MIN_HEIGHT_MM", 650),
+            max_height_mm: settings.or("SVEN_MAX_HEIGHT_MM", 1250),
+            soft_height_limits: HeightLimits {
+                min_height_mm: settings.opt("SVEN_SOFT_MIN_HEIGHT_MM"),
+                max_height_mm: settings.opt("SVEN_SOFT_MAX_HEIGHT_MM"),
+            },
+            desk_height_limits: settings.pairs("SVEN_DESK_HEIGHT_LIMITS"),
+            height_limit_policy: settings.or("SVEN_HEIGHT_LIMIT_POLICY", LimitPolicy::Reject),
+            max_duration_ms: settings.or("SVEN_MAX_DURATION_MS", 30_000),
+            percent_basis: settings.or("SVEN_PERCENT_BASIS", PercentBasis::Height),
+            resolve_relative_moves: settings.or("SVEN_RESOLVE_RELATIVE_MOVES", false),
+            soft_start_pulses_ms: settings.list("SVEN_SOFT_START_PULSES_MS"),
+            soft_start_min_ms: settings.or("SVEN_SOFT_START_MIN_MS", 2000),
+            soft_start_gap_ms: settings.or("SVEN_SOFT_START_GAP_MS", 100),
+            admin_token: settings
+                .raw("SVEN_ADMIN_TOKEN")
+                .filter(|token| !token.is_empty()),
+            api_keys: settings.pairs("SVEN_API_KEYS"),
+            accounts: settings.pairs("SVEN_ACCOUNTS"),
+            session_secret: settings
+                .raw("SVEN_SESSION_SECRET")
+                .filter(|secret| !secret.is_empty())
+                .unwrap_or_else(login::random_secret),
+            session_ttl_secs: settings.or("SVEN_SESSION_TTL_SECS", 12 * 60 * 60),
+            login_failures_per_minute: settings.or("SVEN_LOGIN_FAILURES_PER_MINUTE", 5),
+            cors_origins: settings.list("SVEN_CORS_ORIGINS"),
+            cors_origin_patterns: settings.list("SVEN_CORS_ORIGIN_PATTERNS"),
+            insecure_cors: false,
+            debug_events_capacity: settings.or("SVEN_DEBUG_EVENTS_CAPACITY", 100),
+            store_backend: settings.or("SVEN_STORE", StoreBackend::File),
+            store_path: settings.raw("SVEN_STORE_PATH").map(PathBuf::from),
+            config_file: path,
+            file_settings: settings.file.clone(),
+            default_position: settings.opt("SVEN_DEFAULT_POSITION"),
+            default_height_mm: settings.opt("SVEN_DEFAULT_HEIGHT_MM"),
+            event_log_file: settings.raw("SVEN_EVENT_LOG_FILE").map(PathBuf::from),
+            event_log_max_bytes: settings.or("SVEN_EVENT_LOG_MAX_BYTES", 10 * 1024 * 1024),
+            park_on_shutdown_mm: settings.opt("SVEN_PARK_ON_SHUTDOWN_MM"),
+            park_timeout_secs: settings.or("SVEN_PARK_TIMEOUT_SECS", 30),
+            mqtt_client_id_unique: settings.or("SVEN_MQTT_CLIENT_ID_UNIQUE", false),
+            // SVEN_CONFIRM_TIMEOUT_MS is the older name for the same setting
+            ack_timeout_ms: settings
+                .opt("SVEN_ACK_TIMEOUT_MS")
+                .unwrap_or_else(|| settings.or("SVEN_CONFIRM_TIMEOUT_MS", 30_000)),
+            ack_timeout_max_ms: settings.or("SVEN_ACK_TIMEOUT_MAX_MS", 120_000),
+            publish_timeout_ms: settings.or("SVEN_PUBLISH_TIMEOUT_MS", 5000),
+            command_ttl_secs: settings.or("SVEN_COMMAND_TTL_SECS", 0),
+            spool_commands: settings.or("SVEN_SPOOL_COMMANDS", false),
+            spool_max_age_secs: settings.or("SVEN_SPOOL_MAX_AGE_SECS", 300),
+            spool_max_commands: settings.or("SVEN_SPOOL_MAX_COMMANDS", 20),
+            deadband_mm: settings.or("SVEN_DEADBAND_MM", 0),
+            collision_reverse: settings.or("SVEN_COLLISION_REVERSE", false),
+            collision_reverse_mm: settings.or("SVEN_COLLISION_REVERSE_MM", 20),
+            publish_classes: PublishClasses::default(),
+            command_topic: topic("SVEN_COMMAND_TOPIC", "{prefix}/command"),
+            status_topic: topic("SVEN_STATUS_TOPIC", "{prefix}/status"),
+            error_topic: topic("SVEN_ERROR_TOPIC", "{prefix}/error"),
+            availability_topic: topic("SVEN_AVAILABILITY_TOPIC", "sven-api/status"),
+            state_topic_pattern: topic("SVEN_STATE_TOPIC_PATTERN", "{prefix}/state"),
+            desk_id: settings.opt("SVEN_DESK_ID"),
+            max_payload_bytes: settings.or("SVEN_MAX_PAYLOAD_BYTES", 4096),
+            max_command_body_bytes: settings.or("SVEN_MAX_COMMAND_BODY_BYTES", 16 * 1024),
+            preset_cooldowns: settings
+                .pairs::<u64>("SVEN_PRESET_COOLDOWNS")
+                .into_iter()
+                .map(|(name, secs)| (name.to_ascii_lowercase(), Duration::from_secs(secs)))
+                .collect(),
+            travel_speed_mm_per_s: settings.or("SVEN_TRAVEL_SPEED_MM_S", 35),
+            height_estimation: settings.or("SVEN_HEIGHT_ESTIMATION", false),
+            maint_topic: topic("SVEN_MAINT_TOPIC", "{prefix}/maint"),
+            maint_commands: settings
+                .or("SVEN_MAINT_COMMANDS", "reboot".to_string())
+                .split(',')
+                .map(|verb| verb.trim().to_string())
+                .filter(|verb| !verb.is_empty())
+                .collect(),
+            ota_topic: topic("SVEN_OTA_TOPIC", "{prefix}/ota"),
+            motor_topic: topic("SVEN_MOTOR_TOPIC", "{prefix}/config"),
+            ota_chunk_bytes: settings.or("SVEN_OTA_CHUNK_BYTES", 2048),
+            ota_max_bytes: settings.or("SVEN_OTA_MAX_BYTES", 4 * 1024 * 1024),
+            ota_ack_timeout_ms: settings.or("SVEN_OTA_ACK_TIMEOUT_MS", 5000),
+            ota_retries: settings.or("SVEN_OTA_RETRIES", 3),
+            cache_max_age_secs: settings.or("SVEN_CACHE_MAX_AGE_SECS", 60),
+            power_topic: topic("SVEN_POWER_TOPIC", "{prefix}/power"),
+            power_command_topic: topic("SVEN_POWER_COMMAND_TOPIC", "{prefix}/power/set"),
+            auto_wake: settings.or("SVEN_AUTO_WAKE", false),
+            occupancy_topic: settings.opt("SVEN_OCCUPANCY_TOPIC"),
+            occupancy_field: settings.or("SVEN_OCCUPANCY_FIELD", "occupancy".to_string()),
+            occupancy_suppresses_schedules: settings.or("SVEN_OCCUPANCY_SUPPRESS_SCHEDULES", false),
+            power_meter_topic: settings.opt("SVEN_POWER_METER_TOPIC"),
+            power_meter_field: settings.or("SVEN_POWER_METER_FIELD", "ENERGY.Power".to_string()),
+            power_meter_moving_w: settings.or("SVEN_POWER_METER_MOVING_W", 20.0),
+            quiet_hours: settings.list("SVEN_QUIET_HOURS"),
+            notify_webhooks: settings.list("SVEN_NOTIFY_WEBHOOKS"),
+            ntfy_url: settings.opt("SVEN_NTFY_URL"),
+            telegram_bot_token: settings.opt("SVEN_TELEGRAM_BOT_TOKEN"),
+            telegram_chat_id: settings.opt("SVEN_TELEGRAM_CHAT_ID"),
+            notify_events: settings.list("SVEN_NOTIFY_EVENTS"),
+            alexa_tokens: settings.list("SVEN_ALEXA_TOKENS"),
+            alexa_introspection_url: settings.opt("SVEN_ALEXA_INTROSPECTION_URL"),
+            alexa_device_name: settings.or("SVEN_ALEXA_DEVICE_NAME", "Desk".to_string()),
+            shutdown_drain_secs: settings.or("SVEN_SHUTDOWN_DRAIN_SECS", 5),
+            rate_limit_per_minute: settings.or("SVEN_RATE_LIMIT_PER_MINUTE", 0),
+            rate_limit_overrides: settings.pairs("SVEN_RATE_LIMIT_OVERRIDES"),
+            idempotency_window: Duration::from_secs(
+                settings.or("SVEN_IDEMPOTENCY_WINDOW_SECS", 300),
+            ),
+            audit_capacity: settings.or("SVEN_AUDIT_CAPACITY", 1000),
+            history_capacity: settings.or("SVEN_HISTORY_CAPACITY", 500),
+            history_db: settings
+                .raw("SVEN_HISTORY_DB")
+                .map(PathBuf::from)
+                .or_else(|| {
+                    // Next to the other persisted artifacts, sharing the database with a sqlite store
+                    let store_path = settings.raw("SVEN_STORE_PATH").map(PathBuf::from)?;
+                    match settings.or("SVEN_STORE", StoreBackend::File) {
+                        StoreBackend::File => Some(store_path.join("history.sqlite")),
+                        StoreBackend::Sqlite => Some(store_path),
+                    }
+                }),
+            inactivity_lock_secs: settings.or("SVEN_INACTIVITY_LOCK_SECS", 0),
+            bind_uds: settings.opt("SVEN_BIND_UDS"),
+            tls_cert: settings.opt("SVEN_TLS_CERT"),
+            tls_key: settings.opt("SVEN_TLS_KEY"),
+            tls_self_signed: settings.or("SVEN_TLS_SELF_SIGNED", false),
+            tls_hostnames: settings.list("SVEN_TLS_HOSTNAMES"),
+            users: settings.list("SVEN_USERS"),
+            protected_zones: settings
+                .pairs::<HeightRange>("SVEN_PROTECTED_ZONES")
+                .into_iter()
+                .map(|(name, range)| ProtectedZone {
+                    name,
+                    min_mm: range.min_mm,
+                    max_mm: range.max_mm,
+                })
+                .collect(),
+            stale_state_max_age: settings
+                .opt::<u64>("SVEN_STALE_STATE_SECS")
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+            stale_state_reject_absolute: settings.or("SVEN_STALE_STATE_REJECT_ABSOLUTE", false),
+            stale_state_unavailable: settings.or("SVEN_STALE_STATE_UNAVAILABLE", false),
+            ready_state_max_age: settings
+                .opt::<u64>("SVEN_READY_STATE_MAX_AGE_SECS")
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+            slow_zone_mm: settings.or("SVEN_SLOW_ZONE_MM", 0),
+            slow_zone_step_mm: settings.or("SVEN_SLOW_ZONE_STEP_MM", 10),
+            subscriptions: settings.pairs("SVEN_SUBSCRIPTIONS"),
+            duplicate_preset_policy: settings
+                .or("SVEN_DUPLICATE_PRESET_POLICY", DuplicatePolicy::Warn),
+            duplicate_preset_tolerance_mm: settings.or("SVEN_DUPLICATE_PRESET_TOLERANCE_MM", 10),
+            strict_startup: settings.or("SVEN_STRICT_STARTUP", false),
+            startup_max_attempts: settings.or("SVEN_STARTUP_MAX_ATTEMPTS", 10),
+            memory_budget_bytes: settings
+                .opt::<usize>("SVEN_MEMORY_BUDGET_KB")
+                .map(|kb| kb * 1024),
+            ack_topic: topic("SVEN_ACK_TOPIC", "{prefix}/ack"),
+            wait_for_ack: settings.or("SVEN_WAIT_FOR_ACK", false),
+            command_queue_policy: settings.or("SVEN_COMMAND_QUEUE_POLICY", QueuePolicy::Fifo),
+            desks: settings
+                .or("SVEN_DESKS", String::new())
+                .split(',')
+                .map(|id| id.trim().to_string())
+                .filter(|id| !id.is_empty())
+                .collect(),
+            desk_groups: settings.pairs("SVEN_DESK_GROUPS"),
+            simulate: settings.or("SVEN_SIMULATE", false),
+            ha_discovery: settings.or("SVEN_HA_DISCOVERY", false),
+            ha_discovery_prefix: settings
+                .or("SVEN_HA_DISCOVERY_PREFIX", "homeassistant".to_string()),
+            ha_topic_prefix: topic("SVEN_HA_TOPIC_PREFIX", "{prefix}/ha"),
+            ha_node_id: settings.or("SVEN_HA_NODE_ID", "sven".to_string()),
+            mdns: settings.or("SVEN_MDNS", false),
+            mdns_name: settings.or("SVEN_MDNS_NAME", "Sven desk".to_string()),
+            desk_command_topic: topic("SVEN_DESK_COMMAND_TOPIC", "{prefix}/{id}/command"),
+            desk_state_topic: topic("SVEN_DESK_STATE_TOPIC", "{prefix}/{id}/state"),
+            topic_prefix,
+            transport: settings.or("SVEN_TRANSPORT", TransportKind::Mqtt),
+            transport_url: settings.raw("SVEN_TRANSPORT_URL"),
+            z2m_base_topic: settings.or("SVEN_Z2M_BASE_TOPIC", "zigbee2mqtt".to_string()),
+            z2m_device: settings.or("SVEN_Z2M_DEVICE", "desk".to_string()),
+        };
+
+        // A broker URL takes precedence over the separate host, port and credential settings
+        if let Some(url) = settings.raw("SVEN_MQTT_URL") {
+            let broker = BrokerUrl::parse(&url)?;
+            config.mqtt_host = broker.host;
+            config.mqtt_port = broker.port;
+            config.mqtt_tls = broker.tls;
+            if broker.username.is_some() {
+                config.mqtt_username = broker.username;
+                config.mqtt_password = broker.password;
+            }
+        }
+
+        for class in MessageClass::ALL {
+            let defaults = class.default_options();
+            let name = class.setting_name();
+            let QosLevel(qos) = settings.or(
+                &format!("SVEN_PUBLISH_{}_QOS", name),
+                QosLevel(defaults.qos),
+            );
+            let retain = settings.or(&format!("SVEN_PUBLISH_{}_RETAIN", name), defaults.retain);
+            config
+                .publish_classes
+                .set(class, PublishOptions { qos, retain });
+        }
+
+        if config.min_height_mm >= config.max_height_mm {
+            warn!(
+                "Invalid height bounds {}..{}, falling back to defaults",
+                config.min_height_mm, config.max_height_mm
+            );
+            config.min_height_mm = 650;
+            config.max_height_mm = 1250;
+        }
+
+        if config.collision_reverse && config.collision_reverse_mm == 0 {
+            warn!("SVEN_COLLISION_REVERSE_MM must be positive, using 20");
+            config.collision_reverse_mm = 20;
+        }
+
+        if config.height_estimation && config.travel_speed_mm_per_s == 0 {
+            warn!("SVEN_HEIGHT_ESTIMATION needs a positive SVEN_TRAVEL_SPEED_MM_S, using 35");
+            config.travel_speed_mm_per_s = 35;
+        }
+
+        // One DNS label, so at most 63 bytes
+        let mdns_name = config.mdns_name.trim();
+        if mdns_name.is_empty() || mdns_name.len() > 63 || mdns_name.contains('.') {
+            warn!("SVEN_MDNS_NAME must be 1 to 63 bytes without dots, using 'Sven desk'");
+            config.mdns_name = "Sven desk".to_string();
+        } else {
+            config.mdns_name = mdns_name.to_string();
+        }
+
+        if config.spool_max_commands == 0 {
+            warn!("SVEN_SPOOL_MAX_COMMANDS must be positive, using 20");
+            config.spool_max_commands = 20;
+        }
+
+        if config.soft_start_pulses_ms.contains(&0) {
+            warn!("SVEN_SOFT_START_PULSES_MS must be positive, dropping the empty pulses");
+            config.soft_start_pulses_ms.retain(|&pulse| pulse > 0);
+        }
+
+        if config.mqtt_signature_max_age_secs == 0 {
+            warn!("SVEN_MQTT_SIGNATURE_MAX_AGE_SECS must be positive, using 30");
+            config.mqtt_signature_max_age_secs = 30;
+        }
+
+        if config.session_ttl_secs == 0 {
+            warn!("SVEN_SESSION_TTL_SECS must be positive, using 43200");
+            config.session_ttl_secs = 12 * 60 * 60;
+        }
+
+        if config.max_command_body_bytes == 0 {
+            warn!("SVEN_MAX_COMMAND_BODY_BYTES must be positive, using 16384");
+            config.max_command_body_bytes = 16 * 1024;
+        }
+
+        if config.ota_chunk_bytes == 0 {
+            warn!("SVEN_OTA_CHUNK_BYTES must be positive, using 2048");
+            config.ota_chunk_bytes = 2048;
+        }
+
+        if config.ack_timeout_ms > config.ack_timeout_max_ms {
+            warn!(
+                "SVEN_ACK_TIMEOUT_MS {} exceeds SVEN_ACK_TIMEOUT_MAX_MS {}, using the maximum",
+                config.ack_timeout_ms, config.ack_timeout_max_ms
+            );
+            config.ack_timeout_ms = config.ack_timeout_max_ms;
+        }
+
+        if let Some(height_mm) = config.default_height_mm
+            && !config.height_in_bounds(height_mm)
+        {
+            warn!(
+                "SVEN_DEFAULT_HEIGHT_MM {} is outside {}..{}, ignoring it",
+                height_mm, config.min_height_mm, config.max_height_mm
+            );
+            config.default_height_mm = None;
+        }
+
+        if config.tls_cert.is_some() != config.tls_key.is_some() {
+            return Err("SVEN_TLS_CERT and SVEN_TLS_KEY have to be set together".to_string());
+        }
+
+        if config.transport == TransportKind::Http && config.transport_url.is_none() {
+            return Err("SVEN_TRANSPORT=http needs SVEN_TRANSPORT_URL".to_string());
+        }
+
+        if let Err(e) = TopicRouter::from_config(&config) {
+            return Err(format!("Invalid MQTT subscriptions: {}", e));
+        }
+
+        if config.telegram_bot_token.is_some() != config.telegram_chat_id.is_some() {
+            warn!(
+                "SVEN_TELEGRAM_BOT_TOKEN and SVEN_TELEGRAM_CHAT_ID have to be set together, not sending Telegram notifications"
+            );
+            config.telegram_bot_token = None;
+            config.telegram_chat_id = None;
+        }
+
+        Ok(config)
+    }
+
+    /// The topic the primary desk reports its state on.
+    pub fn primary_state_topic(&self) -> String {
+        let pattern = &self.state_topic_pattern;
+        match (&self.desk_id, pattern.contains('+')) {
+            (Some(desk_id), true) => pattern.replace('+', desk_id),
+            (None, true) => format!("{}/state", self.topic_prefix),
+            (_, false) => pattern.clone(),
+        }
+    }
+
+    pub fn height_in_bounds(&self, height_mm: u32) -> bool {
+        (self.min_height_mm..=self.max_height_mm).contains(&height_mm)
+    }
+
+    pub fn clamp_height(&self, height_mm: u32) -> u32 {
+        height_mm.clamp(self.min_height_mm, self.max_height_mm)
+    }
+
+    /// Config file entries that differ from `reloaded`'s, leaving out those the
+    /// environment overrides.
+    fn changed_settings(&self, reloaded: &Config) -> Vec<String> {
+        let mut keys: Vec<&String> = self
+            .file_settings
+            .keys()
+            .chain(reloaded.file_settings.keys())
+            .filter(|key| self.file_settings.get(*key) != reloaded.file_settings.get(*key))
+            .filter(|key| std::env::var(key).is_err())
+            .collect();
+        keys.sort();
+        keys.dedup();
+        keys.into_iter().cloned().collect()
+    }
+
+    /// This configuration with the changes in `reloaded` that can be applied while
+    /// running: height limits and protected zones, credentials and users, CORS
+    /// origins, the topics only published to and whether occupancy holds schedules
+    /// back. Everything else keeps its current value until a restart.
+    pub fn reload(&self, reloaded: Config) -> (Config, Reloaded) {
+        let mut config = self.clone();
+        let mut outcome = Reloaded::default();
+        for key in self.changed_settings(&reloaded) {
+            match key.as_str() {
+                "SVEN_MIN_HEIGHT_MM" => config.min_height_mm = reloaded.min_height_mm,
+                "SVEN_MAX_HEIGHT_MM" => config.max_height_mm = reloaded.max_height_mm,
+                "SVEN_SOFT_MIN_HEIGHT_MM" => {
+                    config.soft_height_limits.min_height_mm =
+                        reloaded.soft_height_limits.min_height_mm
+                }
+                "SVEN_SOFT_MAX_HEIGHT_MM" => {
+                    config.soft_height_limits.max_height_mm =
+                        reloaded.soft_height_limits.max_height_mm
+                }
+                "SVEN_DESK_HEIGHT_LIMITS" => {
+                    config.desk_height_limits = reloaded.desk_height_limits.clone()
+                }
+                "SVEN_HEIGHT_LIMIT_POLICY" => {
+                    config.height_limit_policy = reloaded.height_limit_policy
+                }
+                "SVEN_PROTECTED_ZONES" => config.protected_zones = reloaded.protected_zones.clone(),
+                "SVEN_ADMIN_TOKEN" => config.admin_token = reloaded.admin_token.clone(),
+                "SVEN_API_KEYS" => config.api_keys = reloaded.api_keys.clone(),
+                "SVEN_ACCOUNTS" => config.accounts = reloaded.accounts.clone(),
+                "SVEN_USERS" => config.users = reloaded.users.clone(),
+                "SVEN_CORS_ORIGINS" => config.cors_origins = reloaded.cors_origins.clone(),
+                "SVEN_CORS_ORIGIN_PATTERNS" => {
+                    config.cors_origin_patterns = reloaded.cors_origin_patterns.clone()
+                }
+                "SVEN_COMMAND_TOPIC" => config.command_topic = reloaded.command_topic.clone(),
+                "SVEN_DESK_COMMAND_TOPIC" => {
+                    config.desk_command_topic = reloaded.desk_command_topic.clone()
+                }
+                "SVEN_MOTOR_TOPIC" => config.motor_topic = reloaded.motor_topic.clone(),
+                "SVEN_MAINT_TOPIC" => config.maint_topic = reloaded.maint_topic.clone(),
+                "SVEN_OTA_TOPIC" => config.ota_topic = reloaded.ota_topic.clone(),
+                "SVEN_POWER_COMMAND_TOPIC" => {
+                    config.power_command_topic = reloaded.power_command_topic.clone()
+                }
+                "SVEN_OCCUPANCY_SUPPRESS_SCHEDULES" => {
+                    config.occupancy_suppresses_schedules = reloaded.occupancy_suppresses_schedules
+                }
+                "SVEN_QUIET_HOURS" => config.quiet_hours = reloaded.quiet_hours.clone(),
+                _ => {
+                    outcome.restart_required.push(key);
+                    continue;
+                }
+            }
+            outcome.applied.push(key);
+        }
+        // Settings still waiting for a restart are reported again on the next reload
+        config.file_settings = reloaded.file_settings;
+        for key in &outcome.restart_required {
+            match self.file_settings.get(key) {
+                Some(value) => config.file_settings.insert(key.clone(), value.clone()),
+                None => config.file_settings.remove(key),
+            };
+        }
+        (config, outcome)
+    }
+}
+
+/// Which changed settings a reload applied and which only take effect after a restart.
+#[derive(Debug, Default, Serialize)]
+pub struct Reloaded {
+    pub applied: Vec<String>,
+    pub restart_required: Vec<String>,
+}
+
+/// Where settings come from: environment variables, falling back to the config file.
+///
+/// File keys are the environment names without the `SVEN_` prefix, in lower case,
+/// with table names joined by `_`. So `[mqtt] host = "broker"` sets `SVEN_MQTT_HOST`
+/// and a top-level `min_height_mm = 700` sets `SVEN_MIN_HEIGHT_MM`. Arrays are joined
+/// with commas, e.g. `protected_zones = ["monitor=900-950"]`.
+struct Settings {
+    file: HashMap<String, String>,
+}
+
+impl Settings {
+    fn load(path: Option<&Path>) -> Result<Self, String> {
+        let mut file = HashMap::new();
+        if let Some(path) = path {
+            let text = std::fs::read_to_string(path)
+                .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+            let table: toml::Table = text
+                .parse()
+                .map_err(|e| format!("failed to parse {}: {}", path.display(), e))?;
+            flatten("SVEN", &table, &mut file)?;
+            info!("Loaded configuration from {}", path.display());
+        }
+        Ok(Settings { file })
+    }
+
+    fn raw(&self, key: &str) -> Option<String> {
+        std::env::var(key)
+            .ok()
+            .or_else(|| self.file.get(key).cloned())
+    }
+
+    fn or<T>(&self, key: &str, default: T) -> T
+    where
+        T: FromStr,
+        T::Err: std::fmt::Display,
+    {
+        self.opt(key).unwrap_or(default)
+    }
+
+    fn opt<T>(&self, key: &str) -> Option<T>
+    where
+        T: FromStr,
+        T::Err: std::fmt::Display,
+    {
+        let value = self.raw(key)?;
+        match value.parse() {
+            Ok(parsed) => Some(parsed),
+            Err(e) => {
+                warn!(
+                    "Invalid value '{}' for {}: {}, using default",
+                    value, key, e
+                );
+                None
+            }
+        }
+    }
+
+    /// Reads a comma separated list, skipping entries that don't parse.
+    fn list<T>(&self, key: &str) -> Vec<T>
+    where
+        T: FromStr,
+        T::Err: std::fmt::Display,
+    {
+        let Some(spec) = self.raw(key) else {
+            return Vec::new();
+        };
+        spec.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .filter_map(|entry| match entry.parse() {
+                Ok(parsed) => Some(parsed),
+                Err(e) => {
+                    warn!("Invalid entry '{}' in {}: {}, ignoring it", entry, key, e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Reads `name=value` pairs separated by commas, e.g. `standing=30,armrest=10`.
+    /// Names may contain `=` themselves; the value is taken after the last one.
+    fn pairs<T>(&self, key: &str) -> HashMap<String, T>
+    where
+        T: FromStr,
+        T::Err: std::fmt::Display,
+    {
+        let Some(spec) = self.raw(key) else {
+            return HashMap::new();
+        };
+        match parse_pairs(&spec) {
+            Ok(pairs) => pairs,
+            Err(e) => {
+                warn!("Invalid {}: {}, ignoring it", key, e);
+                HashMap::new()
+            }
+        }
+    }
+}
+
+/// Turns a parsed config file into environment style keys, see `Settings`.
+fn flatten(
+    prefix: &str,
+    table: &toml::Table,
+    out: &mut HashMap<String, String>,
+) -> Result<(), String> {
+    for (key, value) in table {
+        let name = format!("{}_{}", prefix, key.to_ascii_uppercase());
+        let text = match value {
+            toml::Value::Table(table) => {
+                flatten(&name, table, out)?;
+                continue;
+            }
+            toml::Value::String(text) => text.clone(),
+            toml::Value::Array(items) => items
+                .iter()
+                .map(|item| match item {
+                    toml::Value::String(text) => Ok(text.clone()),
+                    toml::Value::Table(_) | toml::Value::Array(_) => {
+                        Err(format!("{} may only contain plain values", name))
+                    }
+                    other => Ok(other.to_string()),
+                })
+                .collect::<Result<Vec<_>, _>>()?
+                .join(","),
+            other => other.to_string(),
+        };
+        out.insert(name, text);
+    }
+    Ok(())
+}
+
+fn parse_pairs<T>(spec: &str) -> Result<HashMap<String, T>, String>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    spec.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (name, value) = entry
+                .rsplit_once('=')
+                .ok_or_else(|| format!("expected name=value, got '{}'", entry))?;
+            let value = value
+                .trim()
+                .parse()
+                .map_err(|e| format!("invalid value for '{}': {}", name, e))?;
+            Ok((name.trim().to_string(), value))
+        })
+        .collect()
+}
@@ -0,0 +1,127 @@
+use axum::{
+    Json,
+    extract::Extension,
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::listen::ClientAddr;
+use crate::validation::ErrorBody;
+use crate::{AppState, admin};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    /// Which stream the client opened, e.g. `notifications`
+    pub kind: &'static str,
+    pub remote_addr: Option<String>,
+    pub api_key_id: Option<String>,
+    pub connected_at: DateTime<Utc>,
+    pub last_activity: DateTime<Utc>,
+    pub messages_sent: u64,
+}
+
+/// Long-lived client connections (SSE and WebSocket streams) that are currently open.
+///
+/// Uses a blocking mutex because entries are removed from `Drop`.
+#[derive(Default)]
+pub struct ConnectionRegistry {
+    next_id: AtomicU64,
+    connections: Arc<Mutex<BTreeMap<u64, ConnectionInfo>>>,
+}
+
+impl ConnectionRegistry {
+    /// Records a new connection; it stays listed until the returned guard is dropped.
+    pub fn register(
+        &self,
+        kind: &'static str,
+        remote: ClientAddr,
+        api_key_id: Option<String>,
+    ) -> ConnectionGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let now = Utc::now();
+        let remote_addr = match remote {
+            ClientAddr::Tcp(addr) => Some(addr.to_string()),
+            ClientAddr::Unix | ClientAddr::Internal => None,
+        };
+        self.connections.lock().unwrap().insert(
+            id,
+            ConnectionInfo {
+                id,
+                kind,
+                remote_addr,
+                api_key_id,
+                connected_at: now,
+                last_activity: now,
+                messages_sent: 0,
+            },
+        );
+        ConnectionGuard {
+            id,
+            connections: self.connections.clone(),
+        }
+    }
+
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        self.connections.lock().unwrap().values().cloned().collect()
+    }
+}
+
+/// Keeps a connection listed in the registry while the stream is alive.
+pub struct ConnectionGuard {
+    id: u64,
+    connections: Arc<Mutex<BTreeMap<u64, ConnectionInfo>>>,
+}
+
+impl ConnectionGuard {
+    /// Notes that a message was just sent to the client.
+    pub fn touch(&self) {
+        if let Some(info) = self.connections.lock().unwrap().get_mut(&self.id) {
+            info.last_activity = Utc::now();
+            info.messages_sent += 1;
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.connections.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/sven/connections",
+    tag = "admin",
+    responses(
+        (status = 200, body = serde_json::Value),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+        (status = 403, description = "Admin routes are disabled", body = ErrorBody),
+    ),
+)]
+pub async fn get_connections(
+    headers: HeaderMap,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    if let Err(rejection) = admin::require_admin(&headers, &app_state.config()) {
+        return rejection;
+    }
+    let connections = app_state.connections.list();
+    let mut by_kind: BTreeMap<&str, usize> = BTreeMap::new();
+    for connection in &connections {
+        *by_kind.entry(connection.kind).or_default() += 1;
+    }
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "total": connections.len(),
+            "by_kind": by_kind,
+            "connections": connections,
+        })),
+    )
+}
@@ -0,0 +1,38 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Minimum intervals between activations of the same named preset.
+pub struct PresetCooldowns {
+    cooldowns: HashMap<String, Duration>,
+    last_activation: HashMap<String, Instant>,
+}
+
+impl PresetCooldowns {
+    pub fn new(cooldowns: HashMap<String, Duration>) -> Self {
+        PresetCooldowns {
+            cooldowns,
+            last_activation: HashMap::new(),
+        }
+    }
+
+    /// How long `name` is still cooling down, if it is.
+    pub fn remaining(&self, name: &str) -> Option<Duration> {
+        let name = name.to_ascii_lowercase();
+        let cooldown = self.cooldowns.get(&name)?;
+        let elapsed = self.last_activation.get(&name)?.elapsed();
+        (elapsed < *cooldown).then(|| *cooldown - elapsed)
+    }
+
+    /// Records an activation of `name`, or returns the remaining cooldown
+    /// if it was activated too recently.
+    pub fn try_activate(&mut self, name: &str) -> Result<(), Duration> {
+        if let Some(remaining) = self.remaining(name) {
+            return Err(remaining);
+        }
+        let name = name.to_ascii_lowercase();
+        if self.cooldowns.contains_key(&name) {
+            self.last_activation.insert(name, Instant::now());
+        }
+        Ok(())
+    }
+}
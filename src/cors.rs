@@ -0,0 +1,66 @@
+use axum::http::{HeaderValue, Method, header::ETAG, request::Parts};
+use regex_automata::meta::Regex;
+use std::str::FromStr;
+use std::sync::Arc;
+use tower_http::cors::{AllowHeaders, AllowOrigin, Any, CorsLayer};
+use tracing::warn;
+
+use crate::{AppState, listing};
+
+/// An origin pattern from `SVEN_CORS_ORIGIN_PATTERNS`, matched against the whole
+/// origin, e.g. `https://.*\.example\.com`.
+#[derive(Debug, Clone)]
+pub struct OriginPattern(Regex);
+
+impl OriginPattern {
+    pub fn matches(&self, origin: &str) -> bool {
+        self.0.is_match(origin)
+    }
+}
+
+impl FromStr for OriginPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Regex::new(&format!("^(?:{})$", s))
+            .map(OriginPattern)
+            .map_err(|e| format!("invalid origin pattern '{}': {}", s, e))
+    }
+}
+
+/// Browsers may only call the API from the configured origins. Without any, no
+/// cross-origin request is allowed, unless `--insecure-cors` opens it to all. The
+/// origins are looked up per request, so a reload changes them.
+pub fn layer(app_state: &Arc<AppState>) -> CorsLayer {
+    let layer = CorsLayer::new()
+        .allow_methods([
+            Method::GET,
+            Method::POST,
+            Method::PUT,
+            Method::DELETE,
+            Method::OPTIONS,
+        ])
+        // For dashboards revalidating the state and paging collections
+        .expose_headers([ETAG, listing::TOTAL_COUNT]);
+    if app_state.config().insecure_cors {
+        warn!("CORS allows any origin, only use --insecure-cors on a trusted network");
+        return layer.allow_origin(Any).allow_headers(Any);
+    }
+
+    let app_state = app_state.clone();
+    layer
+        .allow_origin(AllowOrigin::predicate(
+            move |origin: &HeaderValue, _: &Parts| {
+                let config = app_state.config();
+                origin.to_str().is_ok_and(|origin| {
+                    config.cors_origins.iter().any(|allowed| allowed == origin)
+                        || config
+                            .cors_origin_patterns
+                            .iter()
+                            .any(|pattern| pattern.matches(origin))
+                })
+            },
+        ))
+        // Unlike `Any`, this also covers `Authorization`
+        .allow_headers(AllowHeaders::mirror_request())
+}
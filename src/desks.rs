@@ -0,0 +1,236 @@
+use axum::extract::ConnectInfo;
+use axum::extract::rejection::JsonRejection;
+use axum::http::HeaderMap;
+use axum::{
+    Json,
+    extract::{Extension, Path, Query},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::{Mutex, watch};
+use utoipa::ToSchema;
+
+use crate::listen::ClientAddr;
+use crate::units::{self, UnitParams};
+use crate::validation::ErrorBody;
+use crate::{AppState, CommandParams, CommandRequest, SvenState, validation};
+
+/// Last report received from one desk, with a channel for waiting on its moves.
+#[derive(Debug)]
+pub struct DeskEntry {
+    pub state_tx: watch::Sender<SvenState>,
+    pub updated_at: Instant,
+}
+
+impl DeskEntry {
+    pub fn new(state: SvenState) -> Self {
+        DeskEntry {
+            state_tx: watch::Sender::new(state),
+            updated_at: Instant::now(),
+        }
+    }
+
+    pub fn state(&self) -> SvenState {
+        *self.state_tx.borrow()
+    }
+
+    pub fn update(&mut self, state: SvenState) {
+        self.state_tx.send_replace(state);
+        self.updated_at = Instant::now();
+    }
+}
+
+/// Desks known from configuration (`SVEN_DESKS`) or from their state reports.
+#[derive(Default)]
+pub struct DeskRegistry {
+    configured: Vec<String>,
+    desks: Mutex<HashMap<String, DeskEntry>>,
+}
+
+impl DeskRegistry {
+    pub fn new(configured: Vec<String>) -> Self {
+        DeskRegistry {
+            configured,
+            desks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn update(&self, desk_id: String, state: SvenState) {
+        let mut desks = self.desks.lock().await;
+        match desks.get_mut(&desk_id) {
+            Some(entry) => entry.update(state),
+            None => {
+                desks.insert(desk_id, DeskEntry::new(state));
+            }
+        }
+    }
+
+    pub async fn is_known(&self, desk_id: &str) -> bool {
+        self.configured.iter().any(|id| id == desk_id)
+            || self.desks.lock().await.contains_key(desk_id)
+    }
+
+    /// Last state and when it arrived, `None` until the desk has reported.
+    pub async fn snapshot(&self, desk_id: &str) -> Option<(SvenState, Instant)> {
+        self.desks
+            .lock()
+            .await
+            .get(desk_id)
+            .map(|entry| (entry.state(), entry.updated_at))
+    }
+
+    pub async fn subscribe(&self, desk_id: &str) -> Option<watch::Receiver<SvenState>> {
+        self.desks
+            .lock()
+            .await
+            .get(desk_id)
+            .map(|entry| entry.state_tx.subscribe())
+    }
+
+    /// Ids of the configured and reported desks, sorted.
+    pub async fn ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self.configured.clone();
+        ids.extend(self.desks.lock().await.keys().cloned());
+        ids.sort();
+        ids.dedup();
+        ids
+    }
+
+    pub async fn lock(&self) -> tokio::sync::MutexGuard<'_, HashMap<String, DeskEntry>> {
+        self.desks.lock().await
+    }
+}
+
+/// Per desk state response, shared by the single and bulk endpoints.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DeskStateView {
+    #[serde(flatten)]
+    pub state: SvenState,
+    pub age_ms: u64,
+    /// Older than `SVEN_STALE_STATE_SECS`; never set when that is unconfigured
+    pub stale: bool,
+    /// Whether the bridge is currently connected to the broker the desk reports through
+    pub connected: bool,
+}
+
+impl DeskStateView {
+    fn new(entry: &DeskEntry, app_state: &AppState, connected: bool) -> Self {
+        let age = entry.updated_at.elapsed();
+        DeskStateView {
+            state: entry.state(),
+            age_ms: age.as_millis() as u64,
+            stale: app_state
+                .config()
+                .stale_state_max_age
+                .is_some_and(|max_age| age > max_age),
+            connected,
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/desks/{desk_id}/state",
+    tag = "desk",
+    params(("desk_id" = String, Path)),
+    responses(
+        (status = 200, body = DeskStateView),
+        (status = 404, description = "Unknown desk", body = ErrorBody),
+    ),
+)]
+pub async fn get_desk_state(
+    Path(desk_id): Path<String>,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    let connected = app_state.reconnect_monitor.lock().await.is_connected();
+    match app_state.desks.lock().await.get(&desk_id) {
+        Some(entry) => (
+            StatusCode::OK,
+            Json(serde_json::json!(DeskStateView::new(
+                entry, &app_state, connected
+            ))),
+        ),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({"error": format!("Unknown desk '{}'", desk_id)})),
+        ),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/desks",
+    tag = "desk",
+    responses(
+        (status = 200, description = "State of every known desk by id", body = BTreeMap<String, DeskStateView>),
+    ),
+)]
+pub async fn get_desk_states(Extension(app_state): Extension<Arc<AppState>>) -> impl IntoResponse {
+    let connected = app_state.reconnect_monitor.lock().await.is_connected();
+    let desks: BTreeMap<String, DeskStateView> = app_state
+        .desks
+        .lock()
+        .await
+        .iter()
+        .map(|(desk_id, entry)| {
+            (
+                desk_id.clone(),
+                DeskStateView::new(entry, &app_state, connected),
+            )
+        })
+        .collect();
+    Json(desks)
+}
+
+/// Sends a command to one desk of the registry, on its own command topic.
+#[utoipa::path(
+    post,
+    path = "/api/desks/{desk_id}/command",
+    tag = "desk",
+    params(("desk_id" = String, Path), CommandParams, UnitParams),
+    request_body = CommandRequest,
+    responses(
+        (status = 200, body = serde_json::Value),
+        (status = 404, description = "Unknown desk", body = ErrorBody),
+        (status = 413, description = "Body larger than SVEN_MAX_COMMAND_BODY_BYTES", body = ErrorBody),
+        (status = 422, description = "Invalid request", body = ErrorBody),
+    ),
+)]
+pub async fn handle_desk_command(
+    Path(desk_id): Path<String>,
+    ConnectInfo(remote): ConnectInfo<ClientAddr>,
+    headers: HeaderMap,
+    Query(params): Query<CommandParams>,
+    Query(unit_params): Query<UnitParams>,
+    Extension(app_state): Extension<Arc<AppState>>,
+    request: Result<Json<units::CommandBody>, JsonRejection>,
+) -> impl IntoResponse {
+    if !app_state.desks.is_known(&desk_id).await {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({"error": format!("Unknown desk '{}'", desk_id)})),
+        );
+    }
+    let Json(body) = match request {
+        Ok(body) => body,
+        Err(rejection) => return validation::body_rejection(rejection),
+    };
+    let unit = match units::negotiate(&headers, &unit_params) {
+        Ok(unit) => unit,
+        Err(rejection) => return rejection,
+    };
+    let mut request = match unit.read_command(body) {
+        Ok(request) => request,
+        Err(rejection) => return rejection,
+    };
+    request.dry_run |= params.dry_run();
+    request.force |= params.force();
+    let (status, Json(mut body)) =
+        crate::execute_command(&app_state, remote, &headers, Some(&desk_id), request).await;
+    unit.annotate(&mut body);
+    (status, Json(body))
+}
@@ -0,0 +1,310 @@
+//! Finding the bridge on the local network. With `SVEN_MDNS` the API is
+//! advertised over mDNS as a `_sven-api._tcp` service named `SVEN_MDNS_NAME`,
+//! its TXT record carrying the version, scheme, whether a key is needed, the
+//! registry desks and the capabilities. The responder is `mdns-sd`'s daemon,
+//! which probes for the name, answers queries and says goodbye on shutdown; the
+//! service is registered again whenever the TXT record changes. The address of
+//! `SVEN_HTTP_HOST` is advertised, or every interface's IPv4 addresses when
+//! serving on all of them. `GET /api/discovery` answers the same details,
+//! without a key, for clients that already know the address.
+
+use axum::{Json, extract::Extension, response::IntoResponse};
+use mdns_sd::{IfKind, ServiceDaemon, ServiceInfo};
+use serde::Serialize;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+use utoipa::ToSchema;
+
+use crate::config::Config;
+use crate::{AppState, auth};
+
+pub const SERVICE: &str = "_sven-api._tcp";
+/// How often the TXT record is checked for changes worth announcing
+const REFRESH: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct DiscoveryInfo {
+    /// Instance name the bridge is advertised under
+    pub name: String,
+    #[schema(example = "_sven-api._tcp")]
+    pub service: String,
+    pub version: String,
+    #[schema(example = "http")]
+    pub scheme: String,
+    /// `None` when serving on a Unix socket
+    pub port: Option<u16>,
+    /// Whether calls need an API key, token or login
+    pub auth_required: bool,
+    /// Registry desks, reachable at `/api/desks/{desk_id}`; the primary desk is at `/api/sven`
+    pub desks: Vec<String>,
+    #[schema(example = json!(["websocket", "events"]))]
+    pub capabilities: Vec<String>,
+    pub grpc_port: Option<u16>,
+}
+
+impl DiscoveryInfo {
+    pub async fn of(app_state: &AppState) -> Self {
+        let config = app_state.config();
+        let tokens_issued = !app_state.tokens.lock().await.0.is_empty();
+        let tls = config.tls_cert.is_some() || config.tls_self_signed;
+        DiscoveryInfo {
+            name: config.mdns_name.clone(),
+            service: SERVICE.to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            scheme: if tls && config.bind_uds.is_none() {
+                "https"
+            } else {
+                "http"
+            }
+            .to_string(),
+            port: config.bind_uds.is_none().then_some(config.http_port),
+            auth_required: auth::protected(&config) || tokens_issued,
+            desks: app_state.desks.ids().await,
+            capabilities: capabilities(&config),
+            grpc_port: grpc_port(&config),
+        }
+    }
+
+    /// TXT record entries, leaving out any too long for one.
+    fn txt(&self) -> Vec<(&'static str, String)> {
+        [
+            ("txtvers", "1".to_string()),
+            ("version", self.version.clone()),
+            ("path", "/api".to_string()),
+            ("scheme", self.scheme.clone()),
+            ("auth", u8::from(self.auth_required).to_string()),
+            ("desks", self.desks.join(",")),
+            ("caps", self.capabilities.join(",")),
+        ]
+        .into_iter()
+        .filter(|(key, value)| key.len() + 1 + value.len() <= 255)
+        .collect()
+    }
+}
+
+fn capabilities(config: &Config) -> Vec<String> {
+    let mut capabilities = vec!["websocket", "events"];
+    if cfg!(feature = "graphql") {
+        capabilities.push("graphql");
+    }
+    if grpc_port(config).is_some() {
+        capabilities.push("grpc");
+    }
+    if !config.accounts.is_empty() {
+        capabilities.push("login");
+    }
+    if !config.desk_groups.is_empty() {
+        capabilities.push("groups");
+    }
+    if config.ha_discovery {
+        capabilities.push("homeassistant");
+    }
+    if config.spool_commands {
+        capabilities.push("spool");
+    }
+    if config.height_estimation {
+        capabilities.push("height_estimation");
+    }
+    if config.simulate {
+        capabilities.push("simulated");
+    }
+    capabilities.into_iter().map(str::to_string).collect()
+}
+
+fn grpc_port(config: &Config) -> Option<u16> {
+    config.grpc_port.filter(|_| cfg!(feature = "grpc"))
+}
+
+/// The bridge's name, version, desks and capabilities, as also advertised over mDNS.
+#[utoipa::path(
+    get,
+    path = "/api/discovery",
+    tag = "info",
+    responses((status = 200, body = DiscoveryInfo)),
+)]
+pub async fn get_discovery(Extension(app_state): Extension<Arc<AppState>>) -> impl IntoResponse {
+    Json(DiscoveryInfo::of(&app_state).await)
+}
+
+/// `name` as a host label: lowercase letters, digits and dashes.
+fn host_label(name: &str) -> String {
+    let label: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    let label = label.trim_matches('-');
+    if label.is_empty() {
+        "sven".to_string()
+    } else {
+        label.to_string()
+    }
+}
+
+/// The service as registered with the daemon, on `address` or, without one,
+/// on whatever addresses the host's interfaces have.
+fn service_info(
+    info: &DiscoveryInfo,
+    port: u16,
+    address: Option<IpAddr>,
+) -> Result<ServiceInfo, mdns_sd::Error> {
+    let host = format!("{}.local.", host_label(&info.name));
+    let ty_domain = format!("{}.local.", SERVICE);
+    let service = match address {
+        Some(address) => ServiceInfo::new(
+            &ty_domain,
+            &info.name,
+            &host,
+            address,
+            port,
+            &info.txt()[..],
+        )?,
+        None => ServiceInfo::new(&ty_domain, &info.name, &host, (), port, &info.txt()[..])?
+            .enable_addr_auto(),
+    };
+    Ok(service)
+}
+
+/// Advertises the API over mDNS until shutdown.
+pub async fn run_mdns(app_state: Arc<AppState>) {
+    let config = app_state.config();
+    if config.bind_uds.is_some() {
+        warn!("SVEN_MDNS is set, but the API is served on a Unix socket; not advertising it");
+        return;
+    }
+    // Serving on every address, or a host name, advertises the interfaces' addresses
+    let address = config
+        .http_host
+        .parse::<IpAddr>()
+        .ok()
+        .filter(|address| !address.is_unspecified());
+    if address.is_some_and(|address| address.is_loopback()) {
+        warn!(
+            "SVEN_MDNS is set, but the API only listens on {}; not advertising it",
+            config.http_host
+        );
+        return;
+    }
+    let port = config.http_port;
+    let ipv4_only = address.is_none() && config.http_host != "::";
+    drop(config);
+    let daemon = match ServiceDaemon::new() {
+        Ok(daemon) => daemon,
+        Err(e) => {
+            warn!(
+                "Failed to start the mDNS responder, not advertising the API: {}",
+                e
+            );
+            return;
+        }
+    };
+    if ipv4_only && let Err(e) = daemon.disable_interface(IfKind::IPv6) {
+        debug!("Failed to leave out IPv6 interfaces: {}", e);
+    }
+
+    let mut announced = DiscoveryInfo::of(&app_state).await;
+    let register = |info: &DiscoveryInfo| -> Option<String> {
+        let service = service_info(info, port, address).and_then(|service| {
+            let fullname = service.get_fullname().to_string();
+            daemon.register(service).map(|()| fullname)
+        });
+        match service {
+            Ok(fullname) => Some(fullname),
+            Err(e) => {
+                warn!("Failed to advertise the API over mDNS: {}", e);
+                None
+            }
+        }
+    };
+    let Some(fullname) = register(&announced) else {
+        let _ = daemon.shutdown();
+        return;
+    };
+    info!(
+        "Advertising '{}' as {} on port {}",
+        announced.name, SERVICE, port
+    );
+    let shutdown = app_state.shutdown.wait();
+    tokio::pin!(shutdown);
+    let mut refresh = tokio::time::interval_at(tokio::time::Instant::now() + REFRESH, REFRESH);
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => break,
+            _ = refresh.tick() => {
+                let current = DiscoveryInfo::of(&app_state).await;
+                if current != announced {
+                    announced = current;
+                    register(&announced);
+                }
+            }
+        }
+    }
+    // Waiting on the goodbye keeps it from being cut off by the daemon's exit
+    if let Ok(unregistered) = daemon.unregister(&fullname) {
+        let _ = tokio::time::timeout(Duration::from_secs(1), unregistered.recv_async()).await;
+        debug!("Withdrew the mDNS advertisement");
+    }
+    let _ = daemon.shutdown();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info() -> DiscoveryInfo {
+        DiscoveryInfo {
+            name: "Office desk".to_string(),
+            service: SERVICE.to_string(),
+            version: "1.2.3".to_string(),
+            scheme: "http".to_string(),
+            port: Some(3001),
+            auth_required: true,
+            desks: vec!["office-1".to_string(), "office-2".to_string()],
+            capabilities: vec!["websocket".to_string()],
+            grpc_port: None,
+        }
+    }
+
+    #[test]
+    fn the_service_carries_the_instance_host_and_txt_record() {
+        let address = "192.168.1.20".parse().unwrap();
+        let service = service_info(&info(), 3001, Some(address)).unwrap();
+        assert_eq!(service.get_fullname(), "Office desk._sven-api._tcp.local.");
+        assert_eq!(service.get_hostname(), "office-desk.local.");
+        assert_eq!(service.get_port(), 3001);
+        assert!(service.get_addresses().contains(&address));
+        assert!(!service.is_addr_auto());
+        assert_eq!(
+            service.get_property_val_str("desks"),
+            Some("office-1,office-2")
+        );
+        assert_eq!(service.get_property_val_str("auth"), Some("1"));
+
+        let service = service_info(&info(), 3001, None).unwrap();
+        assert!(service.is_addr_auto());
+        assert!(service.get_addresses().is_empty());
+    }
+
+    #[test]
+    fn entries_too_long_for_the_txt_record_are_left_out() {
+        let mut info = info();
+        info.desks = (0..40).map(|i| format!("standing-desk-{}", i)).collect();
+        let txt = info.txt();
+        assert!(txt.iter().all(|(key, _)| *key != "desks"));
+        assert!(txt.iter().any(|(key, _)| *key == "caps"));
+        assert!(service_info(&info, 3001, None).is_ok());
+    }
+
+    #[test]
+    fn host_labels_are_lowercase_letters_digits_and_dashes() {
+        assert_eq!(host_label("Office desk #2"), "office-desk--2");
+        assert_eq!(host_label("--"), "sven");
+    }
+}
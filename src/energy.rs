@@ -0,0 +1,118 @@
+//! Power readings from a smart plug the desk is plugged into, such as a Tasmota
+//! or Shelly plug. Readings arrive on `SVEN_POWER_METER_TOPIC`, either as a bare
+//! number of watts or as JSON holding it at `SVEN_POWER_METER_FIELD`, and show up
+//! as `power_w` in the desk state. A draw of `SVEN_POWER_METER_MOVING_W` or more
+//! means the motor is running, which is checked against the heights the desk
+//! reports. With the history database, readings are kept and
+//! `GET /api/sven/stats` adds up the energy used.
+
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use utoipa::ToSchema;
+
+use crate::movement::Direction;
+
+/// What the power draw says the desk is doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerDraw {
+    Idle,
+    Moving,
+}
+
+impl PowerDraw {
+    pub fn from_watts(watts: f64, moving_from_w: f64) -> PowerDraw {
+        if watts >= moving_from_w {
+            PowerDraw::Moving
+        } else {
+            PowerDraw::Idle
+        }
+    }
+
+    /// Whether the draw disagrees with the direction from the height reports.
+    pub fn contradicts(self, direction: Direction) -> bool {
+        (self == PowerDraw::Moving) == (direction == Direction::Idle)
+    }
+}
+
+/// Reads the watts out of a plug's report. JSON objects are followed along
+/// `field`, a dot separated path like `ENERGY.Power` for Tasmota.
+pub fn parse_watts(payload: &[u8], field: &str) -> Option<f64> {
+    let text = std::str::from_utf8(payload).ok()?.trim();
+    let mut value: serde_json::Value = serde_json::from_str(text).ok()?;
+    if value.is_object() {
+        for key in field.split('.') {
+            value = value.get_mut(key)?.take();
+        }
+    }
+    let watts = match value {
+        serde_json::Value::Number(number) => number.as_f64()?,
+        serde_json::Value::String(text) => text.trim().parse().ok()?,
+        _ => return None,
+    };
+    (watts.is_finite() && watts >= 0.0).then_some(watts)
+}
+
+/// Watt-hours used each day of `tz` between `since` and `until`. Each reading
+/// holds until the next one; time before the first reading isn't counted.
+/// `readings` are oldest first.
+pub fn daily_energy_wh<Tz: TimeZone>(
+    readings: &[(DateTime<Utc>, f64)],
+    since: DateTime<Utc>,
+    until: DateTime<Utc>,
+    tz: &Tz,
+) -> BTreeMap<NaiveDate, f64> {
+    let local_date = |at: DateTime<Utc>| at.with_timezone(tz).date_naive();
+    let mut days = BTreeMap::new();
+    for (index, &(read_at, watts)) in readings.iter().enumerate() {
+        let end = readings
+            .get(index + 1)
+            .map_or(until, |(next_at, _)| *next_at)
+            .min(until);
+        let mut start = read_at.max(since);
+        while start < end {
+            let next_midnight = local_date(start)
+                .succ_opt()
+                .and_then(|next| next.and_hms_opt(0, 0, 0))
+                .and_then(|midnight| tz.from_local_datetime(&midnight).earliest())
+                .map(|midnight| midnight.with_timezone(&Utc))
+                .unwrap_or(start + chrono::Duration::days(1));
+            let segment_end = next_midnight.min(end);
+            let hours = (segment_end - start).num_milliseconds() as f64 / 3_600_000.0;
+            *days.entry(local_date(start)).or_default() += watts * hours;
+            start = segment_end;
+        }
+    }
+    days
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn readings_are_found_in_plug_reports() {
+        let tasmota = br#"{"Time":"2026-10-14T09:00:00","ENERGY":{"Total":1.2,"Power":84}}"#;
+        assert_eq!(parse_watts(tasmota, "ENERGY.Power"), Some(84.0));
+        assert_eq!(parse_watts(b"3.5", "ENERGY.Power"), Some(3.5));
+        assert_eq!(parse_watts(br#"{"apower": "12.5"}"#, "apower"), Some(12.5));
+        assert_eq!(parse_watts(br#"{"ENERGY":{}}"#, "ENERGY.Power"), None);
+        assert_eq!(parse_watts(b"-4", "ENERGY.Power"), None);
+        assert_eq!(parse_watts(b"on", "ENERGY.Power"), None);
+
+        assert!(PowerDraw::Moving.contradicts(Direction::Idle));
+        assert!(PowerDraw::Idle.contradicts(Direction::Up));
+        assert!(!PowerDraw::Moving.contradicts(Direction::Down));
+    }
+
+    #[test]
+    fn energy_is_split_at_midnight() {
+        let at = |day: u32, hour: u32| Utc.with_ymd_and_hms(2026, 3, day, hour, 0, 0).unwrap();
+        let readings = [(at(9, 22), 2.0), (at(10, 1), 100.0), (at(10, 2), 2.0)];
+        let days = daily_energy_wh(&readings, at(9, 23), at(10, 4), &Utc);
+        let wh = |day: u32| days[&NaiveDate::from_ymd_opt(2026, 3, day).unwrap()];
+        assert_eq!(wh(9), 2.0);
+        assert_eq!(wh(10), 2.0 + 100.0 + 4.0);
+    }
+}
@@ -0,0 +1,98 @@
+use axum::{Json, extract::Extension, http::StatusCode, response::IntoResponse};
+use serde::Deserialize;
+use std::sync::Arc;
+use tracing::{error, info};
+use utoipa::ToSchema;
+
+use crate::validation::ErrorBody;
+use crate::{AppState, SvenPosition, positions};
+
+/// Desk height as a fraction of body height, from the common rule of thumb that
+/// the desk surface should sit at elbow height: about 0.4 of body height when
+/// seated and 0.6 when standing (e.g. 180 cm gives 720 mm sitting, 1080 mm standing).
+const SITTING_RATIO: f64 = 0.4;
+const STANDING_RATIO: f64 = 0.6;
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct RecommendRequest {
+    pub user_height_cm: u32,
+    /// Save the recommendation as the Bottom and Standing position heights
+    #[serde(default)]
+    pub apply: bool,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/sven/recommend",
+    tag = "positions",
+    request_body = RecommendRequest,
+    responses(
+        (status = 200, description = "Recommended sitting and standing heights", body = serde_json::Value),
+        (status = 422, description = "Invalid request", body = ErrorBody),
+    ),
+)]
+pub async fn handle_recommend(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Json(request): Json<RecommendRequest>,
+) -> impl IntoResponse {
+    if !(100..=250).contains(&request.user_height_cm) {
+        return (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(serde_json::json!({
+                "error": format!(
+                    "user_height_cm must be between 100 and 250, got {}",
+                    request.user_height_cm
+                )
+            })),
+        );
+    }
+
+    let height_mm = f64::from(request.user_height_cm) * 10.0;
+    let config = &app_state.config();
+    let sitting_mm = config.clamp_height((height_mm * SITTING_RATIO).round() as u32);
+    let standing_mm = config.clamp_height((height_mm * STANDING_RATIO).round() as u32);
+
+    let mut warnings = Vec::new();
+    if request.apply {
+        let mut positions = app_state.position_heights.lock().await;
+        let mut updated = positions.clone();
+        updated.heights.insert(SvenPosition::Bottom, sitting_mm);
+        updated.heights.insert(SvenPosition::Standing, standing_mm);
+        let presets = app_state.presets.lock().await;
+        for (position, height_mm) in [
+            (SvenPosition::Bottom, sitting_mm),
+            (SvenPosition::Standing, standing_mm),
+        ] {
+            let existing = updated.named_heights().chain(presets.named_heights());
+            let name = format!("{:?}", position);
+            match positions::check_duplicate(config, existing, &name, height_mm) {
+                Ok(warning) => warnings.extend(warning),
+                Err(rejection) => return rejection,
+            }
+        }
+        *positions = updated;
+        if let Some(store) = &app_state.store
+            && let Err(e) = store.put(&*positions)
+        {
+            error!("Failed to persist position heights: {}", e);
+        }
+        info!(
+            "Applied recommended heights: sitting {} mm, standing {} mm",
+            sitting_mm, standing_mm
+        );
+    }
+
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "sitting_mm": sitting_mm,
+            "standing_mm": standing_mm,
+            "formula": format!(
+                "sitting = {} x body height, standing = {} x body height, clamped to desk limits",
+                SITTING_RATIO, STANDING_RATIO
+            ),
+            "applied": request.apply,
+            "warnings": warnings,
+        })),
+    )
+}
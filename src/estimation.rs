@@ -0,0 +1,196 @@
+//! Heights for controllers without a height sensor, which only take timed moves.
+//! With `SVEN_HEIGHT_ESTIMATION`, `AbsoluteHeight`, `UpRelative` and
+//! `DownRelative` commands for the primary desk are published as `UpDuration` or
+//! `DownDuration` for as long as the desk takes at `SVEN_TRAVEL_SPEED_MM_S`, and
+//! the state's height is worked out from the timed moves sent: it is updated when
+//! a move has run its course, or when a stop cuts it short. Percentages and
+//! calibrated positions become absolute moves first, so they are timed too. The
+//! estimate starts from the last persisted state, `SVEN_DEFAULT_HEIGHT_MM`
+//! otherwise; a long enough `DownDuration` brings it back to the lowest height
+//! should it drift.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+use crate::config::Config;
+use crate::{AppState, DeskCommand, SvenCommand, SvenPosition, SvenState};
+
+/// How often a running move is checked for having run its course.
+const TICK: Duration = Duration::from_millis(100);
+
+/// The timed move `command` is sent as from `current_height_mm`, `None` for
+/// commands that aren't moves to a height.
+pub fn timed(
+    config: &Config,
+    command: &DeskCommand,
+    current_height_mm: u32,
+) -> Option<DeskCommand> {
+    let target_mm = match command.command {
+        SvenCommand::AbsoluteHeight => command.value,
+        SvenCommand::UpRelative => current_height_mm.saturating_add(command.value),
+        SvenCommand::DownRelative => current_height_mm.saturating_sub(command.value),
+        _ => return None,
+    };
+    let target_mm = config.clamp_height(target_mm);
+    let distance_mm = u64::from(target_mm.abs_diff(current_height_mm));
+    let speed = u64::from(config.travel_speed_mm_per_s.max(1));
+    Some(DeskCommand {
+        command: if target_mm >= current_height_mm {
+            SvenCommand::UpDuration
+        } else {
+            SvenCommand::DownDuration
+        },
+        value: (distance_mm * 1000).div_ceil(speed) as u32,
+    })
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TimedMove {
+    from_mm: u32,
+    up: bool,
+    duration: Duration,
+    started: Instant,
+}
+
+/// The timed move of the primary desk in progress, if any.
+#[derive(Debug, Default)]
+pub struct Estimator(Option<TimedMove>);
+
+impl Estimator {
+    /// Starts estimating `command`, a timed move from `from_mm`.
+    pub fn start(&mut self, from_mm: u32, command: &DeskCommand) {
+        let up = match command.command {
+            SvenCommand::UpDuration => true,
+            SvenCommand::DownDuration => false,
+            _ => return,
+        };
+        self.0 = Some(TimedMove {
+            from_mm,
+            up,
+            duration: Duration::from_millis(command.value.into()),
+            started: Instant::now(),
+        });
+    }
+
+    /// Where the move has got to at `now` and whether it's over, `None` when the
+    /// desk isn't moving.
+    fn height_at(&self, config: &Config, now: Instant) -> Option<(u32, bool)> {
+        let timed = self.0?;
+        let elapsed = now.duration_since(timed.started).min(timed.duration);
+        let travelled_mm =
+            (elapsed.as_millis() as u64 * u64::from(config.travel_speed_mm_per_s) / 1000) as u32;
+        let height_mm = if timed.up {
+            timed.from_mm.saturating_add(travelled_mm)
+        } else {
+            timed.from_mm.saturating_sub(travelled_mm)
+        };
+        Some((config.clamp_height(height_mm), elapsed == timed.duration))
+    }
+
+    /// Where the move in progress has got to by `now`.
+    pub fn current(&self, config: &Config, now: Instant) -> Option<u32> {
+        self.height_at(config, now).map(|(height_mm, _)| height_mm)
+    }
+
+    /// The height the finished move ended at, once it has.
+    pub fn finished(&mut self, config: &Config, now: Instant) -> Option<u32> {
+        let (height_mm, over) = self.height_at(config, now)?;
+        if over {
+            self.0 = None;
+        }
+        over.then_some(height_mm)
+    }
+
+    /// The height a stop at `now` leaves the desk at, if it was moving.
+    pub fn stop(&mut self, config: &Config, now: Instant) -> Option<u32> {
+        let (height_mm, _) = self.height_at(config, now)?;
+        self.0 = None;
+        Some(height_mm)
+    }
+}
+
+/// Reports an estimated height as the primary desk's state.
+pub async fn report(app_state: &AppState, height_mm: u32) {
+    let motor = app_state.sven_state.read().await.motor;
+    debug!("Estimated the desk at {} mm", height_mm);
+    crate::apply_state_update(
+        app_state,
+        None,
+        SvenState {
+            height_mm,
+            position: SvenPosition::Custom,
+            motor,
+        },
+    )
+    .await;
+}
+
+/// Reports where timed moves end, while height estimation is on.
+pub async fn run_estimation(app_state: Arc<AppState>) {
+    let mut tick = tokio::time::interval(TICK);
+    loop {
+        tick.tick().await;
+        if !app_state.config().height_estimation {
+            continue;
+        }
+        let finished = app_state
+            .height_estimator
+            .lock()
+            .await
+            .finished(&app_state.config(), Instant::now());
+        if let Some(height_mm) = finished {
+            report(&app_state, height_mm).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heights_are_timed_and_estimated() {
+        let mut config = Config::load_from(None).unwrap();
+        config.travel_speed_mm_per_s = 40;
+        let absolute = DeskCommand {
+            command: SvenCommand::AbsoluteHeight,
+            value: 900,
+        };
+        let up = timed(&config, &absolute, 700).unwrap();
+        assert_eq!(up.command, SvenCommand::UpDuration);
+        assert_eq!(up.value, 5000);
+        let down = DeskCommand {
+            command: SvenCommand::DownRelative,
+            value: 10,
+        };
+        assert_eq!(timed(&config, &down, 700).unwrap().value, 250);
+        let stop = DeskCommand {
+            command: SvenCommand::Stop,
+            value: 0,
+        };
+        assert!(timed(&config, &stop, 700).is_none());
+
+        let mut estimator = Estimator::default();
+        estimator.start(700, &up);
+        let started = estimator.0.unwrap().started;
+        assert_eq!(
+            estimator.finished(&config, started + Duration::from_secs(1)),
+            None
+        );
+        assert_eq!(
+            estimator.finished(&config, started + Duration::from_secs(6)),
+            Some(900)
+        );
+        assert_eq!(estimator.finished(&config, started), None);
+
+        // A stop leaves the desk where it had got to
+        estimator.start(900, &up);
+        let started = estimator.0.unwrap().started;
+        assert_eq!(
+            estimator.stop(&config, started + Duration::from_millis(2500)),
+            Some(1000)
+        );
+        assert_eq!(estimator.stop(&config, started), None);
+    }
+}
@@ -0,0 +1,112 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::path::PathBuf;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tracing::{error, warn};
+
+#[derive(Debug, Serialize)]
+struct EventRecord {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    payload: serde_json::Value,
+    timestamp: DateTime<Utc>,
+    request_id: Option<String>,
+}
+
+/// Appends commands and state changes to a newline-delimited JSON file.
+///
+/// Records are handed to a background writer task so a slow or failing disk
+/// never holds up request handling; if the writer falls behind, records are dropped.
+#[derive(Clone)]
+pub struct EventLog {
+    sender: mpsc::Sender<EventRecord>,
+}
+
+impl EventLog {
+    pub fn spawn(path: PathBuf, max_bytes: u64, queue_capacity: usize) -> Self {
+        let (sender, receiver) = mpsc::channel(queue_capacity);
+        tokio::spawn(write_records(path, max_bytes, receiver));
+        EventLog { sender }
+    }
+
+    /// Records waiting for the writer task.
+    pub fn queued(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity()
+    }
+
+    pub fn queue_capacity(&self) -> usize {
+        self.sender.max_capacity()
+    }
+
+    pub fn log<T: Serialize>(&self, kind: &'static str, payload: &T, request_id: Option<&str>) {
+        let payload = match serde_json::to_value(payload) {
+            Ok(payload) => payload,
+            Err(e) => {
+                error!("Failed to serialize {} event: {:?}", kind, e);
+                return;
+            }
+        };
+        let record = EventRecord {
+            kind,
+            payload,
+            timestamp: Utc::now(),
+            request_id: request_id.map(str::to_string),
+        };
+        if self.sender.try_send(record).is_err() {
+            warn!("Event log writer is behind, dropping {} event", kind);
+        }
+    }
+}
+
+async fn write_records(path: PathBuf, max_bytes: u64, mut receiver: mpsc::Receiver<EventRecord>) {
+    let mut file: Option<tokio::fs::File> = None;
+    let mut size = 0;
+
+    while let Some(record) = receiver.recv().await {
+        let mut line = match serde_json::to_vec(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Failed to serialize event record: {:?}", e);
+                continue;
+            }
+        };
+        line.push(b'\n');
+
+        if file.is_some() && size + line.len() as u64 > max_bytes {
+            file = None;
+            let rotated = path.with_extension("1.ndjson");
+            if let Err(e) = tokio::fs::rename(&path, &rotated).await {
+                error!("Failed to rotate event log {}: {:?}", path.display(), e);
+            }
+        }
+
+        if file.is_none() {
+            match tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .await
+            {
+                Ok(opened) => {
+                    size = opened.metadata().await.map(|m| m.len()).unwrap_or(0);
+                    file = Some(opened);
+                }
+                Err(e) => {
+                    error!("Failed to open event log {}: {:?}", path.display(), e);
+                    continue;
+                }
+            }
+        }
+
+        if let Some(f) = file.as_mut() {
+            match f.write_all(&line).await {
+                Ok(()) => size += line.len() as u64,
+                Err(e) => {
+                    error!("Failed to write event log {}: {:?}", path.display(), e);
+                    file = None;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,87 @@
+//! Command expiry, so a command that couldn't go out in time isn't sent late.
+//! With `SVEN_COMMAND_TTL_SECS` or a request's `ttl_ms`, desk commands carry an
+//! `expires_at` (Unix milliseconds) for the firmware to check, a command still
+//! waiting in the queue when it expires is answered with `command_expired`, and
+//! publishes rumqttc kept for a reconnect are dropped once they have expired, so
+//! the desk doesn't start moving when the broker comes back a minute later.
+
+use chrono::{DateTime, Utc};
+use rumqttc::Request;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// When a command sent now with `ttl` expires; `None` without a TTL.
+pub fn deadline(ttl: Option<Duration>) -> Option<DateTime<Utc>> {
+    ttl.and_then(|ttl| chrono::Duration::from_std(ttl).ok())
+        .map(|ttl| Utc::now() + ttl)
+}
+
+/// The TTL a request asked for, or the configured one. `0` means none.
+pub fn ttl(requested_ms: Option<u64>, default_secs: u64) -> Option<Duration> {
+    let ttl = match requested_ms {
+        Some(ms) => Duration::from_millis(ms),
+        None => Duration::from_secs(default_secs),
+    };
+    Some(ttl).filter(|ttl| !ttl.is_zero())
+}
+
+/// Whether a payload carries an `expires_at` that has passed by `now`.
+pub fn is_expired(payload: &[u8], now: DateTime<Utc>) -> bool {
+    serde_json::from_slice::<serde_json::Value>(payload)
+        .ok()
+        .and_then(|payload| payload["expires_at"].as_i64())
+        .is_some_and(|expires_at| expires_at <= now.timestamp_millis())
+}
+
+/// Drops publishes that expired while waiting for the broker from the requests
+/// rumqttc replays after reconnecting. Returns how many were dropped.
+pub fn drop_expired(pending: &mut VecDeque<Request>, now: DateTime<Utc>) -> usize {
+    let before = pending.len();
+    pending.retain(|request| match request {
+        Request::Publish(publish) => !is_expired(&publish.payload, now),
+        _ => true,
+    });
+    before - pending.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rumqttc::{Publish, QoS};
+
+    fn publish(payload: serde_json::Value) -> Request {
+        Request::Publish(Publish::new(
+            "sven/command",
+            QoS::AtLeastOnce,
+            payload.to_string(),
+        ))
+    }
+
+    #[test]
+    fn only_expired_publishes_are_dropped() {
+        let now = Utc::now();
+        let mut pending = VecDeque::from([
+            publish(serde_json::json!({"command": "Stop", "value": 0})),
+            publish(serde_json::json!({
+                "command": "AbsoluteHeight",
+                "value": 900,
+                "expires_at": (now - chrono::Duration::seconds(5)).timestamp_millis(),
+            })),
+            publish(serde_json::json!({
+                "command": "AbsoluteHeight",
+                "value": 1000,
+                "expires_at": (now + chrono::Duration::seconds(5)).timestamp_millis(),
+            })),
+        ]);
+        assert_eq!(drop_expired(&mut pending, now), 1);
+        assert_eq!(pending.len(), 2);
+    }
+
+    #[test]
+    fn zero_turns_the_ttl_off() {
+        assert_eq!(ttl(None, 0), None);
+        assert_eq!(ttl(None, 30), Some(Duration::from_secs(30)));
+        assert_eq!(ttl(Some(500), 30), Some(Duration::from_millis(500)));
+        assert_eq!(ttl(Some(0), 30), None);
+    }
+}
@@ -0,0 +1,288 @@
+//! Firmware faults reported on `SVEN_ERROR_TOPIC`, `sven/error` by default: an
+//! overload, a collision or a thermal cutoff, as plain text like `collision: left
+//! leg` or JSON with a `kind` (or `fault`/`type`), `message` and `code`. A fault
+//! puts the desk into the `Error` status and latches: movement commands are
+//! refused with `desk_fault` until `POST /api/sven/faults/clear`, which is kept
+//! across restarts. Stops and motor settings still go through.
+
+use axum::{
+    Json,
+    extract::{Extension, Query},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+use tracing::{error, info, warn};
+use utoipa::ToSchema;
+
+use crate::AppState;
+use crate::collision;
+use crate::listing::{Collection, ListQuery};
+use crate::notifications::NotificationKind;
+use crate::persistence::Artifact;
+use crate::status::StatusEvent;
+use crate::validation::ErrorBody;
+
+/// Faults kept for `GET /api/sven/faults`, oldest dropped first.
+const RECENT_FAULTS: usize = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FaultKind {
+    Overload,
+    Collision,
+    Thermal,
+    /// Anything the bridge doesn't recognize
+    Other,
+}
+
+impl FaultKind {
+    fn classify(text: &str) -> FaultKind {
+        let text = text.to_ascii_lowercase();
+        if text.contains("overload") || text.contains("overcurrent") {
+            FaultKind::Overload
+        } else if text.contains("collision") {
+            FaultKind::Collision
+        } else if ["thermal", "overheat", "temperature"]
+            .iter()
+            .any(|word| text.contains(word))
+        {
+            FaultKind::Thermal
+        } else {
+            FaultKind::Other
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Fault {
+    pub id: u64,
+    pub kind: FaultKind,
+    pub message: String,
+    /// The firmware's own error code, if it sent one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<serde_json::Value>,
+    pub received_at: DateTime<Utc>,
+    /// When `POST /api/sven/faults/clear` released it, unset while it blocks the desk
+    pub cleared_at: Option<DateTime<Utc>>,
+}
+
+/// Recent faults, newest last.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Faults {
+    recent: VecDeque<Fault>,
+    next_id: u64,
+}
+
+impl Artifact for Faults {
+    const KEY: &'static str = "faults";
+}
+
+impl Faults {
+    /// Parses a report from the error topic into a fault, `None` for an empty one.
+    fn parse(payload: &[u8]) -> Option<(FaultKind, String, Option<serde_json::Value>)> {
+        let text = String::from_utf8_lossy(payload);
+        let text = text.trim();
+        if text.is_empty() {
+            return None;
+        }
+        if let Ok(serde_json::Value::Object(object)) = serde_json::from_str(text) {
+            let kind = ["kind", "fault", "type"]
+                .iter()
+                .find_map(|key| object.get(*key).and_then(|kind| kind.as_str()));
+            let message = object
+                .get("message")
+                .and_then(|message| message.as_str())
+                .or(kind)
+                .unwrap_or(text)
+                .to_string();
+            let kind = FaultKind::classify(kind.unwrap_or(&message));
+            return Some((kind, message, object.get("code").cloned()));
+        }
+        Some((FaultKind::classify(text), text.to_string(), None))
+    }
+
+    fn record(
+        &mut self,
+        kind: FaultKind,
+        message: String,
+        code: Option<serde_json::Value>,
+    ) -> Fault {
+        self.next_id += 1;
+        let fault = Fault {
+            id: self.next_id,
+            kind,
+            message,
+            code,
+            received_at: Utc::now(),
+            cleared_at: None,
+        };
+        self.recent.push_back(fault.clone());
+        while self.recent.len() > RECENT_FAULTS {
+            self.recent.pop_front();
+        }
+        fault
+    }
+
+    /// The latest fault that hasn't been cleared yet.
+    pub fn active(&self) -> Option<&Fault> {
+        self.recent
+            .iter()
+            .rev()
+            .find(|fault| fault.cleared_at.is_none())
+    }
+
+    /// Clears every active fault, returning how many there were.
+    fn clear(&mut self) -> usize {
+        let now = Utc::now();
+        let mut cleared = 0;
+        for fault in self
+            .recent
+            .iter_mut()
+            .filter(|fault| fault.cleared_at.is_none())
+        {
+            fault.cleared_at = Some(now);
+            cleared += 1;
+        }
+        cleared
+    }
+}
+
+/// The answer to a movement command while a fault is active.
+pub fn blocked(fault: &Fault) -> (StatusCode, Json<serde_json::Value>) {
+    (
+        StatusCode::CONFLICT,
+        Json(serde_json::json!({
+            "error": format!(
+                "The desk reported a fault ({}), clear it before moving the desk",
+                fault.message
+            ),
+            "code": "desk_fault",
+            "fault": fault,
+        })),
+    )
+}
+
+fn persist(app_state: &AppState, faults: &Faults) {
+    if let Some(store) = &app_state.store
+        && let Err(e) = store.put(faults)
+    {
+        error!("Failed to persist faults: {}", e);
+    }
+}
+
+/// Records a report from the error topic, backing off first if the desk was moving.
+pub async fn handle_report(app_state: &Arc<AppState>, payload: &[u8]) {
+    let Some((kind, message, code)) = Faults::parse(payload) else {
+        return;
+    };
+    let mut faults = app_state.faults.lock().await;
+    let fault = faults.record(kind, message, code);
+    persist(app_state, &faults);
+    drop(faults);
+    warn!("Desk fault {:?}: {}", fault.kind, fault.message);
+    app_state.notifier.notify(
+        NotificationKind::Watchdog,
+        format!(
+            "Desk fault: {}, movement is blocked until it is cleared",
+            fault.message
+        ),
+    );
+    let status = *app_state.status_tx.borrow();
+    // Not on the eventloop, which has to keep running for the publish to go out
+    let reason = format!("Desk fault: {}", fault.message);
+    let collision_app_state = app_state.clone();
+    tokio::spawn(async move {
+        collision::handle_obstruction(&collision_app_state, &reason, status).await;
+    });
+    app_state.advance_status(StatusEvent::Failed);
+    app_state.refresh_state_cache().await;
+}
+
+const FAULTS: Collection = Collection {
+    fields: &["kind", "received_at", "cleared_at"],
+    timestamp: Some("received_at"),
+};
+
+/// Recent faults, newest first, and whether one is blocking the desk.
+#[utoipa::path(
+    get,
+    path = "/api/sven/faults",
+    tag = "desk",
+    params(ListQuery),
+    responses(
+        (status = 200, description = "`active` is the fault blocking the desk, if any", body = serde_json::Value),
+        (status = 400, description = "Invalid query", body = ErrorBody),
+    ),
+)]
+pub async fn list_faults(
+    Query(params): Query<BTreeMap<String, String>>,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    let faults = app_state.faults.lock().await;
+    let active = faults.active().cloned();
+    FAULTS.respond(
+        faults.recent.iter().rev(),
+        &params,
+        |faults| serde_json::json!({"active": active, "faults": faults}),
+    )
+}
+
+/// Releases the desk after a fault, once whatever caused it has been dealt with.
+#[utoipa::path(
+    post,
+    path = "/api/sven/faults/clear",
+    tag = "desk",
+    responses((status = 200, description = "How many faults were cleared", body = serde_json::Value)),
+)]
+pub async fn clear_faults(Extension(app_state): Extension<Arc<AppState>>) -> impl IntoResponse {
+    let mut faults = app_state.faults.lock().await;
+    let cleared = faults.clear();
+    persist(&app_state, &faults);
+    drop(faults);
+    if cleared > 0 {
+        info!("Cleared {} desk faults", cleared);
+        app_state.advance_status(StatusEvent::Cleared);
+        app_state.refresh_state_cache().await;
+    }
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({"status": "Faults cleared", "cleared": cleared})),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_are_classified_from_text_or_json() {
+        let (kind, message, _) = Faults::parse(b"collision: left leg").unwrap();
+        assert_eq!(kind, FaultKind::Collision);
+        assert_eq!(message, "collision: left leg");
+
+        let (kind, message, code) =
+            Faults::parse(br#"{"kind": "thermal_cutoff", "message": "Motor too hot", "code": 17}"#)
+                .unwrap();
+        assert_eq!(kind, FaultKind::Thermal);
+        assert_eq!(message, "Motor too hot");
+        assert_eq!(code, Some(serde_json::json!(17)));
+
+        assert_eq!(Faults::parse(b"E42").unwrap().0, FaultKind::Other);
+        assert!(Faults::parse(b"  ").is_none());
+    }
+
+    #[test]
+    fn clearing_releases_every_active_fault() {
+        let mut faults = Faults::default();
+        faults.record(FaultKind::Overload, "overload".to_string(), None);
+        faults.record(FaultKind::Collision, "collision".to_string(), None);
+        assert_eq!(faults.active().unwrap().kind, FaultKind::Collision);
+        assert_eq!(faults.clear(), 2);
+        assert!(faults.active().is_none());
+        assert_eq!(faults.clear(), 0);
+    }
+}
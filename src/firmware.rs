@@ -0,0 +1,461 @@
+//! Over-the-air firmware updates for the desk controller, passed through over MQTT.
+//! An uploaded image is announced on `SVEN_OTA_TOPIC` with its size and SHA-256,
+//! sent in base64 chunks that each carry their own SHA-256, and closed with an
+//! `end` message, after which the controller verifies and boots the image. Every
+//! message waits for the controller's ack on the ack topic before the next goes
+//! out, which is what `GET /api/sven/firmware` reports progress from.
+
+use axum::{
+    Json,
+    body::Bytes,
+    extract::{Extension, Query, rejection::BytesRejection},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::ack::AckStatus;
+use crate::transport::MessageClass;
+use crate::validation::ErrorBody;
+use crate::{AppState, admin, body_limit, request_id};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum UpdateStatus {
+    Uploading,
+    /// Every chunk was acknowledged and the controller accepted the image
+    Done,
+    Failed,
+    Aborted,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct FirmwareUpdate {
+    pub id: String,
+    pub status: UpdateStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    pub size: usize,
+    /// SHA-256 of the whole image, hex encoded
+    pub sha256: String,
+    pub chunk_bytes: usize,
+    pub chunks: usize,
+    /// Chunks the controller has acknowledged
+    pub acked_chunks: usize,
+    pub progress_percent: u8,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub error: Option<String>,
+}
+
+/// What goes out on the OTA topic. `id` is what the controller acks.
+#[derive(Debug, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum OtaMessage<'a> {
+    Begin {
+        id: String,
+        update: &'a str,
+        size: usize,
+        sha256: &'a str,
+        chunks: usize,
+        chunk_bytes: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        version: Option<&'a str>,
+    },
+    Chunk {
+        id: String,
+        update: &'a str,
+        seq: usize,
+        offset: usize,
+        sha256: String,
+        data: String,
+    },
+    End {
+        id: String,
+        update: &'a str,
+        sha256: &'a str,
+    },
+    Abort {
+        id: String,
+        update: &'a str,
+    },
+}
+
+impl OtaMessage<'_> {
+    fn id(&self) -> &str {
+        match self {
+            OtaMessage::Begin { id, .. }
+            | OtaMessage::Chunk { id, .. }
+            | OtaMessage::End { id, .. }
+            | OtaMessage::Abort { id, .. } => id,
+        }
+    }
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    ring::digest::digest(&ring::digest::SHA256, data)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// The chunk messages for `image`, in order.
+fn chunk_messages<'a>(
+    update: &'a str,
+    image: &'a [u8],
+    chunk_bytes: usize,
+) -> impl Iterator<Item = OtaMessage<'a>> {
+    image
+        .chunks(chunk_bytes)
+        .enumerate()
+        .map(move |(seq, chunk)| OtaMessage::Chunk {
+            id: format!("{}.{}", update, seq),
+            update,
+            seq,
+            offset: seq * chunk_bytes,
+            sha256: sha256_hex(chunk),
+            data: base64::engine::general_purpose::STANDARD.encode(chunk),
+        })
+}
+
+/// Whether the update was aborted through the API since it started.
+async fn aborted(app_state: &AppState) -> bool {
+    app_state
+        .firmware
+        .lock()
+        .await
+        .as_ref()
+        .is_some_and(|update| update.status == UpdateStatus::Aborted)
+}
+
+/// Publishes `message` and waits for the controller to ack it, retrying up to
+/// `SVEN_OTA_RETRIES` times when no ack arrives.
+async fn send(app_state: &AppState, message: &OtaMessage<'_>) -> Result<(), String> {
+    let config = &app_state.config();
+    let payload = serde_json::to_vec(message).unwrap();
+    let timeout = Duration::from_millis(config.ota_ack_timeout_ms);
+    for attempt in 0..=config.ota_retries {
+        if attempt > 0 {
+            warn!(
+                "No ack for {}, resending (attempt {})",
+                message.id(),
+                attempt
+            );
+        }
+        let ack_rx = app_state.ack_waiters.register(message.id()).await;
+        app_state
+            .publish(MessageClass::Command, &config.ota_topic, payload.clone())
+            .await
+            .map_err(|e| format!("failed to publish {}: {}", message.id(), e))?;
+        match app_state
+            .ack_waiters
+            .wait(message.id(), ack_rx, timeout)
+            .await
+        {
+            Some(ack) if ack.status == AckStatus::Ok => return Ok(()),
+            Some(ack) => {
+                return Err(format!(
+                    "controller rejected {}: {}",
+                    message.id(),
+                    ack.error.unwrap_or_else(|| "no reason given".to_string())
+                ));
+            }
+            None => {}
+        }
+    }
+    Err(format!("no ack for {} within {:?}", message.id(), timeout))
+}
+
+async fn run(app_state: Arc<AppState>, update: FirmwareUpdate, image: Bytes) {
+    let id = update.id.as_str();
+    let result = async {
+        send(
+            &app_state,
+            &OtaMessage::Begin {
+                id: format!("{}.begin", id),
+                update: id,
+                size: update.size,
+                sha256: &update.sha256,
+                chunks: update.chunks,
+                chunk_bytes: update.chunk_bytes,
+                version: update.version.as_deref(),
+            },
+        )
+        .await?;
+        let mut reported_percent = 0;
+        for message in chunk_messages(id, &image, update.chunk_bytes) {
+            if aborted(&app_state).await {
+                return Ok(());
+            }
+            send(&app_state, &message).await?;
+            let mut firmware = app_state.firmware.lock().await;
+            if let Some(current) = firmware.as_mut().filter(|current| current.id == id) {
+                current.acked_chunks += 1;
+                current.progress_percent = (current.acked_chunks * 100 / current.chunks) as u8;
+                if current.progress_percent >= reported_percent + 10 {
+                    reported_percent = current.progress_percent;
+                    info!("Firmware update {} at {}%", id, reported_percent);
+                }
+            }
+        }
+        send(
+            &app_state,
+            &OtaMessage::End {
+                id: format!("{}.end", id),
+                update: id,
+                sha256: &update.sha256,
+            },
+        )
+        .await
+    }
+    .await;
+
+    let mut firmware = app_state.firmware.lock().await;
+    let Some(current) = firmware
+        .as_mut()
+        .filter(|current| current.id == id && current.status == UpdateStatus::Uploading)
+    else {
+        return;
+    };
+    current.finished_at = Some(Utc::now());
+    match result {
+        Ok(()) => {
+            current.status = UpdateStatus::Done;
+            info!(
+                "Firmware update {} sent, the controller is installing it",
+                id
+            );
+        }
+        Err(e) => {
+            error!("Firmware update {} failed: {}", id, e);
+            current.status = UpdateStatus::Failed;
+            current.error = Some(e);
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct UploadParams {
+    /// Firmware version, passed on to the controller
+    pub version: Option<String>,
+    /// Expected SHA-256 of the image, hex encoded, to catch a corrupted upload
+    pub sha256: Option<String>,
+}
+
+/// Starts an update with the image in the request body. Needs the admin token.
+#[utoipa::path(
+    post,
+    path = "/api/sven/firmware",
+    tag = "admin",
+    params(UploadParams),
+    request_body(content = Vec<u8>, content_type = "application/octet-stream"),
+    responses(
+        (status = 202, description = "Update started", body = FirmwareUpdate),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+        (status = 403, description = "Admin routes are disabled", body = ErrorBody),
+        (status = 409, description = "Another update is running", body = ErrorBody),
+        (status = 413, description = "Image larger than SVEN_OTA_MAX_BYTES", body = ErrorBody),
+        (status = 422, description = "Empty image or checksum mismatch", body = ErrorBody),
+    ),
+)]
+pub async fn upload_firmware(
+    headers: HeaderMap,
+    Query(params): Query<UploadParams>,
+    Extension(app_state): Extension<Arc<AppState>>,
+    image: Result<Bytes, BytesRejection>,
+) -> impl IntoResponse {
+    if let Err(rejection) = admin::require_admin(&headers, &app_state.config()) {
+        return rejection;
+    }
+    let image = match image {
+        Ok(image) => image,
+        Err(rejection) if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+            return body_limit::too_large(app_state.config().ota_max_bytes);
+        }
+        Err(rejection) => {
+            return (
+                rejection.status(),
+                Json(serde_json::json!({"error": rejection.body_text()})),
+            );
+        }
+    };
+    if image.is_empty() {
+        return (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(serde_json::json!({"error": "The firmware image is empty"})),
+        );
+    }
+    let sha256 = sha256_hex(&image);
+    if let Some(expected) = &params.sha256
+        && !expected.eq_ignore_ascii_case(&sha256)
+    {
+        return (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(serde_json::json!({
+                "error": format!("Image SHA-256 is {}, expected {}", sha256, expected),
+                "code": "checksum_mismatch",
+            })),
+        );
+    }
+
+    let mut firmware = app_state.firmware.lock().await;
+    if let Some(current) = firmware.as_ref()
+        && current.status == UpdateStatus::Uploading
+    {
+        return (
+            StatusCode::CONFLICT,
+            Json(serde_json::json!({
+                "error": format!("Firmware update {} is still running", current.id),
+                "code": "update_in_progress",
+            })),
+        );
+    }
+    let chunk_bytes = app_state.config().ota_chunk_bytes;
+    let update = FirmwareUpdate {
+        id: request_id::generate(),
+        status: UpdateStatus::Uploading,
+        version: params.version,
+        size: image.len(),
+        sha256,
+        chunk_bytes,
+        chunks: image.len().div_ceil(chunk_bytes),
+        acked_chunks: 0,
+        progress_percent: 0,
+        started_at: Utc::now(),
+        finished_at: None,
+        error: None,
+    };
+    *firmware = Some(update.clone());
+    drop(firmware);
+    info!(
+        "Starting firmware update {}: {} bytes in {} chunks",
+        update.id, update.size, update.chunks
+    );
+    tokio::spawn(run(app_state.clone(), update.clone(), image));
+    (StatusCode::ACCEPTED, Json(serde_json::json!(update)))
+}
+
+/// The running or most recent update.
+#[utoipa::path(
+    get,
+    path = "/api/sven/firmware",
+    tag = "admin",
+    responses(
+        (status = 200, body = FirmwareUpdate),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+        (status = 403, description = "Admin routes are disabled", body = ErrorBody),
+        (status = 404, description = "No update since startup", body = ErrorBody),
+    ),
+)]
+pub async fn get_firmware(
+    headers: HeaderMap,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    if let Err(rejection) = admin::require_admin(&headers, &app_state.config()) {
+        return rejection;
+    }
+    match app_state.firmware.lock().await.as_ref() {
+        Some(update) => (StatusCode::OK, Json(serde_json::json!(update))),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({"error": "No firmware update since startup"})),
+        ),
+    }
+}
+
+/// Stops sending the running update and tells the controller to discard it.
+#[utoipa::path(
+    delete,
+    path = "/api/sven/firmware",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Update aborted", body = FirmwareUpdate),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+        (status = 403, description = "Admin routes are disabled", body = ErrorBody),
+        (status = 409, description = "No update is running", body = ErrorBody),
+    ),
+)]
+pub async fn abort_firmware(
+    headers: HeaderMap,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    if let Err(rejection) = admin::require_admin(&headers, &app_state.config()) {
+        return rejection;
+    }
+    let mut firmware = app_state.firmware.lock().await;
+    let Some(update) = firmware
+        .as_mut()
+        .filter(|update| update.status == UpdateStatus::Uploading)
+    else {
+        return (
+            StatusCode::CONFLICT,
+            Json(serde_json::json!({"error": "No firmware update is running"})),
+        );
+    };
+    update.status = UpdateStatus::Aborted;
+    update.finished_at = Some(Utc::now());
+    let update = update.clone();
+    drop(firmware);
+
+    warn!("Aborting firmware update {}", update.id);
+    let abort = OtaMessage::Abort {
+        id: format!("{}.abort", update.id),
+        update: &update.id,
+    };
+    if let Err(e) = app_state
+        .publish(
+            MessageClass::Command,
+            &app_state.config().ota_topic,
+            serde_json::to_vec(&abort).unwrap(),
+        )
+        .await
+    {
+        error!("Failed to publish firmware abort: {}", e);
+    }
+    (StatusCode::OK, Json(serde_json::json!(update)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_cover_the_image_with_their_own_checksums() {
+        let image: Vec<u8> = (0..=255).cycle().take(5000).collect();
+        let messages: Vec<_> = chunk_messages("ota", &image, 2048).collect();
+        assert_eq!(messages.len(), 3);
+
+        let mut reassembled = Vec::new();
+        for (seq, message) in messages.iter().enumerate() {
+            let OtaMessage::Chunk {
+                id,
+                offset,
+                sha256,
+                data,
+                ..
+            } = message
+            else {
+                panic!("expected a chunk");
+            };
+            assert_eq!(id, &format!("ota.{}", seq));
+            assert_eq!(*offset, reassembled.len());
+            let chunk = base64::engine::general_purpose::STANDARD
+                .decode(data)
+                .unwrap();
+            assert_eq!(sha256, &sha256_hex(&chunk));
+            reassembled.extend(chunk);
+        }
+        assert_eq!(reassembled, image);
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
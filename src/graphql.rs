@@ -0,0 +1,244 @@
+//! GraphQL API at `/api/graphql`, built with the `graphql` feature. Queries read
+//! the same state, presets and history as the REST routes, and mutations go
+//! through the same command path, so validation, limits and rate limits apply
+//! unchanged. Failed commands become errors with the REST status and `code` in
+//! their extensions.
+
+use async_graphql::{
+    Context, EmptySubscription, ErrorExtensions, Json as GraphQLJson, Object, Schema, SimpleObject,
+};
+use async_graphql_axum::{GraphQLRequest, GraphQLResponse};
+use axum::{
+    Json,
+    extract::{ConnectInfo, Extension},
+    http::{HeaderMap, StatusCode},
+};
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+use tracing::error;
+
+use crate::history::{self, HistoryQuery, HistoryRecord};
+use crate::listen::ClientAddr;
+use crate::{AppState, CommandRequest};
+
+pub type SvenSchema = Schema<QueryRoot, MutationRoot, EmptySubscription>;
+
+pub fn schema() -> SvenSchema {
+    Schema::build(QueryRoot, MutationRoot, EmptySubscription).finish()
+}
+
+/// Who sent the request, for the rate limit and the inactivity lock.
+struct Caller {
+    remote: ClientAddr,
+    headers: HeaderMap,
+}
+
+pub async fn handle_graphql(
+    ConnectInfo(remote): ConnectInfo<ClientAddr>,
+    headers: HeaderMap,
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(schema): Extension<SvenSchema>,
+    request: GraphQLRequest,
+) -> GraphQLResponse {
+    let request = request
+        .into_inner()
+        .data(app_state)
+        .data(Caller { remote, headers });
+    schema.execute(request).await.into()
+}
+
+fn app_state<'a>(ctx: &Context<'a>) -> &'a Arc<AppState> {
+    ctx.data_unchecked::<Arc<AppState>>()
+}
+
+/// A REST style response as the mutation's result, or as an error when it failed.
+fn into_result(
+    (status, Json(body)): (StatusCode, Json<serde_json::Value>),
+) -> async_graphql::Result<GraphQLJson<serde_json::Value>> {
+    if status.is_success() {
+        return Ok(GraphQLJson(body));
+    }
+    let message = body["error"]
+        .as_str()
+        .or(status.canonical_reason())
+        .unwrap_or_default()
+        .to_string();
+    Err(
+        async_graphql::Error::new(message).extend_with(|_, extensions| {
+            extensions.set("status", status.as_u16());
+            if let Some(code) = body["code"].as_str() {
+                extensions.set("code", code);
+            }
+        }),
+    )
+}
+
+/// The desk's state, as `GET /api/sven/state` reports it.
+#[derive(SimpleObject)]
+struct DeskState {
+    height_mm: u32,
+    position: String,
+    direction: String,
+    status: String,
+    broker_connected: bool,
+    power: String,
+    last_updated: Option<DateTime<Utc>>,
+    stale: bool,
+}
+
+#[derive(SimpleObject)]
+struct Preset {
+    name: String,
+    height_mm: u32,
+}
+
+#[derive(SimpleObject)]
+struct HistoryEntry {
+    id: u64,
+    timestamp: DateTime<Utc>,
+    /// `command` or `state`
+    kind: String,
+    request_id: Option<String>,
+    desk_id: Option<String>,
+    command: Option<String>,
+    value: Option<u32>,
+    result: Option<String>,
+    height_mm: Option<u32>,
+    position: Option<String>,
+}
+
+impl From<HistoryRecord> for HistoryEntry {
+    fn from(record: HistoryRecord) -> Self {
+        HistoryEntry {
+            id: record.id,
+            timestamp: record.timestamp,
+            kind: record.kind,
+            request_id: record.request_id,
+            desk_id: record.desk_id,
+            command: record.command,
+            value: record.value,
+            result: record.result,
+            height_mm: record.height_mm,
+            position: record.position,
+        }
+    }
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// State of the primary desk
+    async fn state(&self, ctx: &Context<'_>) -> DeskState {
+        let view = app_state(ctx).state_view().await;
+        DeskState {
+            height_mm: view.state.height_mm,
+            position: format!("{:?}", view.state.position),
+            direction: format!("{:?}", view.direction),
+            status: format!("{:?}", view.status),
+            broker_connected: view.broker_connected,
+            power: format!("{:?}", view.power),
+            last_updated: view.last_updated,
+            stale: view.stale,
+        }
+    }
+
+    async fn presets(&self, ctx: &Context<'_>) -> Vec<Preset> {
+        app_state(ctx)
+            .presets
+            .lock()
+            .await
+            .0
+            .values()
+            .map(|preset| Preset {
+                name: preset.name.clone(),
+                height_mm: preset.height_mm,
+            })
+            .collect()
+    }
+
+    /// Commands and state changes, oldest first, paged like `GET /api/sven/history`
+    async fn history(
+        &self,
+        ctx: &Context<'_>,
+        since: Option<DateTime<Utc>>,
+        command: Option<String>,
+        after: Option<u64>,
+        #[graphql(default = 100)] limit: usize,
+    ) -> async_graphql::Result<Vec<HistoryEntry>> {
+        let query = HistoryQuery {
+            since,
+            until: None,
+            kind: None,
+            command,
+            limit: None,
+            after,
+        };
+        let limit = limit.clamp(1, history::MAX_LIMIT);
+        match history::page(app_state(ctx), &query, limit).await {
+            Ok(records) => Ok(records.into_iter().map(HistoryEntry::from).collect()),
+            Err(e) => {
+                error!("Failed to query history: {}", e);
+                Err("Failed to read history".into())
+            }
+        }
+    }
+}
+
+pub struct MutationRoot;
+
+#[Object]
+impl MutationRoot {
+    /// Sends a command like `POST /api/sven/command`, or to a registered desk when
+    /// `desk` is set, returning the same response body
+    async fn send_command(
+        &self,
+        ctx: &Context<'_>,
+        command: String,
+        value: u32,
+        desk: Option<String>,
+        #[graphql(default)] confirm: bool,
+        #[graphql(default)] dry_run: bool,
+    ) -> async_graphql::Result<GraphQLJson<serde_json::Value>> {
+        let app_state = app_state(ctx);
+        let caller = ctx.data_unchecked::<Caller>();
+        if let Some(desk_id) = &desk
+            && !app_state.desks.is_known(desk_id).await
+        {
+            return into_result((
+                StatusCode::NOT_FOUND,
+                Json(serde_json::json!({"error": format!("Unknown desk '{}'", desk_id)})),
+            ));
+        }
+        let request: CommandRequest = serde_json::from_value(serde_json::json!({
+            "command": command,
+            "value": value,
+            "confirm": confirm,
+            "dry_run": dry_run,
+        }))
+        .map_err(|e| {
+            async_graphql::Error::new(format!("Invalid command: {}", e))
+                .extend_with(|_, extensions| extensions.set("status", 422))
+        })?;
+        into_result(
+            crate::execute_command(
+                app_state,
+                caller.remote,
+                &caller.headers,
+                desk.as_deref(),
+                request,
+            )
+            .await,
+        )
+    }
+
+    /// Halts the desk like `POST /api/sven/stop`
+    async fn stop(
+        &self,
+        ctx: &Context<'_>,
+        desk: Option<String>,
+    ) -> async_graphql::Result<GraphQLJson<serde_json::Value>> {
+        let headers = &ctx.data_unchecked::<Caller>().headers;
+        into_result(crate::stop_desk(app_state(ctx), desk.as_deref(), headers).await)
+    }
+}
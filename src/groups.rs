@@ -0,0 +1,183 @@
+//! Named groups of registry desks (`SVEN_DESK_GROUPS`), so one command can move
+//! a whole row of desks. `POST /api/groups/{name}/command` sends the command to
+//! every member at once, each through the same checks as a single desk command,
+//! and reports how each of them went.
+
+use axum::{
+    Json,
+    extract::rejection::JsonRejection,
+    extract::{ConnectInfo, Extension, Path, Query},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::task::JoinSet;
+use tracing::info;
+use utoipa::ToSchema;
+
+use crate::listen::ClientAddr;
+use crate::units::{self, UnitParams};
+use crate::validation::ErrorBody;
+use crate::{AppState, CommandParams, CommandRequest, validation};
+
+/// The desks of a group, written as desk ids separated by spaces.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct DeskGroup(pub Vec<String>);
+
+impl FromStr for DeskGroup {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut members: Vec<String> = Vec::new();
+        for desk_id in s.split_whitespace() {
+            if !members.iter().any(|member| member == desk_id) {
+                members.push(desk_id.to_string());
+            }
+        }
+        if members.is_empty() {
+            return Err("a group needs at least one desk".to_string());
+        }
+        Ok(DeskGroup(members))
+    }
+}
+
+/// How the command went on one desk: the status and body a single desk command
+/// would have answered with.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DeskResult {
+    pub status: u16,
+    pub body: serde_json::Value,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/groups",
+    tag = "desk",
+    responses(
+        (status = 200, description = "Members of every group by name", body = BTreeMap<String, DeskGroup>),
+    ),
+)]
+pub async fn list_groups(Extension(app_state): Extension<Arc<AppState>>) -> impl IntoResponse {
+    let config = app_state.config();
+    let groups: BTreeMap<&String, &DeskGroup> = config.desk_groups.iter().collect();
+    Json(serde_json::json!(groups))
+}
+
+/// Sends a command to every desk of a group. Answers 200 when it went through on
+/// all of them and 207 with each desk's result otherwise.
+#[utoipa::path(
+    post,
+    path = "/api/groups/{name}/command",
+    tag = "desk",
+    params(("name" = String, Path), CommandParams, UnitParams),
+    request_body = CommandRequest,
+    responses(
+        (status = 200, description = "Command sent to every desk of the group", body = serde_json::Value),
+        (status = 207, description = "The command failed on some desks", body = serde_json::Value),
+        (status = 404, description = "Unknown group", body = ErrorBody),
+        (status = 413, description = "Body larger than SVEN_MAX_COMMAND_BODY_BYTES", body = ErrorBody),
+        (status = 422, description = "Invalid request", body = ErrorBody),
+    ),
+)]
+pub async fn handle_group_command(
+    Path(name): Path<String>,
+    ConnectInfo(remote): ConnectInfo<ClientAddr>,
+    headers: HeaderMap,
+    Query(params): Query<CommandParams>,
+    Query(unit_params): Query<UnitParams>,
+    Extension(app_state): Extension<Arc<AppState>>,
+    request: Result<Json<units::CommandBody>, JsonRejection>,
+) -> impl IntoResponse {
+    let Some(group) = app_state.config().desk_groups.get(&name).cloned() else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({"error": format!("Unknown group '{}'", name)})),
+        );
+    };
+    let Json(body) = match request {
+        Ok(body) => body,
+        Err(rejection) => return validation::body_rejection(rejection),
+    };
+    let unit = match units::negotiate(&headers, &unit_params) {
+        Ok(unit) => unit,
+        Err(rejection) => return rejection,
+    };
+    let mut request = match unit.read_command(body) {
+        Ok(request) => request,
+        Err(rejection) => return rejection,
+    };
+    request.dry_run |= params.dry_run();
+    request.force |= params.force();
+    info!(
+        "Sending {} to group {} ({} desks)",
+        request.command.command,
+        name,
+        group.0.len()
+    );
+
+    let mut sends = JoinSet::new();
+    for desk_id in group.0 {
+        let app_state = app_state.clone();
+        let headers = headers.clone();
+        let request = request.clone();
+        sends.spawn(async move {
+            let (status, Json(mut body)) = if app_state.desks.is_known(&desk_id).await {
+                crate::execute_command(&app_state, remote, &headers, Some(&desk_id), request).await
+            } else {
+                (
+                    StatusCode::NOT_FOUND,
+                    Json(serde_json::json!({"error": format!("Unknown desk '{}'", desk_id)})),
+                )
+            };
+            unit.annotate(&mut body);
+            (desk_id, status, body)
+        });
+    }
+    let mut results = BTreeMap::new();
+    while let Some(sent) = sends.join_next().await {
+        let (desk_id, status, body) = sent.expect("group command task panicked");
+        results.insert(
+            desk_id,
+            DeskResult {
+                status: status.as_u16(),
+                body,
+            },
+        );
+    }
+
+    let failed = results
+        .values()
+        .filter(|result| !(200..300).contains(&result.status))
+        .count();
+    let status = if failed == 0 {
+        StatusCode::OK
+    } else {
+        StatusCode::MULTI_STATUS
+    };
+    (
+        status,
+        Json(serde_json::json!({
+            "group": name,
+            "succeeded": results.len() - failed,
+            "failed": failed,
+            "results": results,
+        })),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn group_members_are_space_separated() {
+        assert_eq!(
+            "row-a  row-b row-a".parse::<DeskGroup>(),
+            Ok(DeskGroup(vec!["row-a".to_string(), "row-b".to_string()]))
+        );
+        assert!(" ".parse::<DeskGroup>().is_err());
+    }
+}
@@ -0,0 +1,169 @@
+//! gRPC service on `SVEN_GRPC_PORT`, built with the `grpc` feature. It serves
+//! `proto/sven.proto` next to the HTTP API: commands go through the same path as
+//! `POST /api/sven/command`, and failures carry the gRPC code closest to the HTTP
+//! status, with the REST `code` in the `x-error-code` metadata. API keys are
+//! checked like on HTTP, from `authorization` or `x-api-key` metadata.
+
+use axum::{Json, http::StatusCode};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio_stream::{Stream, StreamExt, wrappers::WatchStream};
+use tonic::{Code, Request, Response, Status, metadata::MetadataValue};
+use tracing::{error, info};
+
+use crate::listen::ClientAddr;
+use crate::state_cache::StateView;
+use crate::{AppState, CommandRequest, auth};
+
+pub mod proto {
+    tonic::include_proto!("sven.v1");
+}
+
+use proto::desk_server::{Desk, DeskServer};
+
+struct DeskService {
+    app_state: Arc<AppState>,
+}
+
+impl From<StateView> for proto::DeskState {
+    fn from(view: StateView) -> Self {
+        proto::DeskState {
+            height_mm: view.state.height_mm,
+            position: format!("{:?}", view.state.position),
+            direction: format!("{:?}", view.direction),
+            broker_connected: view.broker_connected,
+            power: format!("{:?}", view.power),
+            last_updated: view.last_updated.map(|at| at.to_rfc3339()),
+            stale: view.stale,
+            status: format!("{:?}", view.status),
+        }
+    }
+}
+
+/// The gRPC code for a failed REST style response.
+fn code_for(status: StatusCode) -> Code {
+    match status {
+        StatusCode::BAD_REQUEST | StatusCode::UNPROCESSABLE_ENTITY => Code::InvalidArgument,
+        StatusCode::UNAUTHORIZED => Code::Unauthenticated,
+        StatusCode::FORBIDDEN => Code::PermissionDenied,
+        StatusCode::NOT_FOUND => Code::NotFound,
+        StatusCode::CONFLICT | StatusCode::LOCKED => Code::FailedPrecondition,
+        StatusCode::TOO_MANY_REQUESTS => Code::ResourceExhausted,
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE => Code::Unavailable,
+        StatusCode::GATEWAY_TIMEOUT => Code::DeadlineExceeded,
+        _ => Code::Internal,
+    }
+}
+
+fn failure(status: StatusCode, code: Option<&str>, message: &str) -> Status {
+    let mut failure = Status::new(code_for(status), message);
+    if let Some(code) = code.and_then(|code| MetadataValue::try_from(code).ok()) {
+        failure.metadata_mut().insert("x-error-code", code);
+    }
+    failure
+}
+
+/// Refuses calls without a key allowed to make them, once keys are configured.
+fn authorize<T>(app_state: &AppState, request: &Request<T>, write: bool) -> Result<(), Status> {
+    let headers = request.metadata().clone().into_headers();
+    auth::authorize(&headers, &app_state.config(), write)
+        .map(|_| ())
+        .map_err(|(status, code, message)| failure(status, Some(code), &message))
+}
+
+#[tonic::async_trait]
+impl Desk for DeskService {
+    async fn send_command(
+        &self,
+        request: Request<proto::CommandRequest>,
+    ) -> Result<Response<proto::CommandReply>, Status> {
+        authorize(&self.app_state, &request, true)?;
+        let remote = request
+            .remote_addr()
+            .unwrap_or(SocketAddr::from(([0, 0, 0, 0], 0)));
+        let headers = request.metadata().clone().into_headers();
+        let request = request.into_inner();
+        if let Some(desk_id) = &request.desk
+            && !self.app_state.desks.is_known(desk_id).await
+        {
+            return Err(Status::not_found(format!("Unknown desk '{}'", desk_id)));
+        }
+        let command: CommandRequest = serde_json::from_value(serde_json::json!({
+            "command": request.command,
+            "value": request.value,
+            "confirm": request.confirm,
+            "dry_run": request.dry_run,
+            "timeout_ms": request.timeout_ms,
+        }))
+        .map_err(|e| Status::invalid_argument(format!("Invalid command: {}", e)))?;
+
+        let (status, Json(body)) = crate::execute_command(
+            &self.app_state,
+            ClientAddr::Tcp(remote),
+            &headers,
+            request.desk.as_deref(),
+            command,
+        )
+        .await;
+        if !status.is_success() {
+            let message = body["error"]
+                .as_str()
+                .or(status.canonical_reason())
+                .unwrap_or_default();
+            return Err(failure(status, body["code"].as_str(), message));
+        }
+        Ok(Response::new(proto::CommandReply {
+            status: body["status"].as_str().unwrap_or_default().to_string(),
+            request_id: body["request_id"].as_str().unwrap_or_default().to_string(),
+            target_mm: body["target_mm"].as_u64().map(|mm| mm as u32),
+            eta_ms: body["eta_ms"].as_u64(),
+            json: body.to_string(),
+        }))
+    }
+
+    async fn get_state(
+        &self,
+        request: Request<proto::GetStateRequest>,
+    ) -> Result<Response<proto::DeskState>, Status> {
+        authorize(&self.app_state, &request, false)?;
+        Ok(Response::new(self.app_state.state_view().await.into()))
+    }
+
+    type StreamStateStream = Pin<Box<dyn Stream<Item = Result<proto::DeskState, Status>> + Send>>;
+
+    async fn stream_state(
+        &self,
+        request: Request<proto::StreamStateRequest>,
+    ) -> Result<Response<Self::StreamStateStream>, Status> {
+        authorize(&self.app_state, &request, false)?;
+        let app_state = self.app_state.clone();
+        let states = WatchStream::new(app_state.state_tx.subscribe()).then(move |_| {
+            let app_state = app_state.clone();
+            async move { Ok(app_state.state_view().await.into()) }
+        });
+        Ok(Response::new(Box::pin(
+            self.app_state.shutdown.until(states),
+        )))
+    }
+}
+
+/// Serves the gRPC API on `listener` until shutdown.
+pub async fn serve(app_state: Arc<AppState>, listener: tokio::net::TcpListener) {
+    if let Ok(addr) = listener.local_addr() {
+        info!("gRPC listening on {}", addr);
+    }
+    let service = DeskServer::new(DeskService {
+        app_state: app_state.clone(),
+    });
+    let served = tonic::transport::Server::builder()
+        .add_service(service)
+        .serve_with_incoming_shutdown(
+            tonic::transport::server::TcpIncoming::from(listener),
+            async move { app_state.shutdown.wait().await },
+        )
+        .await;
+    if let Err(e) = served {
+        error!("gRPC server failed: {}", e);
+    }
+}
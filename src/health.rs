@@ -0,0 +1,66 @@
+use axum::{Json, extract::Extension, http::StatusCode, response::IntoResponse};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+use crate::AppState;
+
+/// Liveness: answers as long as the process is serving requests. Broker connectivity
+/// and the moves spooled for it are reported but don't fail the check.
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "health",
+    responses(
+        (status = 200, description = "The process is serving requests", body = serde_json::Value),
+    ),
+)]
+pub async fn get_healthz(Extension(app_state): Extension<Arc<AppState>>) -> impl IntoResponse {
+    let mqtt_connected = app_state.reconnect_monitor.lock().await.is_connected();
+    let spool = app_state.spool.lock().await.status();
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "status": "ok",
+            "mqtt_connected": mqtt_connected,
+            "spool": spool,
+        })),
+    )
+}
+
+/// Readiness: the broker is connected and the desk has reported its state, recently
+/// enough when `SVEN_READY_STATE_MAX_AGE_SECS` is set. 503 until then.
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "health",
+    responses(
+        (status = 200, description = "Connected to the broker with a recent desk state", body = serde_json::Value),
+        (status = 503, description = "Not ready", body = serde_json::Value),
+    ),
+)]
+pub async fn get_readyz(Extension(app_state): Extension<Arc<AppState>>) -> impl IntoResponse {
+    let mqtt_connected = app_state.reconnect_monitor.lock().await.is_connected();
+    let state_received = app_state.state_received.load(Ordering::SeqCst);
+    let state_age = app_state.last_state_at.lock().await.elapsed();
+    let state_fresh = app_state
+        .config()
+        .ready_state_max_age
+        .is_none_or(|max_age| state_age <= max_age);
+
+    let ready = mqtt_connected && state_received && state_fresh;
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(serde_json::json!({
+            "status": if ready { "ready" } else { "not_ready" },
+            "mqtt_connected": mqtt_connected,
+            "state_received": state_received,
+            "state_age_ms": state_received.then_some(state_age.as_millis() as u64),
+            "spool": app_state.spool.lock().await.status(),
+        })),
+    )
+}
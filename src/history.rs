@@ -0,0 +1,538 @@
+use axum::{
+    Json,
+    extract::{Extension, Query},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tracing::error;
+use utoipa::IntoParams;
+
+use crate::audit::AuditEntry;
+use crate::persistence::StoreError;
+use crate::{AppState, DeskCommand, SvenState};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HistoryEntry {
+    pub id: u64,
+    pub timestamp: DateTime<Utc>,
+    pub request_id: String,
+    pub user: Option<String>,
+    pub command: DeskCommand,
+}
+
+/// The most recent commands sent to the desk, oldest first.
+pub struct CommandHistory {
+    capacity: usize,
+    next_id: u64,
+    entries: VecDeque<HistoryEntry>,
+}
+
+impl CommandHistory {
+    pub fn new(capacity: usize) -> Self {
+        CommandHistory {
+            capacity,
+            next_id: 1,
+            entries: VecDeque::with_capacity(capacity.min(1024)),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn record(&mut self, request_id: &str, user: Option<&str>, command: &DeskCommand) {
+        if self.capacity == 0 {
+            return;
+        }
+        while self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(HistoryEntry {
+            id: self.next_id,
+            timestamp: Utc::now(),
+            request_id: request_id.to_string(),
+            user: user.map(str::to_string),
+            command: command.clone(),
+        });
+        self.next_id += 1;
+    }
+
+    /// The last `count` entries for which `keep` holds, oldest first.
+    pub fn last_matching(
+        &self,
+        count: usize,
+        keep: impl Fn(&HistoryEntry) -> bool,
+    ) -> Vec<HistoryEntry> {
+        let mut entries: Vec<_> = self
+            .entries
+            .iter()
+            .rev()
+            .filter(|entry| keep(entry))
+            .take(count)
+            .cloned()
+            .collect();
+        entries.reverse();
+        entries
+    }
+
+    fn query(&self, query: &HistoryQuery, limit: usize) -> Vec<HistoryRecord> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.id > query.after.unwrap_or(0))
+            .filter(|entry| query.since.is_none_or(|since| entry.timestamp >= since))
+            .filter(|entry| query.until.is_none_or(|until| entry.timestamp < until))
+            .filter(|_| {
+                query
+                    .kind
+                    .as_deref()
+                    .is_none_or(|kind| kind.eq_ignore_ascii_case("command"))
+            })
+            .filter(|entry| {
+                query.command.as_deref().is_none_or(|command| {
+                    format!("{:?}", entry.command.command).eq_ignore_ascii_case(command)
+                })
+            })
+            .take(limit)
+            .map(|entry| HistoryRecord {
+                id: entry.id,
+                timestamp: entry.timestamp,
+                kind: "command".to_string(),
+                request_id: Some(entry.request_id.clone()),
+                user: entry.user.clone(),
+                desk_id: None,
+                command: Some(format!("{:?}", entry.command.command)),
+                value: Some(entry.command.value),
+                result: Some("sent".to_string()),
+                height_mm: None,
+                position: None,
+                watts: None,
+            })
+            .collect()
+    }
+}
+
+/// One row of the persistent history: a command that was sent, a state report or
+/// a power reading.
+#[derive(Debug, Clone, Serialize)]
+pub struct HistoryRecord {
+    pub id: u64,
+    pub timestamp: DateTime<Utc>,
+    /// `command`, `state` or `power`
+    pub kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// Who sent the command
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub desk_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<u32>,
+    /// How publishing the command went, `sent` or the error
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height_mm: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub watts: Option<f64>,
+}
+
+/// When a command was sent and the user who sent it.
+pub type CommandSender = (DateTime<Utc>, Option<String>);
+
+/// Commands and state changes kept in SQLite, so history survives restarts.
+///
+/// Timestamps are stored as fixed-width RFC 3339 strings so they sort and
+/// compare correctly as text.
+pub struct HistoryDb {
+    connection: Mutex<rusqlite::Connection>,
+}
+
+impl HistoryDb {
+    pub fn open(path: &Path) -> Result<Self, StoreError> {
+        let connection = rusqlite::Connection::open(path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS history (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                request_id TEXT,
+                desk_id TEXT,
+                command TEXT,
+                value INTEGER,
+                result TEXT,
+                height_mm INTEGER,
+                position TEXT,
+                user TEXT,
+                watts REAL
+            );
+            CREATE INDEX IF NOT EXISTS history_timestamp ON history (timestamp);
+            CREATE TABLE IF NOT EXISTS audit (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp TEXT NOT NULL,
+                request_id TEXT NOT NULL,
+                key TEXT,
+                user TEXT,
+                client TEXT,
+                method TEXT NOT NULL,
+                path TEXT NOT NULL,
+                status INTEGER NOT NULL,
+                detail TEXT
+            );
+            CREATE INDEX IF NOT EXISTS audit_timestamp ON audit (timestamp)",
+        )?;
+        // Databases from before users were recorded
+        let has_user: bool = connection.query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('history') WHERE name = 'user'",
+            [],
+            |row| row.get(0),
+        )?;
+        if !has_user {
+            connection.execute_batch("ALTER TABLE history ADD COLUMN user TEXT")?;
+        }
+        // And from before power readings were
+        let has_watts: bool = connection.query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('history') WHERE name = 'watts'",
+            [],
+            |row| row.get(0),
+        )?;
+        if !has_watts {
+            connection.execute_batch("ALTER TABLE history ADD COLUMN watts REAL")?;
+        }
+        Ok(HistoryDb {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    pub fn record_command(
+        &self,
+        request_id: &str,
+        user: Option<&str>,
+        desk_id: Option<&str>,
+        command: &DeskCommand,
+        result: &str,
+    ) -> Result<(), StoreError> {
+        let connection = self.connection.lock().unwrap();
+        connection
+            .prepare_cached(
+                "INSERT INTO history (timestamp, kind, request_id, user, desk_id, command, value, result)
+                 VALUES (?1, 'command', ?2, ?3, ?4, ?5, ?6, ?7)",
+            )?
+            .execute(rusqlite::params![
+                timestamp(Utc::now()),
+                request_id,
+                user,
+                desk_id,
+                format!("{:?}", command.command),
+                command.value,
+                result,
+            ])?;
+        Ok(())
+    }
+
+    pub fn record_state(&self, desk_id: Option<&str>, state: &SvenState) -> Result<(), StoreError> {
+        let connection = self.connection.lock().unwrap();
+        connection
+            .prepare_cached(
+                "INSERT INTO history (timestamp, kind, desk_id, height_mm, position)
+                 VALUES (?1, 'state', ?2, ?3, ?4)",
+            )?
+            .execute(rusqlite::params![
+                timestamp(Utc::now()),
+                desk_id,
+                state.height_mm,
+                format!("{:?}", state.position),
+            ])?;
+        Ok(())
+    }
+
+    /// Records a smart plug reading, see `energy`.
+    pub fn record_power(&self, desk_id: Option<&str>, watts: f64) -> Result<(), StoreError> {
+        let connection = self.connection.lock().unwrap();
+        connection
+            .prepare_cached(
+                "INSERT INTO history (timestamp, kind, desk_id, watts)
+                 VALUES (?1, 'power', ?2, ?3)",
+            )?
+            .execute(rusqlite::params![timestamp(Utc::now()), desk_id, watts])?;
+        Ok(())
+    }
+
+    /// Power readings for `desk_id` since `since`, oldest first, preceded by the
+    /// last one before it.
+    pub fn power_readings(
+        &self,
+        desk_id: Option<&str>,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<(DateTime<Utc>, f64)>, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare_cached(
+            "SELECT timestamp, watts FROM history
+             WHERE kind = 'power' AND desk_id IS ?1 AND timestamp >= ?2
+             UNION ALL
+             SELECT * FROM (
+                 SELECT timestamp, watts FROM history
+                 WHERE kind = 'power' AND desk_id IS ?1 AND timestamp < ?2
+                 ORDER BY timestamp DESC LIMIT 1
+             )
+             ORDER BY timestamp",
+        )?;
+        let rows = statement.query_map(rusqlite::params![desk_id, timestamp(since)], |row| {
+            let timestamp: String = row.get(0)?;
+            Ok((
+                DateTime::parse_from_rfc3339(&timestamp)
+                    .map(|t| t.with_timezone(&Utc))
+                    .unwrap_or_default(),
+                row.get(1)?,
+            ))
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Heights `desk_id` reported since `since`, oldest first, preceded by the last
+    /// report before it so the height at `since` is known too.
+    pub fn state_reports(
+        &self,
+        desk_id: Option<&str>,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<(DateTime<Utc>, u32)>, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare_cached(
+            "SELECT timestamp, height_mm FROM history
+             WHERE kind = 'state' AND desk_id IS ?1 AND timestamp >= ?2
+             UNION ALL
+             SELECT * FROM (
+                 SELECT timestamp, height_mm FROM history
+                 WHERE kind = 'state' AND desk_id IS ?1 AND timestamp < ?2
+                 ORDER BY timestamp DESC LIMIT 1
+             )
+             ORDER BY timestamp",
+        )?;
+        let rows = statement.query_map(rusqlite::params![desk_id, timestamp(since)], |row| {
+            let timestamp: String = row.get(0)?;
+            Ok((
+                DateTime::parse_from_rfc3339(&timestamp)
+                    .map(|t| t.with_timezone(&Utc))
+                    .unwrap_or_default(),
+                row.get(1)?,
+            ))
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// When commands were sent to `desk_id` since `since` and by whom, oldest
+    /// first, plus the last one before `since`.
+    pub fn command_users(
+        &self,
+        desk_id: Option<&str>,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<CommandSender>, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare_cached(
+            "SELECT timestamp, user FROM history
+             WHERE kind = 'command' AND desk_id IS ?1 AND timestamp >= ?2
+             UNION ALL
+             SELECT * FROM (
+                 SELECT timestamp, user FROM history
+                 WHERE kind = 'command' AND desk_id IS ?1 AND timestamp < ?2
+                 ORDER BY timestamp DESC LIMIT 1
+             )
+             ORDER BY timestamp",
+        )?;
+        let rows = statement.query_map(rusqlite::params![desk_id, timestamp(since)], |row| {
+            let timestamp: String = row.get(0)?;
+            Ok((
+                DateTime::parse_from_rfc3339(&timestamp)
+                    .map(|t| t.with_timezone(&Utc))
+                    .unwrap_or_default(),
+                row.get(1)?,
+            ))
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    pub fn record_audit(&self, entry: &AuditEntry) -> Result<(), StoreError> {
+        let connection = self.connection.lock().unwrap();
+        connection
+            .prepare_cached(
+                "INSERT INTO audit (timestamp, request_id, key, user, client, method, path, status, detail)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            )?
+            .execute(rusqlite::params![
+                timestamp(entry.timestamp),
+                entry.request_id,
+                entry.key,
+                entry.user,
+                entry.client,
+                entry.method,
+                entry.path,
+                entry.status,
+                entry.detail,
+            ])?;
+        Ok(())
+    }
+
+    /// Audit entries between `since` and `until`, oldest first.
+    pub fn audit_entries(
+        &self,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<Vec<AuditEntry>, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare_cached(
+            "SELECT id, timestamp, request_id, key, user, client, method, path, status, detail
+             FROM audit WHERE timestamp >= ?1 AND (?2 IS NULL OR timestamp < ?2) ORDER BY id",
+        )?;
+        let since = since.map(timestamp).unwrap_or_default();
+        let rows = statement.query_map(rusqlite::params![since, until.map(timestamp)], |row| {
+            let timestamp: String = row.get(1)?;
+            Ok(AuditEntry {
+                id: row.get::<_, i64>(0)? as u64,
+                timestamp: DateTime::parse_from_rfc3339(&timestamp)
+                    .map(|t| t.with_timezone(&Utc))
+                    .unwrap_or_default(),
+                request_id: row.get(2)?,
+                key: row.get(3)?,
+                user: row.get(4)?,
+                client: row.get(5)?,
+                method: row.get(6)?,
+                path: row.get(7)?,
+                status: row.get(8)?,
+                detail: row.get(9)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn query(&self, query: &HistoryQuery, limit: usize) -> Result<Vec<HistoryRecord>, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare_cached(
+            "SELECT id, timestamp, kind, request_id, desk_id, command, value, result, height_mm, position, user, watts
+             FROM history
+             WHERE id > ?1 AND timestamp >= ?2 AND (?3 IS NULL OR timestamp < ?3)
+               AND (?4 IS NULL OR kind = ?4 COLLATE NOCASE)
+               AND (?5 IS NULL OR command = ?5 COLLATE NOCASE)
+             ORDER BY id LIMIT ?6",
+        )?;
+        let since = query.since.map(timestamp).unwrap_or_default();
+        let until = query.until.map(timestamp);
+        let rows = statement.query_map(
+            rusqlite::params![
+                query.after.unwrap_or(0) as i64,
+                since,
+                until,
+                query.kind,
+                query.command,
+                limit as i64
+            ],
+            |row| {
+                let timestamp: String = row.get(1)?;
+                Ok(HistoryRecord {
+                    id: row.get::<_, i64>(0)? as u64,
+                    timestamp: DateTime::parse_from_rfc3339(&timestamp)
+                        .map(|t| t.with_timezone(&Utc))
+                        .unwrap_or_default(),
+                    kind: row.get(2)?,
+                    request_id: row.get(3)?,
+                    user: row.get(10)?,
+                    desk_id: row.get(4)?,
+                    command: row.get(5)?,
+                    value: row.get(6)?,
+                    result: row.get(7)?,
+                    height_mm: row.get(8)?,
+                    position: row.get(9)?,
+                    watts: row.get(11)?,
+                })
+            },
+        )?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+}
+
+fn timestamp(at: DateTime<Utc>) -> String {
+    at.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+const DEFAULT_LIMIT: usize = 100;
+pub const MAX_LIMIT: usize = 1000;
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct HistoryQuery {
+    /// Only entries at or after this time, also accepted as `from` like the other
+    /// collections
+    #[serde(alias = "from")]
+    pub since: Option<DateTime<Utc>>,
+    /// Only entries before this time
+    pub until: Option<DateTime<Utc>>,
+    /// Only `command`, `state` or `power` entries
+    pub kind: Option<String>,
+    /// Only commands of this variant, like `AbsoluteHeight`
+    pub command: Option<String>,
+    pub limit: Option<usize>,
+    /// Cursor from a previous page's `next`
+    pub after: Option<u64>,
+}
+
+/// Up to `limit` history entries matching `query`, oldest first, from SQLite when a
+/// history database is configured, otherwise from the in-memory command history.
+pub async fn page(
+    app_state: &AppState,
+    query: &HistoryQuery,
+    limit: usize,
+) -> Result<Vec<HistoryRecord>, StoreError> {
+    match &app_state.history_db {
+        Some(db) => db.query(query, limit),
+        None => Ok(app_state.history.lock().await.query(query, limit)),
+    }
+}
+
+/// Pages through the history, oldest first. Served from SQLite when a history
+/// database is configured, otherwise from the in-memory command history.
+#[utoipa::path(
+    get,
+    path = "/api/sven/history",
+    tag = "history",
+    params(HistoryQuery),
+    responses(
+        (status = 200, description = "A page of history, `next` is the cursor for the following page", body = serde_json::Value),
+    ),
+)]
+pub async fn get_history(
+    Query(query): Query<HistoryQuery>,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let entries = match page(&app_state, &query, limit).await {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("Failed to query history: {}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({"error": "Failed to read history"})),
+            );
+        }
+    };
+    let next = (entries.len() == limit)
+        .then(|| entries.last().map(|entry| entry.id))
+        .flatten();
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "entries": entries,
+            "next": next,
+        })),
+    )
+}
@@ -0,0 +1,138 @@
+//! Home Assistant MQTT discovery. On every connect the desk is announced as a
+//! `cover` (open is the top of the range, closed the bottom) and a `number` for
+//! the height in mm. Both read the desk's own state topic, and their command
+//! topics under `SVEN_HA_TOPIC_PREFIX` are turned into desk commands here.
+
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+use crate::transport::MessageClass;
+use crate::{AppState, DeskCommand, SvenCommand, limits};
+
+/// The height range Home Assistant may move through: the desk's range narrowed
+/// by the primary desk's soft limits.
+fn height_range(app_state: &AppState) -> (u32, u32) {
+    let config = &app_state.config();
+    let soft = limits::limits_for(config, None);
+    (
+        soft.clamp(config.min_height_mm),
+        soft.clamp(config.max_height_mm),
+    )
+}
+
+/// Publishes the retained discovery configs, so Home Assistant picks the desk up.
+pub async fn announce(app_state: Arc<AppState>) {
+    let config = &app_state.config();
+    let node_id = &config.ha_node_id;
+    let prefix = &config.ha_topic_prefix;
+    let (min_mm, max_mm) = height_range(&app_state);
+    let state_topic = app_state.config().primary_state_topic();
+    let device = serde_json::json!({
+        "identifiers": [node_id],
+        "name": "Sven desk",
+        "model": "sven-api",
+        "sw_version": env!("CARGO_PKG_VERSION"),
+    });
+
+    let cover = serde_json::json!({
+        "name": "Desk",
+        "unique_id": format!("{}_desk", node_id),
+        "device": device,
+        "availability_topic": config.status_topic,
+        "command_topic": format!("{}/cover/set", prefix),
+        "set_position_topic": format!("{}/cover/position/set", prefix),
+        "position_topic": state_topic,
+        "position_template": format!(
+            "{{{{ ((value_json.height_mm - {min}) * 100 / {span}) | round(0) | int }}}}",
+            min = min_mm,
+            span = (max_mm - min_mm).max(1)
+        ),
+    });
+    let number = serde_json::json!({
+        "name": "Height",
+        "unique_id": format!("{}_height", node_id),
+        "device": device,
+        "availability_topic": config.status_topic,
+        "command_topic": format!("{}/height/set", prefix),
+        "state_topic": state_topic,
+        "value_template": "{{ value_json.height_mm }}",
+        "min": min_mm,
+        "max": max_mm,
+        "step": 1,
+        "mode": "slider",
+        "unit_of_measurement": "mm",
+        "device_class": "distance",
+    });
+
+    for (component, object_id, payload) in [("cover", "desk", cover), ("number", "height", number)]
+    {
+        let topic = format!(
+            "{}/{}/{}/{}/config",
+            config.ha_discovery_prefix, component, node_id, object_id
+        );
+        if let Err(e) = app_state
+            .publish(MessageClass::Config, &topic, payload.to_string())
+            .await
+        {
+            error!(
+                "Failed to publish Home Assistant discovery to {}: {:?}",
+                topic, e
+            );
+            return;
+        }
+    }
+    info!("Announced desk to Home Assistant as {}", node_id);
+}
+
+/// Turns a message on one of the Home Assistant command topics into a desk command.
+pub async fn handle_command(app_state: Arc<AppState>, topic: String, payload: Vec<u8>) {
+    let prefix = &app_state.config().ha_topic_prefix;
+    let Some(entity) = topic
+        .strip_prefix(prefix.as_str())
+        .and_then(|rest| rest.strip_prefix('/'))
+    else {
+        return;
+    };
+    let payload = String::from_utf8_lossy(&payload).trim().to_string();
+    let (min_mm, max_mm) = height_range(&app_state);
+
+    let height_mm = match (entity, payload.as_str()) {
+        ("cover/set", "OPEN") => max_mm,
+        ("cover/set", "CLOSE") => min_mm,
+        ("cover/set", "STOP") => {
+            let (status, axum::Json(body)) =
+                crate::stop_desk(&app_state, None, &axum::http::HeaderMap::new()).await;
+            if !status.is_success() {
+                warn!("Home Assistant stop failed: {}", body["error"]);
+            }
+            return;
+        }
+        ("cover/position/set", percent) => match percent.parse::<u32>() {
+            Ok(percent) if percent <= 100 => min_mm + (max_mm - min_mm) * percent / 100,
+            _ => {
+                warn!("Ignoring Home Assistant cover position '{}'", percent);
+                return;
+            }
+        },
+        ("height/set", height) => match height.parse::<f64>() {
+            Ok(height_mm) if height_mm >= 0.0 => height_mm.round() as u32,
+            _ => {
+                warn!("Ignoring Home Assistant height '{}'", height);
+                return;
+            }
+        },
+        _ => {
+            warn!("Ignoring '{}' on Home Assistant topic {}", payload, topic);
+            return;
+        }
+    };
+
+    let command = DeskCommand {
+        command: SvenCommand::AbsoluteHeight,
+        value: height_mm,
+    };
+    info!("Home Assistant requested {} mm", height_mm);
+    if let Err(e) = app_state.send_automated(command).await {
+        warn!("Home Assistant command failed: {}", e);
+    }
+}
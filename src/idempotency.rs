@@ -0,0 +1,204 @@
+//! `Idempotency-Key` support for `POST /api/sven/command`, so a client retrying a
+//! request it never saw the answer to doesn't move the desk twice. The response
+//! to a successful command is kept for `SVEN_IDEMPOTENCY_WINDOW_SECS` and
+//! returned again for retries with the same key and body. Failed commands aren't
+//! kept: nothing was sent, so a retry is a fresh attempt.
+
+use axum::{Json, http::HeaderMap, http::StatusCode};
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const HEADER: &str = "idempotency-key";
+
+const MAX_KEY_LEN: usize = 255;
+
+enum Outcome {
+    /// The first request with the key is still being handled
+    InFlight,
+    Done(StatusCode, serde_json::Value),
+}
+
+struct Entry {
+    fingerprint: u64,
+    outcome: Outcome,
+    at: Instant,
+}
+
+pub struct IdempotencyCache {
+    window: Duration,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+/// What to do with a request carrying an idempotency key.
+pub enum Claim<'a> {
+    /// First time the key is seen: handle the request and `complete` the guard
+    New(Guard<'a>),
+    /// A retry, answered with the original response
+    Replay(StatusCode, Json<serde_json::Value>),
+    /// Refused, because the key is in use by a request still running or was used
+    /// for a different body
+    Rejected(StatusCode, Json<serde_json::Value>),
+}
+
+/// Holds a claimed key while its request runs. Dropped without `complete`, for
+/// instance when the client disconnects, the key is released for a retry.
+pub struct Guard<'a> {
+    cache: &'a IdempotencyCache,
+    key: String,
+    completed: bool,
+}
+
+impl Guard<'_> {
+    /// Keeps a successful response for replays and releases the key otherwise.
+    pub fn complete(mut self, status: StatusCode, body: &serde_json::Value) {
+        self.completed = true;
+        let mut entries = self.cache.entries.lock().unwrap();
+        if status.is_success() {
+            if let Some(entry) = entries.get_mut(&self.key) {
+                entry.outcome = Outcome::Done(status, body.clone());
+                entry.at = Instant::now();
+            }
+        } else {
+            entries.remove(&self.key);
+        }
+    }
+}
+
+impl Drop for Guard<'_> {
+    fn drop(&mut self) {
+        if !self.completed {
+            self.cache.entries.lock().unwrap().remove(&self.key);
+        }
+    }
+}
+
+/// Hash of what a request asks for, to tell a retry from a key reused for
+/// another command.
+pub fn fingerprint(request: &impl serde::Serialize) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    serde_json::to_vec(request).unwrap().hash(&mut hasher);
+    hasher.finish()
+}
+
+impl IdempotencyCache {
+    pub fn new(window: Duration) -> Self {
+        IdempotencyCache {
+            window,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The key `headers` carry, `Ok(None)` without one or when the cache is
+    /// disabled. `scope` keeps clients with different API keys apart.
+    pub fn key(
+        &self,
+        headers: &HeaderMap,
+        scope: Option<&str>,
+    ) -> Result<Option<String>, (StatusCode, Json<serde_json::Value>)> {
+        let Some(value) = headers.get(HEADER) else {
+            return Ok(None);
+        };
+        if self.window.is_zero() {
+            return Ok(None);
+        }
+        match value.to_str() {
+            Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LEN => {
+                Ok(Some(format!("{}:{}", scope.unwrap_or_default(), key)))
+            }
+            _ => Err((
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({
+                    "error": format!(
+                        "Idempotency-Key must be 1 to {} visible ASCII characters",
+                        MAX_KEY_LEN
+                    ),
+                    "code": "invalid_idempotency_key",
+                })),
+            )),
+        }
+    }
+
+    pub fn claim(&self, key: String, fingerprint: u64) -> Claim<'_> {
+        let mut entries = self.entries.lock().unwrap();
+        let window = self.window;
+        entries.retain(|_, entry| entry.at.elapsed() < window);
+        match entries.get(&key) {
+            Some(entry) if entry.fingerprint != fingerprint => Claim::Rejected(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(serde_json::json!({
+                    "error": "Idempotency-Key was already used for a different command",
+                    "code": "idempotency_key_reused",
+                })),
+            ),
+            Some(Entry {
+                outcome: Outcome::InFlight,
+                ..
+            }) => Claim::Rejected(
+                StatusCode::CONFLICT,
+                Json(serde_json::json!({
+                    "error": "A request with this Idempotency-Key is still being handled",
+                    "code": "idempotency_in_progress",
+                })),
+            ),
+            Some(Entry {
+                outcome: Outcome::Done(status, body),
+                ..
+            }) => {
+                let mut body = body.clone();
+                body["replayed"] = serde_json::Value::Bool(true);
+                Claim::Replay(*status, Json(body))
+            }
+            None => {
+                entries.insert(
+                    key.clone(),
+                    Entry {
+                        fingerprint,
+                        outcome: Outcome::InFlight,
+                        at: Instant::now(),
+                    },
+                );
+                drop(entries);
+                Claim::New(Guard {
+                    cache: self,
+                    key,
+                    completed: false,
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_successful_responses_are_replayed() {
+        let cache = IdempotencyCache::new(Duration::from_secs(60));
+        let Claim::New(guard) = cache.claim("a".to_string(), 1) else {
+            panic!("first claim should be new");
+        };
+        assert!(matches!(
+            cache.claim("a".to_string(), 1),
+            Claim::Rejected(StatusCode::CONFLICT, _)
+        ));
+        guard.complete(StatusCode::OK, &serde_json::json!({"status": "sent"}));
+        let Claim::Replay(status, Json(body)) = cache.claim("a".to_string(), 1) else {
+            panic!("retry should be replayed");
+        };
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["replayed"], true);
+        assert!(matches!(
+            cache.claim("a".to_string(), 2),
+            Claim::Rejected(StatusCode::UNPROCESSABLE_ENTITY, _)
+        ));
+
+        let Claim::New(guard) = cache.claim("b".to_string(), 1) else {
+            panic!("first claim should be new");
+        };
+        guard.complete(StatusCode::SERVICE_UNAVAILABLE, &serde_json::json!({}));
+        assert!(matches!(cache.claim("b".to_string(), 1), Claim::New(_)));
+    }
+}
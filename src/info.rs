@@ -0,0 +1,74 @@
+use axum::{Json, extract::Extension, response::IntoResponse};
+use std::sync::Arc;
+
+use crate::{AppState, SvenCommand, SvenPosition, limits};
+
+/// Positions accepted by `Position` commands, with the value that selects them
+/// and the height configured for this desk, if any, followed by the custom labels.
+#[utoipa::path(
+    get,
+    path = "/api/sven/positions",
+    tag = "info",
+    responses(
+        (status = 200, description = "Positions with their command value and configured height", body = serde_json::Value),
+    ),
+)]
+pub async fn get_positions(Extension(app_state): Extension<Arc<AppState>>) -> impl IntoResponse {
+    let heights = app_state.position_heights.lock().await;
+    let labels = heights.labels.iter().map(|(label, height_mm)| {
+        serde_json::json!({
+            "label": label,
+            "height_mm": height_mm,
+        })
+    });
+    let positions: Vec<_> = SvenPosition::ALL
+        .iter()
+        .enumerate()
+        .map(|(value, position)| {
+            serde_json::json!({
+                "position": position,
+                "value": value,
+                "height_mm": heights.heights.get(position),
+            })
+        })
+        .chain(labels)
+        .collect();
+    Json(positions)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/sven/limits",
+    tag = "info",
+    responses((status = 200, description = "The desk's height range", body = serde_json::Value)),
+)]
+pub async fn get_limits(Extension(app_state): Extension<Arc<AppState>>) -> impl IntoResponse {
+    Json(serde_json::json!({
+        "min_height_mm": app_state.config().min_height_mm,
+        "max_height_mm": app_state.config().max_height_mm,
+        "soft_limits": limits::limits_for(&app_state.config(), None),
+    }))
+}
+
+/// Supported commands and the unit of their `value`.
+#[utoipa::path(
+    get,
+    path = "/api/sven/commands",
+    tag = "info",
+    responses(
+        (status = 200, description = "Supported commands and the unit of their value", body = serde_json::Value),
+    ),
+)]
+pub async fn get_commands() -> impl IntoResponse {
+    let commands: Vec<_> = SvenCommand::ALL
+        .iter()
+        .map(|command| {
+            serde_json::json!({
+                "command": command,
+                "description": command.to_string(),
+                "value": command.value_unit(),
+            })
+        })
+        .collect();
+    Json(commands)
+}
@@ -0,0 +1,439 @@
+//! Jobs: a handle on a movement that outlives the request that started it. Every
+//! command that is sent and every sequence gets one, reported at
+//! `GET /api/sven/jobs/{id}`. A command job is pending until the desk reports a new
+//! height, moving until it arrives or settles, and failed when it doesn't within
+//! the command's timeout. Cancelling a job stops its desk, and any stop cancels all
+//! of the desk's unfinished jobs.
+//!
+//! While a command job runs, every height the desk reports is recorded on it with
+//! how far along the way to the target it is, and the updated job is relayed to
+//! the event stream as a `progress` event, so a UI can draw a progress bar.
+
+use axum::{
+    Json,
+    extract::{Extension, Path, Query},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use utoipa::ToSchema;
+
+use crate::listing::{Collection, ListQuery};
+use crate::validation::ErrorBody;
+use crate::{AppState, DeskCommand, SvenCommand, SvenState, movement, request_id};
+
+/// Finished jobs kept for status queries, oldest dropped first.
+const KEPT_JOBS: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum JobKind {
+    Command,
+    Sequence,
+    /// A standing session, see `sessions`
+    Session,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    /// Sent, the desk hasn't started moving yet
+    Pending,
+    Moving,
+    Completed,
+    Failed,
+    /// Ended by a stop or a cancel
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn is_finished(self) -> bool {
+        matches!(
+            self,
+            JobStatus::Completed | JobStatus::Failed | JobStatus::Cancelled
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Job {
+    pub id: String,
+    pub kind: JobKind,
+    pub status: JobStatus,
+    /// The request that started the job
+    pub request_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// The registered desk it moves, the primary desk when absent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub desk: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<DeskCommand>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_mm: Option<u32>,
+    /// The height when the desk was told to move
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_mm: Option<u32>,
+    /// The latest height reported while the job ran
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height_mm: Option<u32>,
+    /// How much of the way from `start_mm` to the destination is covered, 0 to 100;
+    /// absent when the destination isn't known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub progress_percent: Option<u8>,
+    /// Steps of a sequence, and how many of them have run
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub steps: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub completed_steps: Option<usize>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    /// Why the job failed
+    pub error: Option<String>,
+    /// Problems the job carried on after
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+}
+
+impl Job {
+    pub fn new(kind: JobKind, request_id: &str, desk: Option<&str>) -> Job {
+        let now = Utc::now();
+        Job {
+            id: request_id::generate(),
+            kind,
+            status: JobStatus::Pending,
+            request_id: request_id.to_string(),
+            user: None,
+            desk: desk.map(str::to_string),
+            command: None,
+            target_mm: None,
+            start_mm: None,
+            height_mm: None,
+            progress_percent: None,
+            steps: None,
+            completed_steps: None,
+            created_at: now,
+            updated_at: now,
+            finished_at: None,
+            error: None,
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Where the job takes the desk from `start_mm`: its target, or for a relative
+    /// move the start plus or minus the distance.
+    fn destination(&self, start_mm: u32) -> Option<u32> {
+        let command = self.command.as_ref()?;
+        self.target_mm.or(match command.command {
+            SvenCommand::UpRelative => Some(start_mm.saturating_add(command.value)),
+            SvenCommand::DownRelative => Some(start_mm.saturating_sub(command.value)),
+            _ => None,
+        })
+    }
+
+    /// Records `height_mm` as the latest height and how far along the job is.
+    fn track(&mut self, height_mm: u32) {
+        let start_mm = *self.start_mm.get_or_insert(height_mm);
+        self.height_mm = Some(height_mm);
+        self.progress_percent = self
+            .destination(start_mm)
+            .map(|destination_mm| progress(start_mm, destination_mm, height_mm));
+    }
+}
+
+/// The share of the way from `start_mm` to `destination_mm` that `height_mm` has
+/// covered, in percent. Overshooting counts as arrived, moving away as not started.
+fn progress(start_mm: u32, destination_mm: u32, height_mm: u32) -> u8 {
+    if start_mm == destination_mm {
+        return 100;
+    }
+    let covered = (height_mm as f64 - start_mm as f64) / (destination_mm as f64 - start_mm as f64);
+    (covered.clamp(0.0, 1.0) * 100.0).round() as u8
+}
+
+#[derive(Default)]
+pub struct Jobs {
+    jobs: VecDeque<Job>,
+}
+
+impl Jobs {
+    /// Registers `job` and returns its id.
+    pub fn insert(&mut self, job: Job) -> String {
+        while self.jobs.len() >= KEPT_JOBS {
+            match self.jobs.iter().position(|job| job.status.is_finished()) {
+                Some(index) => self.jobs.remove(index),
+                None => break,
+            };
+        }
+        let id = job.id.clone();
+        self.jobs.push_back(job);
+        id
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Job> {
+        self.jobs.iter().find(|job| job.id == id)
+    }
+
+    /// Newest first.
+    pub fn list(&self) -> Vec<Job> {
+        self.jobs.iter().rev().cloned().collect()
+    }
+
+    /// Changes a job that hasn't finished yet; finished jobs stay as they ended.
+    pub fn update(&mut self, id: &str, change: impl FnOnce(&mut Job)) {
+        if let Some(job) = self
+            .jobs
+            .iter_mut()
+            .find(|job| job.id == id && !job.status.is_finished())
+        {
+            change(job);
+            job.updated_at = Utc::now();
+        }
+    }
+
+    pub fn set_status(&mut self, id: &str, status: JobStatus) {
+        self.update(id, |job| job.status = status);
+    }
+
+    pub fn finish(&mut self, id: &str, status: JobStatus, error: Option<String>) {
+        self.update(id, |job| {
+            job.status = status;
+            job.error = error;
+            job.finished_at = Some(Utc::now());
+        });
+    }
+
+    /// Cancels the unfinished jobs of `desk` and returns their ids.
+    pub fn cancel_active(&mut self, desk: Option<&str>) -> Vec<String> {
+        let ids: Vec<String> = self
+            .jobs
+            .iter()
+            .filter(|job| !job.status.is_finished() && job.desk.as_deref() == desk)
+            .map(|job| job.id.clone())
+            .collect();
+        for id in &ids {
+            self.finish(id, JobStatus::Cancelled, None);
+        }
+        ids
+    }
+}
+
+/// Changes job `id` and relays the result to the event stream.
+async fn update_and_relay(app_state: &AppState, id: &str, change: impl FnOnce(&mut Jobs)) {
+    let mut jobs = app_state.jobs.lock().await;
+    change(&mut jobs);
+    if let Some(job) = jobs.get(id) {
+        app_state.progress_tx.send_replace(Some(job.clone()));
+    }
+}
+
+/// Follows a sent command through the desk's reports until it arrives at
+/// `target_mm`, or settles when the final height isn't known, tracking its
+/// progress and recording the outcome on the job. `state_rx` must have been
+/// subscribed before the command was published. Returns once the move is over or
+/// `timeout` has passed.
+pub async fn follow(
+    app_state: &AppState,
+    id: &str,
+    mut state_rx: watch::Receiver<SvenState>,
+    target_mm: Option<u32>,
+    timeout: Duration,
+) {
+    let start_mm = state_rx.borrow().height_mm;
+    update_and_relay(app_state, id, |jobs| {
+        jobs.update(id, |job| job.track(start_mm))
+    })
+    .await;
+    let mut moving_rx = state_rx.clone();
+    let tracked = async {
+        while moving_rx.changed().await.is_ok() {
+            let height_mm = moving_rx.borrow_and_update().height_mm;
+            update_and_relay(app_state, id, |jobs| {
+                jobs.update(id, |job| {
+                    if height_mm != start_mm {
+                        job.status = JobStatus::Moving;
+                    }
+                    job.track(height_mm);
+                })
+            })
+            .await;
+        }
+    };
+    let finished = async {
+        match target_mm {
+            Some(target_mm) => movement::wait_for_arrival(&mut state_rx, target_mm, timeout).await,
+            None => movement::wait_for_settle(&mut state_rx, timeout).await,
+        }
+    };
+    tokio::pin!(finished);
+    let result = tokio::select! {
+        result = &mut finished => result,
+        () = tracked => finished.await,
+    };
+    update_and_relay(app_state, id, |jobs| match result {
+        Ok(state) => {
+            jobs.update(id, |job| {
+                job.track(state.height_mm);
+                job.progress_percent = job.progress_percent.map(|_| 100);
+            });
+            jobs.finish(id, JobStatus::Completed, None)
+        }
+        Err(_) => jobs.finish(
+            id,
+            JobStatus::Failed,
+            Some(format!(
+                "Desk did not finish moving within {} ms",
+                timeout.as_millis()
+            )),
+        ),
+    })
+    .await;
+}
+
+const JOBS: Collection = Collection {
+    fields: &[
+        "kind",
+        "status",
+        "user",
+        "desk",
+        "command.command",
+        "created_at",
+        "updated_at",
+        "finished_at",
+    ],
+    timestamp: Some("created_at"),
+};
+
+/// Recent jobs, newest first.
+#[utoipa::path(
+    get,
+    path = "/api/sven/jobs",
+    tag = "jobs",
+    params(ListQuery),
+    responses(
+        (status = 200, body = Vec<Job>),
+        (status = 400, description = "Invalid query", body = ErrorBody),
+    ),
+)]
+pub async fn list_jobs(
+    Query(params): Query<BTreeMap<String, String>>,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    let jobs = app_state.jobs.lock().await.list();
+    JOBS.respond(jobs, &params, |jobs| serde_json::json!({"jobs": jobs}))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/sven/jobs/{id}",
+    tag = "jobs",
+    params(("id" = String, Path)),
+    responses(
+        (status = 200, body = Job),
+        (status = 404, description = "Unknown or expired job", body = ErrorBody),
+    ),
+)]
+pub async fn get_job(
+    Path(id): Path<String>,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    match app_state.jobs.lock().await.get(&id) {
+        Some(job) => (StatusCode::OK, Json(serde_json::json!(job))),
+        None => unknown_job(&id),
+    }
+}
+
+/// Stops the job's desk, which cancels the job along with any other unfinished
+/// job on that desk.
+#[utoipa::path(
+    delete,
+    path = "/api/sven/jobs/{id}",
+    tag = "jobs",
+    params(("id" = String, Path)),
+    responses(
+        (status = 200, description = "Job cancelled", body = Job),
+        (status = 404, description = "Unknown or expired job", body = ErrorBody),
+        (status = 409, description = "Job already finished", body = ErrorBody),
+        (status = 502, description = "Publishing the stop timed out or was refused", body = ErrorBody),
+        (status = 503, description = "Stop could not be published", body = ErrorBody),
+    ),
+)]
+pub async fn cancel_job(
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    let Some(job) = app_state.jobs.lock().await.get(&id).cloned() else {
+        return unknown_job(&id);
+    };
+    if job.status.is_finished() {
+        return (
+            StatusCode::CONFLICT,
+            Json(serde_json::json!({
+                "error": format!("Job {} has already finished", id),
+                "code": "job_finished",
+                "job": job,
+            })),
+        );
+    }
+    let (status, stopped) = crate::stop_desk(&app_state, job.desk.as_deref(), &headers).await;
+    if !status.is_success() {
+        return (status, stopped);
+    }
+    let job = app_state.jobs.lock().await.get(&id).cloned();
+    (StatusCode::OK, Json(serde_json::json!(job)))
+}
+
+pub fn unknown_job(id: &str) -> (StatusCode, Json<serde_json::Value>) {
+    (
+        StatusCode::NOT_FOUND,
+        Json(serde_json::json!({"error": format!("Unknown job '{}'", id)})),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finished_jobs_keep_their_outcome() {
+        let mut jobs = Jobs::default();
+        let primary = jobs.insert(Job::new(JobKind::Command, "a", None));
+        let other = jobs.insert(Job::new(JobKind::Command, "b", Some("left")));
+        let done = jobs.insert(Job::new(JobKind::Command, "c", None));
+        jobs.finish(&done, JobStatus::Completed, None);
+
+        assert_eq!(jobs.cancel_active(None), vec![primary.clone()]);
+        jobs.set_status(&primary, JobStatus::Moving);
+        assert_eq!(jobs.get(&primary).unwrap().status, JobStatus::Cancelled);
+        assert_eq!(jobs.get(&other).unwrap().status, JobStatus::Pending);
+        assert_eq!(jobs.get(&done).unwrap().status, JobStatus::Completed);
+    }
+
+    #[test]
+    fn progress_runs_from_the_start_to_the_destination() {
+        assert_eq!(progress(700, 1100, 700), 0);
+        assert_eq!(progress(700, 1100, 900), 50);
+        assert_eq!(progress(1100, 700, 800), 75);
+        assert_eq!(progress(700, 1100, 1120), 100);
+        assert_eq!(progress(700, 1100, 690), 0);
+        assert_eq!(progress(900, 900, 900), 100);
+
+        let mut job = Job::new(JobKind::Command, "a", None);
+        job.command = Some(DeskCommand {
+            command: SvenCommand::DownRelative,
+            value: 100,
+        });
+        job.track(1000);
+        job.track(960);
+        assert_eq!(job.start_mm, Some(1000));
+        assert_eq!(job.height_mm, Some(960));
+        assert_eq!(job.progress_percent, Some(40));
+    }
+}
@@ -0,0 +1,3019 @@
+//! HTTP bridge to the Sven desk controller over MQTT. `main` loads the config and
+//! wires these pieces together; integration tests build the same app around a
+//! mocked MQTT client.
+//!
+//! Serving the desk as a HomeKit accessory is a non-goal for now: there is no
+//! maintained HAP implementation this tree can build with (`hap` 0.1.0-pre.15
+//! doesn't resolve next to the other dependencies), and the protocol's pairing
+//! and session crypto don't belong in the bridge itself. A HAP bridge such as
+//! homebridge can drive the desk through the HTTP API or MQTT instead.
+
+use axum::{
+    Json, Router,
+    extract::{ConnectInfo, Extension, Query, rejection::JsonRejection},
+    http::{HeaderMap, StatusCode},
+    middleware,
+    response::IntoResponse,
+    routing::{delete, get, post, put},
+};
+use chrono::{self, DateTime, Timelike, Utc};
+use rumqttc::{AsyncClient, Event as MqttEvent, EventLoop, Outgoing, Packet, SubscribeReasonCode};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use tokio::sync::{Mutex, RwLock, watch};
+use tokio::task::JoinHandle;
+
+use axum::http::{
+    HeaderValue,
+    header::{CACHE_CONTROL, IF_NONE_MATCH, ORIGIN},
+};
+use tower_http::compression::CompressionLayer;
+use tower_http::set_header::SetResponseHeaderLayer;
+use tower_http::trace::TraceLayer;
+use tracing::{debug, error, info, warn};
+
+mod ack;
+mod admin;
+mod alexa;
+mod audit;
+mod auth;
+mod backup;
+mod body_limit;
+pub mod broker;
+mod channels;
+pub mod cli;
+mod client;
+mod collision;
+pub mod config;
+mod connections;
+mod cooldown;
+mod cors;
+mod desks;
+mod discovery;
+mod energy;
+mod ergonomics;
+pub mod estimation;
+mod event_log;
+mod expiry;
+mod faults;
+mod firmware;
+#[cfg(feature = "graphql")]
+mod graphql;
+mod groups;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+mod health;
+mod history;
+mod homeassistant;
+mod idempotency;
+mod info;
+mod jobs;
+mod limits;
+pub mod listen;
+mod listing;
+mod live;
+mod lock;
+pub mod logging;
+pub mod login;
+mod macros;
+mod maintenance;
+mod memory;
+mod metrics;
+mod motor;
+mod movement;
+mod mqtt_trace;
+mod notifications;
+mod occupancy;
+mod openapi;
+mod persistence;
+mod positions;
+mod power;
+mod presets;
+mod queue;
+mod quick;
+mod quiet_hours;
+mod rate_limit;
+mod reload;
+mod reminders;
+mod request_id;
+mod rules;
+mod schedules;
+mod sequences;
+mod sessions;
+mod shutdown;
+mod signing;
+pub mod simulate;
+mod soft_start;
+mod spool;
+mod startup;
+mod state_cache;
+mod state_writer;
+mod stats;
+mod status;
+mod subscriptions;
+pub mod tls;
+mod tokens;
+mod topics;
+pub mod transport;
+mod ui;
+mod units;
+mod users;
+mod validation;
+mod versioning;
+pub mod webhooks;
+mod zones;
+
+use ack::{AckStatus, AckWaiters, CommandMessage};
+use audit::AuditLog;
+use config::{Config, PercentBasis};
+use connections::ConnectionRegistry;
+use cooldown::PresetCooldowns;
+use desks::DeskRegistry;
+use estimation::Estimator;
+use event_log::EventLog;
+use faults::Faults;
+use firmware::FirmwareUpdate;
+use history::{CommandHistory, HistoryDb};
+use idempotency::{Claim, IdempotencyCache};
+use jobs::{Job, JobKind, JobStatus, Jobs};
+use listen::ClientAddr;
+use lock::InactivityLock;
+use macros::Macros;
+use memory::BufferLimits;
+use metrics::Metrics;
+use movement::MotionTracker;
+use mqtt_trace::{MqttTrace, ReconnectMonitor};
+use notifications::{NotificationKind, Notifier};
+use persistence::Store;
+use positions::PositionHeights;
+use presets::Presets;
+use queue::CommandQueue;
+use quick::QuickActions;
+use rate_limit::RateLimiter;
+use reminders::{ReminderSettings, Reminders};
+use rules::Rules;
+use schedules::Schedules;
+use sessions::Session;
+use shutdown::Shutdown;
+use signing::NonceCache;
+use spool::Spool;
+use startup::{Backoff, StartupRetry};
+use state_cache::{CachedState, WaitParams};
+use state_writer::StateWriter;
+use status::StatusEvent;
+use subscriptions::{TopicHandler, TopicRouter};
+use tokens::Tokens;
+use transport::{DeskTransport, MessageClass, TransportError, TransportKind};
+use units::{Unit, UnitParams};
+use users::Users;
+use utoipa::{IntoParams, ToSchema};
+use webhooks::{Deliveries, Webhooks};
+
+// Shared with other crates talking to the API, see `client`
+pub use client::{ClientError, SvenClient};
+pub use energy::PowerDraw;
+pub use faults::FaultKind;
+pub use motor::MotorSettings;
+pub use movement::Direction;
+pub use occupancy::Occupancy;
+pub use power::PowerState;
+pub use presets::Preset;
+pub use sessions::SessionView;
+pub use state_cache::StateView;
+pub use status::DeskStatus;
+
+static NIGHT_TIME_THRESHOLD_MM: u32 = 795;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+pub enum SvenCommand {
+    UpDuration,      // value: ms
+    DownDuration,    // value: ms
+    UpRelative,      // value: mm
+    DownRelative,    // value: mm
+    AbsoluteHeight,  // value: mm
+    Position,        // value: SvenPosition
+    Calibrate,       // value: Calibrate
+    UpPercent,       // value: %
+    DownPercent,     // value: %
+    Stop,            // value: ignored
+    SetSpeed,        // value: % of full speed
+    SetAcceleration, // value: AccelerationProfile
+    SetSoftStart,    // value: ms
+}
+
+impl SvenCommand {
+    pub const ALL: [SvenCommand; 13] = [
+        SvenCommand::UpDuration,
+        SvenCommand::DownDuration,
+        SvenCommand::UpRelative,
+        SvenCommand::DownRelative,
+        SvenCommand::AbsoluteHeight,
+        SvenCommand::Position,
+        SvenCommand::Calibrate,
+        SvenCommand::UpPercent,
+        SvenCommand::DownPercent,
+        SvenCommand::Stop,
+        SvenCommand::SetSpeed,
+        SvenCommand::SetAcceleration,
+        SvenCommand::SetSoftStart,
+    ];
+
+    /// Whether the command moves the desk, as opposed to configuring it.
+    pub fn is_movement(&self) -> bool {
+        !matches!(self, SvenCommand::Calibrate | SvenCommand::Stop) && !self.is_motor_setting()
+    }
+
+    /// Whether the command changes a motor setting, see `motor`.
+    pub fn is_motor_setting(&self) -> bool {
+        matches!(
+            self,
+            SvenCommand::SetSpeed | SvenCommand::SetAcceleration | SvenCommand::SetSoftStart
+        )
+    }
+
+    /// Whether the command's outcome is computed from the last reported height.
+    pub fn is_relative(&self) -> bool {
+        matches!(
+            self,
+            SvenCommand::UpRelative
+                | SvenCommand::DownRelative
+                | SvenCommand::UpPercent
+                | SvenCommand::DownPercent
+        )
+    }
+
+    /// Whether the command moves to a fixed height regardless of where the desk is.
+    pub fn is_absolute(&self) -> bool {
+        matches!(self, SvenCommand::AbsoluteHeight | SvenCommand::Position)
+    }
+
+    /// What the `value` of a command with this variant means.
+    pub fn value_unit(&self) -> &'static str {
+        match self {
+            SvenCommand::UpDuration | SvenCommand::DownDuration => "ms",
+            SvenCommand::UpRelative | SvenCommand::DownRelative | SvenCommand::AbsoluteHeight => {
+                "mm"
+            }
+            SvenCommand::Position => "SvenPosition",
+            SvenCommand::Calibrate => "Calibrate",
+            SvenCommand::UpPercent | SvenCommand::DownPercent => "%",
+            SvenCommand::Stop => "ignored",
+            SvenCommand::SetSpeed => "%",
+            SvenCommand::SetAcceleration => "AccelerationProfile",
+            SvenCommand::SetSoftStart => "ms",
+        }
+    }
+}
+
+// Just for printing purposes
+impl std::fmt::Display for SvenCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SvenCommand::UpDuration => write!(f, "Up Duration"),
+            SvenCommand::DownDuration => write!(f, "Down Duration"),
+            SvenCommand::UpRelative => write!(f, "Up Relative"),
+            SvenCommand::DownRelative => write!(f, "Down Relative"),
+            SvenCommand::AbsoluteHeight => write!(f, "Absolute Height"),
+            SvenCommand::Position => write!(f, "Position"),
+            SvenCommand::Calibrate => write!(f, "Calibrate"),
+            SvenCommand::UpPercent => write!(f, "Up Percent"),
+            SvenCommand::DownPercent => write!(f, "Down Percent"),
+            SvenCommand::Stop => write!(f, "Stop"),
+            SvenCommand::SetSpeed => write!(f, "Set Speed"),
+            SvenCommand::SetAcceleration => write!(f, "Set Acceleration"),
+            SvenCommand::SetSoftStart => write!(f, "Set Soft Start"),
+        }
+    }
+}
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, ToSchema)]
+pub struct DeskCommand {
+    pub command: SvenCommand,
+    /// May be left out of a `Position` command that names a `label` instead
+    #[serde(default = "DeskCommand::missing_value")]
+    pub value: u32,
+}
+
+impl DeskCommand {
+    /// Stands in for a `value` the body left out, which no command accepts.
+    const MISSING_VALUE: u32 = u32::MAX;
+
+    fn missing_value() -> u32 {
+        DeskCommand::MISSING_VALUE
+    }
+}
+
+/// Body of `POST /api/sven/command`: the command plus how the caller wants it handled.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct CommandRequest {
+    #[serde(flatten)]
+    pub command: DeskCommand,
+    /// Wait for the desk to finish moving and return the final state
+    #[serde(default)]
+    pub confirm: bool,
+    pub timeout_ms: Option<u64>,
+    /// Send the command even if it moves through a protected zone
+    #[serde(default)]
+    pub override_protected_zones: bool,
+    /// Move the desk even during quiet hours
+    #[serde(default)]
+    pub force: bool,
+    /// Wait for the firmware to acknowledge the command, defaults to `SVEN_WAIT_FOR_ACK`
+    pub ack: Option<bool>,
+    /// Only report what would be published, without sending anything or waking the desk
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Start a long timed move with the pulses in `SVEN_SOFT_START_PULSES_MS`, defaults to true
+    #[serde(default = "soft_start_by_default")]
+    pub soft_start: bool,
+    /// Drop the command instead of sending it once it is this old, defaults to
+    /// `SVEN_COMMAND_TTL_SECS`; `0` for never
+    pub ttl_ms: Option<u64>,
+    /// With a `Position` command, the firmware position or custom label to move to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// A soft start pulse, which gives up its place in the queue once it's sent
+    /// rather than when the desk settles: the sequence's pause covers it
+    #[serde(skip)]
+    pub(crate) pulse: bool,
+}
+
+fn soft_start_by_default() -> bool {
+    true
+}
+
+/// Query parameters of the command endpoints.
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct CommandParams {
+    /// `1` or `true` for a dry run, same as `dry_run` in the body
+    pub dry_run: Option<String>,
+    /// `1` or `true` to move during quiet hours, same as `force` in the body
+    pub force: Option<String>,
+}
+
+impl CommandParams {
+    pub fn dry_run(&self) -> bool {
+        matches!(self.dry_run.as_deref(), Some("1" | "true"))
+    }
+
+    pub fn force(&self) -> bool {
+        matches!(self.force.as_deref(), Some("1" | "true"))
+    }
+}
+
+// Shared state for MQTT client
+pub struct AppState {
+    /// Swapped by a reload, see `reload`
+    config: std::sync::RwLock<Arc<Config>>,
+    transport: Box<dyn DeskTransport>,
+    /// Read by most requests and written by state reports only, so reads don't
+    /// wait for each other; see `benches/state_reads.rs`
+    sven_state: Arc<RwLock<SvenState>>,
+    state_tx: watch::Sender<SvenState>,
+    topic_router: TopicRouter,
+    desks: DeskRegistry,
+    cached_state: Arc<RwLock<CachedState>>,
+    /// Bumped whenever the cached state's ETag changes, for long-polling clients
+    state_version: watch::Sender<u64>,
+    sven_status: Arc<Mutex<String>>,
+    mqtt_trace: Arc<Mutex<MqttTrace>>,
+    mqtt_client_id: String,
+    reconnect_monitor: Arc<Mutex<ReconnectMonitor>>,
+    event_log: Option<EventLog>,
+    notifier: Notifier,
+    preset_cooldowns: Arc<Mutex<PresetCooldowns>>,
+    store: Option<Arc<dyn Store>>,
+    power_tx: watch::Sender<PowerState>,
+    occupancy_tx: watch::Sender<Occupancy>,
+    /// The smart plug's last reading in watts, see `energy`
+    power_meter_tx: watch::Sender<Option<f64>>,
+    status_tx: watch::Sender<DeskStatus>,
+    /// The latest change to a moving job, for the event stream
+    progress_tx: watch::Sender<Option<Job>>,
+    position_heights: Arc<Mutex<PositionHeights>>,
+    presets: Arc<Mutex<Presets>>,
+    users: Arc<Mutex<Users>>,
+    /// Publishes handed to the MQTT client that the eventloop hasn't sent yet
+    pending_publishes: AtomicUsize,
+    rate_limiter: Arc<Mutex<RateLimiter>>,
+    /// Failed logins, keyed by `client:<address>` and `account:<name>`
+    login_limiter: Mutex<RateLimiter>,
+    history: Arc<Mutex<CommandHistory>>,
+    history_db: Option<Arc<HistoryDb>>,
+    /// Writes state reports to `history_db` and `store` off the async workers
+    state_writer: Option<StateWriter>,
+    macros: Arc<Mutex<Macros>>,
+    jobs: Mutex<Jobs>,
+    /// The standing session in progress, see `sessions`
+    session: Mutex<Option<Session>>,
+    idempotency: IdempotencyCache,
+    /// The running or most recent firmware update
+    firmware: Mutex<Option<FirmwareUpdate>>,
+    /// Motor settings sent since startup, see `motor`
+    motor_requested: Mutex<MotorSettings>,
+    audit: Mutex<AuditLog>,
+    schedules: Arc<Mutex<Schedules>>,
+    rules: Mutex<Rules>,
+    faults: Mutex<Faults>,
+    /// Nonces of recent signed state reports, see `signing`
+    state_nonces: Mutex<NonceCache>,
+    webhooks: Mutex<Webhooks>,
+    /// Desk-scoped API tokens, see `tokens`
+    tokens: Mutex<Tokens>,
+    quick_actions: Mutex<QuickActions>,
+    /// Moves waiting for the broker, see `spool`
+    spool: Mutex<Spool>,
+    /// The timed move the height is being estimated from, see `estimation`
+    height_estimator: Mutex<Estimator>,
+    /// The latest delivery attempts of each webhook, see `webhooks`
+    webhook_deliveries: Mutex<Deliveries>,
+    reminders: Arc<Mutex<Reminders>>,
+    /// Shared client for outgoing HTTP, e.g. reminder webhooks
+    http_client: reqwest::Client,
+    inactivity_lock: Arc<Mutex<InactivityLock>>,
+    motion: Arc<Mutex<MotionTracker>>,
+    /// When the primary desk last reported its state, or startup if it never has
+    last_state_at: Arc<Mutex<std::time::Instant>>,
+    /// Whether the primary desk has reported its state since startup, or its
+    /// height is estimated
+    state_received: AtomicBool,
+    /// Wall clock time of the primary desk's last report, for clients
+    last_state_time: Mutex<Option<chrono::DateTime<chrono::Utc>>>,
+    metrics: Metrics,
+    connections: ConnectionRegistry,
+    ack_waiters: AckWaiters,
+    shutdown: Shutdown,
+    /// Bumped by every stop, so running step sequences abandon their remaining steps
+    stop_generation: AtomicU64,
+    command_queue: CommandQueue,
+}
+
+impl AppState {
+    /// The configuration in effect.
+    fn config(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Queues a message for the MQTT eventloop to send, with the QoS and retain
+    /// flag configured for its class.
+    async fn publish(
+        &self,
+        class: MessageClass,
+        topic: &str,
+        payload: impl Into<Vec<u8>>,
+    ) -> Result<(), TransportError> {
+        let options = self.config().publish_classes.options(class);
+        // A client whose request queue is full waits for the eventloop, which
+        // doesn't drain it while the broker is away
+        let timeout = std::time::Duration::from_millis(self.config().publish_timeout_ms);
+        let published = match tokio::time::timeout(
+            timeout,
+            self.transport.publish(topic, payload.into(), options),
+        )
+        .await
+        {
+            Ok(published) => published,
+            Err(_) => Err(TransportError::timeout(timeout)),
+        };
+        if let Err(e) = &published {
+            self.metrics.record_publish_failure(e.kind);
+        }
+        published?;
+        if self.transport.queues_publishes() {
+            self.pending_publishes.fetch_add(1, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    /// Publishes the API's retained availability for other automation to watch.
+    async fn announce_availability(&self, online: bool) {
+        let status = if online { "online" } else { "offline" };
+        let topic = &self.config().availability_topic;
+        if let Err(e) = self.publish(MessageClass::Status, topic, status).await {
+            warn!("Failed to publish availability on {}: {}", topic, e);
+        }
+    }
+
+    /// Publishes a resolved command on the command topic and records it, with the
+    /// `user` that sent it. Commands for a registry `desk` go to that desk's own
+    /// command topic.
+    async fn send_command(
+        &self,
+        desk: Option<&str>,
+        command: &DeskCommand,
+        request_id: &str,
+        user: Option<&str>,
+    ) -> Result<(), TransportError> {
+        let expires_at = expiry::deadline(expiry::ttl(None, self.config().command_ttl_secs));
+        self.send_command_until(desk, command, request_id, user, expires_at)
+            .await
+    }
+
+    /// Like `send_command`, for a command that expires at `expires_at`.
+    async fn send_command_until(
+        &self,
+        desk: Option<&str>,
+        command: &DeskCommand,
+        request_id: &str,
+        user: Option<&str>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<(), TransportError> {
+        let estimating = desk.is_none() && self.config().height_estimation;
+        let timed;
+        let command = if estimating {
+            let moving_mm = self
+                .height_estimator
+                .lock()
+                .await
+                .current(&self.config(), std::time::Instant::now());
+            let height_mm = match moving_mm {
+                Some(height_mm) => height_mm,
+                None => self.sven_state.read().await.height_mm,
+            };
+            timed = estimation::timed(&self.config(), command, height_mm);
+            timed.as_ref().unwrap_or(command)
+        } else {
+            command
+        };
+        if let Some(event_log) = &self.event_log {
+            event_log.log("command", command, Some(request_id));
+        }
+        let (topic, payload) = self.command_message(desk, command, request_id, expires_at);
+        let class = match command.command {
+            SvenCommand::Stop => MessageClass::Stop,
+            _ => MessageClass::Command,
+        };
+        let published = self.publish(class, &topic, payload).await;
+        if let Some(history_db) = &self.history_db {
+            let result = match &published {
+                Ok(()) => "sent".to_string(),
+                Err(e) => format!("failed: {}", e),
+            };
+            if let Err(e) = history_db.record_command(request_id, user, desk, command, &result) {
+                error!("Failed to record command history: {}", e);
+            }
+        }
+        published?;
+        if estimating {
+            self.estimate_after_command(command).await;
+        }
+        if desk.is_none() {
+            self.status_after_command(command).await;
+        }
+        self.history.lock().await.record(request_id, user, command);
+        self.metrics.record_command(command.command).await;
+        Ok(())
+    }
+
+    /// Settles the estimated height of a move that `command` cuts short and
+    /// starts estimating `command` when it's a timed move.
+    async fn estimate_after_command(&self, command: &DeskCommand) {
+        let timed = matches!(
+            command.command,
+            SvenCommand::UpDuration | SvenCommand::DownDuration
+        );
+        if !timed && command.command != SvenCommand::Stop {
+            return;
+        }
+        let mut estimator = self.height_estimator.lock().await;
+        let stopped_at = estimator.stop(&self.config(), std::time::Instant::now());
+        if timed {
+            let from_mm = match stopped_at {
+                Some(height_mm) => height_mm,
+                None => self.sven_state.read().await.height_mm,
+            };
+            estimator.start(from_mm, command);
+        }
+        drop(estimator);
+        if let Some(height_mm) = stopped_at {
+            estimation::report(self, height_mm).await;
+        }
+    }
+
+    /// Marks the primary desk as moving the way `command` sends it, or idle after
+    /// a stop.
+    async fn status_after_command(&self, command: &DeskCommand) {
+        let direction = if command.command == SvenCommand::Stop {
+            movement::Direction::Idle
+        } else {
+            let current_height_mm = self.sven_state.read().await.height_mm;
+            let Some(target_mm) = movement::projected_target_mm(
+                command,
+                current_height_mm,
+                self.config().travel_speed_mm_per_s,
+                &*self.position_heights.lock().await,
+            ) else {
+                return;
+            };
+            match target_mm.cmp(&current_height_mm) {
+                std::cmp::Ordering::Greater => movement::Direction::Up,
+                std::cmp::Ordering::Less => movement::Direction::Down,
+                std::cmp::Ordering::Equal => return,
+            }
+        };
+        if self.advance_status(StatusEvent::Commanded(direction)) {
+            self.refresh_state_cache().await;
+        }
+    }
+
+    /// Topic and payload `command` is published with.
+    fn command_message(
+        &self,
+        desk: Option<&str>,
+        command: &DeskCommand,
+        request_id: &str,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> (String, String) {
+        if let Some(settings) = MotorSettings::from_command(command) {
+            let mut payload = serde_json::json!(settings);
+            payload["id"] = request_id.into();
+            return (self.config().motor_topic.clone(), self.signed(payload));
+        }
+        // Serialize the command as JSON for MQTT payload, tagged so the ack can be matched
+        let payload = serde_json::json!(CommandMessage {
+            command,
+            id: request_id,
+            expires_at: expires_at.map(|at| at.timestamp_millis()),
+        });
+        let topic = match desk {
+            Some(desk_id) => self.config().desk_command_topic.replace("{id}", desk_id),
+            None => self.config().command_topic.clone(),
+        };
+        (topic, self.signed(payload))
+    }
+
+    /// A command message as published: signed when `SVEN_MQTT_SIGNING_SECRET` is set.
+    fn signed(&self, mut message: serde_json::Value) -> String {
+        if let Some(secret) = &self.config().mqtt_signing_secret {
+            signing::sign(secret, &mut message, Utc::now());
+        }
+        message.to_string()
+    }
+
+    /// Whether a message from the desk may be applied: any message without a
+    /// signing secret, only a fresh, correctly signed one with it. State reports,
+    /// acks, faults, power and status all go through here, since each can confirm
+    /// or block a command.
+    async fn verified(&self, topic: &str, payload: &[u8]) -> bool {
+        let Some(secret) = &self.config().mqtt_signing_secret else {
+            return true;
+        };
+        let max_age = chrono::Duration::seconds(self.config().mqtt_signature_max_age_secs as i64);
+        let mut nonces = self.state_nonces.lock().await;
+        match signing::verify(secret, payload, Utc::now(), max_age, &mut nonces) {
+            Ok(()) => true,
+            Err(reason) => {
+                warn!("Dropping unsigned message on {}: {}", topic, reason);
+                false
+            }
+        }
+    }
+
+    /// The text of a status or power message. Signed, it's a JSON message like any
+    /// other, carrying the text in its `payload` field.
+    fn signed_text(&self, payload: Vec<u8>) -> Vec<u8> {
+        if self.config().mqtt_signing_secret.is_none() {
+            return payload;
+        }
+        serde_json::from_slice::<serde_json::Value>(&payload)
+            .ok()
+            .and_then(|message| {
+                message["payload"]
+                    .as_str()
+                    .map(|text| text.as_bytes().to_vec())
+            })
+            .unwrap_or_default()
+    }
+
+    /// Sends a command to the primary desk on behalf of an automation rather than an
+    /// HTTP client. It goes through the same checks as a request would, protected
+    /// zones, quiet hours and the queue included, only without a rate limit or the
+    /// inactivity lock.
+    async fn send_automated(self: &Arc<Self>, command: DeskCommand) -> Result<(), String> {
+        let request = CommandRequest {
+            command,
+            confirm: false,
+            timeout_ms: None,
+            override_protected_zones: false,
+            force: false,
+            ack: None,
+            dry_run: false,
+            soft_start: true,
+            ttl_ms: None,
+            label: None,
+            pulse: false,
+        };
+        let (status, Json(body)) =
+            execute_command(self, ClientAddr::Internal, &HeaderMap::new(), None, request).await;
+        if status.is_success() {
+            Ok(())
+        } else {
+            Err(body["error"].as_str().unwrap_or_default().to_string())
+        }
+    }
+
+    /// Records that the MQTT client is connected, so commands are accepted. Called
+    /// on every ConnAck, and by whatever stands in for the broker otherwise.
+    pub async fn on_broker_connected(self: &Arc<Self>) {
+        self.reconnect_monitor.lock().await.on_connected();
+        self.refresh_state_cache().await;
+        // Sent in the background, the caller may be the event loop the commands go through
+        if !self.spool.lock().await.0.is_empty() {
+            tokio::spawn(spool::flush(self.clone()));
+        }
+    }
+
+    /// Last state of `desk` (the primary desk when `None`) and when it was reported.
+    /// `None` for a registered desk that hasn't reported yet.
+    async fn desk_snapshot(&self, desk: Option<&str>) -> Option<(SvenState, std::time::Instant)> {
+        match desk {
+            Some(desk_id) => self.desks.snapshot(desk_id).await,
+            None => Some((
+                *self.sven_state.read().await,
+                *self.last_state_at.lock().await,
+            )),
+        }
+    }
+
+    /// Watches state reports of `desk`, or of the primary desk when `None`. A desk
+    /// without reports yields a receiver that never changes.
+    async fn subscribe_state(&self, desk: Option<&str>) -> watch::Receiver<SvenState> {
+        match desk {
+            Some(desk_id) => match self.desks.subscribe(desk_id).await {
+                Some(state_rx) => state_rx,
+                None => watch::Sender::new(*self.sven_state.read().await).subscribe(),
+            },
+            None => self.state_tx.subscribe(),
+        }
+    }
+
+    /// Queues subscriptions for every routed topic. Not awaited on the channel since
+    /// it is called from the eventloop that drains it.
+    async fn subscribe_all(&self) -> Result<(), TransportError> {
+        let mut filters: Vec<_> = self
+            .topic_router
+            .subscriptions()
+            .into_iter()
+            .map(|(filter, qos)| (filter.to_string(), qos))
+            .collect();
+        filters.extend(self.rules.lock().await.filters());
+        self.transport.subscribe(filters)
+    }
+
+    /// Waits until the eventloop has sent every queued publish, up to `timeout`.
+    /// Returns how many publishes were still queued when giving up.
+    async fn drain_publishes(&self, timeout: std::time::Duration) -> usize {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let pending = self.pending_publishes.load(Ordering::SeqCst);
+            if pending == 0 || tokio::time::Instant::now() >= deadline {
+                return pending;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+    }
+
+    async fn state_view(&self) -> StateView {
+        let stale = self.state_is_stale().await;
+        let last_updated = *self.last_state_time.lock().await;
+        let session = self.session.lock().await.as_ref().map(Session::view);
+        let fault = self.faults.lock().await.active().map(|fault| fault.kind);
+        let power_w = *self.power_meter_tx.borrow();
+        let state = *self.sven_state.read().await;
+        // Labels closer together than this would have been reported as duplicates
+        let label = self
+            .position_heights
+            .lock()
+            .await
+            .label_at(state.height_mm, self.config().duplicate_preset_tolerance_mm)
+            .map(str::to_string);
+        StateView {
+            state,
+            direction: self.motion.lock().await.direction(),
+            broker_connected: self.reconnect_monitor.lock().await.is_connected(),
+            power: *self.power_tx.borrow(),
+            occupancy: *self.occupancy_tx.borrow(),
+            power_w,
+            power_draw: power_w
+                .map(|watts| PowerDraw::from_watts(watts, self.config().power_meter_moving_w)),
+            status: *self.status_tx.borrow(),
+            last_updated,
+            stale,
+            fault,
+            session,
+            label,
+        }
+    }
+
+    /// Whether the primary desk's state is older than `SVEN_STALE_STATE_SECS`.
+    async fn state_is_stale(&self) -> bool {
+        let age = self.last_state_at.lock().await.elapsed();
+        self.config()
+            .stale_state_max_age
+            .is_some_and(|max_age| age > max_age)
+    }
+
+    /// Moves the desk status along after `event`, returning whether it changed so
+    /// the caller can refresh the state cache.
+    fn advance_status(&self, event: StatusEvent) -> bool {
+        self.status_tx.send_if_modified(|status| {
+            let next = status.next(event);
+            let changed = next != *status;
+            if changed {
+                debug!("Desk status {:?} -> {:?} after {:?}", *status, next, event);
+                *status = next;
+            }
+            changed
+        })
+    }
+
+    /// Re-serializes the cached state response after anything in it changed.
+    async fn refresh_state_cache(&self) {
+        let view = self.state_view().await;
+        let cached = CachedState::new(&view);
+        let mut current = self.cached_state.write().await;
+        let changed = current.etag() != cached.etag();
+        *current = cached;
+        drop(current);
+        if changed {
+            self.state_version.send_modify(|version| *version += 1);
+        }
+    }
+}
+
+/// The client a rate limit applies to: the name of the API key the request
+/// presents, otherwise its `Origin` when `SVEN_RATE_LIMIT_OVERRIDES` has a limit
+/// for it, otherwise its address. Any other `Origin` is up to the client, so it
+/// can't buy a fresh bucket per request.
+fn rate_limit_key(headers: &HeaderMap, remote: ClientAddr, config: &Config) -> String {
+    auth::presented_key_name(headers, config)
+        .or_else(|| {
+            headers
+                .get(ORIGIN)
+                .and_then(|origin| origin.to_str().ok())
+                .filter(|origin| config.rate_limit_overrides.contains_key(*origin))
+                .map(str::to_string)
+        })
+        .unwrap_or_else(|| remote.key())
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/sven/command",
+    tag = "desk",
+    params(
+        CommandParams,
+        UnitParams,
+        ("Idempotency-Key" = Option<String>, Header, description = "Retries with the same key and body get the original response instead of sending the command again"),
+        ("Accept-Units" = Option<String>, Header, description = "`mm`, `cm` or `in` for the height in `value` and the response"),
+    ),
+    request_body = CommandRequest,
+    responses(
+        (status = 200, description = "Command sent, confirmed when requested, or what a dry run would publish", body = serde_json::Value),
+        (status = 202, description = "A long timed move started with soft start pulses, follow it at `/api/sven/jobs/{job_id}`, or a move spooled until the broker is back", body = serde_json::Value),
+        (status = 400, description = "Malformed body, Idempotency-Key or unit", body = validation::ErrorBody),
+        (status = 409, description = "Desk state is unknown or stale, another command is in progress or the command expired waiting for it, the Idempotency-Key is in use, or it is quiet hours and the command isn't forced", body = validation::ErrorBody),
+        (status = 413, description = "Body larger than SVEN_MAX_COMMAND_BODY_BYTES", body = validation::ErrorBody),
+        (status = 422, description = "Invalid command, or an Idempotency-Key reused for another command", body = validation::ErrorBody),
+        (status = 429, description = "Rate limited or cooling down", body = validation::ErrorBody),
+        (status = 502, description = "Publishing timed out (`publish_timeout`) or was refused (`publish_rejected`), or the firmware rejected or didn't acknowledge the command", body = validation::ErrorBody),
+        (status = 503, description = "Not connected to the broker, or it couldn't be reached to publish (`broker_unreachable`)", body = validation::ErrorBody),
+    ),
+)]
+async fn handle_command(
+    ConnectInfo(remote): ConnectInfo<ClientAddr>,
+    headers: HeaderMap,
+    Query(params): Query<CommandParams>,
+    Query(unit_params): Query<UnitParams>,
+    Extension(state): Extension<Arc<AppState>>,
+    request: Result<Json<units::CommandBody>, JsonRejection>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let Json(body) = match request {
+        Ok(body) => body,
+        Err(rejection) => return validation::body_rejection(rejection),
+    };
+    let unit = match units::negotiate(&headers, &unit_params) {
+        Ok(unit) => unit,
+        Err(rejection) => return rejection,
+    };
+    let mut request = match unit.read_command(body) {
+        Ok(request) => request,
+        Err(rejection) => return rejection,
+    };
+    request.dry_run |= params.dry_run();
+    request.force |= params.force();
+    let (status, Json(mut body)) = execute_once(&state, remote, &headers, request).await;
+    unit.annotate(&mut body);
+    (status, Json(body))
+}
+
+/// Runs a command unless it retries one with the same `Idempotency-Key`, which
+/// gets the original response instead.
+async fn execute_once(
+    state: &Arc<AppState>,
+    remote: ClientAddr,
+    headers: &HeaderMap,
+    request: CommandRequest,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let scope = auth::presented_key_name(headers, &state.config());
+    let key = match state.idempotency.key(headers, scope.as_deref()) {
+        // Dry runs send nothing, so there is nothing to deduplicate
+        Ok(key) => key.filter(|_| !request.dry_run),
+        Err(rejection) => return rejection,
+    };
+    let Some(key) = key else {
+        return execute_command(state, remote, headers, None, request).await;
+    };
+    let guard = match state
+        .idempotency
+        .claim(key, idempotency::fingerprint(&request))
+    {
+        Claim::New(guard) => guard,
+        Claim::Replay(status, body) => {
+            info!("Replaying the response to a retried command");
+            return (status, body);
+        }
+        Claim::Rejected(status, body) => return (status, body),
+    };
+    let (status, Json(body)) = execute_command(state, remote, headers, None, request).await;
+    guard.complete(status, &body);
+    (status, Json(body))
+}
+
+/// Halts the primary desk immediately. Same as sending a `Stop` command.
+#[utoipa::path(
+    post,
+    path = "/api/sven/stop",
+    tag = "desk",
+    responses(
+        (status = 200, description = "Stop sent", body = serde_json::Value),
+        (status = 502, description = "Publishing the stop timed out or was refused", body = validation::ErrorBody),
+        (status = 503, description = "Stop could not be published", body = validation::ErrorBody),
+    ),
+)]
+async fn handle_stop(
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<AppState>>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    stop_desk(&state, None, &headers).await
+}
+
+/// Publishes a `Stop` to `desk` ahead of everything else a command goes through:
+/// rate limits, the inactivity lock, cooldowns and shutdown don't apply, and any
+/// step sequence still running is abandoned so no further queued steps go out.
+/// Once the stop is out, the desk's unfinished jobs count as cancelled.
+async fn stop_desk(
+    state: &AppState,
+    desk: Option<&str>,
+    headers: &HeaderMap,
+) -> (StatusCode, Json<serde_json::Value>) {
+    state.stop_generation.fetch_add(1, Ordering::SeqCst);
+    let request_id = request_id::from_headers(headers);
+    let user = users::acting_user(state, headers).await;
+    let stop = DeskCommand {
+        command: SvenCommand::Stop,
+        value: 0,
+    };
+    warn!("Stopping desk {}", desk.unwrap_or("primary"));
+    if let Err(e) = state
+        .send_command(desk, &stop, &request_id, user.as_deref())
+        .await
+    {
+        error!("Failed to publish stop: {}", e);
+        return e.response("Failed to publish stop", Some(&request_id));
+    }
+    let cancelled_jobs = state.jobs.lock().await.cancel_active(desk);
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "status": "Stop sent",
+            "request_id": request_id,
+            "cancelled_jobs": cancelled_jobs,
+        })),
+    )
+}
+
+/// The move a `Position` command with a `label` stands for, see `positions`.
+async fn labelled_command(
+    state: &AppState,
+    command: &DeskCommand,
+    label: &str,
+) -> Result<DeskCommand, (StatusCode, Json<serde_json::Value>)> {
+    if command.command != SvenCommand::Position {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(serde_json::json!({
+                "error": format!("A label only goes with Position, not {}", command.command),
+                "code": "unexpected_label",
+            })),
+        ));
+    }
+    state
+        .position_heights
+        .lock()
+        .await
+        .command_for(label)
+        .ok_or_else(|| {
+            (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(serde_json::json!({
+                    "error": format!("Unknown position or label '{}'", label),
+                    "code": "unknown_label",
+                })),
+            )
+        })
+}
+
+/// Validates and sends a command to `desk`, or to the primary desk when `None`.
+async fn execute_command(
+    state: &Arc<AppState>,
+    remote: ClientAddr,
+    headers: &HeaderMap,
+    desk: Option<&str>,
+    request: CommandRequest,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if let SvenCommand::Stop = request.command.command {
+        return stop_desk(state, desk, headers).await;
+    }
+    // The TTL counts from when the request arrived, so time spent queued counts too
+    let expires_at = expiry::deadline(expiry::ttl(request.ttl_ms, state.config().command_ttl_secs));
+
+    let user = match users::identify(headers, &state.config(), &*state.users.lock().await) {
+        Ok(user) => user,
+        Err(rejection) => return rejection,
+    };
+
+    // Dry runs are free, they can't wear out the motor
+    let client_key = rate_limit_key(headers, remote, &state.config());
+    let automated = matches!(remote, ClientAddr::Internal);
+    if !request.dry_run
+        && !automated
+        && let Err(limited) = state.rate_limiter.lock().await.check(&client_key)
+    {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            Json(serde_json::json!({
+                "error": "Too many commands",
+                "code": "rate_limited",
+                "limit_per_minute": limited.limit_per_minute,
+                "retry_after_ms": limited.retry_after.as_millis() as u64,
+            })),
+        );
+    }
+
+    if state.config().inactivity_lock_secs > 0 && !automated {
+        let mut lock = state.inactivity_lock.lock().await;
+        // An admin-authenticated command both passes and lifts the lock
+        if admin::require_admin(headers, &state.config()).is_ok() {
+            lock.unlock();
+        } else if lock.is_locked() {
+            return (
+                StatusCode::LOCKED,
+                Json(
+                    serde_json::json!({"error": "Desk is locked after inactivity, unlock it first"}),
+                ),
+            );
+        }
+        lock.touch();
+    }
+
+    let command = match request.label.as_deref() {
+        Some(label) => match labelled_command(state, &request.command, label).await {
+            Ok(command) => command,
+            Err(rejection) => return rejection,
+        },
+        None if request.command.value == DeskCommand::MISSING_VALUE => {
+            return (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(serde_json::json!({
+                    "error": "The command needs a value",
+                    "code": "missing_value",
+                })),
+            );
+        }
+        None => request.command,
+    };
+    info!(
+        "Received command {} with value {}",
+        command.command, command.value
+    );
+    if let Err(rejection) = validation::validate_command(&command, &state.config()) {
+        return rejection;
+    }
+    if command.command.is_movement()
+        && !request.force
+        && let Some(window) =
+            quiet_hours::active(&state.config().quiet_hours, chrono::Local::now().time())
+    {
+        return quiet_hours::refused(window);
+    }
+    // The pulses are commands of their own, each checked as it is sent
+    if request.soft_start
+        && !request.dry_run
+        && desk.is_none()
+        && let Some(steps) = soft_start::ramp(&state.config(), &command)
+    {
+        info!(
+            "Starting {} with {} soft start pulses",
+            command.command,
+            steps.len() - 1
+        );
+        let id = sequences::spawn(state, steps, remote, headers.clone()).await;
+        return (
+            StatusCode::ACCEPTED,
+            Json(serde_json::json!({
+                "status": "Soft start sequence started",
+                "job_id": id,
+            })),
+        );
+    }
+
+    if state.shutdown.is_triggered() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(serde_json::json!({
+                "error": "Server is shutting down",
+                "code": "shutting_down",
+            })),
+        );
+    }
+
+    if !state.reconnect_monitor.lock().await.is_connected() {
+        if state.config().spool_commands
+            && !request.dry_run
+            && desk.is_none()
+            && spool::accepts(&command)
+        {
+            return spool::spool(
+                state,
+                &request_id::from_headers(headers),
+                command,
+                expires_at,
+            )
+            .await;
+        }
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(serde_json::json!({
+                "error": "Not connected to the MQTT broker, the command would not reach the desk",
+                "code": "broker_unavailable",
+            })),
+        );
+    }
+
+    let timeout_ms = request.timeout_ms.unwrap_or(state.config().ack_timeout_ms);
+    if timeout_ms > state.config().ack_timeout_max_ms {
+        return (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(serde_json::json!({
+                "error": format!(
+                    "timeout_ms {} exceeds the maximum of {} ms",
+                    timeout_ms, state.config().ack_timeout_max_ms
+                ),
+                "max_timeout_ms": state.config().ack_timeout_max_ms,
+            })),
+        );
+    }
+
+    let request_id = request_id::from_headers(headers);
+    // Settings don't move the desk, so they don't wait for the queue
+    if command.command.is_motor_setting() {
+        if request.dry_run {
+            return dry_run_response(state, desk, &request_id, &command, None, None, None);
+        }
+        return motor::send(state, desk, &command, &request_id, user.as_deref()).await;
+    }
+
+    if desk.is_none()
+        && let Some(fault) = state.faults.lock().await.active()
+    {
+        return faults::blocked(fault);
+    }
+
+    // Everything below reads the desk's state, so it only runs once earlier commands are done
+    let timeout = std::time::Duration::from_millis(timeout_ms);
+    let slot = if request.dry_run {
+        None
+    } else {
+        match state
+            .command_queue
+            .acquire(desk, &request_id, &command, timeout, expires_at)
+            .await
+        {
+            Ok(slot) => Some(slot),
+            Err(rejection) => return rejection.into_response(),
+        }
+    };
+
+    let Some((current_state, updated_at)) = state.desk_snapshot(desk).await else {
+        return (
+            StatusCode::CONFLICT,
+            Json(serde_json::json!({
+                "error": format!("Desk {} has not reported its state yet", desk.unwrap_or_default())
+            })),
+        );
+    };
+
+    if let Some(max_age) = state.config().stale_state_max_age {
+        let age = updated_at.elapsed();
+        let rejected = command.command.is_relative()
+            || (command.command.is_absolute() && state.config().stale_state_reject_absolute);
+        if rejected && age > max_age {
+            return (
+                StatusCode::CONFLICT,
+                Json(serde_json::json!({
+                    "error": "Stale State",
+                    "state_age_ms": age.as_millis() as u64,
+                    "max_age_ms": max_age.as_millis() as u64,
+                })),
+            );
+        }
+    }
+
+    // Percentage commands are resolved against the current height and sent as absolute moves
+    let command = match command.command {
+        SvenCommand::UpPercent | SvenCommand::DownPercent => {
+            match resolve_percent_command(&command, current_state.height_mm, &state.config()) {
+                Ok(resolved) => resolved,
+                Err(e) => {
+                    return (
+                        StatusCode::UNPROCESSABLE_ENTITY,
+                        Json(serde_json::json!({"error": e})),
+                    );
+                }
+            }
+        }
+        // Relative moves too when configured, so the desk doesn't have to track them itself
+        SvenCommand::UpRelative | SvenCommand::DownRelative
+            if state.config().resolve_relative_moves =>
+        {
+            // A registered desk's snapshot always comes from a report, the primary
+            // desk's starts out as a placeholder
+            if desk.is_none() && !state.state_received.load(Ordering::SeqCst) {
+                return (
+                    StatusCode::CONFLICT,
+                    Json(serde_json::json!({
+                        "error": "The desk has not reported its height yet, relative moves can't be resolved",
+                        "code": "state_unknown",
+                    })),
+                );
+            }
+            resolve_relative_command(&command, current_state.height_mm, &state.config())
+        }
+        _ => command,
+    };
+
+    let requested = command.clone();
+    let command = match limits::enforce(
+        &state.config(),
+        desk,
+        command,
+        current_state.height_mm,
+        &*state.position_heights.lock().await,
+    ) {
+        Ok(command) => command,
+        Err(rejection) => {
+            state.notifier.notify(
+                NotificationKind::HeightLimit {
+                    desk: desk.map(str::to_string),
+                },
+                rejection.1["error"].as_str().unwrap_or_default(),
+            );
+            return rejection;
+        }
+    };
+    if command.command != requested.command || command.value != requested.value {
+        state.notifier.notify(
+            NotificationKind::HeightLimit {
+                desk: desk.map(str::to_string),
+            },
+            format!(
+                "{} was clamped to the soft height limit of {} mm",
+                requested.command, command.value
+            ),
+        );
+    }
+
+    if let SvenCommand::Position = command.command {
+        let Some(position) = SvenPosition::from_index(command.value) else {
+            return (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(serde_json::json!({
+                    "error": format!("Unknown position {}", command.value)
+                })),
+            );
+        };
+        let name = format!("{:?}", position);
+        let mut cooldowns = state.preset_cooldowns.lock().await;
+        let cooling_down = if request.dry_run {
+            cooldowns.remaining(&name).map_or(Ok(()), Err)
+        } else {
+            cooldowns.try_activate(&name)
+        };
+        if let Err(remaining) = cooling_down {
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                Json(serde_json::json!({
+                    "error": format!("Position {} is cooling down", name),
+                    "retry_after_ms": remaining.as_millis() as u64,
+                })),
+            );
+        }
+    }
+    let command = state.position_heights.lock().await.resolve(command);
+
+    let current_height_mm = current_state.height_mm;
+
+    if !request.override_protected_zones
+        && let Some(target_mm) = movement::projected_target_mm(
+            &command,
+            current_height_mm,
+            state.config().travel_speed_mm_per_s,
+            &*state.position_heights.lock().await,
+        )
+        && let Some(zone) = zones::crossed_zone(
+            &state.config().protected_zones,
+            current_height_mm,
+            target_mm,
+        )
+    {
+        return (
+            StatusCode::CONFLICT,
+            Json(serde_json::json!({
+                "error": format!(
+                    "Moving from {} mm to {} mm passes through protected zone {}",
+                    current_height_mm, target_mm, zone.name
+                ),
+                "zone": zone,
+            })),
+        );
+    }
+
+    // Jittery sliders send many tiny corrections that would only wear the motor
+    let height_known = desk.is_some() || state.state_received.load(Ordering::SeqCst);
+    if height_known
+        && movement::within_deadband(&command, current_height_mm, state.config().deadband_mm)
+    {
+        info!(
+            "Skipping move to {} mm, the desk is at {} mm",
+            command.value, current_height_mm
+        );
+        return (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "status": "Command skipped",
+                "skipped": true,
+                "code": "within_deadband",
+                "request_id": request_id,
+                "current_mm": current_height_mm,
+                "target_mm": command.value,
+                "deadband_mm": state.config().deadband_mm,
+            })),
+        );
+    }
+
+    let eta_ms = movement::estimate_travel_ms(
+        &command,
+        current_height_mm,
+        state.config().travel_speed_mm_per_s,
+    );
+
+    let slow_zone_plan = if command.command.is_absolute() || command.command.is_relative() {
+        movement::projected_target_mm(
+            &command,
+            current_height_mm,
+            state.config().travel_speed_mm_per_s,
+            &*state.position_heights.lock().await,
+        )
+        .and_then(|target_mm| {
+            movement::slow_zone_plan(current_height_mm, target_mm, &state.config())
+                .map(|steps| (target_mm, steps))
+        })
+    } else {
+        None
+    };
+
+    if request.dry_run {
+        return dry_run_response(
+            state,
+            desk,
+            &request_id,
+            &command,
+            slow_zone_plan,
+            eta_ms,
+            expires_at,
+        );
+    }
+
+    if let Err(rejection) = power::ensure_awake(state).await {
+        return rejection;
+    }
+
+    // Subscribe before publishing so a fast desk can't report arrival before we listen
+    let mut state_rx = state.subscribe_state(desk).await;
+
+    let mut job = Job::new(JobKind::Command, &request_id, desk);
+    job.user = user.clone();
+    job.command = Some(command.clone());
+    job.target_mm = match slow_zone_plan {
+        Some((target_mm, _)) => Some(target_mm),
+        None if command.command.is_absolute() => Some(command.value),
+        None => None,
+    };
+    let job_id = state.jobs.lock().await.insert(job);
+
+    let mut response = serde_json::json!({
+        "status": "Command sent successfully",
+        "request_id": request_id,
+        "job_id": job_id,
+    });
+    if let Some(user) = &user {
+        response["user"] = user.clone().into();
+    }
+    if let SvenCommand::AbsoluteHeight = command.command {
+        response["target_mm"] = command.value.into();
+    }
+
+    let wait_for_ack = request.ack.unwrap_or(state.config().wait_for_ack);
+    let ack_rx = if wait_for_ack {
+        Some(state.ack_waiters.register(&request_id).await)
+    } else {
+        None
+    };
+
+    let sent_at = std::time::Instant::now();
+    // Publish to MQTT broker, stepping through the slow zone in the background if needed
+    let arrival_target_mm = match slow_zone_plan {
+        Some((target_mm, steps)) => {
+            info!(
+                "Approaching {} mm through the slow zone in {} steps",
+                target_mm,
+                steps.len()
+            );
+            response["target_mm"] = target_mm.into();
+            response["slow_zone_steps"] = steps.len().into();
+            let app_state = state.clone();
+            let request_id = request_id.clone();
+            let desk = desk.map(str::to_string);
+            let user = user.clone();
+            let job_id = job_id.clone();
+            let follow_rx = state_rx.clone();
+            let approach_timeout = timeout * steps.len() as u32;
+            tokio::spawn(async move {
+                tokio::join!(
+                    movement::run_steps(
+                        &app_state,
+                        "Slow zone approach",
+                        &steps,
+                        Some(&request_id),
+                        user.as_deref(),
+                        desk.as_deref(),
+                    ),
+                    jobs::follow(
+                        &app_state,
+                        &job_id,
+                        follow_rx,
+                        Some(target_mm),
+                        approach_timeout
+                    ),
+                );
+                drop(slot);
+            });
+            Some(target_mm)
+        }
+        None => {
+            let sent = state
+                .send_command_until(desk, &command, &request_id, user.as_deref(), expires_at)
+                .await;
+            let target_mm = match command.command {
+                SvenCommand::AbsoluteHeight => Some(command.value),
+                _ => None,
+            };
+            // The next queued command waits until this one has finished moving the desk
+            match sent {
+                Ok(()) => {
+                    let app_state = state.clone();
+                    let job_id = job_id.clone();
+                    let release_rx = state_rx.clone();
+                    // A pulse's turn ends here, the sequence's pause covers its move
+                    let slot = slot.filter(|_| !request.pulse);
+                    tokio::spawn(async move {
+                        jobs::follow(&app_state, &job_id, release_rx, target_mm, timeout).await;
+                        drop(slot);
+                    });
+                }
+                Err(e) => {
+                    error!("Failed to publish {}: {}", command.command, e);
+                    state.jobs.lock().await.finish(
+                        &job_id,
+                        JobStatus::Failed,
+                        Some(format!("Failed to send the command: {}", e)),
+                    );
+                    if wait_for_ack {
+                        state.ack_waiters.forget(&request_id).await;
+                    }
+                    let (status, Json(mut body)) =
+                        e.response("Failed to publish the command", Some(&request_id));
+                    body["job_id"] = job_id.into();
+                    return (status, Json(body));
+                }
+            }
+            target_mm
+        }
+    };
+    if let Some(eta_ms) = eta_ms {
+        response["eta_ms"] = eta_ms.into();
+        response["eta_at"] =
+            serde_json::json!(chrono::Utc::now() + chrono::Duration::milliseconds(eta_ms as i64));
+    }
+
+    if wait_for_ack || request.confirm {
+        response["timeout_ms"] = timeout_ms.into();
+    }
+
+    if let Some(ack_rx) = ack_rx {
+        match state.ack_waiters.wait(&request_id, ack_rx, timeout).await {
+            Some(ack) if ack.status == AckStatus::Ok => {
+                response["status"] = "Command acknowledged".into();
+            }
+            Some(ack) => {
+                response["error"] = ack
+                    .error
+                    .unwrap_or_else(|| "Firmware rejected the command".to_string())
+                    .into();
+                return (StatusCode::BAD_GATEWAY, Json(response));
+            }
+            None => {
+                response["error"] = format!(
+                    "Firmware did not acknowledge the command within {} ms",
+                    timeout.as_millis()
+                )
+                .into();
+                return (StatusCode::GATEWAY_TIMEOUT, Json(response));
+            }
+        }
+    }
+
+    if !request.confirm {
+        return (StatusCode::OK, Json(response));
+    }
+    let result = match arrival_target_mm {
+        Some(target_mm) => movement::wait_for_arrival(&mut state_rx, target_mm, timeout).await,
+        None => movement::wait_for_settle(&mut state_rx, timeout).await,
+    };
+    match result {
+        Ok(final_state) => {
+            state
+                .metrics
+                .record_confirm_latency(sent_at.elapsed())
+                .await;
+            response["status"] = "Command confirmed".into();
+            response["state"] = serde_json::json!(final_state);
+            (StatusCode::OK, Json(response))
+        }
+        Err(last_state) => {
+            response["error"] = format!(
+                "Desk did not finish moving within {} ms",
+                timeout.as_millis()
+            )
+            .into();
+            response["state"] = serde_json::json!(last_state);
+            (StatusCode::GATEWAY_TIMEOUT, Json(response))
+        }
+    }
+}
+
+/// What a dry run reports: the command after presets, calibration and limits were
+/// applied, and each message it would have published, in order.
+fn dry_run_response(
+    state: &AppState,
+    desk: Option<&str>,
+    request_id: &str,
+    command: &DeskCommand,
+    slow_zone_plan: Option<(u32, Vec<DeskCommand>)>,
+    eta_ms: Option<u64>,
+    expires_at: Option<DateTime<Utc>>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let (target_mm, steps) = match slow_zone_plan {
+        Some((target_mm, steps)) => (Some(target_mm), steps),
+        None => (
+            matches!(command.command, SvenCommand::AbsoluteHeight).then_some(command.value),
+            vec![command.clone()],
+        ),
+    };
+    let publishes: Vec<_> = steps
+        .iter()
+        .map(|step| {
+            let (topic, payload) = state.command_message(desk, step, request_id, expires_at);
+            serde_json::json!({"topic": topic, "payload": payload})
+        })
+        .collect();
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "status": "Dry run, nothing was published",
+            "dry_run": true,
+            "request_id": request_id,
+            "command": command,
+            "publishes": publishes,
+            "target_mm": target_mm,
+            "eta_ms": eta_ms,
+        })),
+    )
+}
+
+/// Turns an `UpPercent`/`DownPercent` command into an `AbsoluteHeight` command,
+/// clamped to the configured height bounds.
+fn resolve_percent_command(
+    command: &DeskCommand,
+    current_height_mm: u32,
+    config: &Config,
+) -> Result<DeskCommand, String> {
+    if command.value == 0 || command.value > 100 {
+        return Err(format!(
+            "Percentage must be between 1 and 100, got {}",
+            command.value
+        ));
+    }
+
+    let basis_mm = match config.percent_basis {
+        PercentBasis::Height => current_height_mm,
+        PercentBasis::Range => config.max_height_mm - config.min_height_mm,
+    };
+    // In u64, a reported height can be large enough to overflow the product
+    let delta_mm = (u64::from(basis_mm) * u64::from(command.value) / 100) as u32;
+    let target_mm = match command.command {
+        SvenCommand::UpPercent => current_height_mm.saturating_add(delta_mm),
+        _ => current_height_mm.saturating_sub(delta_mm),
+    };
+
+    Ok(DeskCommand {
+        command: SvenCommand::AbsoluteHeight,
+        value: config.clamp_height(target_mm),
+    })
+}
+
+/// Turns an `UpRelative`/`DownRelative` command into an `AbsoluteHeight` command
+/// from the current height, clamped to the configured height bounds.
+fn resolve_relative_command(
+    command: &DeskCommand,
+    current_height_mm: u32,
+    config: &Config,
+) -> DeskCommand {
+    let target_mm = match command.command {
+        SvenCommand::UpRelative => current_height_mm.saturating_add(command.value),
+        _ => current_height_mm.saturating_sub(command.value),
+    };
+    DeskCommand {
+        command: SvenCommand::AbsoluteHeight,
+        value: config.clamp_height(target_mm),
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ToSchema)]
+pub enum SvenPosition {
+    Bottom,
+    Top,
+    Armrest,
+    AboveArmrest,
+    Standing,
+    Custom,
+}
+
+impl SvenPosition {
+    pub const ALL: [SvenPosition; 6] = [
+        SvenPosition::Bottom,
+        SvenPosition::Top,
+        SvenPosition::Armrest,
+        SvenPosition::AboveArmrest,
+        SvenPosition::Standing,
+        SvenPosition::Custom,
+    ];
+
+    /// Maps the `value` of a `Position` command to a position, in declaration order.
+    pub fn from_index(index: u32) -> Option<SvenPosition> {
+        SvenPosition::ALL.get(index as usize).copied()
+    }
+}
+
+impl std::str::FromStr for SvenPosition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "bottom" => Ok(SvenPosition::Bottom),
+            "top" => Ok(SvenPosition::Top),
+            "armrest" => Ok(SvenPosition::Armrest),
+            "abovearmrest" => Ok(SvenPosition::AboveArmrest),
+            "standing" => Ok(SvenPosition::Standing),
+            "custom" => Ok(SvenPosition::Custom),
+            other => Err(format!("unknown position '{}'", other)),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, ToSchema)]
+pub struct SvenState {
+    pub height_mm: u32,
+    pub position: SvenPosition,
+    /// Motor settings the controller runs with, when it reports them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub motor: Option<MotorSettings>,
+}
+
+/// State reported before the first MQTT update: the persisted last state if there is one,
+/// otherwise the configured default, otherwise the desk's lowest position.
+fn initial_state(config: &Config, store: Option<&dyn Store>) -> SvenState {
+    if let Some(state) = persistence::load::<SvenState>(store) {
+        if config.height_in_bounds(state.height_mm) {
+            info!("Restored persisted Sven state: {:?}", state);
+            return state;
+        }
+        warn!(
+            "Persisted height {} is outside configured bounds, ignoring it",
+            state.height_mm
+        );
+    }
+
+    match (config.default_position, config.default_height_mm) {
+        (position, Some(height_mm)) => SvenState {
+            height_mm,
+            position: position.unwrap_or(SvenPosition::Custom),
+            motor: None,
+        },
+        (Some(position), None) => {
+            warn!(
+                "SVEN_DEFAULT_POSITION set without SVEN_DEFAULT_HEIGHT_MM, assuming lowest height"
+            );
+            SvenState {
+                height_mm: config.min_height_mm,
+                position,
+                motor: None,
+            }
+        }
+        (None, None) => SvenState {
+            height_mm: config.min_height_mm,
+            position: SvenPosition::Bottom,
+            motor: None,
+        },
+    }
+}
+
+/// Records a state report. Reports from a wildcard state topic are kept per desk;
+/// the configured desk (or any desk, when none is configured) also drives the
+/// main state endpoints.
+async fn apply_state_update(app_state: &AppState, desk_id: Option<String>, mut state: SvenState) {
+    if let Some(state_writer) = &app_state.state_writer {
+        state_writer.record_history(desk_id.as_deref(), &state);
+    }
+    if let Some(desk_id) = desk_id {
+        let is_primary = app_state
+            .config()
+            .desk_id
+            .as_deref()
+            .is_none_or(|primary| primary == desk_id);
+        debug!("Updated state of desk {}: {:?}", desk_id, state);
+        app_state.desks.update(desk_id, state).await;
+        if !is_primary {
+            return;
+        }
+    }
+
+    {
+        let mut sven_state = app_state.sven_state.write().await;
+        // Reports that leave the motor settings out keep the last known ones
+        state.motor = match (sven_state.motor, state.motor) {
+            (Some(known), Some(reported)) => Some(known.merge(reported)),
+            (known, reported) => reported.or(known),
+        };
+        *sven_state = state;
+        debug!("Updated Sven state: {:?}", *sven_state);
+    }
+    let direction = app_state.motion.lock().await.update(state.height_mm);
+    app_state.advance_status(StatusEvent::Reported(direction));
+    *app_state.last_state_at.lock().await = std::time::Instant::now();
+    *app_state.last_state_time.lock().await = Some(chrono::Utc::now());
+    app_state.state_received.store(true, Ordering::SeqCst);
+    app_state.refresh_state_cache().await;
+    app_state.state_tx.send_replace(state);
+    if let Some(event_log) = &app_state.event_log {
+        event_log.log("state", &state, None);
+    }
+    if let Some(state_writer) = &app_state.state_writer {
+        state_writer.persist(&state);
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/sven/state",
+    tag = "desk",
+    params(
+        UnitParams,
+        WaitParams,
+        ("Accept-Units" = Option<String>, Header, description = "`mm`, `cm` or `in` to add heights in that unit"),
+        ("If-None-Match" = Option<String>, Header, description = "ETag of the state the client has"),
+    ),
+    responses(
+        (status = 200, description = "Current state of the primary desk", body = StateView),
+        (status = 304, description = "The state still matches `If-None-Match`"),
+        (status = 400, description = "Unknown unit", body = validation::ErrorBody),
+        (status = 503, description = "The state is stale and `SVEN_STALE_STATE_UNAVAILABLE` is set", body = StateView),
+    ),
+)]
+async fn get_sven_state(
+    headers: HeaderMap,
+    Query(unit_params): Query<UnitParams>,
+    Query(wait_params): Query<WaitParams>,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> axum::response::Response {
+    let unit = match units::negotiate(&headers, &unit_params) {
+        Ok(unit) => unit,
+        Err(rejection) => return rejection.into_response(),
+    };
+    if wait_params.wait {
+        wait_for_state_change(&app_state, &headers, wait_params.timeout()).await;
+    }
+    let stale = app_state.state_is_stale().await;
+    // The cached body is built right after each report, so it only goes stale
+    // by time passing and is rebuilt here once it has
+    if !stale && unit == Unit::Mm {
+        let cached = app_state.cached_state.read().await.clone();
+        if cached.is_fresh(&headers) {
+            return cached.not_modified();
+        }
+        return cached.into_response();
+    }
+    let status = if stale && app_state.config().stale_state_unavailable {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+    let mut body = serde_json::to_value(app_state.state_view().await).unwrap();
+    unit.annotate(&mut body);
+    let built = CachedState::from_value(&body);
+    if status == StatusCode::OK && built.is_fresh(&headers) {
+        return built.not_modified();
+    }
+    (status, built).into_response()
+}
+
+/// Holds a `?wait=true` request until the cached state changes, `timeout` runs
+/// out or the bridge shuts down. Returns right away when the client's
+/// `If-None-Match` is already out of date.
+async fn wait_for_state_change(
+    app_state: &AppState,
+    headers: &HeaderMap,
+    timeout: std::time::Duration,
+) {
+    let mut changes = app_state.state_version.subscribe();
+    if headers.contains_key(IF_NONE_MATCH) && !app_state.cached_state.read().await.is_fresh(headers)
+    {
+        return;
+    }
+    tokio::select! {
+        _ = tokio::time::timeout(timeout, changes.changed()) => {}
+        _ = app_state.shutdown.wait() => {}
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/sven/status",
+    tag = "desk",
+    responses(
+        (status = 200, description = "Status reported by the desk controller", body = String),
+    ),
+)]
+async fn get_sven_status(Extension(app_state): Extension<Arc<AppState>>) -> impl IntoResponse {
+    let sven_status = app_state.sven_status.lock().await;
+    debug!("Returning Sven status: {}", *sven_status);
+    (StatusCode::OK, Json(sven_status.clone()))
+}
+#[utoipa::path(
+    get,
+    path = "/api/sven/debug/events",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Recent MQTT events", body = serde_json::Value),
+        (status = 401, description = "Missing or invalid admin token", body = validation::ErrorBody),
+        (status = 403, description = "Admin routes are disabled", body = validation::ErrorBody),
+    ),
+)]
+async fn get_debug_events(
+    headers: HeaderMap,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    if let Err(rejection) = admin::require_admin(&headers, &app_state.config()) {
+        return rejection;
+    }
+    let events = app_state.mqtt_trace.lock().await.events();
+    (StatusCode::OK, Json(serde_json::json!(events)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/sven/debug/mqtt",
+    tag = "admin",
+    responses(
+        (status = 200, description = "MQTT client diagnostics", body = serde_json::Value),
+        (status = 401, description = "Missing or invalid admin token", body = validation::ErrorBody),
+        (status = 403, description = "Admin routes are disabled", body = validation::ErrorBody),
+    ),
+)]
+async fn get_debug_mqtt(
+    headers: HeaderMap,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    if let Err(rejection) = admin::require_admin(&headers, &app_state.config()) {
+        return rejection;
+    }
+    let duplicate_since = app_state
+        .reconnect_monitor
+        .lock()
+        .await
+        .possible_duplicate_since();
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "client_id": app_state.mqtt_client_id,
+            "possible_duplicate_client_id": duplicate_since.is_some(),
+            "possible_duplicate_since": duplicate_since,
+            "ack_timeout_ms": app_state.config().ack_timeout_ms,
+            "ack_timeout_max_ms": app_state.config().ack_timeout_max_ms,
+        })),
+    )
+}
+
+async fn set_to_night_mode(Extension(app_state): Extension<Arc<AppState>>) {
+    if let Err(e) = app_state
+        .publish(
+            MessageClass::Command,
+            &app_state.config().command_topic,
+            app_state.signed(serde_json::json!(DeskCommand {
+                command: SvenCommand::AbsoluteHeight,
+                value: NIGHT_TIME_THRESHOLD_MM + 5,
+            })),
+        )
+        .await
+    {
+        error!("Failed to publish night mode height: {}", e);
+    }
+}
+
+/// Moves the desk to the park height and waits until it arrives or the park timeout expires.
+async fn park_desk(app_state: &AppState, height_mm: u32) {
+    let target_mm = limits::limits_for(&app_state.config(), None)
+        .clamp(app_state.config().clamp_height(height_mm));
+    info!("Parking desk at {} mm before shutdown", target_mm);
+    let mut state_rx = app_state.state_tx.subscribe();
+
+    let payload = app_state.signed(serde_json::json!(DeskCommand {
+        command: SvenCommand::AbsoluteHeight,
+        value: target_mm,
+    }));
+    if let Err(e) = app_state
+        .publish(
+            MessageClass::Command,
+            &app_state.config().command_topic,
+            payload,
+        )
+        .await
+    {
+        error!("Failed to publish park command: {:?}", e);
+        return;
+    }
+
+    let timeout = std::time::Duration::from_secs(app_state.config().park_timeout_secs);
+    match movement::wait_for_arrival(&mut state_rx, target_mm, timeout).await {
+        Ok(_) => info!("Desk parked at {} mm", target_mm),
+        Err(_) => warn!(
+            "Desk did not reach park height within {} seconds",
+            timeout.as_secs()
+        ),
+    }
+}
+
+/// Resolves once shutdown has been triggered, for axum's graceful shutdown.
+fn shutdown_requested(app_state: &Arc<AppState>) -> impl Future<Output = ()> + Send + 'static {
+    let app_state = app_state.clone();
+    async move { app_state.shutdown.wait().await }
+}
+
+/// Runs the HTTP server until it has stopped after shutdown, giving in-flight
+/// requests up to the drain timeout to finish.
+async fn drain_http(app_state: &AppState, server: impl IntoFuture<Output = std::io::Result<()>>) {
+    let grace = std::time::Duration::from_secs(app_state.config().shutdown_drain_secs);
+    tokio::select! {
+        served = server.into_future() => served.unwrap(),
+        _ = app_state.shutdown.expired(grace) => warn!(
+            "Requests still in flight {} seconds after shutdown, closing them",
+            grace.as_secs()
+        ),
+    }
+}
+
+/// Waits out the startup backoff, or exits when strict startup has given up.
+async fn retry_startup(startup: &mut StartupRetry, reason: &str) {
+    match startup.on_failure(reason) {
+        Some(delay) => tokio::time::sleep(delay).await,
+        None => std::process::exit(1),
+    }
+}
+
+static HOST_IP: &str = "192.168.1.132";
+
+async fn host_is_active() -> bool {
+    match tokio::process::Command::new("ping")
+        .arg("-c")
+        .arg("1")
+        .arg(HOST_IP)
+        .output()
+        .await
+    {
+        Ok(output) => output.status.success(),
+        Err(e) => {
+            error!("Failed to execute ping command: {:?}", e);
+            false
+        }
+    }
+}
+
+/// Builds the shared state around `mqtt_client`, restoring whatever was persisted.
+/// Fails when the store can't be opened or a subscription pattern is invalid.
+pub fn build_state(
+    config: Config,
+    mqtt_client: AsyncClient,
+    mqtt_client_id: String,
+) -> Result<Arc<AppState>, String> {
+    // Subscriptions are (re)made on every ConnAck, see `spawn_mqtt_loop`
+    let topic_router = TopicRouter::from_config(&config)
+        .map_err(|e| format!("Invalid MQTT subscriptions: {}", e))?;
+    let store: Option<Arc<dyn Store>> = match config.store_path.as_deref() {
+        Some(path) => Some(
+            persistence::open_store(config.store_backend, path)
+                .map_err(|e| {
+                    format!(
+                        "Failed to open {:?} store at {}: {}",
+                        config.store_backend,
+                        path.display(),
+                        e
+                    )
+                })?
+                .into(),
+        ),
+        None => None,
+    };
+    let sven_state = initial_state(&config, store.as_deref());
+    let macros: Macros = persistence::load_or_default(store.as_deref());
+    let position_heights: PositionHeights = persistence::load_or_default(store.as_deref());
+    let label = position_heights
+        .label_at(sven_state.height_mm, config.duplicate_preset_tolerance_mm)
+        .map(str::to_string);
+    // An estimated height is known from the start, there are no reports to wait for
+    let height_estimated = config.height_estimation;
+    let presets: Presets = persistence::load_or_default(store.as_deref());
+    let users = persistence::load_or_default::<Users>(store.as_deref()).with_configured(&config);
+    let schedules: Schedules = persistence::load_or_default(store.as_deref());
+    let rules: Rules = persistence::load_or_default(store.as_deref());
+    let webhooks: Webhooks = persistence::load_or_default(store.as_deref());
+    let tokens: Tokens = persistence::load_or_default(store.as_deref());
+    let quick_actions: QuickActions = persistence::load_or_default(store.as_deref());
+    let spool: Spool = persistence::load_or_default(store.as_deref());
+    let faults: Faults = persistence::load_or_default(store.as_deref());
+    let reminder_settings: ReminderSettings = persistence::load_or_default(store.as_deref());
+    let limits = BufferLimits::from_config(&config);
+    let history_db = config.history_db.as_deref().and_then(|path| {
+        HistoryDb::open(path)
+            .inspect_err(|e| {
+                error!(
+                    "Failed to open history database at {}: {}",
+                    path.display(),
+                    e
+                )
+            })
+            .ok()
+            .map(Arc::new)
+    });
+    let state_writer = (store.is_some() || history_db.is_some())
+        .then(|| StateWriter::spawn(store.clone(), history_db.clone()));
+    Ok(Arc::new(AppState {
+        mqtt_trace: Arc::new(Mutex::new(MqttTrace::new(limits.trace_events))),
+        mqtt_client_id,
+        reconnect_monitor: Arc::new(Mutex::new(ReconnectMonitor::default())),
+        transport: transport::from_config(&config, mqtt_client),
+        sven_state: Arc::new(RwLock::new(sven_state)),
+        state_tx: watch::Sender::new(sven_state),
+        topic_router,
+        desks: DeskRegistry::new(config.desks.clone()),
+        cached_state: Arc::new(RwLock::new(CachedState::new(&StateView {
+            state: sven_state,
+            direction: movement::Direction::Idle,
+            broker_connected: false,
+            power: PowerState::Unknown,
+            occupancy: Occupancy::Unknown,
+            power_w: None,
+            power_draw: None,
+            status: DeskStatus::Offline,
+            last_updated: None,
+            stale: false,
+            fault: None,
+            session: None,
+            label,
+        }))),
+        state_version: watch::Sender::new(0),
+        power_tx: watch::Sender::new(PowerState::Unknown),
+        occupancy_tx: watch::Sender::new(Occupancy::Unknown),
+        power_meter_tx: watch::Sender::new(None),
+        status_tx: watch::Sender::new(DeskStatus::Offline),
+        progress_tx: watch::Sender::new(None),
+        sven_status: Arc::new(Mutex::new("offline".to_string())),
+        notifier: Notifier::new(),
+        store,
+        position_heights: Arc::new(Mutex::new(position_heights)),
+        presets: Arc::new(Mutex::new(presets)),
+        users: Arc::new(Mutex::new(users)),
+        pending_publishes: AtomicUsize::new(0),
+        history: Arc::new(Mutex::new(CommandHistory::new(limits.history_entries))),
+        history_db,
+        state_writer,
+        macros: Arc::new(Mutex::new(macros)),
+        jobs: Mutex::new(Jobs::default()),
+        session: Mutex::new(None),
+        idempotency: IdempotencyCache::new(config.idempotency_window),
+        firmware: Mutex::new(None),
+        motor_requested: Mutex::new(MotorSettings::default()),
+        audit: Mutex::new(AuditLog::new(config.audit_capacity)),
+        schedules: Arc::new(Mutex::new(schedules)),
+        rules: Mutex::new(rules),
+        faults: Mutex::new(faults),
+        state_nonces: Mutex::new(NonceCache::default()),
+        webhooks: Mutex::new(webhooks),
+        tokens: Mutex::new(tokens),
+        quick_actions: Mutex::new(quick_actions),
+        spool: Mutex::new(spool),
+        height_estimator: Mutex::new(Estimator::default()),
+        webhook_deliveries: Mutex::new(Deliveries::default()),
+        reminders: Arc::new(Mutex::new(Reminders::new(reminder_settings))),
+        http_client: reqwest::Client::new(),
+        motion: Arc::new(Mutex::new(MotionTracker::new())),
+        last_state_at: Arc::new(Mutex::new(std::time::Instant::now())),
+        state_received: AtomicBool::new(height_estimated),
+        last_state_time: Mutex::new(None),
+        metrics: Metrics::default(),
+        connections: ConnectionRegistry::default(),
+        ack_waiters: AckWaiters::default(),
+        shutdown: Shutdown::new(),
+        stop_generation: AtomicU64::new(0),
+        command_queue: CommandQueue::new(config.command_queue_policy),
+        inactivity_lock: Arc::new(Mutex::new(InactivityLock::new(
+            std::time::Duration::from_secs(config.inactivity_lock_secs),
+        ))),
+        rate_limiter: Arc::new(Mutex::new(RateLimiter::new(
+            config.rate_limit_per_minute,
+            config.rate_limit_overrides.clone(),
+        ))),
+        login_limiter: Mutex::new(RateLimiter::new(
+            config.login_failures_per_minute,
+            std::collections::HashMap::new(),
+        )),
+        preset_cooldowns: Arc::new(Mutex::new(PresetCooldowns::new(
+            config.preset_cooldowns.clone(),
+        ))),
+        event_log: config
+            .event_log_file
+            .clone()
+            .map(|path| EventLog::spawn(path, config.event_log_max_bytes, limits.event_log_queue)),
+        config: std::sync::RwLock::new(Arc::new(config)),
+    }))
+}
+
+/// Starts the automations that run next to the API: the inactivity lock, motion
+/// settling, schedules, reminders and night mode.
+pub fn spawn_background_tasks(app_state: &Arc<AppState>) {
+    if app_state.config().inactivity_lock_secs > 0 {
+        tokio::spawn(lock::run_inactivity_lock(app_state.clone()));
+    }
+
+    let motion_app_state = app_state.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+            let settled = motion_app_state.motion.lock().await.settle();
+            if settled {
+                collision::check_stall(&motion_app_state).await;
+            }
+            let status_changed = (settled && motion_app_state.advance_status(StatusEvent::Settled))
+                || (motion_app_state.state_is_stale().await
+                    && motion_app_state.advance_status(StatusEvent::WentOffline));
+            if settled || status_changed {
+                motion_app_state.refresh_state_cache().await;
+            }
+        }
+    });
+
+    tokio::spawn(schedules::run_schedules(app_state.clone()));
+    tokio::spawn(rules::run_rules(app_state.clone()));
+    tokio::spawn(reminders::run_reminders(app_state.clone()));
+    tokio::spawn(stats::run_standing_goal(app_state.clone()));
+    tokio::spawn(channels::run_channels(app_state.clone()));
+    tokio::spawn(webhooks::run_webhooks(app_state.clone()));
+    tokio::spawn(estimation::run_estimation(app_state.clone()));
+    if app_state.config().mdns {
+        tokio::spawn(discovery::run_mdns(app_state.clone()));
+    }
+
+    let night_mode_app_state = app_state.clone();
+    tokio::spawn(async move {
+        loop {
+            let sven_state = {
+                let sven_state = night_mode_app_state.sven_state.read().await;
+                *sven_state
+            };
+
+            if sven_state.height_mm >= NIGHT_TIME_THRESHOLD_MM {
+                info!(
+                    "Current height is {}... Already in night mode!",
+                    sven_state.height_mm
+                );
+                tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+                continue;
+            }
+
+            static NIGHT_TIME_START: u32 = 23;
+            static NIGHT_TIME_END: u32 = 6;
+
+            let now = chrono::Local::now();
+            if now.hour() < NIGHT_TIME_START && now.hour() >= NIGHT_TIME_END {
+                debug!("Not night time, skipping night mode check");
+                let wait_time = now
+                    .with_hour(NIGHT_TIME_START)
+                    .unwrap()
+                    .with_minute(0)
+                    .unwrap()
+                    .with_second(0)
+                    .unwrap()
+                    - now;
+                debug!(
+                    "Waiting until night time starts in {} seconds",
+                    wait_time.num_seconds()
+                );
+                tokio::time::sleep(std::time::Duration::from_secs(
+                    wait_time.num_seconds() as u64
+                ))
+                .await;
+                continue;
+            }
+
+            if quiet_hours::active(&night_mode_app_state.config().quiet_hours, now.time()).is_some()
+            {
+                debug!("Quiet hours, will not set to night mode");
+                tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+                continue;
+            }
+
+            if host_is_active().await {
+                debug!("Host is still active, will not set to night mode");
+                tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+                continue;
+            }
+
+            info!(
+                "It's night time and current height is {}, setting desk to night mode",
+                sven_state.height_mm
+            );
+            night_mode_app_state.notifier.notify(
+                NotificationKind::Automation {
+                    automation: "night_mode".to_string(),
+                },
+                format!(
+                    "Night time and host inactive, raising desk from {} mm",
+                    sven_state.height_mm
+                ),
+            );
+            if night_mode_app_state.shutdown.is_triggered() {
+                return;
+            }
+            set_to_night_mode(Extension(night_mode_app_state.clone())).await;
+            tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+        }
+    });
+}
+
+/// Records a smart plug reading and checks the draw against the height reports.
+async fn handle_power_reading(app_state: &AppState, watts: f64) {
+    let config = app_state.config();
+    if let Some(history_db) = &app_state.history_db
+        && let Err(e) = history_db.record_power(config.desk_id.as_deref(), watts)
+    {
+        error!("Failed to record power reading: {}", e);
+    }
+    let previous = app_state.power_meter_tx.send_replace(Some(watts));
+    let draw = PowerDraw::from_watts(watts, config.power_meter_moving_w);
+    let direction = app_state.motion.lock().await.direction();
+    let previous_draw =
+        previous.map(|watts| PowerDraw::from_watts(watts, config.power_meter_moving_w));
+    // Only on a change of draw, readings keep coming while the desk stands still
+    if previous_draw != Some(draw) && draw.contradicts(direction) {
+        warn!(
+            "The desk draws {} W ({:?}) but its height reports say it is {:?}",
+            watts, draw, direction
+        );
+    }
+    app_state.refresh_state_cache().await;
+}
+
+/// Applies a status message from the desk controller: `online`, `offline` or an
+/// error it reports.
+async fn handle_status(app_state: &Arc<AppState>, status: String) {
+    let mut sven_status = app_state.sven_status.lock().await;
+    if status == "offline" && *sven_status != "offline" {
+        app_state
+            .notifier
+            .notify(NotificationKind::Watchdog, "Desk controller went offline");
+    }
+    let event = match status.as_str() {
+        "offline" => Some(StatusEvent::WentOffline),
+        "online" => Some(StatusEvent::CameOnline),
+        _ => status::error_message(&status).map(|message| {
+            warn!("Desk controller reported an error: {}", message);
+            app_state.notifier.notify(
+                NotificationKind::Watchdog,
+                format!("Desk controller reported an error: {}", message),
+            );
+            StatusEvent::Failed
+        }),
+    };
+    *sven_status = status;
+    debug!("Updated Sven status: {}", *sven_status);
+    drop(sven_status);
+    if let Some(event) = event
+        && app_state.advance_status(event)
+    {
+        app_state.refresh_state_cache().await;
+    }
+}
+
+/// Dispatches a message received on the transport to the handler of its topic.
+/// `retained` messages are the broker's last copy, replayed on subscribing.
+async fn handle_incoming(
+    app_state: &Arc<AppState>,
+    topic: String,
+    payload: Vec<u8>,
+    retained: bool,
+) {
+    debug!("Received MQTT packet: {} ({} bytes)", topic, payload.len());
+    // Checked before any topic is deserialized, so an oversized payload
+    // never gets parsed
+    if payload.len() > app_state.config().max_payload_bytes {
+        warn!(
+            "Skipping {} byte payload on {}: exceeds limit of {} bytes",
+            payload.len(),
+            topic,
+            app_state.config().max_payload_bytes
+        );
+        return;
+    }
+    let (topic, payload) = app_state.transport.incoming(topic, payload);
+    let rule_topic = rules::on_message(app_state, &topic, &payload).await;
+    match app_state.topic_router.route(&topic) {
+        // Home Assistant can't sign its commands, so with a secret they're refused
+        Some((TopicHandler::HomeAssistant, _))
+            if app_state.config().mqtt_signing_secret.is_some() =>
+        {
+            warn!("Dropping unsigned Home Assistant command on {}", topic);
+        }
+        // The firmware's last will can't be signed, and going offline only ever
+        // blocks commands
+        Some((TopicHandler::Status, _)) if payload == b"offline" => {
+            handle_status(app_state, String::from("offline")).await
+        }
+        Some((
+            TopicHandler::State
+            | TopicHandler::Status
+            | TopicHandler::Fault
+            | TopicHandler::Power
+            | TopicHandler::Ack,
+            _,
+        )) if !app_state.verified(&topic, &payload).await => {}
+        Some((TopicHandler::State, desk_id)) => {
+            // Deserialize the payload into SvenState
+            if let Ok(state) = serde_json::from_slice::<SvenState>(&payload) {
+                // The retained report fills the state in at startup, before the desk
+                // reports again
+                if retained {
+                    info!(
+                        "Restored retained state from {}: {} mm",
+                        topic, state.height_mm
+                    );
+                }
+                apply_state_update(app_state, desk_id, state).await;
+            } else {
+                error!("Failed to deserialize Sven state");
+            }
+        }
+        Some((TopicHandler::Status, _)) => {
+            match String::from_utf8(app_state.signed_text(payload)) {
+                Ok(status) => handle_status(app_state, status).await,
+                Err(_) => error!("Failed to deserialize Sven status"),
+            }
+        }
+        Some((TopicHandler::Fault, _)) => faults::handle_report(app_state, &payload).await,
+        Some((TopicHandler::Power, _)) => {
+            if let Some(power) = PowerState::parse(&app_state.signed_text(payload)) {
+                app_state.power_tx.send_replace(power);
+                app_state.refresh_state_cache().await;
+                debug!("Updated Sven power state: {:?}", power);
+            } else {
+                error!("Failed to parse Sven power state");
+            }
+        }
+        Some((TopicHandler::Occupancy, _)) => {
+            match Occupancy::parse(&payload, &app_state.config().occupancy_field) {
+                Some(occupancy) => {
+                    let previous = app_state.occupancy_tx.send_replace(occupancy);
+                    if previous != occupancy {
+                        app_state.refresh_state_cache().await;
+                        info!("Desk is now {:?}", occupancy);
+                    }
+                }
+                None => warn!(
+                    "Ignoring occupancy report on {}: {}",
+                    topic,
+                    String::from_utf8_lossy(&payload)
+                ),
+            }
+        }
+        Some((TopicHandler::PowerMeter, _)) => {
+            match energy::parse_watts(&payload, &app_state.config().power_meter_field) {
+                Some(watts) => handle_power_reading(app_state, watts).await,
+                None => warn!(
+                    "Ignoring power reading on {}: {}",
+                    topic,
+                    String::from_utf8_lossy(&payload)
+                ),
+            }
+        }
+        Some((TopicHandler::Ack, _)) => match serde_json::from_slice::<ack::Ack>(&payload) {
+            Ok(ack) => {
+                let id = ack.id.clone();
+                if !app_state.ack_waiters.resolve(ack).await {
+                    info!("Ack for {} arrived with nobody waiting", id);
+                }
+            }
+            Err(e) => error!("Failed to deserialize ack: {}", e),
+        },
+        Some((TopicHandler::HomeAssistant, _)) => {
+            tokio::spawn(homeassistant::handle_command(
+                app_state.clone(),
+                topic.clone(),
+                payload,
+            ));
+        }
+        Some((TopicHandler::Log, _)) => debug!(
+            "Message on {}: {}",
+            topic,
+            String::from_utf8_lossy(&payload)
+        ),
+        None if rule_topic => {}
+        None => warn!("Unknown topic: {}", topic),
+    }
+}
+
+/// Polls the MQTT eventloop, dispatching incoming messages and (re)subscribing
+/// on every connect, until the client disconnects.
+pub fn spawn_mqtt_loop(app_state: Arc<AppState>, mut eventloop: EventLoop) -> JoinHandle<()> {
+    let mqtt_app_state = app_state;
+    let mut startup = StartupRetry::new(
+        mqtt_app_state.config().strict_startup,
+        mqtt_app_state.config().startup_max_attempts,
+    );
+    let mut reconnect_backoff = Backoff::new();
+    tokio::spawn(async move {
+        loop {
+            let event = eventloop.poll().await;
+            {
+                let mut trace = mqtt_app_state.mqtt_trace.lock().await;
+                match &event {
+                    Ok(event) => trace.record_event(event),
+                    Err(e) => trace.record_error(e),
+                }
+            }
+            match event {
+                Ok(MqttEvent::Incoming(Packet::Publish(publish))) => {
+                    handle_incoming(
+                        &mqtt_app_state,
+                        publish.topic,
+                        publish.payload.to_vec(),
+                        publish.retain,
+                    )
+                    .await;
+                }
+                Ok(MqttEvent::Outgoing(Outgoing::Disconnect)) => {
+                    info!("MQTT disconnected");
+                    break;
+                }
+                Ok(MqttEvent::Outgoing(Outgoing::Publish(publish))) => {
+                    let _ = mqtt_app_state.pending_publishes.fetch_update(
+                        Ordering::SeqCst,
+                        Ordering::SeqCst,
+                        |pending| pending.checked_sub(1),
+                    );
+                    debug!("MQTT Published packet: {:?}", publish);
+                }
+                Ok(MqttEvent::Incoming(Packet::ConnAck(_))) => {
+                    info!("MQTT connected as {}", mqtt_app_state.mqtt_client_id);
+                    mqtt_app_state.on_broker_connected().await;
+                    reconnect_backoff.reset();
+                    if startup.is_subscribed() {
+                        mqtt_app_state.notifier.notify(
+                            NotificationKind::Broker { connected: true },
+                            "Reconnected to the MQTT broker",
+                        );
+                    }
+                    while let Err(e) = mqtt_app_state.subscribe_all().await {
+                        error!("Failed to subscribe to MQTT topics: {:?}", e);
+                        if startup.is_subscribed() {
+                            break;
+                        }
+                        retry_startup(&mut startup, &e.to_string()).await;
+                    }
+                    // Spawned since publishing waits on the eventloop this runs in
+                    let availability_app_state = mqtt_app_state.clone();
+                    tokio::spawn(async move {
+                        availability_app_state.announce_availability(true).await
+                    });
+                    if mqtt_app_state.config().ha_discovery {
+                        if mqtt_app_state.config().mqtt_signing_secret.is_some() {
+                            warn!("Not announcing to Home Assistant: it can't sign commands");
+                        } else {
+                            tokio::spawn(homeassistant::announce(mqtt_app_state.clone()));
+                        }
+                    }
+                }
+                Ok(MqttEvent::Incoming(Packet::SubAck(suback))) => {
+                    if suback
+                        .return_codes
+                        .iter()
+                        .all(|code| matches!(code, SubscribeReasonCode::Success(_)))
+                    {
+                        startup.on_subscribed();
+                    } else if !startup.is_subscribed() {
+                        retry_startup(&mut startup, "broker rejected a subscription").await;
+                        if let Err(e) = mqtt_app_state.subscribe_all().await {
+                            error!("Failed to subscribe to MQTT topics: {:?}", e);
+                        }
+                    } else {
+                        warn!("Broker rejected a subscription: {:?}", suback.return_codes);
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("MQTT error: {:?}", e);
+                    if !startup.is_subscribed() {
+                        retry_startup(&mut startup, &e.to_string()).await;
+                        continue;
+                    }
+                    let (was_connected, duplicate_id) = {
+                        let mut monitor = mqtt_app_state.reconnect_monitor.lock().await;
+                        (monitor.is_connected(), monitor.on_disconnected())
+                    };
+                    if was_connected {
+                        mqtt_app_state.refresh_state_cache().await;
+                        mqtt_app_state.notifier.notify(
+                            NotificationKind::Broker { connected: false },
+                            "Lost the connection to the MQTT broker",
+                        );
+                    }
+                    // rumqttc reconnects on the next poll; back off so a broker restart
+                    // doesn't turn into a busy loop
+                    let delay = reconnect_backoff.next_delay();
+                    warn!("Reconnecting to MQTT in {} ms", delay.as_millis());
+                    tokio::time::sleep(delay).await;
+                    // rumqttc replays what it couldn't send once it reconnects
+                    let expired = expiry::drop_expired(&mut eventloop.pending, Utc::now());
+                    if expired > 0 {
+                        warn!(
+                            "Dropped {} commands that expired while disconnected",
+                            expired
+                        );
+                        let _ = mqtt_app_state.pending_publishes.fetch_update(
+                            Ordering::SeqCst,
+                            Ordering::SeqCst,
+                            |pending| Some(pending.saturating_sub(expired)),
+                        );
+                    }
+                    if duplicate_id {
+                        warn!(
+                            "MQTT connection keeps being dropped right after connecting: possible duplicate client id '{}'. \
+                             Check for another running instance or set SVEN_MQTT_CLIENT_ID_UNIQUE=true",
+                            mqtt_app_state.mqtt_client_id
+                        );
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// The HTTP API with all its routes and layers.
+pub fn build_app(app_state: Arc<AppState>) -> Router {
+    let cors = cors::layer(&app_state);
+
+    // Data that only changes with configuration may be cached by browsers
+    let cacheable_routes = Router::new()
+        .route("/api/sven/positions", get(info::get_positions))
+        .route("/api/sven/limits", get(info::get_limits))
+        .route("/api/sven/commands", get(info::get_commands))
+        .layer(SetResponseHeaderLayer::overriding(
+            CACHE_CONTROL,
+            HeaderValue::from_str(&format!(
+                "public, max-age={}, must-revalidate",
+                app_state.config().cache_max_age_secs
+            ))
+            .unwrap(),
+        ));
+
+    // Live data must always be fetched fresh. History and stats, like the OpenAPI
+    // document, are large enough to be worth compressing for clients that accept it
+    let live_routes = Router::new()
+        .route("/api/sven/state", get(get_sven_state))
+        .route("/api/sven/status", get(get_sven_status))
+        .route("/api/sven/states", get(desks::get_desk_states))
+        .route("/api/sven/metrics.json", get(metrics::get_metrics_json))
+        .route("/metrics", get(metrics::get_metrics))
+        .route(
+            "/api/sven/history",
+            get(history::get_history).layer(CompressionLayer::new()),
+        )
+        .route("/api/sven/audit/export", get(audit::export_audit))
+        .route(
+            "/api/sven/stats",
+            get(stats::get_stats).layer(CompressionLayer::new()),
+        )
+        .route("/api/sven/{desk_id}/state", get(desks::get_desk_state))
+        .route("/api/desks", get(desks::get_desk_states))
+        .route("/api/desks/{desk_id}/state", get(desks::get_desk_state))
+        .layer(SetResponseHeaderLayer::overriding(
+            CACHE_CONTROL,
+            HeaderValue::from_static("no-cache"),
+        ));
+
+    // Where the HTTP transport's bridge delivers the desk's messages
+    let transport_routes = if app_state.config().transport == TransportKind::Http {
+        Router::new().route(
+            "/api/sven/transport/messages",
+            post(transport::handle_message),
+        )
+    } else {
+        Router::new()
+    };
+
+    let docs_routes = if app_state.config().swagger_ui {
+        Router::new().route("/api/docs", get(openapi::get_docs))
+    } else {
+        Router::new()
+    };
+
+    // Alexa can't present an API key, directives carry their own token instead
+    let alexa_routes = if !app_state.config().alexa_tokens.is_empty()
+        || app_state.config().alexa_introspection_url.is_some()
+    {
+        Router::new().route("/api/alexa", post(alexa::handle_directive))
+    } else {
+        Router::new()
+    };
+
+    let ui_routes = if app_state.config().ui {
+        Router::new()
+            .route("/", get(ui::get_index))
+            .route("/login", get(ui::get_login))
+            .route("/ui/{name}", get(ui::get_asset))
+    } else {
+        Router::new()
+    };
+
+    #[cfg(feature = "graphql")]
+    let graphql_routes = Router::new()
+        .route("/api/graphql", post(graphql::handle_graphql))
+        .layer(Extension(graphql::schema()));
+    #[cfg(not(feature = "graphql"))]
+    let graphql_routes = Router::new();
+
+    let command_body_bytes = app_state.config().max_command_body_bytes;
+    let app = Router::new()
+        .route(
+            "/api/sven/command",
+            body_limit::limited(post(handle_command), command_body_bytes),
+        )
+        .route(
+            "/api/sven/stop",
+            body_limit::limited(post(handle_stop), command_body_bytes),
+        )
+        .route("/api/sven/queue", get(queue::get_queue))
+        .route("/api/sven/debug/events", get(get_debug_events))
+        .route("/api/sven/debug/mqtt", get(get_debug_mqtt))
+        .route(
+            "/api/login",
+            get(login::get_session).post(login::handle_login),
+        )
+        .route("/api/logout", post(login::handle_logout))
+        .route("/api/admin/backup", get(backup::get_backup))
+        .route("/api/admin/restore", post(backup::handle_restore))
+        .route("/api/admin/config/reload", post(reload::handle_reload))
+        .route(
+            "/api/admin/tokens",
+            get(tokens::list_tokens).post(tokens::create_token),
+        )
+        .route("/api/admin/tokens/{name}", delete(tokens::delete_token))
+        .route("/api/sven/connections", get(connections::get_connections))
+        .route("/api/sven/debug/memory", get(memory::get_memory))
+        .route("/api/sven/maint", post(maintenance::handle_maintenance))
+        .route(
+            "/api/sven/settings",
+            get(motor::get_settings).put(motor::put_settings),
+        )
+        .route(
+            "/api/sven/firmware",
+            body_limit::limited(
+                get(firmware::get_firmware)
+                    .post(firmware::upload_firmware)
+                    .delete(firmware::abort_firmware),
+                app_state.config().ota_max_bytes,
+            ),
+        )
+        .route("/api/sven/recommend", post(ergonomics::handle_recommend))
+        .route("/api/sven/unlock", post(lock::handle_unlock))
+        .route(
+            "/api/sven/presets",
+            get(presets::list_presets).post(presets::save_preset),
+        )
+        .route("/api/sven/presets/{name}", delete(presets::delete_preset))
+        .route(
+            "/api/sven/users",
+            get(users::list_users).post(users::register_user),
+        )
+        .route("/api/sven/users/{name}", delete(users::delete_user))
+        .route(
+            "/api/sven/users/{name}/presets/{preset}",
+            put(users::save_user_preset).delete(users::delete_user_preset),
+        )
+        .route(
+            "/api/sven/presets/{name}/apply",
+            post(presets::apply_preset),
+        )
+        .route(
+            "/api/sven/quick",
+            get(quick::get_quick_actions)
+                .put(quick::set_quick_actions)
+                .post(quick::run_quick_action),
+        )
+        .route(
+            "/api/sven/positions/{position}",
+            put(positions::set_position_height),
+        )
+        .route("/api/sven/calibrate", post(positions::handle_calibrate))
+        .route(
+            "/api/sven/calibrate/{position}",
+            delete(positions::delete_calibration),
+        )
+        .route("/api/sven/macros", get(macros::list_macros))
+        .route(
+            "/api/sven/macros/from-history",
+            post(macros::create_from_history),
+        )
+        .route("/api/sven/macros/{name}/run", post(macros::run_macro))
+        .route("/api/sven/jobs", get(jobs::list_jobs))
+        .route(
+            "/api/sven/jobs/{id}",
+            get(jobs::get_job).delete(jobs::cancel_job),
+        )
+        .route("/api/sven/sequence", post(sequences::start_sequence))
+        .route(
+            "/api/sven/sessions",
+            get(sessions::get_session)
+                .post(sessions::start_session)
+                .delete(sessions::end_session),
+        )
+        .route("/api/sven/sequence/{id}", get(sequences::get_sequence))
+        .route(
+            "/api/sven/schedules",
+            get(schedules::list_schedules).post(schedules::create_schedule),
+        )
+        .route(
+            "/api/sven/schedules/{id}",
+            put(schedules::update_schedule).delete(schedules::delete_schedule),
+        )
+        .route(
+            "/api/sven/schedules/{id}/pause",
+            post(schedules::pause_schedule),
+        )
+        .route(
+            "/api/sven/schedules/{id}/resume",
+            post(schedules::resume_schedule),
+        )
+        .route(
+            "/api/sven/rules",
+            get(rules::list_rules).post(rules::create_rule),
+        )
+        .route(
+            "/api/sven/rules/{id}",
+            put(rules::update_rule).delete(rules::delete_rule),
+        )
+        .route("/api/sven/rules/{id}/trigger", post(rules::trigger_rule))
+        .route(
+            "/api/sven/webhooks",
+            get(webhooks::list_webhooks).post(webhooks::create_webhook),
+        )
+        .route("/api/sven/webhooks/{id}", delete(webhooks::delete_webhook))
+        .route(
+            "/api/sven/webhooks/{id}/deliveries",
+            get(webhooks::list_deliveries),
+        )
+        .route("/api/sven/faults", get(faults::list_faults))
+        .route("/api/sven/faults/clear", post(faults::clear_faults))
+        .route(
+            "/api/sven/reminders",
+            get(reminders::get_reminders).put(reminders::set_reminders),
+        )
+        .route(
+            "/api/sven/notifications",
+            get(notifications::get_notifications),
+        )
+        .route(
+            "/api/desks/{desk_id}/command",
+            body_limit::limited(post(desks::handle_desk_command), command_body_bytes),
+        )
+        .route("/api/groups", get(groups::list_groups))
+        .route(
+            "/api/groups/{name}/command",
+            body_limit::limited(post(groups::handle_group_command), command_body_bytes),
+        )
+        .route("/api/discovery", get(discovery::get_discovery))
+        .route("/api/sven/ws", get(live::get_ws))
+        .route("/api/sven/events", get(live::get_events))
+        .merge(cacheable_routes)
+        .merge(live_routes)
+        .merge(transport_routes)
+        .merge(docs_routes)
+        .merge(ui_routes)
+        .merge(alexa_routes)
+        .merge(graphql_routes)
+        .route(
+            "/api/openapi.json",
+            get(openapi::get_openapi).layer(CompressionLayer::new()),
+        )
+        .route("/healthz", get(health::get_healthz))
+        .route("/readyz", get(health::get_readyz))
+        .route_layer(middleware::from_fn(metrics::track_http))
+        .layer(middleware::from_fn(auth::require_api_key))
+        // Outside authentication, so refused requests are audited too
+        .layer(middleware::from_fn(audit::record))
+        .layer(Extension(app_state.clone()))
+        .layer(
+            // Everything logged while handling a request carries its method, path,
+            // client and the request id that also tags the MQTT messages it causes
+            TraceLayer::new_for_http().make_span_with(|request: &axum::extract::Request| {
+                let client = request
+                    .extensions()
+                    .get::<ConnectInfo<ClientAddr>>()
+                    .map(|ConnectInfo(remote)| remote.key());
+                tracing::info_span!(
+                    "http",
+                    method = %request.method(),
+                    path = %request.uri().path(),
+                    client = client.as_deref().unwrap_or("unknown"),
+                    request_id = request
+                        .headers()
+                        .get(request_id::HEADER)
+                        .and_then(|id| id.to_str().ok())
+                        .unwrap_or_default(),
+                )
+            }),
+        )
+        .layer(middleware::from_fn(request_id::propagate));
+
+    // Outside the routes, since `/api/v1/...` paths are routed without the version
+    Router::new()
+        .fallback_service(app)
+        .layer(middleware::from_fn(versioning::negotiate))
+        .layer(cors)
+}
+
+/// Serves `app` on the configured Unix socket or TCP address until a shutdown
+/// signal arrives and in-flight requests have drained.
+pub async fn serve(app_state: &Arc<AppState>, app: Router) {
+    let signal_app_state = app_state.clone();
+    tokio::spawn(async move { shutdown::on_signal(&signal_app_state.shutdown).await });
+    tokio::spawn(reload::on_hangup(app_state.clone()));
+
+    spawn_grpc(app_state).await;
+    let tls_config = tls::server_config(&app_state.config())
+        .unwrap_or_else(|e| panic!("Invalid TLS settings: {}", e));
+    let app = app.into_make_service_with_connect_info::<ClientAddr>();
+    if let Some(path) = app_state.config().bind_uds.as_deref() {
+        if tls_config.is_some() {
+            warn!(
+                "TLS is only used on TCP, serving {} unencrypted",
+                path.display()
+            );
+        }
+        let listener = listen::bind_uds(path).unwrap();
+        info!("Listening on Unix socket {}", path.display());
+        let server =
+            axum::serve(listener, app).with_graceful_shutdown(shutdown_requested(app_state));
+        drain_http(app_state, server).await;
+        if let Err(e) = std::fs::remove_file(path) {
+            error!("Failed to remove socket {}: {:?}", path.display(), e);
+        }
+    } else {
+        let listener = tokio::net::TcpListener::bind((
+            app_state.config().http_host.as_str(),
+            app_state.config().http_port,
+        ))
+        .await
+        .unwrap();
+        let scheme = if tls_config.is_some() {
+            "https"
+        } else {
+            "http"
+        };
+        info!(
+            "Listening on {}://{}:{}",
+            scheme,
+            app_state.config().http_host,
+            app_state.config().http_port
+        );
+        match tls_config {
+            Some(tls_config) => {
+                let listener = tls::TlsListener::new(listener, tls_config).unwrap();
+                let server = axum::serve(listener, app)
+                    .with_graceful_shutdown(shutdown_requested(app_state));
+                drain_http(app_state, server).await;
+            }
+            None => {
+                let server = axum::serve(listener, app)
+                    .with_graceful_shutdown(shutdown_requested(app_state));
+                drain_http(app_state, server).await;
+            }
+        }
+    }
+}
+
+/// Starts the gRPC service next to the HTTP server when `SVEN_GRPC_PORT` is set.
+#[cfg(feature = "grpc")]
+async fn spawn_grpc(app_state: &Arc<AppState>) {
+    let Some(port) = app_state.config().grpc_port else {
+        return;
+    };
+    let listener = tokio::net::TcpListener::bind((app_state.config().http_host.as_str(), port))
+        .await
+        .unwrap();
+    tokio::spawn(grpc::serve(app_state.clone(), listener));
+}
+
+#[cfg(not(feature = "grpc"))]
+async fn spawn_grpc(app_state: &Arc<AppState>) {
+    if app_state.config().grpc_port.is_some() {
+        warn!("SVEN_GRPC_PORT is set, but gRPC needs a build with the grpc feature");
+    }
+}
+
+/// Finishes a shutdown once the server has stopped: parks the desk if configured,
+/// flushes queued publishes, disconnects and waits for the MQTT loop to end.
+pub async fn finish_shutdown(app_state: &AppState, mqtt_loop: JoinHandle<()>) {
+    if let Some(park_mm) = app_state.config().park_on_shutdown_mm {
+        park_desk(app_state, park_mm).await;
+    }
+
+    // A clean disconnect doesn't set off the last will
+    app_state.announce_availability(false).await;
+    let queued = app_state.pending_publishes.load(Ordering::SeqCst);
+    if queued > 0 {
+        let drain_timeout = std::time::Duration::from_secs(app_state.config().shutdown_drain_secs);
+        let dropped = app_state.drain_publishes(drain_timeout).await;
+        info!(
+            "Flushed {} queued messages before shutdown, dropped {}",
+            queued.saturating_sub(dropped),
+            dropped
+        );
+    }
+
+    if let Some(state_writer) = &app_state.state_writer {
+        state_writer.flush().await;
+    }
+
+    if let Err(e) = app_state.transport.disconnect().await {
+        error!(
+            "Failed to disconnect the {} transport: {}",
+            app_state.transport.name(),
+            e
+        );
+    }
+    if tokio::time::timeout(std::time::Duration::from_secs(5), mqtt_loop)
+        .await
+        .is_err()
+    {
+        warn!("MQTT event loop did not stop in time");
+    }
+}
@@ -0,0 +1,142 @@
+use axum::{Json, http::StatusCode};
+use serde::Serialize;
+use std::str::FromStr;
+
+use crate::config::Config;
+use crate::movement;
+use crate::positions::PositionHeights;
+use crate::{DeskCommand, SvenCommand};
+
+/// Soft height limits kept inside the desk's mechanical range, e.g. to stay
+/// below a shelf. Written as `min-max` with either side optional: `700-1180`,
+/// `-1180` or `700-`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct HeightLimits {
+    pub min_height_mm: Option<u32>,
+    pub max_height_mm: Option<u32>,
+}
+
+impl HeightLimits {
+    pub fn clamp(&self, height_mm: u32) -> u32 {
+        let height_mm = self
+            .min_height_mm
+            .map_or(height_mm, |min| height_mm.max(min));
+        self.max_height_mm
+            .map_or(height_mm, |max| height_mm.min(max))
+    }
+
+    /// `self` with the sides it leaves open taken from `fallback`.
+    fn or(self, fallback: HeightLimits) -> HeightLimits {
+        HeightLimits {
+            min_height_mm: self.min_height_mm.or(fallback.min_height_mm),
+            max_height_mm: self.max_height_mm.or(fallback.max_height_mm),
+        }
+    }
+}
+
+impl FromStr for HeightLimits {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (min, max) = s
+            .split_once('-')
+            .ok_or_else(|| format!("expected min-max, got '{}'", s))?;
+        let side = |value: &str| -> Result<Option<u32>, String> {
+            let value = value.trim();
+            if value.is_empty() {
+                return Ok(None);
+            }
+            value
+                .parse()
+                .map(Some)
+                .map_err(|_| format!("invalid height '{}'", value))
+        };
+        let limits = HeightLimits {
+            min_height_mm: side(min)?,
+            max_height_mm: side(max)?,
+        };
+        if let (Some(min), Some(max)) = (limits.min_height_mm, limits.max_height_mm)
+            && min >= max
+        {
+            return Err(format!("minimum {} must be below maximum {}", min, max));
+        }
+        Ok(limits)
+    }
+}
+
+/// What happens to a move that would end outside the soft limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitPolicy {
+    /// Send the desk to the limit instead
+    Clamp,
+    /// Refuse the command
+    Reject,
+}
+
+impl FromStr for LimitPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "clamp" => Ok(LimitPolicy::Clamp),
+            "reject" => Ok(LimitPolicy::Reject),
+            other => Err(format!("unknown height limit policy '{}'", other)),
+        }
+    }
+}
+
+/// The soft limits for `desk`, or the primary desk when `None`: its entry in
+/// `SVEN_DESK_HEIGHT_LIMITS`, with open sides falling back to the global limits.
+pub fn limits_for(config: &Config, desk: Option<&str>) -> HeightLimits {
+    desk.or(config.desk_id.as_deref())
+        .and_then(|desk_id| config.desk_height_limits.get(desk_id))
+        .copied()
+        .unwrap_or_default()
+        .or(config.soft_height_limits)
+}
+
+/// Checks where `command` would leave the desk against the soft limits of `desk`,
+/// returning the command to send: unchanged, or under the clamp policy an
+/// `AbsoluteHeight` move to the limit it would have crossed.
+pub fn enforce(
+    config: &Config,
+    desk: Option<&str>,
+    command: DeskCommand,
+    current_height_mm: u32,
+    position_heights: &PositionHeights,
+) -> Result<DeskCommand, (StatusCode, Json<serde_json::Value>)> {
+    let limits = limits_for(config, desk);
+    let Some(target_mm) = movement::projected_target_mm(
+        &command,
+        current_height_mm,
+        config.travel_speed_mm_per_s,
+        position_heights,
+    ) else {
+        return Ok(command);
+    };
+
+    let limit_mm = match (limits.min_height_mm, limits.max_height_mm) {
+        (_, Some(max)) if target_mm > max => max,
+        (Some(min), _) if target_mm < min => min,
+        _ => return Ok(command),
+    };
+
+    match config.height_limit_policy {
+        LimitPolicy::Clamp => Ok(DeskCommand {
+            command: SvenCommand::AbsoluteHeight,
+            value: limit_mm,
+        }),
+        LimitPolicy::Reject => Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(serde_json::json!({
+                "error": format!(
+                    "{} would move the desk to {} mm, beyond the soft limit of {} mm",
+                    command.command, target_mm, limit_mm
+                ),
+                "code": "height_limit_exceeded",
+                "target_mm": target_mm,
+                "limits": limits,
+            })),
+        )),
+    }
+}
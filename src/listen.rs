@@ -0,0 +1,58 @@
+use axum::extract::connect_info::Connected;
+use axum::serve::IncomingStream;
+use std::net::SocketAddr;
+use std::path::Path;
+use tokio::net::{TcpListener, UnixListener};
+use tracing::info;
+
+/// Where a request came from, for both TCP and Unix socket listeners.
+#[derive(Debug, Clone, Copy)]
+pub enum ClientAddr {
+    Tcp(SocketAddr),
+    Unix,
+    /// The bridge itself, for moves its automations send
+    Internal,
+}
+
+impl ClientAddr {
+    /// Identifies the client for rate limiting when it sent no `Origin`.
+    pub fn key(&self) -> String {
+        match self {
+            ClientAddr::Tcp(addr) => addr.ip().to_string(),
+            ClientAddr::Unix => "unix".to_string(),
+            ClientAddr::Internal => "internal".to_string(),
+        }
+    }
+}
+
+impl Connected<IncomingStream<'_, TcpListener>> for ClientAddr {
+    fn connect_info(stream: IncomingStream<'_, TcpListener>) -> Self {
+        ClientAddr::Tcp(*stream.remote_addr())
+    }
+}
+
+impl Connected<IncomingStream<'_, UnixListener>> for ClientAddr {
+    fn connect_info(_stream: IncomingStream<'_, UnixListener>) -> Self {
+        ClientAddr::Unix
+    }
+}
+
+/// Binds a Unix socket at `path`, replacing a stale socket file left behind by a crash.
+/// Refuses to take over a socket another process is still serving on.
+pub fn bind_uds(path: &Path) -> std::io::Result<UnixListener> {
+    if path.exists() {
+        match std::os::unix::net::UnixStream::connect(path) {
+            Ok(_) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AddrInUse,
+                    format!("{} is in use by another process", path.display()),
+                ));
+            }
+            Err(_) => {
+                info!("Removing stale socket {}", path.display());
+                std::fs::remove_file(path)?;
+            }
+        }
+    }
+    UnixListener::bind(path)
+}
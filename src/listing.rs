@@ -0,0 +1,323 @@
+//! The query layer shared by the collection endpoints: `limit` and `offset`,
+//! `sort=field` (`-field` for descending), `from` and `until` on the collection's
+//! timestamp, and any other parameter filtering on the field of that name, e.g.
+//! `GET /api/sven/jobs?status=failed&sort=-created_at&limit=10`. Filters compare
+//! case-insensitively and reach into objects with dots, like `command.command`.
+//! The body keeps its usual shape; `X-Total-Count` carries how many items matched
+//! before paging. The history is paged by cursor instead, see `history`.
+
+use axum::{
+    Json,
+    http::{HeaderName, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use utoipa::IntoParams;
+
+use crate::validation::ErrorBody;
+
+pub const TOTAL_COUNT: HeaderName = HeaderName::from_static("x-total-count");
+pub const MAX_LIMIT: usize = 1000;
+
+type Rejection = (StatusCode, Json<serde_json::Value>);
+
+/// The parameters every collection endpoint takes. Handlers extract the raw
+/// parameters, since any other name is a filter; this describes them for the
+/// OpenAPI document.
+#[derive(Debug, Default, Deserialize, IntoParams)]
+pub struct ListQuery {
+    /// At most this many items, up to 1000
+    pub limit: Option<usize>,
+    /// Items to skip
+    pub offset: Option<usize>,
+    /// Field to sort by, prefixed with `-` for descending
+    pub sort: Option<String>,
+    /// Only items at or after this time
+    pub from: Option<DateTime<Utc>>,
+    /// Only items before this time
+    pub until: Option<DateTime<Utc>>,
+}
+
+/// What a collection can be filtered and sorted by.
+pub struct Collection {
+    /// Fields usable as filters and sort keys, dotted for nested ones
+    pub fields: &'static [&'static str],
+    /// The field `from` and `until` apply to, if the items have one
+    pub timestamp: Option<&'static str>,
+}
+
+/// One page of a collection and how many items matched overall.
+pub struct Page {
+    pub items: Vec<serde_json::Value>,
+    pub total: usize,
+}
+
+impl Page {
+    /// Answers with `body` made from the items and the total in `X-Total-Count`.
+    pub fn respond(
+        self,
+        body: impl FnOnce(Vec<serde_json::Value>) -> serde_json::Value,
+    ) -> Response {
+        let total = self.total.to_string();
+        ([(TOTAL_COUNT, total)], Json(body(self.items))).into_response()
+    }
+}
+
+fn invalid(message: String) -> Rejection {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(serde_json::json!(ErrorBody {
+            error: message,
+            code: Some("invalid_query".to_string()),
+        })),
+    )
+}
+
+fn field<'a>(item: &'a serde_json::Value, path: &str) -> &'a serde_json::Value {
+    path.split('.').fold(item, |value, key| &value[key])
+}
+
+fn matches(value: &serde_json::Value, wanted: &str) -> bool {
+    match value {
+        serde_json::Value::String(value) => value.eq_ignore_ascii_case(wanted),
+        serde_json::Value::Null => wanted.eq_ignore_ascii_case("null"),
+        serde_json::Value::Array(values) => values.iter().any(|value| matches(value, wanted)),
+        value => value.to_string().eq_ignore_ascii_case(wanted),
+    }
+}
+
+/// Numbers by value, everything else by its text; missing values sort last.
+fn compare(a: &serde_json::Value, b: &serde_json::Value) -> Ordering {
+    use serde_json::Value::{Null, Number, String};
+    match (a, b) {
+        (Null, Null) => Ordering::Equal,
+        (Null, _) => Ordering::Greater,
+        (_, Null) => Ordering::Less,
+        (Number(a), Number(b)) => a
+            .as_f64()
+            .partial_cmp(&b.as_f64())
+            .unwrap_or(Ordering::Equal),
+        (String(a), String(b)) => a.cmp(b),
+        (a, b) => a.to_string().cmp(&b.to_string()),
+    }
+}
+
+fn timestamp(item: &serde_json::Value, path: &str) -> Option<DateTime<Utc>> {
+    field(item, path)
+        .as_str()
+        .and_then(|at| DateTime::parse_from_rfc3339(at).ok())
+        .map(|at| at.with_timezone(&Utc))
+}
+
+fn parse_time(
+    params: &BTreeMap<String, String>,
+    name: &str,
+) -> Result<Option<DateTime<Utc>>, Rejection> {
+    params
+        .get(name)
+        .map(|at| {
+            DateTime::parse_from_rfc3339(at)
+                .map(|at| at.with_timezone(&Utc))
+                .map_err(|_| invalid(format!("{} must be an RFC 3339 time, got '{}'", name, at)))
+        })
+        .transpose()
+}
+
+fn parse_count(params: &BTreeMap<String, String>, name: &str) -> Result<Option<usize>, Rejection> {
+    params
+        .get(name)
+        .map(|count| {
+            count
+                .parse()
+                .map_err(|_| invalid(format!("{} must be a number, got '{}'", name, count)))
+        })
+        .transpose()
+}
+
+impl Collection {
+    fn check_field(&self, name: &str) -> Result<(), Rejection> {
+        if self.fields.contains(&name) {
+            Ok(())
+        } else {
+            Err(invalid(format!(
+                "Unknown field '{}', expected one of: {}",
+                name,
+                self.fields.join(", ")
+            )))
+        }
+    }
+
+    /// Lists `items` as `params` ask, with `body` made from the page, or answers
+    /// why the query is invalid.
+    pub fn respond<T: Serialize>(
+        &self,
+        items: impl IntoIterator<Item = T>,
+        params: &BTreeMap<String, String>,
+        body: impl FnOnce(Vec<serde_json::Value>) -> serde_json::Value,
+    ) -> Response {
+        match self.list(items, params) {
+            Ok(page) => page.respond(body),
+            Err(rejection) => rejection.into_response(),
+        }
+    }
+
+    /// Filters, sorts and pages `items` as `params` ask. Without a `sort` the
+    /// items keep the order given.
+    pub fn list<T: Serialize>(
+        &self,
+        items: impl IntoIterator<Item = T>,
+        params: &BTreeMap<String, String>,
+    ) -> Result<Page, Rejection> {
+        let query = ListQuery {
+            limit: parse_count(params, "limit")?,
+            offset: parse_count(params, "offset")?,
+            sort: params.get("sort").cloned(),
+            from: parse_time(params, "from")?,
+            until: parse_time(params, "until")?,
+        };
+        let range = query.from.is_some() || query.until.is_some();
+        let timestamp_field = match self.timestamp {
+            Some(field) => field,
+            None if range => {
+                return Err(invalid(
+                    "This collection has no timestamp to filter on".to_string(),
+                ));
+            }
+            None => "",
+        };
+        let filters: Vec<(&str, &str)> = params
+            .iter()
+            .filter(|(name, _)| {
+                !matches!(
+                    name.as_str(),
+                    "limit" | "offset" | "sort" | "from" | "until"
+                )
+            })
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        for (name, _) in &filters {
+            self.check_field(name)?;
+        }
+        let sort = query
+            .sort
+            .as_deref()
+            .map(|sort| match sort.strip_prefix('-') {
+                Some(name) => (name, true),
+                None => (sort, false),
+            });
+        if let Some((name, _)) = sort {
+            self.check_field(name)?;
+        }
+
+        let mut items: Vec<serde_json::Value> = items
+            .into_iter()
+            .map(|item| serde_json::json!(item))
+            .filter(|item| {
+                filters
+                    .iter()
+                    .all(|(name, wanted)| matches(field(item, name), wanted))
+            })
+            .filter(|item| {
+                !range
+                    || timestamp(item, timestamp_field).is_some_and(|at| {
+                        query.from.is_none_or(|from| at >= from)
+                            && query.until.is_none_or(|until| at < until)
+                    })
+            })
+            .collect();
+        if let Some((name, descending)) = sort {
+            // Stable, so equal items keep their usual order
+            items.sort_by(|a, b| {
+                let ordering = compare(field(a, name), field(b, name));
+                if descending {
+                    ordering.reverse()
+                } else {
+                    ordering
+                }
+            });
+        }
+        let total = items.len();
+        let limit = query.limit.unwrap_or(MAX_LIMIT).clamp(1, MAX_LIMIT);
+        let items = items
+            .into_iter()
+            .skip(query.offset.unwrap_or(0))
+            .take(limit)
+            .collect();
+        Ok(Page { items, total })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JOBS: Collection = Collection {
+        fields: &["status", "command.command", "created_at"],
+        timestamp: Some("created_at"),
+    };
+
+    fn params(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn jobs() -> Vec<serde_json::Value> {
+        vec![
+            serde_json::json!({"status": "completed", "command": {"command": "AbsoluteHeight"}, "created_at": "2026-01-01T10:00:00Z"}),
+            serde_json::json!({"status": "failed", "command": {"command": "Position"}, "created_at": "2026-01-02T10:00:00Z"}),
+            serde_json::json!({"status": "completed", "command": {"command": "Position"}, "created_at": "2026-01-03T10:00:00Z"}),
+        ]
+    }
+
+    #[test]
+    fn filters_sort_and_pages() {
+        let page = JOBS
+            .list(
+                jobs(),
+                &params(&[("status", "COMPLETED"), ("sort", "-created_at")]),
+            )
+            .unwrap();
+        assert_eq!(page.total, 2);
+        assert_eq!(page.items[0]["created_at"], "2026-01-03T10:00:00Z");
+
+        let page = JOBS
+            .list(
+                jobs(),
+                &params(&[
+                    ("command.command", "position"),
+                    ("limit", "1"),
+                    ("offset", "1"),
+                ]),
+            )
+            .unwrap();
+        assert_eq!(page.total, 2);
+        assert_eq!(page.items.len(), 1);
+        assert_eq!(page.items[0]["status"], "completed");
+
+        let page = JOBS
+            .list(
+                jobs(),
+                &params(&[
+                    ("from", "2026-01-02T00:00:00Z"),
+                    ("until", "2026-01-03T00:00:00Z"),
+                ]),
+            )
+            .unwrap();
+        assert_eq!(page.total, 1);
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        let Err((status, Json(body))) = JOBS.list(jobs(), &params(&[("colour", "red")])) else {
+            panic!("unknown field accepted");
+        };
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "invalid_query");
+        assert!(JOBS.list(jobs(), &params(&[("sort", "-height")])).is_err());
+    }
+}
@@ -0,0 +1,131 @@
+use axum::{
+    extract::{
+        ConnectInfo, Extension, Query,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    response::{
+        IntoResponse,
+        sse::{Event, KeepAlive, Sse},
+    },
+};
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio_stream::{Stream, StreamExt, wrappers::WatchStream};
+
+use crate::auth::ApiKeyId;
+use crate::listen::ClientAddr;
+use crate::{AppState, SvenState};
+
+#[derive(Debug, Default, Deserialize, utoipa::IntoParams)]
+pub struct WsQuery {
+    /// Also send `{"job": Job}` messages as movement jobs progress
+    #[serde(default)]
+    pub progress: bool,
+}
+
+/// Pushes every state update to the client as a JSON `SvenState` text message,
+/// starting with the current state.
+#[utoipa::path(
+    get,
+    path = "/api/sven/ws",
+    tag = "streams",
+    params(WsQuery),
+    responses((status = 101, description = "WebSocket sending a JSON SvenState on every change")),
+)]
+pub async fn get_ws(
+    ws: WebSocketUpgrade,
+    Query(query): Query<WsQuery>,
+    ConnectInfo(remote): ConnectInfo<ClientAddr>,
+    api_key: Option<Extension<ApiKeyId>>,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    let api_key = api_key.map(|Extension(key)| key.0);
+    ws.on_upgrade(move |socket| stream_state(socket, query, remote, api_key, app_state))
+}
+
+async fn stream_state(
+    mut socket: WebSocket,
+    query: WsQuery,
+    remote: ClientAddr,
+    api_key: Option<String>,
+    app_state: Arc<AppState>,
+) {
+    let connection = app_state.connections.register("websocket", remote, api_key);
+    let mut state_rx = app_state.state_tx.subscribe();
+    let mut progress_rx = app_state.progress_tx.subscribe();
+    progress_rx.mark_unchanged();
+    // A job update to send instead of the state
+    let mut pending = None;
+    loop {
+        let text = pending
+            .take()
+            .unwrap_or_else(|| serde_json::to_string(&*state_rx.borrow_and_update()).unwrap());
+        if socket.send(Message::Text(text.into())).await.is_err() {
+            return;
+        }
+        connection.touch();
+
+        // Incoming messages are ignored; reading them only notices the client leaving
+        loop {
+            tokio::select! {
+                changed = state_rx.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                    break;
+                }
+                Ok(()) = progress_rx.changed(), if query.progress => {
+                    if let Some(job) = &*progress_rx.borrow_and_update() {
+                        pending = Some(serde_json::json!({"job": job}).to_string());
+                        break;
+                    }
+                }
+                _ = app_state.shutdown.wait() => {
+                    let _ = socket.send(Message::Close(None)).await;
+                    return;
+                }
+                incoming = socket.recv() => match incoming {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                    Some(Ok(_)) => {}
+                },
+            }
+        }
+    }
+}
+
+/// Server-sent events variant of the WebSocket stream, for clients that can't do
+/// WebSockets. Each update is a `state` event carrying the JSON `SvenState`, each
+/// change of the desk status a `status` event and each step of a movement job a
+/// `progress` event carrying the `Job`; keep-alive comments stop proxies from
+/// closing an idle stream.
+#[utoipa::path(
+    get,
+    path = "/api/sven/events",
+    tag = "streams",
+    responses(
+        (status = 200, description = "Server-sent `state` events carrying the JSON SvenState, `status` events carrying the DeskStatus and `progress` events carrying the Job", body = SvenState, content_type = "text/event-stream"),
+    ),
+)]
+pub async fn get_events(
+    ConnectInfo(remote): ConnectInfo<ClientAddr>,
+    api_key: Option<Extension<ApiKeyId>>,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let connection =
+        app_state
+            .connections
+            .register("events", remote, api_key.map(|Extension(key)| key.0));
+    let states = WatchStream::new(app_state.state_tx.subscribe())
+        .map(|state| Event::default().event("state").json_data(state).unwrap());
+    let statuses = WatchStream::new(app_state.status_tx.subscribe())
+        .map(|status| Event::default().event("status").json_data(status).unwrap());
+    let progress = WatchStream::from_changes(app_state.progress_tx.subscribe()).filter_map(|job| {
+        job.map(|job| Event::default().event("progress").json_data(job).unwrap())
+    });
+    let stream = states.merge(statuses).merge(progress).map(move |event| {
+        connection.touch();
+        Ok(event)
+    });
+    Sse::new(app_state.shutdown.until(stream)).keep_alive(KeepAlive::default())
+}
@@ -0,0 +1,108 @@
+use axum::{
+    Json,
+    extract::Extension,
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+use crate::notifications::NotificationKind;
+use crate::validation::ErrorBody;
+use crate::{AppState, admin};
+
+/// Locks the desk after a period without commands, until it's explicitly unlocked.
+pub struct InactivityLock {
+    idle_limit: Duration,
+    last_activity: Instant,
+    locked: bool,
+    warned: bool,
+}
+
+impl InactivityLock {
+    pub fn new(idle_limit: Duration) -> Self {
+        InactivityLock {
+            idle_limit,
+            last_activity: Instant::now(),
+            locked: false,
+            warned: false,
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Resets the idle timer, e.g. after a command.
+    pub fn touch(&mut self) {
+        self.last_activity = Instant::now();
+        self.warned = false;
+    }
+
+    pub fn unlock(&mut self) {
+        self.locked = false;
+        self.touch();
+    }
+
+    /// How long before locking the impending lock is announced.
+    fn warning_lead(&self) -> Duration {
+        (self.idle_limit / 4).min(Duration::from_secs(60))
+    }
+}
+
+/// Engages the lock once the desk has been idle long enough, announcing it shortly before.
+pub async fn run_inactivity_lock(app_state: Arc<AppState>) {
+    loop {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let mut lock = app_state.inactivity_lock.lock().await;
+        if lock.locked {
+            continue;
+        }
+        let idle = lock.last_activity.elapsed();
+        if idle >= lock.idle_limit {
+            lock.locked = true;
+            info!("Desk idle for {} seconds, locking it", idle.as_secs());
+            app_state.notifier.notify(
+                NotificationKind::InactivityLock { locked: true },
+                "Desk locked after inactivity",
+            );
+        } else if !lock.warned && idle + lock.warning_lead() >= lock.idle_limit {
+            lock.warned = true;
+            let remaining = lock.idle_limit - idle;
+            app_state.notifier.notify(
+                NotificationKind::InactivityLock { locked: false },
+                format!(
+                    "Desk will lock in {} seconds without a command",
+                    remaining.as_secs()
+                ),
+            );
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/sven/unlock",
+    tag = "admin",
+    responses(
+        (status = 200, body = serde_json::Value),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+        (status = 403, description = "Admin routes are disabled", body = ErrorBody),
+    ),
+)]
+pub async fn handle_unlock(
+    headers: HeaderMap,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    if let Err(rejection) = admin::require_admin(&headers, &app_state.config()) {
+        return rejection;
+    }
+    app_state.inactivity_lock.lock().await.unlock();
+    info!("Desk unlocked");
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({"status": "Desk unlocked"})),
+    )
+}
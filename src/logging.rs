@@ -0,0 +1,54 @@
+use std::str::FromStr;
+use tracing::{Level, warn};
+use tracing_subscriber::EnvFilter;
+
+use crate::config::Config;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per line, including the fields of the enclosing spans
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("unknown log format '{}'", other)),
+        }
+    }
+}
+
+/// Runs `load` with a plain stderr logger, so what is logged while the configuration
+/// is read isn't lost before `init` can set up the configured one.
+pub fn bootstrap<T>(load: impl FnOnce() -> T) -> T {
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_max_level(Level::INFO)
+        .finish();
+    tracing::subscriber::with_default(subscriber, load)
+}
+
+/// Installs the global logger. `SVEN_LOG_LEVEL` takes `EnvFilter` directives, so
+/// `info,sven_api=debug` adds the MQTT packet log without the dependencies' debug output.
+pub fn init(config: &Config) {
+    let (filter, invalid) = match EnvFilter::try_new(&config.log_level) {
+        Ok(filter) => (filter, None),
+        Err(e) => (EnvFilter::new("info"), Some(e)),
+    };
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    match config.log_format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().with_current_span(true).init(),
+    }
+    if let Some(e) = invalid {
+        warn!(
+            "Invalid SVEN_LOG_LEVEL '{}': {}, logging at info",
+            config.log_level, e
+        );
+    }
+}
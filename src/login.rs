@@ -0,0 +1,492 @@
+//! Password login for people using the built-in page or the admin routes from a
+//! browser. `SVEN_ACCOUNTS` lists `name=role:hash` accounts, the hash coming from
+//! `sven-api hash-password`, and the role one of `viewer` (may only read),
+//! `controller` (may move the desk) or `admin` (may also use the admin routes).
+//!
+//! `POST /api/login` answers with a `sven_session` cookie signed with
+//! `SVEN_SESSION_SECRET`, which the API checks like an API key until it expires
+//! after `SVEN_SESSION_TTL_SECS`. Without a configured secret one is made up at
+//! startup, so sessions end with a restart. With accounts configured the API and
+//! the page at `/` need a login or a key.
+
+use axum::{
+    Json,
+    extract::{ConnectInfo, Extension, rejection::JsonRejection},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{DateTime, Utc};
+use ring::{hmac, pbkdf2, rand::SecureRandom};
+use serde::{Deserialize, Serialize};
+use std::num::NonZeroU32;
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::AppState;
+use crate::config::Config;
+use crate::listen::ClientAddr;
+use crate::rate_limit::RateLimited;
+use crate::validation::{self, ErrorBody};
+
+pub const COOKIE: &str = "sven_session";
+
+const ITERATIONS: u32 = 100_000;
+const HASH_SCHEME: &str = "pbkdf2-sha256";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Reads only
+    Viewer,
+    /// Reads and moves the desk
+    Controller,
+    /// Everything, admin routes included
+    Admin,
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "viewer" => Ok(Role::Viewer),
+            "controller" => Ok(Role::Controller),
+            "admin" => Ok(Role::Admin),
+            other => Err(format!("unknown role '{}'", other)),
+        }
+    }
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Role::Viewer => "viewer",
+            Role::Controller => "controller",
+            Role::Admin => "admin",
+        })
+    }
+}
+
+/// A PBKDF2 password hash, written `pbkdf2-sha256$<iterations>$<salt>$<hash>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PasswordHash {
+    iterations: NonZeroU32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl PasswordHash {
+    pub fn verify(&self, password: &str) -> bool {
+        pbkdf2::verify(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            self.iterations,
+            &self.salt,
+            password.as_bytes(),
+            &self.hash,
+        )
+        .is_ok()
+    }
+}
+
+impl FromStr for PasswordHash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let [scheme, iterations, salt, hash] = s.split('$').collect::<Vec<_>>()[..] else {
+            return Err("a password hash is written scheme$iterations$salt$hash".to_string());
+        };
+        if scheme != HASH_SCHEME {
+            return Err(format!("unknown password hash scheme '{}'", scheme));
+        }
+        let decode = |part: &str| {
+            URL_SAFE_NO_PAD
+                .decode(part)
+                .map_err(|e| format!("invalid password hash: {}", e))
+        };
+        Ok(PasswordHash {
+            iterations: iterations
+                .parse()
+                .map_err(|_| format!("invalid iteration count '{}'", iterations))?,
+            salt: decode(salt)?,
+            hash: decode(hash)?,
+        })
+    }
+}
+
+impl std::fmt::Display for PasswordHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}${}${}${}",
+            HASH_SCHEME,
+            self.iterations,
+            URL_SAFE_NO_PAD.encode(&self.salt),
+            URL_SAFE_NO_PAD.encode(&self.hash)
+        )
+    }
+}
+
+/// Hashes `password` with a fresh salt, for `SVEN_ACCOUNTS`.
+pub fn hash_password(password: &str) -> PasswordHash {
+    hash_with(password, ITERATIONS)
+}
+
+fn hash_with(password: &str, iterations: u32) -> PasswordHash {
+    let mut salt = vec![0; 16];
+    ring::rand::SystemRandom::new()
+        .fill(&mut salt)
+        .expect("Failed to generate a salt");
+    let iterations = NonZeroU32::new(iterations).unwrap();
+    let mut hash = vec![0; ring::digest::SHA256_OUTPUT_LEN];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        &salt,
+        password.as_bytes(),
+        &mut hash,
+    );
+    PasswordHash {
+        iterations,
+        salt,
+        hash,
+    }
+}
+
+/// An account from `SVEN_ACCOUNTS`, written `role:hash`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Account {
+    pub role: Role,
+    pub password: PasswordHash,
+}
+
+impl FromStr for Account {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((role, hash)) = s.split_once(':') else {
+            return Err("an account is written role:hash".to_string());
+        };
+        Ok(Account {
+            role: role.parse()?,
+            password: hash.parse()?,
+        })
+    }
+}
+
+/// A secret to sign session cookies with when `SVEN_SESSION_SECRET` isn't set.
+pub fn random_secret() -> String {
+    let mut secret = [0; 32];
+    ring::rand::SystemRandom::new()
+        .fill(&mut secret)
+        .expect("Failed to generate a session secret");
+    URL_SAFE_NO_PAD.encode(secret)
+}
+
+/// Who a session cookie was handed to, valid until `expires_at`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct Session {
+    pub name: String,
+    pub role: Role,
+    pub expires_at: DateTime<Utc>,
+}
+
+fn key(config: &Config) -> hmac::Key {
+    hmac::Key::new(hmac::HMAC_SHA256, config.session_secret.as_bytes())
+}
+
+/// The cookie value for `session`: its fields and their signature.
+fn sign(config: &Config, session: &Session) -> String {
+    let payload = URL_SAFE_NO_PAD.encode(format!(
+        "{}:{}:{}",
+        session.role,
+        session.expires_at.timestamp(),
+        session.name
+    ));
+    let signature = hmac::sign(&key(config), payload.as_bytes());
+    format!("{}.{}", payload, URL_SAFE_NO_PAD.encode(signature))
+}
+
+/// The session a cookie value stands for, if its signature holds, it hasn't
+/// expired and the account still exists with that role.
+fn verify(config: &Config, value: &str, now: DateTime<Utc>) -> Option<Session> {
+    let (payload, signature) = value.split_once('.')?;
+    hmac::verify(
+        &key(config),
+        payload.as_bytes(),
+        &URL_SAFE_NO_PAD.decode(signature).ok()?,
+    )
+    .ok()?;
+    let payload = String::from_utf8(URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+    let mut fields = payload.splitn(3, ':');
+    let role: Role = fields.next()?.parse().ok()?;
+    let expires_at = DateTime::from_timestamp(fields.next()?.parse().ok()?, 0)?;
+    let name = fields.next()?.to_string();
+    if expires_at <= now || config.accounts.get(&name).map(|account| account.role) != Some(role) {
+        return None;
+    }
+    Some(Session {
+        name,
+        role,
+        expires_at,
+    })
+}
+
+fn cookie_value(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| *name == COOKIE)
+        .map(|(_, value)| value)
+}
+
+/// The valid session `headers` carry, if any.
+pub fn session(headers: &HeaderMap, config: &Config) -> Option<Session> {
+    if config.accounts.is_empty() {
+        return None;
+    }
+    verify(config, cookie_value(headers)?, Utc::now())
+}
+
+fn set_cookie(config: &Config, value: &str, max_age_secs: u64) -> HeaderValue {
+    let secure = if config.tls_cert.is_some() || config.tls_self_signed {
+        "; Secure"
+    } else {
+        ""
+    };
+    HeaderValue::from_str(&format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Strict{}",
+        COOKIE, value, max_age_secs, secure
+    ))
+    .expect("Cookie values are base64")
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct Credentials {
+    pub name: String,
+    pub password: String,
+}
+
+fn invalid_credentials() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        Json(serde_json::json!(ErrorBody {
+            error: "Invalid name or password".to_string(),
+            code: Some("invalid_credentials".to_string()),
+        })),
+    )
+        .into_response()
+}
+
+fn too_many_failures(limited: RateLimited) -> Response {
+    let retry_after_secs = limited.retry_after.as_secs_f64().ceil() as u64;
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, retry_after_secs.to_string())],
+        Json(serde_json::json!({
+            "error": "Too many failed logins, try again later",
+            "code": "too_many_logins",
+            "retry_after_ms": limited.retry_after.as_millis() as u64,
+        })),
+    )
+        .into_response()
+}
+
+/// Logs in with an account from `SVEN_ACCOUNTS`, answering with a session cookie.
+/// Failed logins are limited to `SVEN_LOGIN_FAILURES_PER_MINUTE` for each client
+/// and each account name.
+#[utoipa::path(
+    post,
+    path = "/api/login",
+    tag = "login",
+    request_body = Credentials,
+    responses(
+        (status = 200, description = "Logged in, the session cookie is set", body = Session),
+        (status = 401, description = "Unknown name or wrong password", body = ErrorBody),
+        (status = 403, description = "No accounts are configured", body = ErrorBody),
+        (status = 429, description = "Too many failed logins from the client or for the account", body = ErrorBody),
+    ),
+)]
+pub async fn handle_login(
+    ConnectInfo(remote): ConnectInfo<ClientAddr>,
+    Extension(app_state): Extension<Arc<AppState>>,
+    request: Result<Json<Credentials>, JsonRejection>,
+) -> Response {
+    let config = app_state.config();
+    if config.accounts.is_empty() {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(serde_json::json!(ErrorBody {
+                error: "Login is disabled, set SVEN_ACCOUNTS".to_string(),
+                code: Some("login_disabled".to_string()),
+            })),
+        )
+            .into_response();
+    }
+    let credentials = match request {
+        Ok(Json(credentials)) => credentials,
+        Err(rejection) => return validation::body_rejection(rejection).into_response(),
+    };
+
+    // Every attempt is counted up front, so parallel guesses can't all get
+    // through before the first failure is, and successful ones are given back
+    let limited = [
+        format!("client:{}", remote.key()),
+        format!("account:{}", credentials.name),
+    ];
+    {
+        let mut limiter = app_state.login_limiter.lock().await;
+        for (checked, key) in limited.iter().enumerate() {
+            if let Err(rate_limited) = limiter.check(key) {
+                for key in &limited[..checked] {
+                    limiter.refund(key);
+                }
+                warn!("Throttled logins as '{}'", credentials.name);
+                return too_many_failures(rate_limited);
+            }
+        }
+    }
+
+    // Hashing happens either way, so it is no quicker to guess names than passwords.
+    // It is slow on purpose, so it runs off the async workers
+    let account = config.accounts.get(&credentials.name).cloned();
+    let password = credentials.password.clone();
+    let verified = tokio::task::spawn_blocking(move || match account {
+        Some(account) => account.password.verify(&password).then_some(account),
+        None => {
+            hash_password(&password);
+            None
+        }
+    })
+    .await
+    .ok()
+    .flatten();
+    let Some(account) = verified else {
+        warn!("Failed login as '{}'", credentials.name);
+        return invalid_credentials();
+    };
+    let mut limiter = app_state.login_limiter.lock().await;
+    for key in &limited {
+        limiter.refund(key);
+    }
+    drop(limiter);
+
+    let session = Session {
+        name: credentials.name,
+        role: account.role,
+        // Whole seconds, as the cookie carries it
+        expires_at: DateTime::from_timestamp(
+            Utc::now().timestamp() + config.session_ttl_secs as i64,
+            0,
+        )
+        .unwrap(),
+    };
+    info!("'{}' logged in as {}", session.name, session.role);
+    let cookie = set_cookie(&config, &sign(&config, &session), config.session_ttl_secs);
+    ([(header::SET_COOKIE, cookie)], Json(session)).into_response()
+}
+
+/// The session the request carries.
+#[utoipa::path(
+    get,
+    path = "/api/login",
+    tag = "login",
+    responses(
+        (status = 200, description = "The current session", body = Session),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+    ),
+)]
+pub async fn get_session(
+    headers: HeaderMap,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> Response {
+    match session(&headers, &app_state.config()) {
+        Some(session) => Json(session).into_response(),
+        None => (
+            StatusCode::UNAUTHORIZED,
+            Json(serde_json::json!(ErrorBody {
+                error: "Not logged in".to_string(),
+                code: Some("not_logged_in".to_string()),
+            })),
+        )
+            .into_response(),
+    }
+}
+
+/// Clears the session cookie.
+#[utoipa::path(
+    post,
+    path = "/api/logout",
+    tag = "login",
+    responses((status = 200, description = "The session cookie is cleared", body = serde_json::Value)),
+)]
+pub async fn handle_logout(Extension(app_state): Extension<Arc<AppState>>) -> Response {
+    (
+        [(header::SET_COOKIE, set_cookie(&app_state.config(), "", 0))],
+        Json(serde_json::json!({"status": "Logged out"})),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        let mut config = Config::load_from(None).unwrap();
+        config.accounts.insert(
+            "ana".to_string(),
+            Account {
+                role: Role::Viewer,
+                password: hash_with("hunter2", 1),
+            },
+        );
+        config
+    }
+
+    #[test]
+    fn password_hashes_round_trip_and_verify() {
+        let hash = hash_with("hunter2", 10);
+        let parsed: PasswordHash = hash.to_string().parse().unwrap();
+        assert_eq!(parsed, hash);
+        assert!(parsed.verify("hunter2"));
+        assert!(!parsed.verify("hunter3"));
+        assert!("md5$1$a$b".parse::<PasswordHash>().is_err());
+        assert!("viewer:nohash".parse::<Account>().is_err());
+    }
+
+    #[test]
+    fn sessions_need_a_valid_signature_and_a_matching_account() {
+        let config = config();
+        let now = Utc::now();
+        let session = Session {
+            name: "ana".to_string(),
+            role: Role::Viewer,
+            expires_at: DateTime::from_timestamp(now.timestamp() + 3600, 0).unwrap(),
+        };
+        let value = sign(&config, &session);
+        assert_eq!(verify(&config, &value, now), Some(session.clone()));
+        assert_eq!(
+            verify(&config, &value, now + chrono::Duration::hours(2)),
+            None
+        );
+
+        let promoted = sign(
+            &config,
+            &Session {
+                role: Role::Admin,
+                ..session.clone()
+            },
+        );
+        assert_eq!(verify(&config, &promoted, now), None);
+
+        let mut other = config.clone();
+        other.session_secret = "another secret".to_string();
+        assert_eq!(verify(&other, &value, now), None);
+    }
+}
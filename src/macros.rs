@@ -0,0 +1,150 @@
+use axum::{
+    Json,
+    extract::{Extension, Path, Query},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tracing::{error, info};
+use utoipa::ToSchema;
+
+use crate::listing::{Collection, ListQuery};
+use crate::persistence::Artifact;
+use crate::validation::ErrorBody;
+use crate::{AppState, DeskCommand, movement};
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Macro {
+    pub name: String,
+    pub steps: Vec<DeskCommand>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Macros(pub BTreeMap<String, Macro>);
+
+impl Artifact for Macros {
+    const KEY: &'static str = "macros";
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct FromHistoryRequest {
+    pub count: usize,
+    pub name: String,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/sven/macros/from-history",
+    tag = "macros",
+    request_body = FromHistoryRequest,
+    responses(
+        (status = 201, body = Macro),
+        (status = 422, description = "Invalid request", body = ErrorBody),
+    ),
+)]
+pub async fn create_from_history(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Json(request): Json<FromHistoryRequest>,
+) -> impl IntoResponse {
+    if request.name.trim().is_empty() || request.count == 0 {
+        return (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(serde_json::json!({"error": "A macro needs a name and a count of at least 1"})),
+        );
+    }
+
+    let steps: Vec<DeskCommand> = app_state
+        .history
+        .lock()
+        .await
+        .last_matching(request.count, |entry| entry.command.command.is_movement())
+        .into_iter()
+        .map(|entry| entry.command)
+        .collect();
+    if steps.is_empty() {
+        return (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(serde_json::json!({"error": "No movement commands in history"})),
+        );
+    }
+
+    let created = Macro {
+        name: request.name.clone(),
+        steps,
+        created_at: Utc::now(),
+    };
+    let mut macros = app_state.macros.lock().await;
+    macros.0.insert(request.name, created.clone());
+    if let Some(store) = &app_state.store
+        && let Err(e) = store.put(&*macros)
+    {
+        error!("Failed to persist macros: {}", e);
+    }
+    info!(
+        "Saved macro {} with {} steps",
+        created.name,
+        created.steps.len()
+    );
+
+    (StatusCode::CREATED, Json(serde_json::json!(created)))
+}
+
+const MACROS: Collection = Collection {
+    fields: &["name", "created_at"],
+    timestamp: Some("created_at"),
+};
+
+#[utoipa::path(
+    get,
+    path = "/api/sven/macros",
+    tag = "macros",
+    params(ListQuery),
+    responses(
+        (status = 200, body = Vec<Macro>),
+        (status = 400, description = "Invalid query", body = ErrorBody),
+    ),
+)]
+pub async fn list_macros(
+    Query(params): Query<BTreeMap<String, String>>,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    let macros = app_state.macros.lock().await;
+    MACROS.respond(macros.0.values(), &params, serde_json::Value::Array)
+}
+
+/// Runs the macro's steps in the background, waiting for each move to finish
+/// before sending the next.
+#[utoipa::path(
+    post,
+    path = "/api/sven/macros/{name}/run",
+    tag = "macros",
+    params(("name" = String, Path)),
+    responses((status = 202, body = serde_json::Value), (status = 404, body = ErrorBody)),
+)]
+pub async fn run_macro(
+    Path(name): Path<String>,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    let Some(found) = app_state.macros.lock().await.0.get(&name).cloned() else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({"error": format!("Unknown macro '{}'", name)})),
+        );
+    };
+
+    let steps = found.steps.len();
+    tokio::spawn(async move {
+        let label = format!("Macro {}", found.name);
+        movement::run_steps(&app_state, &label, &found.steps, None, None, None).await;
+        info!("Macro {} finished", found.name);
+    });
+
+    (
+        StatusCode::ACCEPTED,
+        Json(serde_json::json!({"status": "Macro started", "name": name, "steps": steps})),
+    )
+}
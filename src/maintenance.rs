@@ -0,0 +1,73 @@
+use axum::{
+    Json,
+    extract::Extension,
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::{error, info};
+use utoipa::ToSchema;
+
+use crate::transport::MessageClass;
+use crate::validation::ErrorBody;
+use crate::{AppState, admin};
+
+/// A firmware maintenance command such as `reboot`, forwarded on the maintenance topic.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct MaintenanceCommand {
+    pub command: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub args: Option<serde_json::Value>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/sven/maint",
+    tag = "admin",
+    request_body = MaintenanceCommand,
+    responses(
+        (status = 202, body = serde_json::Value),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+        (status = 403, description = "Admin routes are disabled", body = ErrorBody),
+    ),
+)]
+pub async fn handle_maintenance(
+    headers: HeaderMap,
+    Extension(app_state): Extension<Arc<AppState>>,
+    Json(maintenance): Json<MaintenanceCommand>,
+) -> impl IntoResponse {
+    if let Err(rejection) = admin::require_admin(&headers, &app_state.config()) {
+        return rejection;
+    }
+
+    let allowed = &app_state.config().maint_commands;
+    if !allowed.contains(&maintenance.command) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({
+                "error": format!("Maintenance command '{}' is not allowed", maintenance.command),
+                "allowed": allowed,
+            })),
+        );
+    }
+
+    info!("Forwarding maintenance command {}", maintenance.command);
+    let payload = serde_json::to_string(&maintenance).unwrap();
+    if let Err(e) = app_state
+        .publish(
+            MessageClass::Command,
+            &app_state.config().maint_topic,
+            payload,
+        )
+        .await
+    {
+        error!("Failed to publish maintenance command: {}", e);
+        return e.response("Failed to publish maintenance command", None);
+    }
+
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({"status": "Maintenance command sent successfully"})),
+    )
+}
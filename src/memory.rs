@@ -0,0 +1,153 @@
+//! Splits `SVEN_MEMORY_BUDGET_KB` across the in-memory buffers.
+//!
+//! The budget is divided by fixed shares: 50% for the command history, 30% for
+//! the MQTT debug trace and 20% for the event log write queue. Each share is turned
+//! into an entry count using a rough per-entry size estimate, and a buffer never
+//! grows past the smaller of that count and its own configured capacity. The
+//! buffers are rings (or a bounded queue for the event log), so once full the
+//! oldest entries are evicted (or, for the queue, new records dropped).
+
+use axum::{
+    Json,
+    extract::Extension,
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use serde::Serialize;
+use std::sync::Arc;
+use tracing::info;
+
+use crate::config::Config;
+use crate::history::HistoryEntry;
+use crate::mqtt_trace::MqttTraceEvent;
+use crate::validation::ErrorBody;
+use crate::{AppState, admin};
+
+const HISTORY_SHARE_PERCENT: usize = 50;
+const TRACE_SHARE_PERCENT: usize = 30;
+const EVENT_LOG_SHARE_PERCENT: usize = 20;
+
+/// Without a budget the event log queue keeps its historical size.
+const DEFAULT_EVENT_LOG_QUEUE: usize = 256;
+
+/// Estimated bytes per entry, including typical heap data (request id, topic, payload).
+const HISTORY_ENTRY_BYTES: usize = std::mem::size_of::<HistoryEntry>() + 36;
+const TRACE_EVENT_BYTES: usize = std::mem::size_of::<MqttTraceEvent>() + 64;
+const EVENT_RECORD_BYTES: usize = 256;
+
+/// Entry limits for each buffer after applying the budget.
+#[derive(Debug, Clone, Copy)]
+pub struct BufferLimits {
+    pub history_entries: usize,
+    pub trace_events: usize,
+    pub event_log_queue: usize,
+}
+
+impl BufferLimits {
+    pub fn from_config(config: &Config) -> Self {
+        let configured = BufferLimits {
+            history_entries: config.history_capacity,
+            trace_events: config.debug_events_capacity,
+            event_log_queue: DEFAULT_EVENT_LOG_QUEUE,
+        };
+        let Some(budget) = config.memory_budget_bytes else {
+            return configured;
+        };
+        let cap = |configured: usize, percent: usize, entry_bytes: usize| {
+            configured.min((budget * percent / 100 / entry_bytes).max(1))
+        };
+        let limits = BufferLimits {
+            history_entries: cap(
+                configured.history_entries,
+                HISTORY_SHARE_PERCENT,
+                HISTORY_ENTRY_BYTES,
+            ),
+            trace_events: cap(
+                configured.trace_events,
+                TRACE_SHARE_PERCENT,
+                TRACE_EVENT_BYTES,
+            ),
+            event_log_queue: cap(
+                configured.event_log_queue,
+                EVENT_LOG_SHARE_PERCENT,
+                EVENT_RECORD_BYTES,
+            ),
+        };
+        info!(
+            "Memory budget {} KiB: history {} entries, MQTT trace {} events, event log queue {}",
+            budget / 1024,
+            limits.history_entries,
+            limits.trace_events,
+            limits.event_log_queue
+        );
+        limits
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct BufferUsage {
+    name: &'static str,
+    entries: usize,
+    capacity: usize,
+    estimated_bytes: usize,
+    share_percent: usize,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/sven/debug/memory",
+    tag = "admin",
+    responses(
+        (status = 200, body = serde_json::Value),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+        (status = 403, description = "Admin routes are disabled", body = ErrorBody),
+    ),
+)]
+pub async fn get_memory(
+    headers: HeaderMap,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    if let Err(rejection) = admin::require_admin(&headers, &app_state.config()) {
+        return rejection;
+    }
+
+    let history = app_state.history.lock().await;
+    let trace = app_state.mqtt_trace.lock().await;
+    let (queued, queue_capacity) = app_state
+        .event_log
+        .as_ref()
+        .map_or((0, 0), |log| (log.queued(), log.queue_capacity()));
+    let buffers = [
+        BufferUsage {
+            name: "history",
+            entries: history.len(),
+            capacity: history.capacity(),
+            estimated_bytes: history.len() * HISTORY_ENTRY_BYTES,
+            share_percent: HISTORY_SHARE_PERCENT,
+        },
+        BufferUsage {
+            name: "mqtt_trace",
+            entries: trace.len(),
+            capacity: trace.capacity(),
+            estimated_bytes: trace.len() * TRACE_EVENT_BYTES,
+            share_percent: TRACE_SHARE_PERCENT,
+        },
+        BufferUsage {
+            name: "event_log_queue",
+            entries: queued,
+            capacity: queue_capacity,
+            estimated_bytes: queued * EVENT_RECORD_BYTES,
+            share_percent: EVENT_LOG_SHARE_PERCENT,
+        },
+    ];
+    let total: usize = buffers.iter().map(|buffer| buffer.estimated_bytes).sum();
+
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "budget_bytes": app_state.config().memory_budget_bytes,
+            "estimated_bytes": total,
+            "buffers": buffers,
+        })),
+    )
+}
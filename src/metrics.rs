@@ -0,0 +1,313 @@
+use axum::{
+    Json,
+    extract::{Extension, MatchedPath, Request},
+    http::header::CONTENT_TYPE,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+use crate::transport::TransportErrorKind;
+use crate::{AppState, SvenCommand};
+
+/// Upper bounds of the HTTP latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Counters collected while the bridge runs, served as JSON on `/api/sven/metrics.json`
+/// and in Prometheus text format on `/metrics`.
+#[derive(Default)]
+pub struct Metrics {
+    commands: Mutex<BTreeMap<String, u64>>,
+    /// By `TransportErrorKind`, in the order of `TransportErrorKind::ALL`
+    publish_failures: [AtomicU64; TransportErrorKind::ALL.len()],
+    confirm_latency: Mutex<LatencyStats>,
+    /// Keyed by method and route pattern
+    http_latency: Mutex<BTreeMap<(String, String), Histogram>>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct LatencyStats {
+    pub count: u64,
+    pub sum_ms: u64,
+    pub max_ms: u64,
+}
+
+impl LatencyStats {
+    fn record(&mut self, latency: Duration) {
+        let ms = latency.as_millis() as u64;
+        self.count += 1;
+        self.sum_ms += ms;
+        self.max_ms = self.max_ms.max(ms);
+    }
+}
+
+/// Counts per `LATENCY_BUCKETS` bucket; each bucket only counts its own range,
+/// the cumulative counts Prometheus expects are summed up when rendering.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Histogram {
+    pub buckets: [u64; LATENCY_BUCKETS.len()],
+    pub count: u64,
+    pub sum_seconds: f64,
+}
+
+impl Histogram {
+    fn record(&mut self, latency: Duration) {
+        let seconds = latency.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|le| seconds <= *le) {
+            self.buckets[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum_seconds += seconds;
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RouteLatency {
+    pub method: String,
+    pub route: String,
+    #[serde(flatten)]
+    pub histogram: Histogram,
+}
+
+/// Point in time view of every metric.
+#[derive(Debug, Serialize)]
+pub struct MetricsSnapshot {
+    /// Commands sent, by command name
+    pub commands_total: BTreeMap<String, u64>,
+    pub publish_failures_total: u64,
+    /// Publish failures by error code
+    pub publish_failures: BTreeMap<&'static str, u64>,
+    pub height_mm: u32,
+    pub mqtt_connected: bool,
+    /// Time since the primary desk last reported its state, `None` before the first report
+    pub state_age_ms: Option<u64>,
+    /// Time from sending a confirmed command until the desk finished moving
+    pub confirm_latency_ms: LatencyStats,
+    pub http_request_duration: Vec<RouteLatency>,
+}
+
+impl Metrics {
+    pub async fn record_command(&self, command: SvenCommand) {
+        *self
+            .commands
+            .lock()
+            .await
+            .entry(format!("{:?}", command))
+            .or_default() += 1;
+    }
+
+    pub fn record_publish_failure(&self, kind: TransportErrorKind) {
+        self.publish_failures[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub async fn record_confirm_latency(&self, latency: Duration) {
+        self.confirm_latency.lock().await.record(latency);
+    }
+
+    async fn record_http(&self, method: String, route: String, latency: Duration) {
+        self.http_latency
+            .lock()
+            .await
+            .entry((method, route))
+            .or_default()
+            .record(latency);
+    }
+
+    pub async fn snapshot(&self, app_state: &AppState) -> MetricsSnapshot {
+        let state_age = app_state.last_state_at.lock().await.elapsed();
+        let publish_failures: BTreeMap<_, _> = TransportErrorKind::ALL
+            .iter()
+            .zip(&self.publish_failures)
+            .map(|(kind, count)| (kind.code(), count.load(Ordering::Relaxed)))
+            .collect();
+        MetricsSnapshot {
+            commands_total: self.commands.lock().await.clone(),
+            publish_failures_total: publish_failures.values().sum(),
+            publish_failures,
+            height_mm: app_state.sven_state.read().await.height_mm,
+            mqtt_connected: app_state.reconnect_monitor.lock().await.is_connected(),
+            state_age_ms: app_state
+                .state_received
+                .load(Ordering::SeqCst)
+                .then_some(state_age.as_millis() as u64),
+            confirm_latency_ms: *self.confirm_latency.lock().await,
+            http_request_duration: self
+                .http_latency
+                .lock()
+                .await
+                .iter()
+                .map(|((method, route), histogram)| RouteLatency {
+                    method: method.clone(),
+                    route: route.clone(),
+                    histogram: histogram.clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Times every routed request. Layered with `route_layer` so the route pattern is
+/// known, which keeps path parameters like desk ids out of the labels.
+pub async fn track_http(request: Request, next: Next) -> Response {
+    let (Some(app_state), Some(route)) = (
+        request.extensions().get::<Arc<AppState>>().cloned(),
+        request.extensions().get::<MatchedPath>().cloned(),
+    ) else {
+        return next.run(request).await;
+    };
+    let method = request.method().to_string();
+    let started = Instant::now();
+    let response = next.run(request).await;
+    app_state
+        .metrics
+        .record_http(method, route.as_str().to_string(), started.elapsed())
+        .await;
+    response
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/sven/metrics.json",
+    tag = "metrics",
+    responses((status = 200, body = serde_json::Value)),
+)]
+pub async fn get_metrics_json(Extension(app_state): Extension<Arc<AppState>>) -> impl IntoResponse {
+    Json(app_state.metrics.snapshot(&app_state).await)
+}
+
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "metrics",
+    responses(
+        (status = 200, description = "Prometheus text format", body = String, content_type = "text/plain"),
+    ),
+)]
+pub async fn get_metrics(Extension(app_state): Extension<Arc<AppState>>) -> impl IntoResponse {
+    let snapshot = app_state.metrics.snapshot(&app_state).await;
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        render_prometheus(&snapshot),
+    )
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn render_prometheus(snapshot: &MetricsSnapshot) -> String {
+    let mut out = String::new();
+
+    header(
+        &mut out,
+        "sven_commands_total",
+        "counter",
+        "Commands sent to the desk, by command.",
+    );
+    for (command, count) in &snapshot.commands_total {
+        let _ = writeln!(
+            out,
+            "sven_commands_total{{command=\"{}\"}} {}",
+            command, count
+        );
+    }
+
+    header(
+        &mut out,
+        "sven_mqtt_publish_failures_total",
+        "counter",
+        "Messages that could not be published, by error code.",
+    );
+    for (code, count) in &snapshot.publish_failures {
+        let _ = writeln!(
+            out,
+            "sven_mqtt_publish_failures_total{{code=\"{}\"}} {}",
+            code, count
+        );
+    }
+
+    header(
+        &mut out,
+        "sven_height_mm",
+        "gauge",
+        "Last reported desk height in millimetres.",
+    );
+    let _ = writeln!(out, "sven_height_mm {}", snapshot.height_mm);
+
+    header(
+        &mut out,
+        "sven_mqtt_connected",
+        "gauge",
+        "Whether the bridge is connected to the MQTT broker.",
+    );
+    let _ = writeln!(out, "sven_mqtt_connected {}", snapshot.mqtt_connected as u8);
+
+    if let Some(age_ms) = snapshot.state_age_ms {
+        header(
+            &mut out,
+            "sven_state_age_seconds",
+            "gauge",
+            "Time since the desk last reported its state.",
+        );
+        let _ = writeln!(out, "sven_state_age_seconds {}", age_ms as f64 / 1000.0);
+    }
+
+    header(
+        &mut out,
+        "sven_confirm_latency_seconds",
+        "summary",
+        "Time from sending a confirmed command until the desk finished moving.",
+    );
+    let latency = &snapshot.confirm_latency_ms;
+    let _ = writeln!(
+        out,
+        "sven_confirm_latency_seconds_sum {}",
+        latency.sum_ms as f64 / 1000.0
+    );
+    let _ = writeln!(out, "sven_confirm_latency_seconds_count {}", latency.count);
+
+    header(
+        &mut out,
+        "sven_http_request_duration_seconds",
+        "histogram",
+        "HTTP request latency, by method and route.",
+    );
+    for route in &snapshot.http_request_duration {
+        let labels = format!("method=\"{}\",route=\"{}\"", route.method, route.route);
+        let mut cumulative = 0;
+        for (le, count) in LATENCY_BUCKETS.iter().zip(route.histogram.buckets) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "sven_http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                labels, le, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "sven_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+            labels, route.histogram.count
+        );
+        let _ = writeln!(
+            out,
+            "sven_http_request_duration_seconds_sum{{{}}} {}",
+            labels, route.histogram.sum_seconds
+        );
+        let _ = writeln!(
+            out,
+            "sven_http_request_duration_seconds_count{{{}}} {}",
+            labels, route.histogram.count
+        );
+    }
+
+    out
+}
@@ -0,0 +1,269 @@
+//! Motor settings of the desk controller: travel speed, acceleration profile and
+//! soft-start ramp. They are set with the `SetSpeed`, `SetAcceleration` and
+//! `SetSoftStart` commands or all at once through `PUT /api/sven/settings`, and go
+//! out on `SVEN_MOTOR_TOPIC` rather than the command topic. The controller reports
+//! the settings it runs with in its state, under `motor`.
+
+use axum::{
+    Json,
+    extract::Extension,
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::{error, info};
+use utoipa::ToSchema;
+
+use crate::validation::ErrorBody;
+use crate::{AppState, DeskCommand, SvenCommand, request_id, users, validation};
+
+/// Slowest speed the controller accepts, as a share of its full speed.
+pub const MIN_SPEED_PERCENT: u32 = 10;
+/// Longest soft-start ramp.
+pub const MAX_SOFT_START_MS: u32 = 3000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AccelerationProfile {
+    Gentle,
+    Normal,
+    Brisk,
+}
+
+impl AccelerationProfile {
+    pub const ALL: [AccelerationProfile; 3] = [
+        AccelerationProfile::Gentle,
+        AccelerationProfile::Normal,
+        AccelerationProfile::Brisk,
+    ];
+
+    /// The profile a `SetAcceleration` value stands for.
+    pub fn from_index(index: u32) -> Option<AccelerationProfile> {
+        AccelerationProfile::ALL.get(index as usize).copied()
+    }
+
+    fn index(self) -> u32 {
+        AccelerationProfile::ALL
+            .iter()
+            .position(|profile| *profile == self)
+            .unwrap() as u32
+    }
+}
+
+/// The motor settings, each left out when it isn't known or isn't being changed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct MotorSettings {
+    /// Travel speed as a share of full speed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub speed_percent: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub acceleration: Option<AccelerationProfile>,
+    /// How long the motor ramps up to speed, 0 to start at full speed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub soft_start_ms: Option<u32>,
+}
+
+impl MotorSettings {
+    /// The setting a motor command changes, `None` for other commands.
+    pub fn from_command(command: &DeskCommand) -> Option<MotorSettings> {
+        let mut settings = MotorSettings::default();
+        match command.command {
+            SvenCommand::SetSpeed => settings.speed_percent = Some(command.value),
+            SvenCommand::SetAcceleration => {
+                settings.acceleration = AccelerationProfile::from_index(command.value);
+            }
+            SvenCommand::SetSoftStart => settings.soft_start_ms = Some(command.value),
+            _ => return None,
+        }
+        Some(settings)
+    }
+
+    /// One command per setting that is present.
+    pub fn commands(&self) -> Vec<DeskCommand> {
+        let command = |command, value| DeskCommand { command, value };
+        [
+            self.speed_percent
+                .map(|speed| command(SvenCommand::SetSpeed, speed)),
+            self.acceleration
+                .map(|profile| command(SvenCommand::SetAcceleration, profile.index())),
+            self.soft_start_ms
+                .map(|ramp_ms| command(SvenCommand::SetSoftStart, ramp_ms)),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    /// These settings with the ones `newer` has taking their place.
+    pub fn merge(self, newer: MotorSettings) -> MotorSettings {
+        MotorSettings {
+            speed_percent: newer.speed_percent.or(self.speed_percent),
+            acceleration: newer.acceleration.or(self.acceleration),
+            soft_start_ms: newer.soft_start_ms.or(self.soft_start_ms),
+        }
+    }
+}
+
+/// The settings the controller last reported, and those sent since startup, which
+/// differ until the controller has applied them.
+#[utoipa::path(
+    get,
+    path = "/api/sven/settings",
+    tag = "desk",
+    responses((status = 200, body = serde_json::Value)),
+)]
+pub async fn get_settings(Extension(app_state): Extension<Arc<AppState>>) -> impl IntoResponse {
+    let reported = app_state.sven_state.read().await.motor;
+    let requested = *app_state.motor_requested.lock().await;
+    Json(serde_json::json!({
+        "reported": reported.unwrap_or_default(),
+        "requested": requested,
+    }))
+}
+
+/// Changes the motor settings given in the body, leaving the others as they are.
+#[utoipa::path(
+    put,
+    path = "/api/sven/settings",
+    tag = "desk",
+    request_body = MotorSettings,
+    responses(
+        (status = 200, description = "Settings sent", body = serde_json::Value),
+        (status = 422, description = "No or invalid settings", body = ErrorBody),
+        (status = 502, description = "Publishing timed out or was refused", body = ErrorBody),
+        (status = 503, description = "Not connected to the broker, or it couldn't be reached to publish", body = ErrorBody),
+    ),
+)]
+pub async fn put_settings(
+    headers: HeaderMap,
+    Extension(app_state): Extension<Arc<AppState>>,
+    Json(settings): Json<MotorSettings>,
+) -> impl IntoResponse {
+    let commands = settings.commands();
+    if commands.is_empty() {
+        return (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(serde_json::json!({
+                "error": "Set at least one of speed_percent, acceleration and soft_start_ms",
+            })),
+        );
+    }
+    for command in &commands {
+        if let Err(rejection) = validation::validate_command(command, &app_state.config()) {
+            return rejection;
+        }
+    }
+    let user = match users::identify(
+        &headers,
+        &app_state.config(),
+        &*app_state.users.lock().await,
+    ) {
+        Ok(user) => user,
+        Err(rejection) => return rejection,
+    };
+    if !app_state.reconnect_monitor.lock().await.is_connected() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(serde_json::json!({
+                "error": "Not connected to the MQTT broker, the settings would not reach the desk",
+                "code": "broker_unavailable",
+            })),
+        );
+    }
+
+    let request_id = request_id::from_headers(&headers);
+    for (n, command) in commands.iter().enumerate() {
+        let step_id = format!("{}.{}", request_id, n + 1);
+        if let Err(e) = app_state
+            .send_command(None, command, &step_id, user.as_deref())
+            .await
+        {
+            error!("Failed to publish {}: {}", command.command, e);
+            return e.response("Failed to publish the settings", Some(&request_id));
+        }
+        record_requested(&app_state, command).await;
+    }
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "status": "Settings sent",
+            "request_id": request_id,
+            "settings": settings,
+        })),
+    )
+}
+
+/// Sends a single motor setting command on behalf of the command endpoint.
+pub async fn send(
+    app_state: &AppState,
+    desk: Option<&str>,
+    command: &DeskCommand,
+    request_id: &str,
+    user: Option<&str>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if desk.is_some() {
+        return (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(serde_json::json!({
+                "error": "Motor settings can only be changed on the primary desk",
+                "code": "unsupported_for_desk",
+            })),
+        );
+    }
+    if let Err(e) = app_state
+        .send_command(None, command, request_id, user)
+        .await
+    {
+        error!("Failed to publish {}: {}", command.command, e);
+        return e.response("Failed to publish the setting", Some(request_id));
+    }
+    record_requested(app_state, command).await;
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "status": "Setting sent",
+            "request_id": request_id,
+            "settings": MotorSettings::from_command(command),
+        })),
+    )
+}
+
+async fn record_requested(app_state: &AppState, command: &DeskCommand) {
+    if let Some(setting) = MotorSettings::from_command(command) {
+        let mut requested = app_state.motor_requested.lock().await;
+        *requested = requested.merge(setting);
+        info!("Requested motor settings {:?}", *requested);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_round_trip_through_commands() {
+        let settings = MotorSettings {
+            speed_percent: Some(40),
+            acceleration: Some(AccelerationProfile::Gentle),
+            soft_start_ms: None,
+        };
+        let commands = settings.commands();
+        assert_eq!(commands.len(), 2);
+        let rebuilt = commands
+            .iter()
+            .filter_map(MotorSettings::from_command)
+            .fold(MotorSettings::default(), MotorSettings::merge);
+        assert_eq!(rebuilt, settings);
+
+        let newer = MotorSettings {
+            speed_percent: Some(100),
+            ..MotorSettings::default()
+        };
+        assert_eq!(settings.merge(newer).speed_percent, Some(100));
+        assert_eq!(
+            settings.merge(newer).acceleration,
+            Some(AccelerationProfile::Gentle)
+        );
+    }
+}
@@ -0,0 +1,289 @@
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{error, warn};
+use utoipa::ToSchema;
+
+use crate::config::Config;
+use crate::positions::PositionHeights;
+use crate::{AppState, DeskCommand, SvenCommand, SvenPosition, SvenState};
+
+/// Height difference at which a move is considered to have arrived.
+pub const ARRIVAL_TOLERANCE_MM: u32 = 5;
+
+/// How long the height has to stay unchanged before a move counts as finished.
+const SETTLE_TIME: Duration = Duration::from_secs(1);
+
+/// Waits until the reported height is within `ARRIVAL_TOLERANCE_MM` of `target_mm`.
+/// On timeout the last known state is returned as the error.
+pub async fn wait_for_arrival(
+    state_rx: &mut watch::Receiver<SvenState>,
+    target_mm: u32,
+    timeout: Duration,
+) -> Result<SvenState, SvenState> {
+    let arrival = async {
+        loop {
+            let state = *state_rx.borrow_and_update();
+            if state.height_mm.abs_diff(target_mm) <= ARRIVAL_TOLERANCE_MM {
+                return Ok(state);
+            }
+            if state_rx.changed().await.is_err() {
+                return Err(state);
+            }
+        }
+    };
+    match tokio::time::timeout(timeout, arrival).await {
+        Ok(result) => result,
+        Err(_) => Err(*state_rx.borrow()),
+    }
+}
+
+/// Waits for the desk to start reporting a new height and then stop changing,
+/// for moves whose final height isn't known up front.
+pub async fn wait_for_settle(
+    state_rx: &mut watch::Receiver<SvenState>,
+    timeout: Duration,
+) -> Result<SvenState, SvenState> {
+    state_rx.mark_unchanged();
+    let settle = async {
+        if state_rx.changed().await.is_err() {
+            return Err(*state_rx.borrow());
+        }
+        loop {
+            match tokio::time::timeout(SETTLE_TIME, state_rx.changed()).await {
+                Ok(Ok(())) => continue,
+                Ok(Err(_)) => return Err(*state_rx.borrow()),
+                Err(_) => return Ok(*state_rx.borrow_and_update()),
+            }
+        }
+    };
+    match tokio::time::timeout(timeout, settle).await {
+        Ok(result) => result,
+        Err(_) => Err(*state_rx.borrow()),
+    }
+}
+
+/// Predicted time for `command` to complete starting from `current_height_mm`,
+/// for commands whose travel is known up front.
+pub fn estimate_travel_ms(
+    command: &DeskCommand,
+    current_height_mm: u32,
+    speed_mm_per_s: u32,
+) -> Option<u64> {
+    let distance_mm = match command.command {
+        SvenCommand::UpDuration | SvenCommand::DownDuration => return Some(command.value.into()),
+        SvenCommand::UpRelative | SvenCommand::DownRelative => command.value,
+        SvenCommand::AbsoluteHeight => command.value.abs_diff(current_height_mm),
+        _ => return None,
+    };
+    if speed_mm_per_s == 0 {
+        return None;
+    }
+    Some(u64::from(distance_mm) * 1000 / u64::from(speed_mm_per_s))
+}
+
+/// Where the desk will end up after `command`, when that can be worked out up front.
+/// Duration commands are projected from the configured travel speed.
+pub fn projected_target_mm(
+    command: &DeskCommand,
+    current_height_mm: u32,
+    speed_mm_per_s: u32,
+    position_heights: &PositionHeights,
+) -> Option<u32> {
+    let duration_mm = || command.value.saturating_mul(speed_mm_per_s) / 1000;
+    match command.command {
+        SvenCommand::UpRelative => Some(current_height_mm.saturating_add(command.value)),
+        SvenCommand::DownRelative => Some(current_height_mm.saturating_sub(command.value)),
+        SvenCommand::UpDuration => Some(current_height_mm.saturating_add(duration_mm())),
+        SvenCommand::DownDuration => Some(current_height_mm.saturating_sub(duration_mm())),
+        SvenCommand::AbsoluteHeight => Some(command.value),
+        SvenCommand::Position => SvenPosition::from_index(command.value)
+            .and_then(|position| position_heights.heights.get(&position).copied()),
+        _ => None,
+    }
+}
+
+/// Whether `command` is an absolute move to within `deadband_mm` of where the desk
+/// already is, too small to be worth publishing.
+pub fn within_deadband(command: &DeskCommand, current_height_mm: u32, deadband_mm: u32) -> bool {
+    command.command == SvenCommand::AbsoluteHeight
+        && command.value.abs_diff(current_height_mm) < deadband_mm
+}
+
+/// Splits a move that ends within `slow_zone_mm` of either limit into a move to
+/// the edge of the zone followed by short relative steps, so the desk doesn't
+/// hit its end stops at full speed. Returns `None` when the move needs no splitting.
+pub fn slow_zone_plan(
+    current_mm: u32,
+    target_mm: u32,
+    config: &Config,
+) -> Option<Vec<DeskCommand>> {
+    let zone_mm = config.slow_zone_mm;
+    let step_mm = config.slow_zone_step_mm;
+    if zone_mm == 0 || step_mm == 0 {
+        return None;
+    }
+
+    let upper_edge = config.max_height_mm.saturating_sub(zone_mm);
+    let lower_edge = config.min_height_mm.saturating_add(zone_mm);
+    let (edge_mm, step_command) = if target_mm > current_mm && target_mm > upper_edge {
+        (upper_edge.max(current_mm), SvenCommand::UpRelative)
+    } else if target_mm < current_mm && target_mm < lower_edge {
+        (lower_edge.min(current_mm), SvenCommand::DownRelative)
+    } else {
+        return None;
+    };
+
+    let mut steps = Vec::new();
+    if edge_mm != current_mm {
+        steps.push(DeskCommand {
+            command: SvenCommand::AbsoluteHeight,
+            value: edge_mm,
+        });
+    }
+    let mut remaining_mm = target_mm.abs_diff(edge_mm);
+    while remaining_mm > 0 {
+        let value = remaining_mm.min(step_mm);
+        steps.push(DeskCommand {
+            command: step_command,
+            value,
+        });
+        remaining_mm -= value;
+    }
+
+    (steps.len() > 1).then_some(steps)
+}
+
+/// Sends `steps` one after another, waiting for each move to finish before the
+/// next. A step that times out is logged and the sequence carries on; a step that
+/// can't be published ends it, and so does a stop. `label` names the sequence in
+/// log messages, `user` is recorded as the sender of every step, and `desk` picks a
+/// desk from the registry instead of the primary desk.
+pub async fn run_steps(
+    app_state: &AppState,
+    label: &str,
+    steps: &[DeskCommand],
+    request_id: Option<&str>,
+    user: Option<&str>,
+    desk: Option<&str>,
+) {
+    let timeout = Duration::from_millis(app_state.config().ack_timeout_ms);
+    let generation = app_state.stop_generation.load(Ordering::SeqCst);
+    for (i, step) in steps.iter().enumerate() {
+        if app_state.stop_generation.load(Ordering::SeqCst) != generation {
+            warn!("{} stopped before step {}", label, i + 1);
+            return;
+        }
+        let mut state_rx = app_state.subscribe_state(desk).await;
+        let request_id = request_id
+            .map(str::to_string)
+            .unwrap_or_else(crate::request_id::generate);
+        if let Err(e) = app_state.send_command(desk, step, &request_id, user).await {
+            error!("{} failed at step {}: {:?}", label, i + 1, e);
+            return;
+        }
+        let finished = match step.command {
+            SvenCommand::AbsoluteHeight => {
+                wait_for_arrival(&mut state_rx, step.value, timeout).await
+            }
+            _ => wait_for_settle(&mut state_rx, timeout).await,
+        };
+        if finished.is_err() {
+            warn!(
+                "{} step {} did not finish in time, continuing",
+                label,
+                i + 1
+            );
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum Direction {
+    Up,
+    Down,
+    Idle,
+}
+
+/// Height changes up to this size between reports are treated as sensor noise.
+const DIRECTION_THRESHOLD_MM: u32 = 2;
+
+/// Derives the direction of travel from consecutive height reports.
+pub struct MotionTracker {
+    last_height_mm: Option<u32>,
+    direction: Direction,
+    last_movement: std::time::Instant,
+}
+
+impl MotionTracker {
+    pub fn new() -> Self {
+        MotionTracker {
+            last_height_mm: None,
+            direction: Direction::Idle,
+            last_movement: std::time::Instant::now(),
+        }
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    pub fn update(&mut self, height_mm: u32) -> Direction {
+        if let Some(last) = self.last_height_mm {
+            if height_mm.abs_diff(last) > DIRECTION_THRESHOLD_MM {
+                self.direction = if height_mm > last {
+                    Direction::Up
+                } else {
+                    Direction::Down
+                };
+                self.last_movement = std::time::Instant::now();
+            } else {
+                self.direction = Direction::Idle;
+            }
+        }
+        self.last_height_mm = Some(height_mm);
+        self.direction
+    }
+
+    /// Falls back to `Idle` once reports have stopped for `SETTLE_TIME`, since
+    /// firmware may simply go quiet after a move. Returns true if that changed anything.
+    pub fn settle(&mut self) -> bool {
+        if self.direction != Direction::Idle && self.last_movement.elapsed() >= SETTLE_TIME {
+            self.direction = Direction::Idle;
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_small_absolute_moves_fall_in_the_deadband() {
+        let to = |command, value| DeskCommand { command, value };
+        assert!(within_deadband(
+            &to(SvenCommand::AbsoluteHeight, 1003),
+            1000,
+            5
+        ));
+        assert!(within_deadband(
+            &to(SvenCommand::AbsoluteHeight, 996),
+            1000,
+            5
+        ));
+        assert!(!within_deadband(
+            &to(SvenCommand::AbsoluteHeight, 1005),
+            1000,
+            5
+        ));
+        assert!(!within_deadband(&to(SvenCommand::UpRelative, 2), 1000, 5));
+        assert!(!within_deadband(
+            &to(SvenCommand::AbsoluteHeight, 1000),
+            1000,
+            0
+        ));
+    }
+}
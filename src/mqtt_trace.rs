@@ -0,0 +1,187 @@
+use chrono::{DateTime, Utc};
+use rumqttc::{ConnectionError, Event as MqttEvent, Outgoing, Packet};
+use serde::Serialize;
+use std::collections::VecDeque;
+
+/// A single entry in the MQTT event trace.
+#[derive(Debug, Clone, Serialize)]
+pub struct MqttTraceEvent {
+    pub kind: &'static str,
+    pub direction: &'static str,
+    pub topic: Option<String>,
+    pub size: usize,
+    pub detail: Option<String>,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Ring buffer holding the last N events seen by the MQTT eventloop.
+pub struct MqttTrace {
+    capacity: usize,
+    events: VecDeque<MqttTraceEvent>,
+}
+
+impl MqttTrace {
+    pub fn new(capacity: usize) -> Self {
+        MqttTrace {
+            capacity,
+            events: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn record_event(&mut self, event: &MqttEvent) {
+        let entry = match event {
+            MqttEvent::Incoming(packet) => {
+                let (topic, size) = match packet {
+                    Packet::Publish(publish) => {
+                        (Some(publish.topic.clone()), publish.payload.len())
+                    }
+                    _ => (None, 0),
+                };
+                MqttTraceEvent {
+                    kind: packet_kind(packet),
+                    direction: "incoming",
+                    topic,
+                    size,
+                    detail: None,
+                    timestamp: Utc::now(),
+                }
+            }
+            MqttEvent::Outgoing(outgoing) => MqttTraceEvent {
+                kind: outgoing_kind(outgoing),
+                direction: "outgoing",
+                topic: None,
+                size: 0,
+                detail: None,
+                timestamp: Utc::now(),
+            },
+        };
+        self.push(entry);
+    }
+
+    pub fn record_error(&mut self, error: &ConnectionError) {
+        self.push(MqttTraceEvent {
+            kind: "disconnected",
+            direction: "internal",
+            topic: None,
+            size: 0,
+            detail: Some(error.to_string()),
+            timestamp: Utc::now(),
+        });
+    }
+
+    pub fn events(&self) -> Vec<MqttTraceEvent> {
+        self.events.iter().cloned().collect()
+    }
+
+    fn push(&mut self, entry: MqttTraceEvent) {
+        if self.capacity == 0 {
+            return;
+        }
+        while self.events.len() >= self.capacity {
+            self.events.pop_front();
+        }
+        self.events.push_back(entry);
+    }
+}
+
+fn packet_kind(packet: &Packet) -> &'static str {
+    match packet {
+        Packet::Connect(_) => "connect",
+        Packet::ConnAck(_) => "connected",
+        Packet::Publish(_) => "publish",
+        Packet::PubAck(_) => "puback",
+        Packet::PubRec(_) => "pubrec",
+        Packet::PubRel(_) => "pubrel",
+        Packet::PubComp(_) => "pubcomp",
+        Packet::Subscribe(_) => "subscribe",
+        Packet::SubAck(_) => "suback",
+        Packet::Unsubscribe(_) => "unsubscribe",
+        Packet::UnsubAck(_) => "unsuback",
+        Packet::PingReq => "pingreq",
+        Packet::PingResp => "pingresp",
+        Packet::Disconnect => "disconnected",
+    }
+}
+
+fn outgoing_kind(outgoing: &Outgoing) -> &'static str {
+    match outgoing {
+        Outgoing::Publish(_) => "publish",
+        Outgoing::Subscribe(_) => "subscribe",
+        Outgoing::Unsubscribe(_) => "unsubscribe",
+        Outgoing::PubAck(_) => "puback",
+        Outgoing::PubRec(_) => "pubrec",
+        Outgoing::PubRel(_) => "pubrel",
+        Outgoing::PubComp(_) => "pubcomp",
+        Outgoing::PingReq => "pingreq",
+        Outgoing::PingResp => "pingresp",
+        Outgoing::Disconnect => "disconnect",
+        Outgoing::AwaitAck(_) => "awaitack",
+    }
+}
+
+/// How short a connection has to be to count towards a reconnect storm.
+const RAPID_DISCONNECT_WINDOW: std::time::Duration = std::time::Duration::from_secs(5);
+/// How far back rapid disconnects are counted.
+const STORM_WINDOW: std::time::Duration = std::time::Duration::from_secs(60);
+/// Rapid disconnects within `STORM_WINDOW` that trigger the duplicate client id warning.
+const STORM_THRESHOLD: usize = 3;
+
+/// Detects the connect/disconnect ping-pong a broker causes when two clients share an id:
+/// each connection is accepted and then kicked shortly after.
+#[derive(Default)]
+pub struct ReconnectMonitor {
+    connected_at: Option<std::time::Instant>,
+    rapid_disconnects: VecDeque<std::time::Instant>,
+    last_warning: Option<DateTime<Utc>>,
+}
+
+impl ReconnectMonitor {
+    pub fn is_connected(&self) -> bool {
+        self.connected_at.is_some()
+    }
+
+    pub fn on_connected(&mut self) {
+        self.connected_at = Some(std::time::Instant::now());
+    }
+
+    /// Returns true when this disconnect tips the recent history over into a likely
+    /// duplicate client id.
+    pub fn on_disconnected(&mut self) -> bool {
+        let now = std::time::Instant::now();
+        let Some(connected_at) = self.connected_at.take() else {
+            return false;
+        };
+        if now.duration_since(connected_at) > RAPID_DISCONNECT_WINDOW {
+            return false;
+        }
+
+        self.rapid_disconnects.push_back(now);
+        while let Some(oldest) = self.rapid_disconnects.front() {
+            if now.duration_since(*oldest) <= STORM_WINDOW {
+                break;
+            }
+            self.rapid_disconnects.pop_front();
+        }
+
+        if self.rapid_disconnects.len() >= STORM_THRESHOLD {
+            self.rapid_disconnects.clear();
+            self.last_warning = Some(Utc::now());
+            return true;
+        }
+        false
+    }
+
+    /// When the duplicate client id warning last fired, if within the storm window.
+    pub fn possible_duplicate_since(&self) -> Option<DateTime<Utc>> {
+        self.last_warning
+            .filter(|at| Utc::now() - *at < chrono::Duration::from_std(STORM_WINDOW).unwrap())
+    }
+}
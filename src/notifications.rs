@@ -0,0 +1,129 @@
+use axum::{
+    extract::{ConnectInfo, Extension},
+    response::sse::{Event, KeepAlive, Sse},
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tokio_stream::{Stream, StreamExt, wrappers::BroadcastStream};
+use utoipa::ToSchema;
+
+use crate::AppState;
+use crate::auth::ApiKeyId;
+use crate::listen::ClientAddr;
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NotificationKind {
+    Watchdog,
+    Automation {
+        automation: String,
+    },
+    InactivityLock {
+        locked: bool,
+    },
+    Broker {
+        connected: bool,
+    },
+    /// A command ran into a soft height limit and was clamped or rejected
+    HeightLimit {
+        desk: Option<String>,
+    },
+    /// A standing session is about to end or has ended
+    Session {
+        job_id: String,
+    },
+    /// The desk has been sitting for longer than the reminder settings allow
+    Reminder {
+        sitting_minutes: i64,
+    },
+    /// Today's standing time reached the daily standing goal
+    GoalMet {
+        standing_minutes: u64,
+        goal_minutes: u64,
+    },
+}
+
+impl NotificationKind {
+    /// The `type` the kind is serialized with, which `SVEN_NOTIFY_EVENTS` lists.
+    pub fn name(&self) -> &'static str {
+        match self {
+            NotificationKind::Watchdog => "watchdog",
+            NotificationKind::Automation { .. } => "automation",
+            NotificationKind::InactivityLock { .. } => "inactivity_lock",
+            NotificationKind::Broker { .. } => "broker",
+            NotificationKind::HeightLimit { .. } => "height_limit",
+            NotificationKind::Session { .. } => "session",
+            NotificationKind::Reminder { .. } => "reminder",
+            NotificationKind::GoalMet { .. } => "goal_met",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Notification {
+    #[serde(flatten)]
+    pub kind: NotificationKind,
+    pub message: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Fans out notifications from watchdogs, automations, reminders, the standing
+/// goal and the inactivity lock to every connected notification stream.
+#[derive(Clone)]
+pub struct Notifier {
+    sender: broadcast::Sender<Notification>,
+}
+
+impl Notifier {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(64);
+        Notifier { sender }
+    }
+
+    pub fn notify(&self, kind: NotificationKind, message: impl Into<String>) {
+        // Sending only fails when nobody is listening, which is fine
+        let _ = self.sender.send(Notification {
+            kind,
+            message: message.into(),
+            timestamp: Utc::now(),
+        });
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Notification> {
+        self.sender.subscribe()
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/sven/notifications",
+    tag = "streams",
+    responses(
+        (status = 200, description = "Server-sent events, one per notification", body = Notification, content_type = "text/event-stream"),
+    ),
+)]
+pub async fn get_notifications(
+    ConnectInfo(remote): ConnectInfo<ClientAddr>,
+    api_key: Option<Extension<ApiKeyId>>,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let connection = app_state.connections.register(
+        "notifications",
+        remote,
+        api_key.map(|Extension(key)| key.0),
+    );
+    let stream =
+        BroadcastStream::new(app_state.notifier.subscribe()).filter_map(move |notification| {
+            // Lagging receivers just miss the notifications they fell behind on
+            let notification = notification.ok()?;
+            connection.touch();
+            Some(Ok(Event::default()
+                .event("notification")
+                .json_data(notification)
+                .unwrap()))
+        });
+    Sse::new(app_state.shutdown.until(stream)).keep_alive(KeepAlive::default())
+}
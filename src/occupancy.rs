@@ -0,0 +1,91 @@
+//! Desk occupancy from an external presence sensor, such as a mmWave sensor
+//! publishing through zigbee2mqtt. Reports arrive on `SVEN_OCCUPANCY_TOPIC`, either
+//! as a bare value or as a JSON object holding it at `SVEN_OCCUPANCY_FIELD`, and
+//! show up as `occupancy` in the desk state. With
+//! `SVEN_OCCUPANCY_SUPPRESS_SCHEDULES`, schedules skip their run while the desk is
+//! vacant.
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// Values read as occupied, besides `true` and non-zero numbers.
+const OCCUPIED_WORDS: [&str; 5] = ["on", "occupied", "present", "detected", "yes"];
+/// Values read as vacant, besides `false` and zero.
+const VACANT_WORDS: [&str; 6] = ["off", "vacant", "unoccupied", "absent", "clear", "no"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Occupancy {
+    /// No sensor is configured, or it hasn't reported since startup
+    Unknown,
+    Occupied,
+    Vacant,
+}
+
+impl Occupancy {
+    /// Maps a sensor report to the occupancy it describes. JSON objects are
+    /// followed along `field`, a dot separated path like `presence` or
+    /// `target.detected`.
+    pub fn parse(payload: &[u8], field: &str) -> Option<Occupancy> {
+        let text = std::str::from_utf8(payload).ok()?.trim();
+        let mut value = match serde_json::from_str::<serde_json::Value>(text) {
+            Ok(value) => value,
+            // A bare word like `on`
+            Err(_) => serde_json::Value::String(text.to_string()),
+        };
+        if value.is_object() {
+            for key in field.split('.') {
+                value = value.get_mut(key)?.take();
+            }
+        }
+        match value {
+            serde_json::Value::Bool(occupied) => Some(Occupancy::from_bool(occupied)),
+            serde_json::Value::Number(number) => {
+                Some(Occupancy::from_bool(number.as_f64()? != 0.0))
+            }
+            serde_json::Value::String(word) => {
+                let word = word.trim().to_ascii_lowercase();
+                if OCCUPIED_WORDS.contains(&word.as_str()) {
+                    Some(Occupancy::Occupied)
+                } else if VACANT_WORDS.contains(&word.as_str()) {
+                    Some(Occupancy::Vacant)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn from_bool(occupied: bool) -> Occupancy {
+        if occupied {
+            Occupancy::Occupied
+        } else {
+            Occupancy::Vacant
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sensor_reports_map_to_occupancy() {
+        let parse = |payload: &str| Occupancy::parse(payload.as_bytes(), "presence");
+
+        assert_eq!(parse("ON"), Some(Occupancy::Occupied));
+        assert_eq!(parse("\"clear\""), Some(Occupancy::Vacant));
+        assert_eq!(parse("0"), Some(Occupancy::Vacant));
+        assert_eq!(
+            parse(r#"{"presence": true, "illuminance": 12}"#),
+            Some(Occupancy::Occupied)
+        );
+        assert_eq!(parse(r#"{"occupancy": true}"#), None);
+        assert_eq!(parse("maybe"), None);
+        assert_eq!(
+            Occupancy::parse(br#"{"target": {"distance": 0.8}}"#, "target.distance"),
+            Some(Occupancy::Occupied)
+        );
+    }
+}
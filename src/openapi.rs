@@ -0,0 +1,155 @@
+use axum::{
+    Json,
+    response::{Html, IntoResponse},
+};
+use utoipa::OpenApi;
+
+use crate::{
+    alexa, audit, backup, connections, desks, discovery, ergonomics, faults, firmware, groups,
+    health, history, info, jobs, live, lock, login, macros, maintenance, memory, metrics, motor,
+    notifications, positions, presets, queue, quick, reload, reminders, rules, schedules,
+    sequences, sessions, stats, tokens, users, webhooks,
+};
+
+/// The OpenAPI document, generated from the handler annotations and schema derives.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "sven-api", description = "HTTP bridge to the Sven desk controller over MQTT. Every `/api/...` path is also served as `/api/v1/...`."),
+    paths(
+        crate::handle_command,
+        crate::handle_stop,
+        queue::get_queue,
+        faults::list_faults,
+        faults::clear_faults,
+        jobs::list_jobs,
+        jobs::get_job,
+        jobs::cancel_job,
+        crate::get_sven_state,
+        crate::get_sven_status,
+        crate::get_debug_events,
+        crate::get_debug_mqtt,
+        login::handle_login,
+        login::get_session,
+        login::handle_logout,
+        backup::get_backup,
+        backup::handle_restore,
+        reload::handle_reload,
+        tokens::list_tokens,
+        tokens::create_token,
+        tokens::delete_token,
+        info::get_positions,
+        info::get_limits,
+        info::get_commands,
+        discovery::get_discovery,
+        desks::get_desk_state,
+        desks::get_desk_states,
+        desks::handle_desk_command,
+        groups::list_groups,
+        groups::handle_group_command,
+        alexa::handle_directive,
+        metrics::get_metrics_json,
+        metrics::get_metrics,
+        history::get_history,
+        audit::export_audit,
+        stats::get_stats,
+        connections::get_connections,
+        memory::get_memory,
+        maintenance::handle_maintenance,
+        motor::get_settings,
+        motor::put_settings,
+        firmware::upload_firmware,
+        firmware::get_firmware,
+        firmware::abort_firmware,
+        ergonomics::handle_recommend,
+        lock::handle_unlock,
+        presets::list_presets,
+        presets::save_preset,
+        users::list_users,
+        users::register_user,
+        users::delete_user,
+        users::save_user_preset,
+        users::delete_user_preset,
+        presets::delete_preset,
+        presets::apply_preset,
+        quick::get_quick_actions,
+        quick::set_quick_actions,
+        quick::run_quick_action,
+        positions::set_position_height,
+        positions::handle_calibrate,
+        positions::delete_calibration,
+        macros::list_macros,
+        macros::create_from_history,
+        macros::run_macro,
+        sequences::start_sequence,
+        sequences::get_sequence,
+        sessions::start_session,
+        sessions::get_session,
+        sessions::end_session,
+        schedules::list_schedules,
+        schedules::create_schedule,
+        schedules::update_schedule,
+        schedules::delete_schedule,
+        schedules::pause_schedule,
+        schedules::resume_schedule,
+        rules::list_rules,
+        rules::create_rule,
+        rules::update_rule,
+        rules::delete_rule,
+        rules::trigger_rule,
+        webhooks::list_webhooks,
+        webhooks::create_webhook,
+        webhooks::delete_webhook,
+        webhooks::list_deliveries,
+        reminders::get_reminders,
+        reminders::set_reminders,
+        notifications::get_notifications,
+        live::get_ws,
+        live::get_events,
+        health::get_healthz,
+        health::get_readyz,
+    ),
+    tags(
+        (name = "desk", description = "State and commands"),
+        (name = "jobs", description = "Movements in progress and how they ended"),
+        (name = "info", description = "What the desk and bridge support"),
+        (name = "presets", description = "Named heights"),
+        (name = "users", description = "People sharing the desk and their own presets"),
+        (name = "positions", description = "Heights of the firmware positions"),
+        (name = "macros", description = "Recorded and ad hoc command sequences"),
+        (name = "schedules", description = "Sit/stand schedules"),
+        (name = "rules", description = "Automations: triggers, conditions and actions"),
+        (name = "webhooks", description = "Signed callbacks for desk events"),
+        (name = "reminders", description = "Sitting reminders"),
+        (name = "history", description = "Command and state history"),
+        (name = "streams", description = "Live updates"),
+        (name = "integrations", description = "Voice assistants and other platforms"),
+        (name = "metrics", description = "Counters and gauges"),
+        (name = "health", description = "Probes"),
+        (name = "login", description = "Password login with a session cookie"),
+        (name = "admin", description = "Diagnostics and maintenance, require the admin token or an admin login"),
+    )
+)]
+pub struct ApiDoc;
+
+pub async fn get_openapi() -> impl IntoResponse {
+    Json(ApiDoc::openapi())
+}
+
+/// Swagger UI for the document above, loaded from a CDN so nothing is bundled.
+pub async fn get_docs() -> impl IntoResponse {
+    Html(
+        r##"<!doctype html>
+<html>
+<head>
+  <meta charset="utf-8">
+  <title>sven-api</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+  <script>SwaggerUIBundle({ url: "/api/openapi.json", dom_id: "#swagger-ui" });</script>
+</body>
+</html>"##,
+    )
+}
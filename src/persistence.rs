@@ -0,0 +1,337 @@
+use serde::{Serialize, de::DeserializeOwned};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use tracing::{error, warn};
+
+use crate::SvenState;
+
+/// A type that is persisted as a whole under a fixed key.
+pub trait Artifact: Serialize + DeserializeOwned {
+    const KEY: &'static str;
+}
+
+impl Artifact for SvenState {
+    const KEY: &'static str = "state";
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    /// The file or database couldn't be read or written
+    Io(std::io::Error),
+    /// The stored bytes aren't valid JSON, e.g. after a partial write
+    Parse(serde_json::Error),
+    /// Valid JSON that doesn't match the artifact's shape, e.g. written by another version
+    SchemaMismatch(serde_json::Error),
+    Database(rusqlite::Error),
+}
+
+impl StoreError {
+    /// Whether the stored value itself is bad, as opposed to the storage being unavailable.
+    pub fn is_corrupt(&self) -> bool {
+        matches!(self, StoreError::Parse(_) | StoreError::SchemaMismatch(_))
+    }
+}
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreError::Io(e) => write!(f, "IO error: {}", e),
+            StoreError::Parse(e) => write!(f, "parse error: {}", e),
+            StoreError::SchemaMismatch(e) => write!(f, "schema mismatch: {}", e),
+            StoreError::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<std::io::Error> for StoreError {
+    fn from(e: std::io::Error) -> Self {
+        StoreError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(e: serde_json::Error) -> Self {
+        match e.classify() {
+            serde_json::error::Category::Io => StoreError::Io(e.into()),
+            serde_json::error::Category::Data => StoreError::SchemaMismatch(e),
+            serde_json::error::Category::Syntax | serde_json::error::Category::Eof => {
+                StoreError::Parse(e)
+            }
+        }
+    }
+}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(e: rusqlite::Error) -> Self {
+        StoreError::Database(e)
+    }
+}
+
+/// Key/value persistence for serialized artifacts. Use the typed
+/// `get`/`put` on `dyn Store` rather than the raw methods.
+pub trait Store: Send + Sync {
+    fn get_raw(&self, key: &str) -> Result<Option<Vec<u8>>, StoreError>;
+    fn put_raw(&self, key: &str, value: &[u8]) -> Result<(), StoreError>;
+    /// Moves a value that failed to load aside as `<key>.corrupt` for later inspection.
+    fn quarantine(&self, key: &str) -> Result<(), StoreError>;
+}
+
+impl dyn Store + '_ {
+    pub fn get<T: Artifact>(&self) -> Result<Option<T>, StoreError> {
+        match self.get_raw(T::KEY)? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn put<T: Artifact>(&self, value: &T) -> Result<(), StoreError> {
+        self.put_raw(T::KEY, &serde_json::to_vec_pretty(value)?)
+    }
+}
+
+/// Loads an artifact at startup. A corrupt value is logged and quarantined so the
+/// service starts from defaults rather than failing on every restart.
+pub fn load<T: Artifact>(store: Option<&dyn Store>) -> Option<T> {
+    let store = store?;
+    match store.get::<T>() {
+        Ok(value) => value,
+        Err(e) => {
+            error!("Failed to load persisted {}: {}", T::KEY, e);
+            if e.is_corrupt() {
+                match store.quarantine(T::KEY) {
+                    Ok(()) => warn!("Moved corrupt {} aside as {}.corrupt", T::KEY, T::KEY),
+                    Err(e) => error!("Failed to quarantine corrupt {}: {}", T::KEY, e),
+                }
+            }
+            None
+        }
+    }
+}
+
+pub fn load_or_default<T: Artifact + Default>(store: Option<&dyn Store>) -> T {
+    load(store).unwrap_or_default()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreBackend {
+    File,
+    Sqlite,
+}
+
+impl FromStr for StoreBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "file" => Ok(StoreBackend::File),
+            "sqlite" => Ok(StoreBackend::Sqlite),
+            other => Err(format!("unknown store backend '{}'", other)),
+        }
+    }
+}
+
+pub fn open_store(backend: StoreBackend, path: &Path) -> Result<Box<dyn Store>, StoreError> {
+    match backend {
+        StoreBackend::File => Ok(Box::new(FileStore::open(path)?)),
+        StoreBackend::Sqlite => Ok(Box::new(SqliteStore::open(path)?)),
+    }
+}
+
+/// Stores each artifact as `<dir>/<key>.json`.
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    pub fn open(dir: &Path) -> Result<Self, StoreError> {
+        std::fs::create_dir_all(dir)?;
+        Ok(FileStore {
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", key))
+    }
+}
+
+impl Store for FileStore {
+    fn get_raw(&self, key: &str) -> Result<Option<Vec<u8>>, StoreError> {
+        match std::fs::read(self.path(key)) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes to a temporary file and renames it into place, so a crash
+    /// mid-write never leaves a truncated file behind.
+    fn put_raw(&self, key: &str, value: &[u8]) -> Result<(), StoreError> {
+        let path = self.path(key);
+        let tmp_path = path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, value)?;
+        std::fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    fn quarantine(&self, key: &str) -> Result<(), StoreError> {
+        let path = self.path(key);
+        std::fs::rename(&path, path.with_extension("json.corrupt"))?;
+        Ok(())
+    }
+}
+
+/// Stores artifacts as rows in an embedded SQLite database.
+pub struct SqliteStore {
+    connection: Mutex<rusqlite::Connection>,
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> Result<Self, StoreError> {
+        let connection = rusqlite::Connection::open(path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS artifacts (
+                key TEXT PRIMARY KEY,
+                value BLOB NOT NULL,
+                updated_at TEXT NOT NULL
+            )",
+        )?;
+        Ok(SqliteStore {
+            connection: Mutex::new(connection),
+        })
+    }
+}
+
+impl Store for SqliteStore {
+    fn get_raw(&self, key: &str) -> Result<Option<Vec<u8>>, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement =
+            connection.prepare_cached("SELECT value FROM artifacts WHERE key = ?1")?;
+        let mut rows = statement.query([key])?;
+        match rows.next()? {
+            Some(row) => Ok(Some(row.get(0)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn put_raw(&self, key: &str, value: &[u8]) -> Result<(), StoreError> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "INSERT INTO artifacts (key, value, updated_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT(key) DO UPDATE SET value = excluded.value, updated_at = excluded.updated_at",
+            rusqlite::params![key, value, chrono::Utc::now().to_rfc3339()],
+        )?;
+        Ok(())
+    }
+
+    fn quarantine(&self, key: &str) -> Result<(), StoreError> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "INSERT OR REPLACE INTO artifacts (key, value, updated_at)
+             SELECT key || '.corrupt', value, updated_at FROM artifacts WHERE key = ?1",
+            [key],
+        )?;
+        connection.execute("DELETE FROM artifacts WHERE key = ?1", [key])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SvenPosition;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sven-store-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn sample_state() -> SvenState {
+        SvenState {
+            height_mm: 900,
+            position: SvenPosition::Custom,
+            motor: None,
+        }
+    }
+
+    #[test]
+    fn missing_artifact_loads_as_none() {
+        let dir = temp_dir();
+        let store: Box<dyn Store> = Box::new(FileStore::open(&dir).unwrap());
+        assert!(store.get::<SvenState>().unwrap().is_none());
+        assert!(load::<SvenState>(Some(&*store)).is_none());
+    }
+
+    #[test]
+    fn round_trips_through_both_backends() {
+        let dir = temp_dir();
+        let stores: Vec<Box<dyn Store>> = vec![
+            Box::new(FileStore::open(&dir).unwrap()),
+            Box::new(SqliteStore::open(&dir.join("store.sqlite")).unwrap()),
+        ];
+        for store in stores {
+            store.put(&sample_state()).unwrap();
+            assert_eq!(store.get::<SvenState>().unwrap(), Some(sample_state()));
+        }
+    }
+
+    #[test]
+    fn truncated_file_is_a_parse_error_and_quarantined() {
+        let dir = temp_dir();
+        std::fs::write(dir.join("state.json"), br#"{"height_mm": 9"#).unwrap();
+        let store: Box<dyn Store> = Box::new(FileStore::open(&dir).unwrap());
+
+        assert!(matches!(
+            store.get::<SvenState>(),
+            Err(StoreError::Parse(_))
+        ));
+        assert!(load::<SvenState>(Some(&*store)).is_none());
+        assert!(!dir.join("state.json").exists());
+        assert!(dir.join("state.json.corrupt").exists());
+    }
+
+    #[test]
+    fn wrong_shape_is_a_schema_mismatch_and_quarantined() {
+        let dir = temp_dir();
+        std::fs::write(dir.join("state.json"), br#"{"height": "high"}"#).unwrap();
+        let store: Box<dyn Store> = Box::new(FileStore::open(&dir).unwrap());
+
+        assert!(matches!(
+            store.get::<SvenState>(),
+            Err(StoreError::SchemaMismatch(_))
+        ));
+        assert!(load::<SvenState>(Some(&*store)).is_none());
+        assert!(dir.join("state.json.corrupt").exists());
+    }
+
+    #[test]
+    fn unreadable_file_is_an_io_error_and_left_in_place() {
+        let dir = temp_dir();
+        // A directory where the file should be can't be read as one
+        std::fs::create_dir_all(dir.join("state.json")).unwrap();
+        let store: Box<dyn Store> = Box::new(FileStore::open(&dir).unwrap());
+
+        assert!(matches!(store.get::<SvenState>(), Err(StoreError::Io(_))));
+        assert!(load::<SvenState>(Some(&*store)).is_none());
+        assert!(dir.join("state.json").exists());
+        assert!(!dir.join("state.json.corrupt").exists());
+    }
+
+    #[test]
+    fn corrupt_sqlite_row_is_quarantined() {
+        let dir = temp_dir();
+        let store: Box<dyn Store> = Box::new(SqliteStore::open(&dir.join("store.sqlite")).unwrap());
+        store.put_raw("state", b"not json").unwrap();
+
+        assert!(load::<SvenState>(Some(&*store)).is_none());
+        assert!(store.get_raw("state").unwrap().is_none());
+        assert_eq!(
+            store.get_raw("state.corrupt").unwrap().as_deref(),
+            Some(&b"not json"[..])
+        );
+    }
+}
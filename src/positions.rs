@@ -0,0 +1,479 @@
+//! Heights calibrated for the firmware's positions, and positions users name
+//! themselves. A label, say "Drafting" or "Kinder", is saved with
+//! `PUT /api/sven/positions/{label}` and stored in the same table as the
+//! calibrations; `{"command": "Position", "label": "Drafting"}` moves there and
+//! the state names the label the desk is at. Labels are matched regardless of
+//! case and can't reuse the name of a firmware position.
+
+use axum::{
+    Json,
+    extract::{Extension, Path},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use serde::de::Deserializer;
+use serde::ser::{SerializeMap, Serializer};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use tracing::{error, info};
+use utoipa::ToSchema;
+
+use crate::config::Config;
+use crate::persistence::Artifact;
+use crate::validation::ErrorBody;
+use crate::{AppState, DeskCommand, SvenCommand, SvenPosition};
+
+/// Longest custom label, in characters.
+const MAX_LABEL_CHARS: usize = 40;
+
+/// Heights configured for named positions on this particular desk.
+#[derive(Debug, Clone, Default)]
+pub struct PositionHeights {
+    /// Calibrated heights of the firmware's positions
+    pub heights: BTreeMap<SvenPosition, u32>,
+    /// Heights of custom labels, keyed as they were written
+    pub labels: BTreeMap<String, u32>,
+}
+
+impl Artifact for PositionHeights {
+    const KEY: &'static str = "positions";
+}
+
+// One table of names and heights, as it was stored before there were labels
+impl Serialize for PositionHeights {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut table = serializer.serialize_map(Some(self.heights.len() + self.labels.len()))?;
+        for (position, height_mm) in &self.heights {
+            table.serialize_entry(position, height_mm)?;
+        }
+        for (label, height_mm) in &self.labels {
+            table.serialize_entry(label, height_mm)?;
+        }
+        table.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for PositionHeights {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut heights = PositionHeights::default();
+        for (name, height_mm) in BTreeMap::<String, u32>::deserialize(deserializer)? {
+            match name.parse::<SvenPosition>() {
+                Ok(position) => heights.heights.insert(position, height_mm),
+                Err(_) => heights.labels.insert(name, height_mm),
+            };
+        }
+        Ok(heights)
+    }
+}
+
+/// What a position route names: one of the firmware's positions or a custom label.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PositionName {
+    Fixed(SvenPosition),
+    Label(String),
+}
+
+impl FromStr for PositionName {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(position) = s.parse::<SvenPosition>() {
+            return Ok(PositionName::Fixed(position));
+        }
+        let label = s.trim();
+        if label.is_empty() {
+            return Err("A label must not be empty".to_string());
+        }
+        if label.chars().count() > MAX_LABEL_CHARS {
+            return Err(format!(
+                "A label is at most {} characters long",
+                MAX_LABEL_CHARS
+            ));
+        }
+        if label.chars().any(char::is_control) {
+            return Err("A label must not contain control characters".to_string());
+        }
+        Ok(PositionName::Label(label.to_string()))
+    }
+}
+
+impl std::fmt::Display for PositionName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PositionName::Fixed(position) => write!(f, "{:?}", position),
+            PositionName::Label(label) => write!(f, "{}", label),
+        }
+    }
+}
+
+impl PositionHeights {
+    /// Position names and heights, for duplicate checks against other kinds of presets.
+    pub fn named_heights(&self) -> impl Iterator<Item = (String, u32)> + '_ {
+        self.heights
+            .iter()
+            .map(|(position, height_mm)| (format!("{:?}", position), *height_mm))
+            .chain(
+                self.labels
+                    .iter()
+                    .map(|(label, height_mm)| (label.clone(), *height_mm)),
+            )
+    }
+
+    /// The label as written and its height, whatever the case `label` is in.
+    pub fn label(&self, label: &str) -> Option<(&str, u32)> {
+        let wanted = label.trim().to_lowercase();
+        self.labels
+            .iter()
+            .find(|(saved, _)| saved.to_lowercase() == wanted)
+            .map(|(saved, height_mm)| (saved.as_str(), *height_mm))
+    }
+
+    /// The label closest to `height_mm`, if one is within `tolerance_mm`.
+    pub fn label_at(&self, height_mm: u32, tolerance_mm: u32) -> Option<&str> {
+        self.labels
+            .iter()
+            .filter(|(_, saved_mm)| saved_mm.abs_diff(height_mm) <= tolerance_mm)
+            .min_by_key(|(_, saved_mm)| saved_mm.abs_diff(height_mm))
+            .map(|(label, _)| label.as_str())
+    }
+
+    /// The move to a position named in a command: a `Position` command for a
+    /// firmware position, an `AbsoluteHeight` move for a label.
+    pub fn command_for(&self, name: &str) -> Option<DeskCommand> {
+        if let Ok(position) = name.parse::<SvenPosition>() {
+            let index = SvenPosition::ALL.iter().position(|p| *p == position)?;
+            return Some(DeskCommand {
+                command: SvenCommand::Position,
+                value: index as u32,
+            });
+        }
+        self.label(name).map(|(_, height_mm)| DeskCommand {
+            command: SvenCommand::AbsoluteHeight,
+            value: height_mm,
+        })
+    }
+
+    /// Turns a `Position` command into an `AbsoluteHeight` move when the position
+    /// has been calibrated, so the desk goes where it was calibrated rather than
+    /// wherever the firmware thinks the position is.
+    pub fn resolve(&self, command: DeskCommand) -> DeskCommand {
+        let calibrated_mm = match command.command {
+            SvenCommand::Position => SvenPosition::from_index(command.value)
+                .and_then(|position| self.heights.get(&position))
+                .copied(),
+            _ => None,
+        };
+        match calibrated_mm {
+            Some(height_mm) => DeskCommand {
+                command: SvenCommand::AbsoluteHeight,
+                value: height_mm,
+            },
+            None => command,
+        }
+    }
+}
+
+/// What happens when a saved height duplicates another position's.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// Save anyway and report the conflict
+    Warn,
+    /// Refuse to save
+    Reject,
+}
+
+impl FromStr for DuplicatePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "warn" => Ok(DuplicatePolicy::Warn),
+            "reject" => Ok(DuplicatePolicy::Reject),
+            other => Err(format!("unknown duplicate policy '{}'", other)),
+        }
+    }
+}
+
+/// Applies the configured duplicate policy to saving `height_mm` under `name`,
+/// comparing against every other saved height in `existing`.
+/// Returns the warning to report when the save may go ahead despite a duplicate.
+pub fn check_duplicate(
+    config: &Config,
+    existing: impl IntoIterator<Item = (String, u32)>,
+    name: &str,
+    height_mm: u32,
+) -> Result<Option<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let tolerance_mm = config.duplicate_preset_tolerance_mm;
+    let Some((other, other_mm)) = existing
+        .into_iter()
+        .find(|(other, other_mm)| other != name && other_mm.abs_diff(height_mm) <= tolerance_mm)
+    else {
+        return Ok(None);
+    };
+    let message = format!(
+        "{} mm for {} is within {} mm of {} at {} mm",
+        height_mm, name, tolerance_mm, other, other_mm
+    );
+    match config.duplicate_preset_policy {
+        DuplicatePolicy::Warn => Ok(Some(serde_json::json!({
+            "message": message,
+            "conflicts_with": other,
+            "conflicting_height_mm": other_mm,
+        }))),
+        DuplicatePolicy::Reject => Err((
+            StatusCode::CONFLICT,
+            Json(serde_json::json!({
+                "error": message,
+                "conflicts_with": other,
+                "conflicting_height_mm": other_mm,
+            })),
+        )),
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SetPositionRequest {
+    pub height_mm: u32,
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/sven/positions/{position}",
+    tag = "positions",
+    params(("position" = String, Path, description = "A firmware position or a custom label")),
+    request_body = SetPositionRequest,
+    responses(
+        (status = 200, body = serde_json::Value),
+        (status = 409, description = "Duplicates another position's height", body = ErrorBody),
+        (status = 422, description = "Invalid request or label", body = ErrorBody),
+    ),
+)]
+pub async fn set_position_height(
+    Path(position): Path<String>,
+    Extension(app_state): Extension<Arc<AppState>>,
+    Json(request): Json<SetPositionRequest>,
+) -> impl IntoResponse {
+    let name = match position.parse::<PositionName>() {
+        Ok(name) => name,
+        Err(e) => return invalid_label(e),
+    };
+    save_position_height(&app_state, name, request.height_mm).await
+}
+
+fn invalid_label(message: String) -> (StatusCode, Json<serde_json::Value>) {
+    (
+        StatusCode::UNPROCESSABLE_ENTITY,
+        Json(serde_json::json!(ErrorBody {
+            error: message,
+            code: Some("invalid_label".to_string()),
+        })),
+    )
+}
+
+fn persist(app_state: &AppState, heights: &PositionHeights) {
+    if let Some(store) = &app_state.store
+        && let Err(e) = store.put(heights)
+    {
+        error!("Failed to persist position heights: {}", e);
+    }
+}
+
+/// `{"position": ...}` or `{"label": ...}`, for responses about `name`.
+fn describe(name: &PositionName) -> serde_json::Value {
+    match name {
+        PositionName::Fixed(position) => serde_json::json!({"position": position}),
+        PositionName::Label(label) => serde_json::json!({"label": label}),
+    }
+}
+
+async fn save_position_height(
+    app_state: &AppState,
+    name: PositionName,
+    height_mm: u32,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let config = &app_state.config();
+    if !config.height_in_bounds(height_mm) {
+        return (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(serde_json::json!({
+                "error": format!(
+                    "height_mm must be between {} and {}, got {}",
+                    config.min_height_mm, config.max_height_mm, height_mm
+                )
+            })),
+        );
+    }
+
+    let mut heights = app_state.position_heights.lock().await;
+    // Saving a label again in another case renames it
+    if let PositionName::Label(label) = &name
+        && let Some((saved, _)) = heights.label(label)
+    {
+        let saved = saved.to_string();
+        heights.labels.remove(&saved);
+    }
+    let existing: Vec<_> = heights
+        .named_heights()
+        .chain(app_state.presets.lock().await.named_heights())
+        .collect();
+    let warning = match check_duplicate(config, existing, &name.to_string(), height_mm) {
+        Ok(warning) => warning,
+        Err(rejection) => return rejection,
+    };
+    match &name {
+        PositionName::Fixed(position) => heights.heights.insert(*position, height_mm),
+        PositionName::Label(label) => heights.labels.insert(label.clone(), height_mm),
+    };
+    persist(app_state, &heights);
+    drop(heights);
+    info!("Saved {} at {} mm", name, height_mm);
+    app_state.refresh_state_cache().await;
+
+    let mut response = describe(&name);
+    response["height_mm"] = height_mm.into();
+    if let Some(warning) = warning {
+        response["warning"] = warning;
+    }
+    (StatusCode::OK, Json(response))
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CalibrateRequest {
+    /// A firmware position or a custom label
+    #[schema(example = "Standing")]
+    pub position: String,
+    /// Height the position is at on this desk, the current height when omitted
+    pub height_mm: Option<u32>,
+}
+
+/// Calibrates a position: `Position` commands for it are then sent as moves to
+/// this height. Without `height_mm` the desk's current height is used, so a
+/// position or label can be calibrated by moving the desk there first.
+#[utoipa::path(
+    post,
+    path = "/api/sven/calibrate",
+    tag = "positions",
+    request_body = CalibrateRequest,
+    responses(
+        (status = 200, body = serde_json::Value),
+        (status = 409, description = "Duplicates another position's height, or the current height is unknown", body = ErrorBody),
+        (status = 422, description = "Invalid request or label", body = ErrorBody),
+    ),
+)]
+pub async fn handle_calibrate(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Json(request): Json<CalibrateRequest>,
+) -> impl IntoResponse {
+    let name = match request.position.parse::<PositionName>() {
+        Ok(name) => name,
+        Err(e) => return invalid_label(e),
+    };
+    let height_mm = match request.height_mm {
+        Some(height_mm) => height_mm,
+        None if app_state.state_received.load(Ordering::SeqCst) => {
+            app_state.sven_state.read().await.height_mm
+        }
+        None => {
+            return (
+                StatusCode::CONFLICT,
+                Json(serde_json::json!({
+                    "error": "The desk has not reported its height yet, pass height_mm",
+                    "code": "state_unknown",
+                })),
+            );
+        }
+    };
+    save_position_height(&app_state, name, height_mm).await
+}
+
+/// Removes a position's calibration, leaving `Position` commands for it to the
+/// firmware, or removes a custom label.
+#[utoipa::path(
+    delete,
+    path = "/api/sven/calibrate/{position}",
+    tag = "positions",
+    params(("position" = String, Path, description = "A firmware position or a custom label")),
+    responses(
+        (status = 200, body = serde_json::Value),
+        (status = 404, description = "Unknown or uncalibrated position", body = ErrorBody),
+    ),
+)]
+pub async fn delete_calibration(
+    Path(position): Path<String>,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    let mut heights = app_state.position_heights.lock().await;
+    let removed = match position.parse::<SvenPosition>() {
+        Ok(fixed) => heights
+            .heights
+            .remove(&fixed)
+            .map(|_| PositionName::Fixed(fixed)),
+        Err(_) => {
+            let saved = heights.label(&position).map(|(saved, _)| saved.to_string());
+            saved.and_then(|saved| {
+                heights.labels.remove(&saved)?;
+                Some(PositionName::Label(saved))
+            })
+        }
+    };
+    let Some(name) = removed else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({"error": format!("{} is not calibrated", position)})),
+        );
+    };
+    persist(&app_state, &heights);
+    drop(heights);
+    info!("Removed calibration of {}", name);
+    app_state.refresh_state_cache().await;
+    let mut response = describe(&name);
+    response["status"] = "Calibration removed".into();
+    (StatusCode::OK, Json(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_share_the_calibration_table() {
+        let stored = r#"{"Standing": 1100, "Drafting": 950, "Kinder": 700}"#;
+        let heights: PositionHeights = serde_json::from_str(stored).unwrap();
+        assert_eq!(heights.heights[&SvenPosition::Standing], 1100);
+        assert_eq!(heights.label("drafting"), Some(("Drafting", 950)));
+        assert_eq!(
+            serde_json::to_value(&heights).unwrap(),
+            serde_json::from_str::<serde_json::Value>(stored).unwrap()
+        );
+
+        assert_eq!(
+            heights.command_for("KINDER"),
+            Some(DeskCommand {
+                command: SvenCommand::AbsoluteHeight,
+                value: 700,
+            })
+        );
+        assert_eq!(
+            heights
+                .command_for("standing")
+                .map(|command| command.command),
+            Some(SvenCommand::Position)
+        );
+        assert_eq!(heights.command_for("Lounge"), None);
+        assert_eq!(heights.label_at(955, 10), Some("Drafting"));
+        assert_eq!(heights.label_at(1000, 10), None);
+
+        assert_eq!(
+            "top".parse::<PositionName>(),
+            Ok(PositionName::Fixed(SvenPosition::Top))
+        );
+        assert!(" ".parse::<PositionName>().is_err());
+        assert_eq!(
+            "Zeichnen ✏".parse::<PositionName>(),
+            Ok(PositionName::Label("Zeichnen ✏".to_string()))
+        );
+    }
+}
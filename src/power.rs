@@ -0,0 +1,76 @@
+use axum::{Json, http::StatusCode};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tracing::{error, info};
+use utoipa::ToSchema;
+
+use crate::AppState;
+use crate::transport::MessageClass;
+
+/// How long to wait for the controller to report it's awake after a wake request.
+const WAKE_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum PowerState {
+    Unknown,
+    Active,
+    Standby,
+}
+
+impl PowerState {
+    /// Parses a power report, either a bare word or a JSON string.
+    pub fn parse(payload: &[u8]) -> Option<PowerState> {
+        let text = std::str::from_utf8(payload).ok()?.trim();
+        let text = text.trim_matches('"');
+        match text.to_ascii_lowercase().as_str() {
+            "on" | "active" | "awake" => Some(PowerState::Active),
+            "standby" | "sleep" | "off" => Some(PowerState::Standby),
+            _ => None,
+        }
+    }
+}
+
+/// Makes sure the motor controller is awake before a movement is published:
+/// wakes it when auto-wake is enabled, otherwise rejects the command.
+pub async fn ensure_awake(
+    app_state: &AppState,
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    if *app_state.power_tx.borrow() != PowerState::Standby {
+        return Ok(());
+    }
+
+    let standby_error = |message: &str| {
+        (
+            StatusCode::CONFLICT,
+            Json(serde_json::json!({"error": message, "power": PowerState::Standby})),
+        )
+    };
+
+    if !app_state.config().auto_wake {
+        return Err(standby_error("Desk is in standby"));
+    }
+
+    info!("Desk is in standby, waking it before moving");
+    let mut power_rx = app_state.power_tx.subscribe();
+    if let Err(e) = app_state
+        .publish(
+            MessageClass::Command,
+            &app_state.config().power_command_topic,
+            "wake",
+        )
+        .await
+    {
+        error!("Failed to publish wake request: {}", e);
+        return Err(e.response("Desk is in standby and could not be woken", None));
+    }
+
+    match tokio::time::timeout(
+        WAKE_TIMEOUT,
+        power_rx.wait_for(|power| *power != PowerState::Standby),
+    )
+    .await
+    {
+        Ok(Ok(_)) => Ok(()),
+        _ => Err(standby_error("Desk did not wake up from standby")),
+    }
+}
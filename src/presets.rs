@@ -0,0 +1,285 @@
+use axum::{
+    Json,
+    extract::{ConnectInfo, Extension, Path, Query},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tracing::{error, info};
+use utoipa::ToSchema;
+
+use crate::config::Config;
+use crate::listen::ClientAddr;
+use crate::listing::{Collection, ListQuery};
+use crate::persistence::Artifact;
+use crate::validation::ErrorBody;
+use crate::{AppState, CommandParams, CommandRequest, DeskCommand, SvenCommand, positions, users};
+
+/// Longest accepted preset name.
+const MAX_NAME_LEN: usize = 64;
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Preset {
+    pub name: String,
+    pub height_mm: u32,
+    pub created_at: DateTime<Utc>,
+    /// The user whose own height this is, unset for shared presets
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+}
+
+/// User defined named heights, besides the firmware's fixed positions.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Presets(pub BTreeMap<String, Preset>);
+
+impl Artifact for Presets {
+    const KEY: &'static str = "presets";
+}
+
+impl Presets {
+    pub fn named_heights(&self) -> impl Iterator<Item = (String, u32)> + '_ {
+        self.0
+            .values()
+            .map(|preset| (preset.name.clone(), preset.height_mm))
+    }
+}
+
+/// The trimmed preset name, or why `name` and `height_mm` can't be saved.
+pub fn check_preset(
+    config: &Config,
+    name: &str,
+    height_mm: u32,
+) -> Result<String, (StatusCode, Json<serde_json::Value>)> {
+    let name = name.trim();
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(serde_json::json!({
+                "error": format!("Preset names must be 1 to {} characters", MAX_NAME_LEN)
+            })),
+        ));
+    }
+    if !config.height_in_bounds(height_mm) {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(serde_json::json!({
+                "error": format!(
+                    "height_mm must be between {} and {}, got {}",
+                    config.min_height_mm, config.max_height_mm, height_mm
+                )
+            })),
+        ));
+    }
+    Ok(name.to_string())
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SavePresetRequest {
+    pub name: String,
+    pub height_mm: u32,
+}
+
+const PRESETS: Collection = Collection {
+    fields: &["name", "height_mm", "user", "created_at"],
+    timestamp: Some("created_at"),
+};
+
+/// The shared presets, with the acting user's own heights in place of the shared
+/// ones they replace.
+#[utoipa::path(
+    get,
+    path = "/api/sven/presets",
+    tag = "presets",
+    params(ListQuery),
+    responses(
+        (status = 200, body = Vec<Preset>),
+        (status = 400, description = "Invalid query", body = ErrorBody),
+    ),
+)]
+pub async fn list_presets(
+    Query(params): Query<BTreeMap<String, String>>,
+    headers: HeaderMap,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    let user = users::acting_user(&app_state, &headers).await;
+    let mut presets = app_state.presets.lock().await.0.clone();
+    if let Some(user) = user
+        && let Some(user) = app_state.users.lock().await.0.get(&user)
+    {
+        presets.extend(user.presets.clone());
+    }
+    PRESETS.respond(presets.into_values(), &params, serde_json::Value::Array)
+}
+
+/// Saves a preset, replacing any existing preset with the same name.
+#[utoipa::path(
+    post,
+    path = "/api/sven/presets",
+    tag = "presets",
+    request_body = SavePresetRequest,
+    responses(
+        (status = 201, body = Preset),
+        (status = 409, description = "Duplicates another position's height", body = ErrorBody),
+        (status = 422, description = "Invalid request", body = ErrorBody),
+    ),
+)]
+pub async fn save_preset(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Json(request): Json<SavePresetRequest>,
+) -> impl IntoResponse {
+    let config = &app_state.config();
+    let name = match check_preset(config, &request.name, request.height_mm) {
+        Ok(name) => name,
+        Err(rejection) => return rejection,
+    };
+
+    let heights = app_state.position_heights.lock().await;
+    let mut presets = app_state.presets.lock().await;
+    let existing: Vec<_> = presets
+        .named_heights()
+        .chain(heights.named_heights())
+        .collect();
+    let warning = match positions::check_duplicate(config, existing, &name, request.height_mm) {
+        Ok(warning) => warning,
+        Err(rejection) => return rejection,
+    };
+
+    let preset = Preset {
+        name: name.clone(),
+        height_mm: request.height_mm,
+        created_at: Utc::now(),
+        user: None,
+    };
+    presets.0.insert(name, preset.clone());
+    if let Some(store) = &app_state.store
+        && let Err(e) = store.put(&*presets)
+    {
+        error!("Failed to persist presets: {}", e);
+    }
+    info!("Saved preset {} at {} mm", preset.name, preset.height_mm);
+
+    let mut response = serde_json::json!(preset);
+    if let Some(warning) = warning {
+        response["warning"] = warning;
+    }
+    (StatusCode::CREATED, Json(response))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/sven/presets/{name}",
+    tag = "presets",
+    params(("name" = String, Path)),
+    responses((status = 200, body = serde_json::Value), (status = 404, body = ErrorBody)),
+)]
+pub async fn delete_preset(
+    Path(name): Path<String>,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    let mut presets = app_state.presets.lock().await;
+    if presets.0.remove(&name).is_none() {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({"error": format!("Unknown preset '{}'", name)})),
+        );
+    }
+    if let Some(store) = &app_state.store
+        && let Err(e) = store.put(&*presets)
+    {
+        error!("Failed to persist presets: {}", e);
+    }
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({"status": "Preset deleted", "name": name})),
+    )
+}
+
+/// Moves the desk to the preset's height, the acting user's own height for it if
+/// they saved one. Goes through the regular command path, so rate limits, the
+/// inactivity lock and protected zones apply as usual.
+#[utoipa::path(
+    post,
+    path = "/api/sven/presets/{name}/apply",
+    tag = "presets",
+    params(("name" = String, Path), CommandParams),
+    responses(
+        (status = 200, body = serde_json::Value),
+        (status = 404, body = ErrorBody),
+        (status = 429, description = "Preset is cooling down", body = ErrorBody),
+    ),
+)]
+pub async fn apply_preset(
+    Path(name): Path<String>,
+    connect_info: ConnectInfo<ClientAddr>,
+    headers: HeaderMap,
+    Query(params): Query<CommandParams>,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    let dry_run = params.dry_run();
+    let user = match users::identify(
+        &headers,
+        &app_state.config(),
+        &*app_state.users.lock().await,
+    ) {
+        Ok(user) => user,
+        Err(rejection) => return rejection,
+    };
+    let preset = app_state.users.lock().await.preset_for(
+        user.as_deref(),
+        &name,
+        &*app_state.presets.lock().await,
+    );
+    let Some(preset) = preset else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({"error": format!("Unknown preset '{}'", name)})),
+        );
+    };
+    let mut cooldowns = app_state.preset_cooldowns.lock().await;
+    let cooling_down = if dry_run {
+        cooldowns.remaining(&name).map_or(Ok(()), Err)
+    } else {
+        cooldowns.try_activate(&name)
+    };
+    drop(cooldowns);
+    if let Err(remaining) = cooling_down {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            Json(serde_json::json!({
+                "error": format!("Preset {} is cooling down", name),
+                "retry_after_ms": remaining.as_millis() as u64,
+            })),
+        );
+    }
+
+    info!(
+        "Applying preset {} ({} mm{})",
+        preset.name,
+        preset.height_mm,
+        preset
+            .user
+            .as_deref()
+            .map(|user| format!(", {}'s", user))
+            .unwrap_or_default()
+    );
+    let request = CommandRequest {
+        command: DeskCommand {
+            command: SvenCommand::AbsoluteHeight,
+            value: preset.height_mm,
+        },
+        confirm: false,
+        timeout_ms: None,
+        override_protected_zones: false,
+        force: params.force(),
+        ack: None,
+        dry_run,
+        soft_start: true,
+        ttl_ms: None,
+        label: None,
+        pulse: false,
+    };
+    crate::execute_command(&app_state, connect_info.0, &headers, None, request).await
+}
@@ -0,0 +1,222 @@
+use axum::{Json, extract::Extension, http::StatusCode, response::IntoResponse};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::OwnedMutexGuard;
+
+use crate::{AppState, DeskCommand};
+
+/// What happens to a command that arrives while another one is still moving the desk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueuePolicy {
+    /// Refuse it with 409 Conflict
+    Reject,
+    /// Run it once every earlier command has finished
+    Fifo,
+    /// Run only the newest waiting command; older waiting ones are superseded
+    LatestWins,
+}
+
+impl FromStr for QueuePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "reject" => Ok(QueuePolicy::Reject),
+            "fifo" => Ok(QueuePolicy::Fifo),
+            "latest" | "latest-wins" | "latest_wins" => Ok(QueuePolicy::LatestWins),
+            other => Err(format!("unknown command queue policy '{}'", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QueuedCommand {
+    pub request_id: String,
+    pub command: DeskCommand,
+    pub queued_at: DateTime<Utc>,
+    #[serde(skip)]
+    ticket: u64,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct LaneStatus {
+    /// The command currently moving the desk
+    pub active: Option<QueuedCommand>,
+    /// Commands waiting for their turn, oldest first
+    pub waiting: Vec<QueuedCommand>,
+}
+
+/// Serializes the commands of one desk. Tokio's mutex hands out the slot in the
+/// order commands asked for it, which is what makes `Fifo` first-in first-out.
+#[derive(Default)]
+struct Lane {
+    slot: Arc<tokio::sync::Mutex<()>>,
+    status: Mutex<LaneStatus>,
+    last_ticket: Mutex<u64>,
+}
+
+pub enum QueueRejection {
+    /// Another command is moving the desk and the policy is `Reject`
+    Busy { active: Option<QueuedCommand> },
+    /// A newer command arrived while this one was waiting
+    Superseded,
+    /// The command's turn didn't come within its timeout
+    Timeout,
+    /// The command's TTL ran out while it was waiting
+    Expired,
+}
+
+impl QueueRejection {
+    pub fn into_response(self) -> (StatusCode, Json<serde_json::Value>) {
+        let body = match self {
+            QueueRejection::Busy { active } => serde_json::json!({
+                "error": "Another command is still in progress",
+                "code": "command_in_progress",
+                "active": active,
+            }),
+            QueueRejection::Superseded => serde_json::json!({
+                "error": "Superseded by a newer command while waiting",
+                "code": "command_superseded",
+            }),
+            QueueRejection::Timeout => serde_json::json!({
+                "error": "Timed out waiting for earlier commands to finish",
+                "code": "queue_timeout",
+            }),
+            QueueRejection::Expired => serde_json::json!({
+                "error": "The command expired while waiting for earlier commands to finish",
+                "code": "command_expired",
+            }),
+        };
+        (StatusCode::CONFLICT, Json(body))
+    }
+}
+
+/// Holds a desk's slot; the next queued command runs once this is dropped.
+pub struct QueueSlot {
+    _guard: OwnedMutexGuard<()>,
+    lane: Arc<Lane>,
+}
+
+impl Drop for QueueSlot {
+    fn drop(&mut self) {
+        self.lane.status.lock().unwrap().active = None;
+    }
+}
+
+/// One lane per desk, the primary desk under the empty key.
+///
+/// Uses blocking mutexes for the status because it is cleared from `Drop`.
+pub struct CommandQueue {
+    policy: QueuePolicy,
+    lanes: Mutex<BTreeMap<String, Arc<Lane>>>,
+}
+
+impl CommandQueue {
+    pub fn new(policy: QueuePolicy) -> Self {
+        CommandQueue {
+            policy,
+            lanes: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn lane(&self, desk: Option<&str>) -> Arc<Lane> {
+        self.lanes
+            .lock()
+            .unwrap()
+            .entry(desk.unwrap_or_default().to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Waits for `desk` to be free, according to the policy, waiting at most
+    /// `timeout` and no longer than until the command `expires_at`.
+    pub async fn acquire(
+        &self,
+        desk: Option<&str>,
+        request_id: &str,
+        command: &DeskCommand,
+        timeout: Duration,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<QueueSlot, QueueRejection> {
+        let lane = self.lane(desk);
+        let ticket = {
+            let mut last_ticket = lane.last_ticket.lock().unwrap();
+            *last_ticket += 1;
+            *last_ticket
+        };
+        let entry = QueuedCommand {
+            request_id: request_id.to_string(),
+            command: command.clone(),
+            queued_at: Utc::now(),
+            ticket,
+        };
+
+        let guard = if self.policy == QueuePolicy::Reject {
+            match lane.slot.clone().try_lock_owned() {
+                Ok(guard) => guard,
+                Err(_) => {
+                    let active = lane.status.lock().unwrap().active.clone();
+                    return Err(QueueRejection::Busy { active });
+                }
+            }
+        } else {
+            let until_expiry = expires_at.map(|at| (at - Utc::now()).to_std().unwrap_or_default());
+            let expires_first = until_expiry.is_some_and(|left| left < timeout);
+            let wait = until_expiry.map_or(timeout, |left| left.min(timeout));
+            lane.status.lock().unwrap().waiting.push(entry.clone());
+            let acquired = tokio::time::timeout(wait, lane.slot.clone().lock_owned()).await;
+            let mut status = lane.status.lock().unwrap();
+            status.waiting.retain(|waiting| waiting.ticket != ticket);
+            let Ok(guard) = acquired else {
+                return Err(if expires_first {
+                    QueueRejection::Expired
+                } else {
+                    QueueRejection::Timeout
+                });
+            };
+            if self.policy == QueuePolicy::LatestWins && *lane.last_ticket.lock().unwrap() != ticket
+            {
+                return Err(QueueRejection::Superseded);
+            }
+            guard
+        };
+
+        lane.status.lock().unwrap().active = Some(entry);
+        Ok(QueueSlot {
+            _guard: guard,
+            lane,
+        })
+    }
+
+    fn status(&self) -> BTreeMap<String, LaneStatus> {
+        self.lanes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(desk, lane)| {
+                let name = if desk.is_empty() { "primary" } else { desk };
+                (name.to_string(), lane.status.lock().unwrap().clone())
+            })
+            .collect()
+    }
+}
+
+/// The queue policy and, per desk, the command in progress and those waiting.
+#[utoipa::path(
+    get,
+    path = "/api/sven/queue",
+    tag = "desk",
+    responses((status = 200, body = serde_json::Value)),
+)]
+pub async fn get_queue(Extension(app_state): Extension<Arc<AppState>>) -> impl IntoResponse {
+    let queue = &app_state.command_queue;
+    Json(serde_json::json!({
+        "policy": queue.policy,
+        "desks": queue.status(),
+    }))
+}
@@ -0,0 +1,230 @@
+//! One-button quick actions: `POST /api/sven/quick` moves the desk to whatever
+//! fits the time of day, picked from rules like weekday mornings to Standing and
+//! after lunch to Armrest, so a single stream deck or smart button always does
+//! the right thing. The rules are set with `PUT /api/sven/quick` and stored.
+
+use axum::{
+    Json,
+    extract::{ConnectInfo, Extension, Query},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use chrono::{Datelike, Local, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::{error, info};
+use utoipa::ToSchema;
+
+use crate::listen::ClientAddr;
+use crate::persistence::Artifact;
+use crate::quiet_hours::QuietWindow;
+use crate::schedules::{self, ScheduleAction, parse_days};
+use crate::validation::ErrorBody;
+use crate::{AppState, CommandParams, CommandRequest};
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct QuickRule {
+    /// Local `HH:MM-HH:MM` window; one ending before it starts spans midnight
+    pub window: String,
+    /// `daily`, `weekdays`, `weekends` or a list like `mon,wed,fri`, counted from
+    /// the day the button is pressed
+    #[serde(default = "daily")]
+    pub days: String,
+    pub action: ScheduleAction,
+}
+
+fn daily() -> String {
+    "daily".to_string()
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct QuickActions {
+    /// Checked in order, the first one matching wins
+    pub rules: Vec<QuickRule>,
+    /// What the button does when no rule matches; nothing without one
+    pub default: Option<ScheduleAction>,
+}
+
+impl Artifact for QuickActions {
+    const KEY: &'static str = "quick_actions";
+}
+
+impl QuickActions {
+    /// The action for `now`, with the index of the rule that picked it, absent
+    /// when it's the default.
+    pub fn pick(&self, now: NaiveDateTime) -> Option<(Option<usize>, &ScheduleAction)> {
+        let matches = |rule: &QuickRule| {
+            let in_window = rule
+                .window
+                .parse::<QuietWindow>()
+                .is_ok_and(|window| window.contains(now.time()));
+            in_window && parse_days(&rule.days).is_ok_and(|days| days.contains(&now.weekday()))
+        };
+        match self.rules.iter().position(matches) {
+            Some(index) => Some((Some(index), &self.rules[index].action)),
+            None => self.default.as_ref().map(|action| (None, action)),
+        }
+    }
+}
+
+fn validate(quick: &QuickActions) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    for (index, rule) in quick.rules.iter().enumerate() {
+        let problem = rule
+            .window
+            .parse::<QuietWindow>()
+            .err()
+            .or_else(|| parse_days(&rule.days).err());
+        if let Some(problem) = problem {
+            return Err((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(serde_json::json!(ErrorBody {
+                    error: format!("Rule {}: {}", index + 1, problem),
+                    code: Some("invalid_quick_action".to_string()),
+                })),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The quick action rules, and what the button would do right now as `current`.
+#[utoipa::path(
+    get,
+    path = "/api/sven/quick",
+    tag = "presets",
+    responses((status = 200, body = serde_json::Value)),
+)]
+pub async fn get_quick_actions(
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    let quick = app_state.quick_actions.lock().await;
+    let current = quick
+        .pick(Local::now().naive_local())
+        .map(|(rule, action)| serde_json::json!({"rule": rule, "action": action}));
+    let mut body = serde_json::json!(*quick);
+    body["current"] = current.into();
+    Json(body)
+}
+
+/// Replaces the quick action rules.
+#[utoipa::path(
+    put,
+    path = "/api/sven/quick",
+    tag = "presets",
+    request_body = QuickActions,
+    responses(
+        (status = 200, body = QuickActions),
+        (status = 422, description = "Invalid window or days", body = ErrorBody),
+    ),
+)]
+pub async fn set_quick_actions(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Json(quick): Json<QuickActions>,
+) -> impl IntoResponse {
+    if let Err(rejection) = validate(&quick) {
+        return rejection;
+    }
+    if let Some(store) = &app_state.store
+        && let Err(e) = store.put(&quick)
+    {
+        error!("Failed to persist quick actions: {}", e);
+    }
+    *app_state.quick_actions.lock().await = quick.clone();
+    (StatusCode::OK, Json(serde_json::json!(quick)))
+}
+
+/// Moves the desk as the rule for the current local time says. Goes through the
+/// regular command path like a preset.
+#[utoipa::path(
+    post,
+    path = "/api/sven/quick",
+    tag = "presets",
+    params(CommandParams),
+    responses(
+        (status = 200, body = serde_json::Value),
+        (status = 409, description = "No rule matches and there is no default, or its preset is gone", body = ErrorBody),
+    ),
+)]
+pub async fn run_quick_action(
+    connect_info: ConnectInfo<ClientAddr>,
+    headers: HeaderMap,
+    Query(params): Query<CommandParams>,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    let conflict = |error: String| {
+        (
+            StatusCode::CONFLICT,
+            Json(serde_json::json!(ErrorBody {
+                error,
+                code: Some("no_quick_action".to_string()),
+            })),
+        )
+    };
+    let picked = app_state
+        .quick_actions
+        .lock()
+        .await
+        .pick(Local::now().naive_local())
+        .map(|(rule, action)| (rule, action.clone()));
+    let Some((rule, action)) = picked else {
+        return conflict("No quick action rule matches now and there is no default".to_string());
+    };
+    let command = match schedules::resolve(&app_state, &action).await {
+        Ok(command) => command,
+        Err(e) => return conflict(format!("The quick action can't run: {}", e)),
+    };
+    info!("Quick action {:?} (rule {:?})", action, rule);
+    let request = CommandRequest {
+        command,
+        confirm: false,
+        timeout_ms: None,
+        override_protected_zones: false,
+        force: params.force(),
+        ack: None,
+        dry_run: params.dry_run(),
+        soft_start: true,
+        ttl_ms: None,
+        label: None,
+        pulse: false,
+    };
+    let (status, Json(mut body)) =
+        crate::execute_command(&app_state, connect_info.0, &headers, None, request).await;
+    body["quick_action"] = serde_json::json!({"rule": rule, "action": action});
+    (status, Json(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SvenPosition;
+
+    #[test]
+    fn the_first_matching_rule_wins() {
+        let rule = |window: &str, days: &str, position| QuickRule {
+            window: window.to_string(),
+            days: days.to_string(),
+            action: ScheduleAction::Position(position),
+        };
+        let quick = QuickActions {
+            rules: vec![
+                rule("06:00-12:00", "weekdays", SvenPosition::Standing),
+                rule("12:00-14:00", "daily", SvenPosition::Armrest),
+                rule("06:00-14:00", "daily", SvenPosition::Top),
+            ],
+            default: Some(ScheduleAction::HeightMm(750)),
+        };
+        // 2026-10-14 is a Wednesday, 2026-10-17 a Saturday
+        let at = |date: &str| NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M").unwrap();
+        let picked = |date| quick.pick(at(date)).map(|(rule, _)| rule);
+
+        assert_eq!(picked("2026-10-14 09:30"), Some(Some(0)));
+        assert_eq!(picked("2026-10-14 12:30"), Some(Some(1)));
+        assert_eq!(picked("2026-10-17 09:30"), Some(Some(2)));
+        assert_eq!(picked("2026-10-14 20:00"), Some(None));
+        assert!(
+            QuickActions::default()
+                .pick(at("2026-10-14 09:30"))
+                .is_none()
+        );
+    }
+}
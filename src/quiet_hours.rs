@@ -0,0 +1,107 @@
+//! Quiet hours: local time windows, `SVEN_QUIET_HOURS=22:00-07:00`, during which
+//! the desk only moves when someone asks for it explicitly. Schedules, rules,
+//! integrations and the night mode skip their moves, and commands from the API
+//! are refused unless they are sent with `force: true`. Stops always go through.
+
+use axum::{Json, http::StatusCode};
+use chrono::NaiveTime;
+use serde::Serialize;
+use std::str::FromStr;
+
+use crate::schedules::parse_time;
+
+/// A `HH:MM-HH:MM` window; one ending before it starts spans midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct QuietWindow {
+    #[serde(serialize_with = "hours_minutes")]
+    pub from: NaiveTime,
+    #[serde(serialize_with = "hours_minutes")]
+    pub to: NaiveTime,
+}
+
+fn hours_minutes<S: serde::Serializer>(time: &NaiveTime, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&time.format("%H:%M"))
+}
+
+impl QuietWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.from <= self.to {
+            self.from <= time && time < self.to
+        } else {
+            time >= self.from || time < self.to
+        }
+    }
+}
+
+impl FromStr for QuietWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (from, to) = s
+            .split_once('-')
+            .ok_or_else(|| format!("expected HH:MM-HH:MM, got '{}'", s))?;
+        let window = QuietWindow {
+            from: parse_time(from)?,
+            to: parse_time(to)?,
+        };
+        if window.from == window.to {
+            return Err(format!("quiet hours {} are empty", s));
+        }
+        Ok(window)
+    }
+}
+
+impl std::fmt::Display for QuietWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.from.format("%H:%M"),
+            self.to.format("%H:%M")
+        )
+    }
+}
+
+/// The window `time` falls in, if any.
+pub fn active(windows: &[QuietWindow], time: NaiveTime) -> Option<&QuietWindow> {
+    windows.iter().find(|window| window.contains(time))
+}
+
+/// The answer to a command sent during `window` without `force`.
+pub fn refused(window: &QuietWindow) -> (StatusCode, Json<serde_json::Value>) {
+    (
+        StatusCode::CONFLICT,
+        Json(serde_json::json!({
+            "error": format!(
+                "Quiet hours until {}, send the command with force to move anyway",
+                window.to.format("%H:%M")
+            ),
+            "code": "quiet_hours",
+            "quiet_hours": window,
+        })),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> NaiveTime {
+        parse_time(time).unwrap()
+    }
+
+    #[test]
+    fn windows_can_span_midnight() {
+        let windows: Vec<QuietWindow> = ["22:00-07:00", "12:30-13:00"]
+            .iter()
+            .map(|window| window.parse().unwrap())
+            .collect();
+        assert!(active(&windows, at("06:00")).is_some());
+        assert!(active(&windows, at("23:15")).is_some());
+        assert_eq!(active(&windows, at("12:45")), Some(&windows[1]));
+        assert!(active(&windows, at("07:00")).is_none());
+        assert!(active(&windows, at("13:00")).is_none());
+        assert!("07:00-07:00".parse::<QuietWindow>().is_err());
+        assert!("22:00".parse::<QuietWindow>().is_err());
+    }
+}
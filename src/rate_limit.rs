@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Buckets untouched for this long are forgotten.
+const IDLE_EXPIRY: Duration = Duration::from_secs(600);
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+pub struct RateLimited {
+    pub limit_per_minute: u32,
+    pub retry_after: Duration,
+}
+
+/// Token bucket limiter keyed by client. Each client may burst up to its
+/// per-minute limit and refills continuously at that rate.
+pub struct RateLimiter {
+    default_per_minute: u32,
+    overrides: HashMap<String, u32>,
+    buckets: HashMap<String, Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(default_per_minute: u32, overrides: HashMap<String, u32>) -> Self {
+        RateLimiter {
+            default_per_minute,
+            overrides,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// The limit that applies to `key`, 0 meaning unlimited.
+    pub fn limit_for(&self, key: &str) -> u32 {
+        self.overrides
+            .get(key)
+            .copied()
+            .unwrap_or(self.default_per_minute)
+    }
+
+    pub fn check(&mut self, key: &str) -> Result<(), RateLimited> {
+        let limit = self.limit_for(key);
+        if limit == 0 {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        self.buckets
+            .retain(|_, bucket| now.duration_since(bucket.updated) < IDLE_EXPIRY);
+
+        let per_second = f64::from(limit) / 60.0;
+        let bucket = self.buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: f64::from(limit),
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_second).min(f64::from(limit));
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(RateLimited {
+                limit_per_minute: limit,
+                retry_after: Duration::from_secs_f64((1.0 - bucket.tokens) / per_second),
+            })
+        }
+    }
+
+    /// Gives back the request `check` took from `key`, for requests that only
+    /// count when they fail.
+    pub fn refund(&mut self, key: &str) {
+        let limit = self.limit_for(key);
+        if let Some(bucket) = self.buckets.get_mut(key) {
+            bucket.tokens = (bucket.tokens + 1.0).min(f64::from(limit));
+        }
+    }
+}
@@ -0,0 +1,101 @@
+//! Applying changes to the config file without restarting, on
+//! `POST /api/admin/config/reload` or SIGHUP. Height limits, credentials, CORS
+//! origins, the command topics and schedule settings take effect right away; for
+//! anything else the answer lists the settings that need a restart.
+
+use axum::{
+    Json,
+    extract::Extension,
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+use crate::config::{Config, DEFAULT_CONFIG_FILE, Reloaded};
+use crate::validation::ErrorBody;
+use crate::{AppState, admin};
+
+/// Reads the config file again and swaps in what can change while running.
+async fn reload(app_state: &AppState) -> Result<Reloaded, String> {
+    let current = app_state.config();
+    // A config file created since startup is picked up where startup would look
+    let path = current
+        .config_file
+        .clone()
+        .or_else(|| Some(PathBuf::from(DEFAULT_CONFIG_FILE)).filter(|path| path.exists()));
+    let (config, outcome) = current.reload(Config::load_from(path)?);
+    let users_changed = config.users != current.users;
+    *app_state.config.write().unwrap() = Arc::new(config);
+
+    if users_changed {
+        let mut users = app_state.users.lock().await;
+        *users = std::mem::take(&mut *users).with_configured(&app_state.config());
+    }
+    if !outcome.applied.is_empty() {
+        app_state.refresh_state_cache().await;
+        info!("Reloaded configuration: {}", outcome.applied.join(", "));
+    }
+    if !outcome.restart_required.is_empty() {
+        warn!(
+            "Restart to apply the changed settings: {}",
+            outcome.restart_required.join(", ")
+        );
+    }
+    Ok(outcome)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/admin/config/reload",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Settings applied and settings that need a restart", body = serde_json::Value),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+        (status = 403, description = "Admin routes are disabled", body = ErrorBody),
+        (status = 422, description = "The config file can't be read, nothing was changed", body = ErrorBody),
+    ),
+)]
+pub async fn handle_reload(
+    headers: HeaderMap,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    if let Err(rejection) = admin::require_admin(&headers, &app_state.config()) {
+        return rejection;
+    }
+    match reload(&app_state).await {
+        Ok(outcome) => (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "status": "Configuration reloaded",
+                "applied": outcome.applied,
+                "restart_required": outcome.restart_required,
+            })),
+        ),
+        Err(e) => (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(serde_json::json!(ErrorBody {
+                error: format!("Invalid configuration: {}", e),
+                code: Some("invalid_config".to_string()),
+            })),
+        ),
+    }
+}
+
+/// Reloads the configuration on every SIGHUP.
+pub async fn on_hangup(app_state: Arc<AppState>) {
+    #[cfg(unix)]
+    {
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+            .expect("Failed to install SIGHUP handler");
+        while hangup.recv().await.is_some() {
+            info!("SIGHUP received, reloading the configuration");
+            if let Err(e) = reload(&app_state).await {
+                error!("Keeping the current configuration: {}", e);
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = app_state;
+}
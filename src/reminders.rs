@@ -0,0 +1,225 @@
+use axum::{Json, extract::Extension, http::StatusCode, response::IntoResponse};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+use utoipa::ToSchema;
+
+use crate::AppState;
+use crate::notifications::NotificationKind;
+use crate::persistence::Artifact;
+use crate::transport::MessageClass;
+use crate::validation::ErrorBody;
+
+/// How often the desk height is checked for sitting time.
+const TICK: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct ReminderSettings {
+    pub enabled: bool,
+    /// The desk counts as a sitting desk below this height
+    pub sitting_below_mm: u32,
+    /// Sitting time after which the reminder fires
+    pub max_sitting_minutes: u64,
+    /// How long to wait before reminding again while still sitting
+    pub repeat_minutes: u64,
+    /// Receives a JSON POST for every reminder
+    pub webhook_url: Option<String>,
+    /// Receives the same JSON as an MQTT message
+    pub mqtt_topic: Option<String>,
+    /// Daily standing time to aim for, notified as `goal_met` once reached
+    pub standing_goal_minutes: Option<u64>,
+}
+
+impl Default for ReminderSettings {
+    fn default() -> Self {
+        ReminderSettings {
+            enabled: false,
+            sitting_below_mm: 900,
+            max_sitting_minutes: 45,
+            repeat_minutes: 15,
+            webhook_url: None,
+            mqtt_topic: None,
+            standing_goal_minutes: None,
+        }
+    }
+}
+
+impl Artifact for ReminderSettings {
+    const KEY: &'static str = "reminders";
+}
+
+/// Settings plus the sitting session being tracked.
+#[derive(Debug, Default)]
+pub struct Reminders {
+    pub settings: ReminderSettings,
+    sitting_since: Option<DateTime<Utc>>,
+    last_fired: Option<DateTime<Utc>>,
+}
+
+impl Reminders {
+    pub fn new(settings: ReminderSettings) -> Self {
+        Reminders {
+            settings,
+            ..Default::default()
+        }
+    }
+
+    /// Updates the sitting session for the current height and returns how long the
+    /// desk has been sitting when a reminder is due.
+    fn observe(&mut self, height_mm: u32, now: DateTime<Utc>) -> Option<chrono::Duration> {
+        if !self.settings.enabled || height_mm >= self.settings.sitting_below_mm {
+            self.sitting_since = None;
+            self.last_fired = None;
+            return None;
+        }
+        let sitting_since = *self.sitting_since.get_or_insert(now);
+        let sitting = now - sitting_since;
+        if sitting < chrono::Duration::minutes(self.settings.max_sitting_minutes as i64) {
+            return None;
+        }
+        let repeat = chrono::Duration::minutes(self.settings.repeat_minutes.max(1) as i64);
+        if self.last_fired.is_some_and(|last| now - last < repeat) {
+            return None;
+        }
+        self.last_fired = Some(now);
+        Some(sitting)
+    }
+
+    fn status(&self) -> serde_json::Value {
+        serde_json::json!({
+            "settings": self.settings,
+            "sitting_since": self.sitting_since,
+            "last_fired": self.last_fired,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Reminder {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    sitting_minutes: i64,
+    height_mm: u32,
+    timestamp: DateTime<Utc>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/sven/reminders",
+    tag = "reminders",
+    responses(
+        (status = 200, description = "Settings and the sitting session being tracked", body = serde_json::Value),
+    ),
+)]
+pub async fn get_reminders(Extension(app_state): Extension<Arc<AppState>>) -> impl IntoResponse {
+    Json(app_state.reminders.lock().await.status())
+}
+
+/// Replaces the reminder settings; fields left out take their defaults.
+#[utoipa::path(
+    put,
+    path = "/api/sven/reminders",
+    tag = "reminders",
+    request_body = ReminderSettings,
+    responses(
+        (status = 200, body = serde_json::Value),
+        (status = 422, description = "Invalid request", body = ErrorBody),
+    ),
+)]
+pub async fn set_reminders(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Json(settings): Json<ReminderSettings>,
+) -> impl IntoResponse {
+    if settings.max_sitting_minutes == 0 {
+        return (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(serde_json::json!({"error": "max_sitting_minutes must be at least 1"})),
+        );
+    }
+    if let Some(url) = &settings.webhook_url
+        && reqwest::Url::parse(url).is_err()
+    {
+        return (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(serde_json::json!({"error": format!("Invalid webhook_url '{}'", url)})),
+        );
+    }
+
+    if let Some(store) = &app_state.store
+        && let Err(e) = store.put(&settings)
+    {
+        error!("Failed to persist reminder settings: {}", e);
+    }
+    let mut reminders = app_state.reminders.lock().await;
+    reminders.settings = settings;
+    info!("Reminder settings updated: {:?}", reminders.settings);
+    (StatusCode::OK, Json(reminders.status()))
+}
+
+async fn deliver(app_state: &AppState, settings: &ReminderSettings, reminder: &Reminder) {
+    let payload = serde_json::to_vec(reminder).unwrap();
+    if let Some(url) = &settings.webhook_url {
+        let sent = app_state
+            .http_client
+            .post(url)
+            .json(reminder)
+            .timeout(Duration::from_secs(10))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(e) = sent {
+            warn!("Reminder webhook {} failed: {}", url, e);
+        }
+    }
+    if let Some(topic) = &settings.mqtt_topic {
+        let options = app_state
+            .config()
+            .publish_classes
+            .options(MessageClass::Command);
+        let published = app_state.transport.publish(topic, payload, options).await;
+        if let Err(e) = published {
+            warn!("Failed to publish reminder on {}: {}", topic, e);
+        }
+    }
+}
+
+/// Watches the primary desk's height and sends a reminder when it has been
+/// sitting for too long.
+pub async fn run_reminders(app_state: Arc<AppState>) {
+    loop {
+        tokio::time::sleep(TICK).await;
+        let height_mm = app_state.sven_state.read().await.height_mm;
+        let now = Utc::now();
+        let (sitting, settings) = {
+            let mut reminders = app_state.reminders.lock().await;
+            let Some(sitting) = reminders.observe(height_mm, now) else {
+                continue;
+            };
+            (sitting, reminders.settings.clone())
+        };
+
+        let reminder = Reminder {
+            kind: "sitting_reminder",
+            sitting_minutes: sitting.num_minutes(),
+            height_mm,
+            timestamp: now,
+        };
+        info!(
+            "Sitting for {} minutes, sending a reminder",
+            reminder.sitting_minutes
+        );
+        app_state.notifier.notify(
+            NotificationKind::Reminder {
+                sitting_minutes: reminder.sitting_minutes,
+            },
+            format!(
+                "Sitting for {} minutes, time to stand up",
+                reminder.sitting_minutes
+            ),
+        );
+        deliver(&app_state, &settings, &reminder).await;
+    }
+}
@@ -0,0 +1,62 @@
+//! `X-Request-Id` handling. A client may send its own id, otherwise one is made
+//! up; either way it tags the request's log lines, comes back on the response,
+//! and is the `id` of any command the request publishes, so a desk movement in
+//! the MQTT traffic or the history leads back to the request that caused it.
+
+use axum::{
+    extract::Request,
+    http::{HeaderMap, HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+
+pub const HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest client supplied id that is kept; longer ones are replaced.
+const MAX_LEN: usize = 128;
+
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_LEN
+        && id
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"-_.:".contains(&byte))
+}
+
+pub fn generate() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+/// The request's id: the `X-Request-Id` it came with, if usable, or a new one.
+pub fn from_headers(headers: &HeaderMap) -> String {
+    headers
+        .get(HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| is_valid(id))
+        .map(str::to_string)
+        .unwrap_or_else(generate)
+}
+
+/// Settles the request's id before anything else sees the request, so handlers
+/// and the trace span read the same one, and echoes it on the response.
+pub async fn propagate(mut request: Request, next: Next) -> Response {
+    let id = from_headers(request.headers());
+    let value = HeaderValue::from_str(&id).expect("request ids are visible ASCII");
+    request.headers_mut().insert(HEADER, value.clone());
+    let mut response = next.run(request).await;
+    response.headers_mut().insert(HEADER, value);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_plain_ids_are_kept() {
+        assert!(is_valid("4f1c-22:a.b_c"));
+        assert!(!is_valid(""));
+        assert!(!is_valid("has space"));
+        assert!(!is_valid(&"x".repeat(MAX_LEN + 1)));
+    }
+}
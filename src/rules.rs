@@ -0,0 +1,663 @@
+//! Automation rules: a trigger, conditions that all have to hold when it fires,
+//! and actions run in order. Triggers are a local time of day, the desk status or
+//! occupancy changing, a message on an MQTT topic, or `POST
+//! /api/sven/rules/{id}/trigger` for webhooks. Commands go through the same path
+//! as schedules, so they're validated and kept within the soft limits. Actions
+//! can trigger other rules, e.g. a move changes the status, so rules reacting to
+//! the status want a condition that stops them from chaining forever.
+
+use axum::{
+    Json,
+    extract::{Extension, Path, Query},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use chrono::{DateTime, Local, NaiveTime, Utc};
+use rumqttc::QoS;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+use utoipa::ToSchema;
+
+use crate::listing::{Collection, ListQuery};
+use crate::notifications::NotificationKind;
+use crate::occupancy::Occupancy;
+use crate::persistence::Artifact;
+use crate::schedules::{self, parse_days, parse_time};
+use crate::status::DeskStatus;
+use crate::topics::TopicPattern;
+use crate::validation::{self, ErrorBody};
+use crate::{AppState, DeskCommand, SvenCommand};
+
+/// How often the runner looks for due time triggers.
+const TICK: Duration = Duration::from_secs(15);
+
+/// What makes a rule fire.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Trigger {
+    /// Local time of day, with `days` and `time` written as for schedules
+    Time { days: String, time: String },
+    /// The desk status changed to `status`
+    Status { status: DeskStatus },
+    /// The presence sensor changed to `occupancy`
+    Occupancy { occupancy: Occupancy },
+    /// A message on `topic`, which may contain wildcards, optionally only with
+    /// exactly this payload
+    Mqtt {
+        topic: String,
+        payload: Option<String>,
+    },
+    /// `POST /api/sven/rules/{id}/trigger`
+    Webhook,
+}
+
+/// Checked when the trigger fires; the rule only acts when all of them hold.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Condition {
+    /// The desk is between the two heights, inclusive
+    HeightBetween {
+        min_mm: u32,
+        max_mm: u32,
+    },
+    Status {
+        status: DeskStatus,
+    },
+    Occupancy {
+        occupancy: Occupancy,
+    },
+    /// Local time, `HH:MM`; a window like `22:00` to `06:00` spans midnight
+    TimeBetween {
+        from: String,
+        to: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RuleAction {
+    Command {
+        command: SvenCommand,
+        value: u32,
+    },
+    /// A saved preset, looked up when the rule fires
+    Preset {
+        name: String,
+    },
+    /// An `automation` notification
+    Notify {
+        message: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Rule {
+    pub id: String,
+    pub name: String,
+    pub trigger: Trigger,
+    pub conditions: Vec<Condition>,
+    pub actions: Vec<RuleAction>,
+    pub paused: bool,
+    pub created_at: DateTime<Utc>,
+    /// When the trigger last fired, whether or not the conditions held
+    pub last_triggered: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Rules(pub BTreeMap<String, Rule>);
+
+impl Artifact for Rules {
+    const KEY: &'static str = "rules";
+}
+
+impl Rules {
+    /// Topics the MQTT triggers listen on, to subscribe to after connecting.
+    pub fn filters(&self) -> Vec<(String, QoS)> {
+        let topics: BTreeSet<&String> = self
+            .0
+            .values()
+            .filter_map(|rule| match &rule.trigger {
+                Trigger::Mqtt { topic, .. } => Some(topic),
+                _ => None,
+            })
+            .collect();
+        topics
+            .into_iter()
+            .map(|topic| (topic.clone(), QoS::AtLeastOnce))
+            .collect()
+    }
+}
+
+/// What conditions are checked against, taken when the trigger fires.
+#[derive(Debug, Clone, Copy)]
+struct Facts {
+    height_mm: u32,
+    status: DeskStatus,
+    occupancy: Occupancy,
+    time: NaiveTime,
+}
+
+impl Condition {
+    fn holds(&self, facts: &Facts) -> bool {
+        match self {
+            Condition::HeightBetween { min_mm, max_mm } => {
+                (*min_mm..=*max_mm).contains(&facts.height_mm)
+            }
+            Condition::Status { status } => facts.status == *status,
+            Condition::Occupancy { occupancy } => facts.occupancy == *occupancy,
+            Condition::TimeBetween { from, to } => {
+                let (Ok(from), Ok(to)) = (parse_time(from), parse_time(to)) else {
+                    return false;
+                };
+                if from <= to {
+                    from <= facts.time && facts.time < to
+                } else {
+                    facts.time >= from || facts.time < to
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct RuleRequest {
+    pub name: String,
+    pub trigger: Trigger,
+    #[serde(default)]
+    pub conditions: Vec<Condition>,
+    pub actions: Vec<RuleAction>,
+    #[serde(default)]
+    pub paused: bool,
+}
+
+fn invalid(message: String) -> (StatusCode, Json<serde_json::Value>) {
+    (
+        StatusCode::UNPROCESSABLE_ENTITY,
+        Json(serde_json::json!(ErrorBody {
+            error: message,
+            code: Some("invalid_rule".to_string()),
+        })),
+    )
+}
+
+fn validate(
+    app_state: &AppState,
+    request: &RuleRequest,
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    if request.name.trim().is_empty() {
+        return Err(invalid("A rule needs a name".to_string()));
+    }
+    if request.actions.is_empty() {
+        return Err(invalid("A rule needs at least one action".to_string()));
+    }
+    match &request.trigger {
+        Trigger::Time { days, time } => {
+            parse_days(days).map_err(|e| invalid(format!("Invalid days: {}", e)))?;
+            parse_time(time).map_err(|e| invalid(format!("Invalid time: {}", e)))?;
+        }
+        Trigger::Mqtt { topic, .. } => {
+            TopicPattern::parse(topic).map_err(|e| invalid(format!("Invalid topic: {}", e)))?;
+        }
+        Trigger::Status { .. } | Trigger::Occupancy { .. } | Trigger::Webhook => {}
+    }
+    for condition in &request.conditions {
+        match condition {
+            Condition::TimeBetween { from, to } => {
+                for time in [from, to] {
+                    parse_time(time).map_err(|e| invalid(format!("Invalid time: {}", e)))?;
+                }
+            }
+            Condition::HeightBetween { min_mm, max_mm } if min_mm > max_mm => {
+                return Err(invalid("min_mm must not exceed max_mm".to_string()));
+            }
+            _ => {}
+        }
+    }
+    for action in &request.actions {
+        if let RuleAction::Command { command, value } = action {
+            let command = DeskCommand {
+                command: *command,
+                value: *value,
+            };
+            validation::validate_command(&command, &app_state.config())?;
+        }
+    }
+    Ok(())
+}
+
+fn persist(app_state: &AppState, rules: &Rules) {
+    if let Some(store) = &app_state.store
+        && let Err(e) = store.put(rules)
+    {
+        error!("Failed to persist rules: {}", e);
+    }
+}
+
+fn unknown(id: &str) -> (StatusCode, Json<serde_json::Value>) {
+    (
+        StatusCode::NOT_FOUND,
+        Json(serde_json::json!({"error": format!("Unknown rule '{}'", id)})),
+    )
+}
+
+/// Subscribes to a new MQTT trigger's topic right away; otherwise it's picked up
+/// on the next connect.
+pub async fn subscribe(app_state: &AppState, trigger: &Trigger) {
+    let Trigger::Mqtt { topic, .. } = trigger else {
+        return;
+    };
+    if !app_state.reconnect_monitor.lock().await.is_connected() {
+        return;
+    }
+    if let Err(e) = app_state
+        .transport
+        .subscribe(vec![(topic.clone(), QoS::AtLeastOnce)])
+    {
+        warn!("Failed to subscribe to {} for a rule: {}", topic, e);
+    }
+}
+
+const RULES: Collection = Collection {
+    fields: &[
+        "name",
+        "trigger.type",
+        "paused",
+        "created_at",
+        "last_triggered",
+    ],
+    timestamp: Some("created_at"),
+};
+
+#[utoipa::path(
+    get,
+    path = "/api/sven/rules",
+    tag = "rules",
+    params(ListQuery),
+    responses(
+        (status = 200, body = Vec<Rule>),
+        (status = 400, description = "Invalid query", body = ErrorBody),
+    ),
+)]
+pub async fn list_rules(
+    Query(params): Query<BTreeMap<String, String>>,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    let rules = app_state.rules.lock().await;
+    RULES.respond(rules.0.values(), &params, serde_json::Value::Array)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/sven/rules",
+    tag = "rules",
+    request_body = RuleRequest,
+    responses(
+        (status = 201, body = Rule),
+        (status = 422, description = "Invalid trigger, condition or action", body = ErrorBody),
+    ),
+)]
+pub async fn create_rule(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Json(request): Json<RuleRequest>,
+) -> impl IntoResponse {
+    if let Err(rejection) = validate(&app_state, &request) {
+        return rejection;
+    }
+    let rule = Rule {
+        id: uuid::Uuid::new_v4().to_string(),
+        name: request.name.trim().to_string(),
+        trigger: request.trigger,
+        conditions: request.conditions,
+        actions: request.actions,
+        paused: request.paused,
+        created_at: Utc::now(),
+        last_triggered: None,
+    };
+    let mut rules = app_state.rules.lock().await;
+    rules.0.insert(rule.id.clone(), rule.clone());
+    persist(&app_state, &rules);
+    drop(rules);
+    subscribe(&app_state, &rule.trigger).await;
+    info!("Added rule {} ({:?})", rule.name, rule.trigger);
+    (StatusCode::CREATED, Json(serde_json::json!(rule)))
+}
+
+/// Replaces a rule, keeping its id and when it last fired.
+#[utoipa::path(
+    put,
+    path = "/api/sven/rules/{id}",
+    tag = "rules",
+    params(("id" = String, Path)),
+    request_body = RuleRequest,
+    responses(
+        (status = 200, body = Rule),
+        (status = 404, body = ErrorBody),
+        (status = 422, description = "Invalid trigger, condition or action", body = ErrorBody),
+    ),
+)]
+pub async fn update_rule(
+    Path(id): Path<String>,
+    Extension(app_state): Extension<Arc<AppState>>,
+    Json(request): Json<RuleRequest>,
+) -> impl IntoResponse {
+    if let Err(rejection) = validate(&app_state, &request) {
+        return rejection;
+    }
+    let mut rules = app_state.rules.lock().await;
+    let Some(rule) = rules.0.get_mut(&id) else {
+        return unknown(&id);
+    };
+    rule.name = request.name.trim().to_string();
+    rule.trigger = request.trigger;
+    rule.conditions = request.conditions;
+    rule.actions = request.actions;
+    rule.paused = request.paused;
+    let updated = rule.clone();
+    persist(&app_state, &rules);
+    drop(rules);
+    subscribe(&app_state, &updated.trigger).await;
+    (StatusCode::OK, Json(serde_json::json!(updated)))
+}
+
+/// Removes a rule. Its MQTT topic stays subscribed until the next reconnect.
+#[utoipa::path(
+    delete,
+    path = "/api/sven/rules/{id}",
+    tag = "rules",
+    params(("id" = String, Path)),
+    responses((status = 200, body = serde_json::Value), (status = 404, body = ErrorBody)),
+)]
+pub async fn delete_rule(
+    Path(id): Path<String>,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    let mut rules = app_state.rules.lock().await;
+    if rules.0.remove(&id).is_none() {
+        return unknown(&id);
+    }
+    persist(&app_state, &rules);
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({"status": "Rule deleted", "id": id})),
+    )
+}
+
+/// Fires a rule with a webhook trigger, answering once its actions ran.
+#[utoipa::path(
+    post,
+    path = "/api/sven/rules/{id}/trigger",
+    tag = "rules",
+    params(("id" = String, Path)),
+    responses(
+        (status = 200, description = "Whether the conditions held and the actions ran", body = serde_json::Value),
+        (status = 404, body = ErrorBody),
+        (status = 409, description = "The rule is paused or has another trigger", body = ErrorBody),
+        (status = 502, description = "An action failed", body = ErrorBody),
+    ),
+)]
+pub async fn trigger_rule(
+    Path(id): Path<String>,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    let Some(rule) = app_state.rules.lock().await.0.get(&id).cloned() else {
+        return unknown(&id);
+    };
+    let conflict = |error: &str, code: &str| {
+        (
+            StatusCode::CONFLICT,
+            Json(serde_json::json!({"error": error, "code": code})),
+        )
+    };
+    if !matches!(rule.trigger, Trigger::Webhook) {
+        return conflict("The rule has no webhook trigger", "not_a_webhook_rule");
+    }
+    if rule.paused {
+        return conflict("The rule is paused", "rule_paused");
+    }
+    match fire(&app_state, &rule).await {
+        Ok(fired) => (
+            StatusCode::OK,
+            Json(serde_json::json!({"id": id, "fired": fired})),
+        ),
+        Err(e) => (
+            StatusCode::BAD_GATEWAY,
+            Json(serde_json::json!({"error": e, "code": "action_failed", "id": id})),
+        ),
+    }
+}
+
+async fn facts(app_state: &AppState) -> Facts {
+    Facts {
+        height_mm: app_state.sven_state.read().await.height_mm,
+        status: *app_state.status_tx.borrow(),
+        occupancy: *app_state.occupancy_tx.borrow(),
+        time: Local::now().time(),
+    }
+}
+
+async fn run_action(
+    app_state: &Arc<AppState>,
+    rule: &Rule,
+    action: &RuleAction,
+) -> Result<(), String> {
+    let command = match action {
+        RuleAction::Command { command, value } => DeskCommand {
+            command: *command,
+            value: *value,
+        },
+        RuleAction::Preset { name } => {
+            let presets = app_state.presets.lock().await;
+            let preset = presets
+                .0
+                .get(name)
+                .ok_or_else(|| format!("preset '{}' no longer exists", name))?;
+            DeskCommand {
+                command: SvenCommand::AbsoluteHeight,
+                value: preset.height_mm,
+            }
+        }
+        RuleAction::Notify { message } => {
+            app_state.notifier.notify(
+                NotificationKind::Automation {
+                    automation: format!("rule {}", rule.name),
+                },
+                message.clone(),
+            );
+            return Ok(());
+        }
+    };
+    app_state.send_automated(command).await
+}
+
+/// Checks the conditions and runs the actions, stopping at the first that fails.
+/// Returns whether the conditions held.
+async fn fire(app_state: &Arc<AppState>, rule: &Rule) -> Result<bool, String> {
+    {
+        let mut rules = app_state.rules.lock().await;
+        if let Some(stored) = rules.0.get_mut(&rule.id) {
+            stored.last_triggered = Some(Utc::now());
+        }
+        persist(app_state, &rules);
+    }
+    let facts = facts(app_state).await;
+    if !rule
+        .conditions
+        .iter()
+        .all(|condition| condition.holds(&facts))
+    {
+        info!(
+            "Rule {} triggered, but its conditions don't hold",
+            rule.name
+        );
+        return Ok(false);
+    }
+    info!("Rule {} fired", rule.name);
+    for action in &rule.actions {
+        run_action(app_state, rule, action)
+            .await
+            .map_err(|e| format!("Rule {} failed: {}", rule.name, e))?;
+    }
+    Ok(true)
+}
+
+/// Fires each of `rules` in the background, so a slow action doesn't hold up
+/// the caller.
+fn fire_all(app_state: &Arc<AppState>, rules: Vec<Rule>) {
+    for rule in rules {
+        let app_state = app_state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = fire(&app_state, &rule).await {
+                warn!("{}", e);
+            }
+        });
+    }
+}
+
+async fn matching(app_state: &AppState, matches: impl Fn(&Trigger) -> bool) -> Vec<Rule> {
+    app_state
+        .rules
+        .lock()
+        .await
+        .0
+        .values()
+        .filter(|rule| !rule.paused && matches(&rule.trigger))
+        .cloned()
+        .collect()
+}
+
+fn payload_matches(expected: Option<&str>, payload: &[u8]) -> bool {
+    expected.is_none_or(|expected| {
+        std::str::from_utf8(payload).is_ok_and(|payload| payload.trim() == expected)
+    })
+}
+
+/// Fires the rules listening on `topic`. Returns whether any rule listens on it,
+/// paused or not, so the message isn't reported as being on an unknown topic.
+pub async fn on_message(app_state: &Arc<AppState>, topic: &str, payload: &[u8]) -> bool {
+    let listens = |trigger: &Trigger| match trigger {
+        Trigger::Mqtt { topic: filter, .. } => {
+            TopicPattern::parse(filter).is_ok_and(|pattern| pattern.match_topic(topic).is_some())
+        }
+        _ => false,
+    };
+    let listening = app_state
+        .rules
+        .lock()
+        .await
+        .0
+        .values()
+        .any(|rule| listens(&rule.trigger));
+    if listening {
+        let rules = matching(app_state, |trigger| {
+            listens(trigger)
+                && matches!(trigger, Trigger::Mqtt { payload: expected, .. }
+                    if payload_matches(expected.as_deref(), payload))
+        })
+        .await;
+        fire_all(app_state, rules);
+    }
+    listening
+}
+
+/// Fires the time, status and occupancy triggers until shutdown. Times are local,
+/// like the schedules.
+pub async fn run_rules(app_state: Arc<AppState>) {
+    let mut status_rx = app_state.status_tx.subscribe();
+    let mut occupancy_rx = app_state.occupancy_tx.subscribe();
+    let mut tick = tokio::time::interval(TICK);
+    loop {
+        let rules = tokio::select! {
+            _ = tick.tick() => {
+                let now = Local::now();
+                matching(&app_state, |trigger| matches!(trigger, Trigger::Time { .. }))
+                .await
+                .into_iter()
+                .filter(|rule| match &rule.trigger {
+                    Trigger::Time { days, time } => schedules::is_due(days, time, rule.last_triggered, now),
+                    _ => false,
+                })
+                .collect()
+            }
+            Ok(()) = status_rx.changed() => {
+                let status = *status_rx.borrow_and_update();
+                matching(&app_state, |trigger| matches!(trigger,
+                    Trigger::Status { status: wanted } if *wanted == status))
+                .await
+            }
+            Ok(()) = occupancy_rx.changed() => {
+                let occupancy = *occupancy_rx.borrow_and_update();
+                matching(&app_state, |trigger| matches!(trigger,
+                    Trigger::Occupancy { occupancy: wanted } if *wanted == occupancy))
+                .await
+            }
+            _ = app_state.shutdown.wait() => return,
+        };
+        fire_all(&app_state, rules);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn facts(time: &str) -> Facts {
+        Facts {
+            height_mm: 720,
+            status: DeskStatus::Idle,
+            occupancy: Occupancy::Occupied,
+            time: parse_time(time).unwrap(),
+        }
+    }
+
+    #[test]
+    fn time_windows_can_span_midnight() {
+        let night = Condition::TimeBetween {
+            from: "22:00".to_string(),
+            to: "06:00".to_string(),
+        };
+        assert!(night.holds(&facts("23:30")));
+        assert!(night.holds(&facts("05:59")));
+        assert!(!night.holds(&facts("12:00")));
+
+        let lunch = Condition::TimeBetween {
+            from: "12:00".to_string(),
+            to: "13:00".to_string(),
+        };
+        assert!(lunch.holds(&facts("12:00")));
+        assert!(!lunch.holds(&facts("13:00")));
+    }
+
+    #[test]
+    fn conditions_check_the_desk() {
+        let facts = facts("09:00");
+        assert!(
+            Condition::HeightBetween {
+                min_mm: 700,
+                max_mm: 750
+            }
+            .holds(&facts)
+        );
+        assert!(
+            !Condition::Status {
+                status: DeskStatus::MovingUp
+            }
+            .holds(&facts)
+        );
+        assert!(
+            Condition::Occupancy {
+                occupancy: Occupancy::Occupied
+            }
+            .holds(&facts)
+        );
+    }
+
+    #[test]
+    fn payloads_match_exactly_when_given() {
+        assert!(payload_matches(None, b"anything"));
+        assert!(payload_matches(Some("on"), b"on\n"));
+        assert!(!payload_matches(Some("on"), b"off"));
+    }
+}
@@ -0,0 +1,438 @@
+use axum::{
+    Json,
+    extract::{Extension, Path, Query},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use chrono::{DateTime, Datelike, Local, NaiveTime, TimeZone, Utc, Weekday};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+use utoipa::ToSchema;
+
+use crate::listing::{Collection, ListQuery};
+use crate::notifications::NotificationKind;
+use crate::occupancy::Occupancy;
+use crate::persistence::Artifact;
+use crate::validation::ErrorBody;
+use crate::{AppState, DeskCommand, SvenCommand, SvenPosition};
+
+/// How often the runner looks for due schedules.
+const TICK: Duration = Duration::from_secs(15);
+/// How late a run may start; occurrences missed by more, e.g. while the bridge was
+/// down, are skipped rather than moving the desk at an unexpected time.
+const GRACE: chrono::Duration = chrono::Duration::seconds(90);
+
+/// Where a schedule moves the desk.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ScheduleAction {
+    Position(SvenPosition),
+    /// A saved preset, looked up when the schedule runs
+    Preset(String),
+    HeightMm(u32),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Schedule {
+    pub id: String,
+    pub name: String,
+    /// `daily`, `weekdays`, `weekends` or a comma separated list like `mon,wed,fri`
+    pub days: String,
+    /// Local time of day, `HH:MM`
+    pub time: String,
+    pub action: ScheduleAction,
+    pub paused: bool,
+    pub created_at: DateTime<Utc>,
+    pub last_run: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Schedules(pub BTreeMap<String, Schedule>);
+
+impl Artifact for Schedules {
+    const KEY: &'static str = "schedules";
+}
+
+pub fn parse_days(spec: &str) -> Result<Vec<Weekday>, String> {
+    use Weekday::*;
+    match spec.trim().to_ascii_lowercase().as_str() {
+        "daily" => Ok(vec![Mon, Tue, Wed, Thu, Fri, Sat, Sun]),
+        "weekdays" => Ok(vec![Mon, Tue, Wed, Thu, Fri]),
+        "weekends" => Ok(vec![Sat, Sun]),
+        list => list
+            .split(',')
+            .map(|day| {
+                day.trim()
+                    .parse::<Weekday>()
+                    .map_err(|_| format!("unknown day '{}'", day.trim()))
+            })
+            .collect(),
+    }
+}
+
+pub fn parse_time(time: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(time.trim(), "%H:%M")
+        .map_err(|_| format!("time must be HH:MM, got '{}'", time))
+}
+
+/// Today's occurrence of `time` in local time, if `days` include today.
+fn occurrence_today(days: &str, time: &str, now: DateTime<Local>) -> Option<DateTime<Local>> {
+    let days = parse_days(days).ok()?;
+    if !days.contains(&now.weekday()) {
+        return None;
+    }
+    let time = parse_time(time).ok()?;
+    Local
+        .from_local_datetime(&now.date_naive().and_time(time))
+        .earliest()
+}
+
+/// Whether today's occurrence of `days` at `time` has come, at most `GRACE` ago,
+/// and nothing ran since. Shared with the time triggers of `rules`.
+pub fn is_due(
+    days: &str,
+    time: &str,
+    last_run: Option<DateTime<Utc>>,
+    now: DateTime<Local>,
+) -> bool {
+    let Some(occurrence) = occurrence_today(days, time, now) else {
+        return false;
+    };
+    let already_ran = last_run.is_some_and(|last_run| last_run >= occurrence.with_timezone(&Utc));
+    now >= occurrence && now - occurrence <= GRACE && !already_ran
+}
+
+impl Schedule {
+    fn is_due(&self, now: DateTime<Local>) -> bool {
+        !self.paused && is_due(&self.days, &self.time, self.last_run, now)
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ScheduleRequest {
+    pub name: String,
+    pub days: String,
+    pub time: String,
+    pub action: ScheduleAction,
+    #[serde(default)]
+    pub paused: bool,
+}
+
+fn validate(request: &ScheduleRequest) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    let invalid = |message: String| {
+        Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(serde_json::json!({"error": message})),
+        ))
+    };
+    if request.name.trim().is_empty() {
+        return invalid("A schedule needs a name".to_string());
+    }
+    if let Err(e) = parse_days(&request.days) {
+        return invalid(format!("Invalid days: {}", e));
+    }
+    if let Err(e) = parse_time(&request.time) {
+        return invalid(format!("Invalid time: {}", e));
+    }
+    Ok(())
+}
+
+fn persist(app_state: &AppState, schedules: &Schedules) {
+    if let Some(store) = &app_state.store
+        && let Err(e) = store.put(schedules)
+    {
+        error!("Failed to persist schedules: {}", e);
+    }
+}
+
+fn unknown(id: &str) -> (StatusCode, Json<serde_json::Value>) {
+    (
+        StatusCode::NOT_FOUND,
+        Json(serde_json::json!({"error": format!("Unknown schedule '{}'", id)})),
+    )
+}
+
+const SCHEDULES: Collection = Collection {
+    fields: &["name", "days", "time", "paused", "created_at", "last_run"],
+    timestamp: Some("created_at"),
+};
+
+#[utoipa::path(
+    get,
+    path = "/api/sven/schedules",
+    tag = "schedules",
+    params(ListQuery),
+    responses(
+        (status = 200, body = Vec<Schedule>),
+        (status = 400, description = "Invalid query", body = ErrorBody),
+    ),
+)]
+pub async fn list_schedules(
+    Query(params): Query<BTreeMap<String, String>>,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    let schedules = app_state.schedules.lock().await;
+    SCHEDULES.respond(schedules.0.values(), &params, serde_json::Value::Array)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/sven/schedules",
+    tag = "schedules",
+    request_body = ScheduleRequest,
+    responses(
+        (status = 201, body = Schedule),
+        (status = 422, description = "Invalid request", body = ErrorBody),
+    ),
+)]
+pub async fn create_schedule(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Json(request): Json<ScheduleRequest>,
+) -> impl IntoResponse {
+    if let Err(rejection) = validate(&request) {
+        return rejection;
+    }
+    let schedule = Schedule {
+        id: uuid::Uuid::new_v4().to_string(),
+        name: request.name.trim().to_string(),
+        days: request.days,
+        time: request.time,
+        action: request.action,
+        paused: request.paused,
+        created_at: Utc::now(),
+        last_run: None,
+    };
+    let mut schedules = app_state.schedules.lock().await;
+    schedules.0.insert(schedule.id.clone(), schedule.clone());
+    persist(&app_state, &schedules);
+    info!(
+        "Added schedule {} ({} at {})",
+        schedule.name, schedule.days, schedule.time
+    );
+    (StatusCode::CREATED, Json(serde_json::json!(schedule)))
+}
+
+/// Replaces a schedule's rule, keeping its id and run history.
+#[utoipa::path(
+    put,
+    path = "/api/sven/schedules/{id}",
+    tag = "schedules",
+    params(("id" = String, Path)),
+    request_body = ScheduleRequest,
+    responses(
+        (status = 200, body = Schedule),
+        (status = 404, body = ErrorBody),
+        (status = 422, description = "Invalid request", body = ErrorBody),
+    ),
+)]
+pub async fn update_schedule(
+    Path(id): Path<String>,
+    Extension(app_state): Extension<Arc<AppState>>,
+    Json(request): Json<ScheduleRequest>,
+) -> impl IntoResponse {
+    if let Err(rejection) = validate(&request) {
+        return rejection;
+    }
+    let mut schedules = app_state.schedules.lock().await;
+    let Some(schedule) = schedules.0.get_mut(&id) else {
+        return unknown(&id);
+    };
+    schedule.name = request.name.trim().to_string();
+    schedule.days = request.days;
+    schedule.time = request.time;
+    schedule.action = request.action;
+    schedule.paused = request.paused;
+    let updated = schedule.clone();
+    persist(&app_state, &schedules);
+    (StatusCode::OK, Json(serde_json::json!(updated)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/sven/schedules/{id}",
+    tag = "schedules",
+    params(("id" = String, Path)),
+    responses((status = 200, body = serde_json::Value), (status = 404, body = ErrorBody)),
+)]
+pub async fn delete_schedule(
+    Path(id): Path<String>,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    let mut schedules = app_state.schedules.lock().await;
+    if schedules.0.remove(&id).is_none() {
+        return unknown(&id);
+    }
+    persist(&app_state, &schedules);
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({"status": "Schedule deleted", "id": id})),
+    )
+}
+
+async fn set_paused(
+    app_state: &AppState,
+    id: &str,
+    paused: bool,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let mut schedules = app_state.schedules.lock().await;
+    let Some(schedule) = schedules.0.get_mut(id) else {
+        return unknown(id);
+    };
+    schedule.paused = paused;
+    let updated = schedule.clone();
+    persist(app_state, &schedules);
+    (StatusCode::OK, Json(serde_json::json!(updated)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/sven/schedules/{id}/pause",
+    tag = "schedules",
+    params(("id" = String, Path)),
+    responses((status = 200, body = Schedule), (status = 404, body = ErrorBody)),
+)]
+pub async fn pause_schedule(
+    Path(id): Path<String>,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    set_paused(&app_state, &id, true).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/sven/schedules/{id}/resume",
+    tag = "schedules",
+    params(("id" = String, Path)),
+    responses((status = 200, body = Schedule), (status = 404, body = ErrorBody)),
+)]
+pub async fn resume_schedule(
+    Path(id): Path<String>,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    set_paused(&app_state, &id, false).await
+}
+
+/// The command a schedule's action resolves to right now.
+pub async fn resolve(app_state: &AppState, action: &ScheduleAction) -> Result<DeskCommand, String> {
+    match action {
+        ScheduleAction::Position(position) => Ok(DeskCommand {
+            command: SvenCommand::Position,
+            value: *position as u32,
+        }),
+        ScheduleAction::Preset(name) => {
+            let presets = app_state.presets.lock().await;
+            let preset = presets
+                .0
+                .get(name)
+                .ok_or_else(|| format!("preset '{}' no longer exists", name))?;
+            Ok(DeskCommand {
+                command: SvenCommand::AbsoluteHeight,
+                value: preset.height_mm,
+            })
+        }
+        ScheduleAction::HeightMm(height_mm) => Ok(DeskCommand {
+            command: SvenCommand::AbsoluteHeight,
+            value: *height_mm,
+        }),
+    }
+}
+
+async fn run(app_state: &Arc<AppState>, schedule: &Schedule) -> Result<(), String> {
+    let command = resolve(app_state, &schedule.action).await?;
+    app_state.send_automated(command).await
+}
+
+/// Runs due schedules, checking every `TICK`. Times are local, like the night mode.
+pub async fn run_schedules(app_state: Arc<AppState>) {
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(TICK) => {}
+            _ = app_state.shutdown.wait() => return,
+        }
+        let now = Local::now();
+        let due: Vec<Schedule> = app_state
+            .schedules
+            .lock()
+            .await
+            .0
+            .values()
+            .filter(|schedule| schedule.is_due(now))
+            .cloned()
+            .collect();
+
+        for schedule in due {
+            let vacant = *app_state.occupancy_tx.borrow() == Occupancy::Vacant;
+            if app_state.config().occupancy_suppresses_schedules && vacant {
+                info!("Skipping schedule {}: nobody is at the desk", schedule.name);
+            } else {
+                info!("Running schedule {}", schedule.name);
+                match run(&app_state, &schedule).await {
+                    Ok(()) => app_state.notifier.notify(
+                        NotificationKind::Automation {
+                            automation: "schedule".to_string(),
+                        },
+                        format!("Schedule {} moved the desk", schedule.name),
+                    ),
+                    Err(e) => warn!("Schedule {} failed: {}", schedule.name, e),
+                }
+            }
+            // Marked as run even on failure or when skipped, so it doesn't retry every tick
+            let mut schedules = app_state.schedules.lock().await;
+            if let Some(stored) = schedules.0.get_mut(&schedule.id) {
+                stored.last_run = Some(Utc::now());
+            }
+            persist(&app_state, &schedules);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::zones::ProtectedZone;
+    use rumqttc::AsyncClient;
+
+    #[tokio::test]
+    async fn scheduled_moves_keep_out_of_protected_zones() {
+        let mut config = Config::load_from(None).unwrap();
+        config.protected_zones = vec![ProtectedZone {
+            name: "monitor".to_string(),
+            min_mm: 900,
+            max_mm: 950,
+        }];
+        let (requests_tx, requests) = flume::bounded(10);
+        let app_state = crate::build_state(
+            config,
+            AsyncClient::from_senders(requests_tx),
+            "sven-test".to_string(),
+        )
+        .unwrap();
+        app_state.on_broker_connected().await;
+        let schedule = Schedule {
+            id: "s1".to_string(),
+            name: "stand up".to_string(),
+            days: "daily".to_string(),
+            time: "09:00".to_string(),
+            action: ScheduleAction::HeightMm(1000),
+            paused: false,
+            created_at: Utc::now(),
+            last_run: None,
+        };
+
+        // The desk starts at its lowest position, below the zone
+        let refused = run(&app_state, &schedule).await.unwrap_err();
+        assert!(refused.contains("protected zone monitor"), "{}", refused);
+        assert!(requests.is_empty());
+
+        let below = Schedule {
+            action: ScheduleAction::HeightMm(850),
+            ..schedule
+        };
+        run(&app_state, &below).await.unwrap();
+        assert!(!requests.is_empty());
+    }
+}
@@ -0,0 +1,332 @@
+//! Movement sequences: an ordered list of commands and pauses, run in the
+//! background as a job, reported at `GET /api/sven/jobs/{id}` and, for existing
+//! clients, `GET /api/sven/sequence/{id}`. Every command step goes through the same
+//! path as `POST /api/sven/command`, so limits, zones and rate limits apply to each
+//! move, and gets a job of its own. A stop cancels the sequence.
+
+use axum::{
+    Json,
+    extract::{ConnectInfo, Extension, Path},
+    http::{HeaderMap, HeaderValue, StatusCode},
+    response::IntoResponse,
+};
+use serde::Deserialize;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::jobs::{self, Job, JobKind, JobStatus};
+use crate::listen::ClientAddr;
+use crate::soft_start;
+use crate::validation::{self, ErrorBody};
+use crate::{AppState, CommandRequest, DeskCommand, SvenCommand, movement, request_id};
+
+const MAX_STEPS: usize = 50;
+const MAX_DELAY_MS: u64 = 60 * 60 * 1000;
+/// How often a pause checks whether the sequence was stopped.
+const STOP_POLL: Duration = Duration::from_millis(200);
+
+/// A step of a sequence: a command, a pause, or a command followed by a pause.
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct SequenceStep {
+    /// Command to send, left out for a plain pause
+    pub command: Option<SvenCommand>,
+    #[serde(default)]
+    pub value: u32,
+    /// Wait for the move to finish before going on, defaults to true
+    #[serde(default = "wait_by_default")]
+    pub wait: bool,
+    /// Then also wait until the desk has reached this height
+    pub until_height_mm: Option<u32>,
+    /// Pause after the step
+    #[serde(default)]
+    pub delay_ms: u64,
+    /// A soft start pulse, see `CommandRequest::pulse`
+    #[serde(skip)]
+    pub(crate) pulse: bool,
+}
+
+fn wait_by_default() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SequenceRequest {
+    pub steps: Vec<SequenceStep>,
+}
+
+fn invalid(message: String) -> (StatusCode, Json<serde_json::Value>) {
+    (
+        StatusCode::UNPROCESSABLE_ENTITY,
+        Json(serde_json::json!({"error": message, "code": "invalid_sequence"})),
+    )
+}
+
+/// Checks every step before anything moves, so a typo in step five doesn't leave
+/// the desk halfway through a routine.
+fn validate(
+    app_state: &AppState,
+    steps: &[SequenceStep],
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    if steps.is_empty() || steps.len() > MAX_STEPS {
+        return Err(invalid(format!(
+            "A sequence needs between 1 and {} steps",
+            MAX_STEPS
+        )));
+    }
+    for (index, step) in steps.iter().enumerate() {
+        let number = index + 1;
+        if step.delay_ms > MAX_DELAY_MS {
+            return Err(invalid(format!(
+                "Step {} pauses for longer than {} ms",
+                number, MAX_DELAY_MS
+            )));
+        }
+        if let Some(height_mm) = step.until_height_mm
+            && !app_state.config().height_in_bounds(height_mm)
+        {
+            return Err(invalid(format!(
+                "Step {} waits for {} mm, outside {}..{} mm",
+                number,
+                height_mm,
+                app_state.config().min_height_mm,
+                app_state.config().max_height_mm
+            )));
+        }
+        match step.command {
+            Some(command) => {
+                let command = DeskCommand {
+                    command,
+                    value: step.value,
+                };
+                if let Err((status, Json(mut body))) =
+                    validation::validate_command(&command, &app_state.config())
+                {
+                    body["error"] = format!(
+                        "Step {}: {}",
+                        number,
+                        body["error"].as_str().unwrap_or_default()
+                    )
+                    .into();
+                    return Err((status, Json(body)));
+                }
+            }
+            None if step.delay_ms == 0 && step.until_height_mm.is_none() => {
+                return Err(invalid(format!(
+                    "Step {} has no command, delay or height to wait for",
+                    number
+                )));
+            }
+            None => {}
+        }
+    }
+    Ok(())
+}
+
+/// Sleeps for `delay`, returning early with `false` when the desk was stopped.
+async fn pause(app_state: &AppState, delay: Duration, generation: u64) -> bool {
+    let deadline = tokio::time::Instant::now() + delay;
+    while tokio::time::Instant::now() < deadline {
+        if app_state.stop_generation.load(Ordering::SeqCst) != generation {
+            return false;
+        }
+        tokio::time::sleep_until(deadline.min(tokio::time::Instant::now() + STOP_POLL)).await;
+    }
+    app_state.stop_generation.load(Ordering::SeqCst) == generation
+}
+
+async fn run(
+    app_state: Arc<AppState>,
+    id: String,
+    steps: Vec<SequenceStep>,
+    remote: ClientAddr,
+    mut headers: HeaderMap,
+) {
+    let timeout = Duration::from_millis(app_state.config().ack_timeout_ms);
+    let generation = app_state.stop_generation.load(Ordering::SeqCst);
+    let finish = |status: JobStatus, error: Option<String>| {
+        let app_state = app_state.clone();
+        let id = id.clone();
+        async move {
+            app_state.jobs.lock().await.finish(&id, status, error);
+            info!("Sequence {} ended: {:?}", id, status);
+        }
+    };
+    app_state
+        .jobs
+        .lock()
+        .await
+        .set_status(&id, JobStatus::Moving);
+
+    for (index, step) in steps.iter().enumerate() {
+        let number = index + 1;
+        if app_state.stop_generation.load(Ordering::SeqCst) != generation {
+            return finish(JobStatus::Cancelled, None).await;
+        }
+        if let Some(command) = step.command {
+            // Each step is its own request, traceable to the sequence by its id
+            let step_id = format!("{}.{}", id, number);
+            headers.insert(
+                request_id::HEADER,
+                HeaderValue::from_str(&step_id).expect("request ids are visible ASCII"),
+            );
+            let request = CommandRequest {
+                command: DeskCommand {
+                    command,
+                    value: step.value,
+                },
+                confirm: step.wait,
+                timeout_ms: None,
+                override_protected_zones: false,
+                force: false,
+                ack: None,
+                dry_run: false,
+                // Long timed steps were already split into pulses
+                soft_start: false,
+                ttl_ms: None,
+                label: None,
+                pulse: step.pulse,
+            };
+            let (status, Json(body)) =
+                crate::execute_command(&app_state, remote, &headers, None, request).await;
+            if status == StatusCode::GATEWAY_TIMEOUT {
+                warn!("Sequence {} step {} did not finish in time", id, number);
+                app_state.jobs.lock().await.update(&id, |job| {
+                    job.warnings
+                        .push(format!("Step {} did not finish in time", number));
+                });
+            } else if !status.is_success() {
+                let error = format!(
+                    "Step {} failed: {}",
+                    number,
+                    body["error"].as_str().unwrap_or_default()
+                );
+                return finish(JobStatus::Failed, Some(error)).await;
+            }
+        }
+        if let Some(height_mm) = step.until_height_mm {
+            let mut state_rx = app_state.subscribe_state(None).await;
+            if movement::wait_for_arrival(&mut state_rx, height_mm, timeout)
+                .await
+                .is_err()
+            {
+                let error = format!("Step {}: desk did not reach {} mm", number, height_mm);
+                return finish(JobStatus::Failed, Some(error)).await;
+            }
+        }
+        if step.delay_ms > 0
+            && !pause(&app_state, Duration::from_millis(step.delay_ms), generation).await
+        {
+            return finish(JobStatus::Cancelled, None).await;
+        }
+        app_state
+            .jobs
+            .lock()
+            .await
+            .update(&id, |job| job.completed_steps = Some(number));
+    }
+    finish(JobStatus::Completed, None).await
+}
+
+/// A long timed step as its soft start pulses, which then keep the step's wait
+/// for a height and pause.
+fn with_soft_start(app_state: &AppState, step: SequenceStep) -> Vec<SequenceStep> {
+    let Some(command) = step.command else {
+        return vec![step];
+    };
+    let command = DeskCommand {
+        command,
+        value: step.value,
+    };
+    let Some(mut steps) = soft_start::ramp(&app_state.config(), &command) else {
+        return vec![step];
+    };
+    if let Some(last) = steps.last_mut() {
+        last.wait = step.wait;
+        last.until_height_mm = step.until_height_mm;
+        last.delay_ms = step.delay_ms;
+    }
+    steps
+}
+
+/// Creates the sequence's job and runs its steps in the background, answering
+/// the job's id. Boxed, since `execute_command` starts soft start sequences and
+/// the steps go through it again.
+pub fn spawn(
+    app_state: &Arc<AppState>,
+    steps: Vec<SequenceStep>,
+    remote: ClientAddr,
+    headers: HeaderMap,
+) -> Pin<Box<dyn Future<Output = String> + Send>> {
+    let app_state = app_state.clone();
+    Box::pin(async move {
+        let mut job = Job::new(JobKind::Sequence, &request_id::from_headers(&headers), None);
+        job.steps = Some(steps.len());
+        job.completed_steps = Some(0);
+        let id = app_state.jobs.lock().await.insert(job);
+        info!("Starting sequence {} with {} steps", id, steps.len());
+        tokio::spawn(run(app_state, id.clone(), steps, remote, headers));
+        id
+    })
+}
+
+/// Starts running the steps in order in the background.
+#[utoipa::path(
+    post,
+    path = "/api/sven/sequence",
+    tag = "macros",
+    request_body = SequenceRequest,
+    responses(
+        (status = 202, description = "Sequence started, follow it at `/api/sven/jobs/{id}`"),
+        (status = 422, description = "Invalid step", body = ErrorBody),
+    ),
+)]
+pub async fn start_sequence(
+    ConnectInfo(remote): ConnectInfo<ClientAddr>,
+    headers: HeaderMap,
+    Extension(app_state): Extension<Arc<AppState>>,
+    Json(request): Json<SequenceRequest>,
+) -> impl IntoResponse {
+    if let Err(rejection) = validate(&app_state, &request.steps) {
+        return rejection;
+    }
+    let steps: Vec<SequenceStep> = request
+        .steps
+        .into_iter()
+        .flat_map(|step| with_soft_start(&app_state, step))
+        .collect();
+    let count = steps.len();
+    let id = spawn(&app_state, steps, remote, headers).await;
+    (
+        StatusCode::ACCEPTED,
+        Json(
+            serde_json::json!({"status": "Sequence started", "id": id, "job_id": id, "steps": count}),
+        ),
+    )
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/sven/sequence/{id}",
+    tag = "macros",
+    params(("id" = String, Path)),
+    responses(
+        (status = 200, body = Job),
+        (status = 404, description = "Unknown or expired sequence", body = ErrorBody),
+    ),
+)]
+pub async fn get_sequence(
+    Path(id): Path<String>,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    match app_state.jobs.lock().await.get(&id) {
+        Some(job) if job.kind == JobKind::Sequence => {
+            (StatusCode::OK, Json(serde_json::json!(job)))
+        }
+        _ => jobs::unknown_job(&id),
+    }
+}
@@ -0,0 +1,374 @@
+//! Standing sessions: `POST /api/sven/sessions` raises the desk, counts down and
+//! lowers it again when the time is up, optionally with a notification shortly
+//! before. A session is a job, so it shows up at `GET /api/sven/jobs/{id}`, and the
+//! time left is part of the desk state while it runs. Both moves go through the
+//! same path as `POST /api/sven/command`. A stop cancels the session and leaves the
+//! desk where it is; `DELETE /api/sven/sessions` ends it early and lowers the desk.
+
+use axum::{
+    Json,
+    extract::{ConnectInfo, Extension},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::jobs::{Job, JobKind, JobStatus};
+use crate::listen::ClientAddr;
+use crate::notifications::NotificationKind;
+use crate::validation::{self, ErrorBody};
+use crate::{AppState, CommandRequest, DeskCommand, SvenCommand, SvenPosition, request_id};
+
+const MAX_DURATION_SECS: u64 = 12 * 60 * 60;
+/// How often a running session checks the clock and whether it was stopped.
+const TICK: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SessionRequest {
+    /// How long to stand
+    pub duration_secs: u64,
+    /// Where to stand, the calibrated `Standing` position when absent
+    pub standing_height_mm: Option<u32>,
+    /// Where to go at the end, the height the session started at when absent
+    pub sitting_height_mm: Option<u32>,
+    /// Send a `session` notification this long before the end
+    pub remind_before_secs: Option<u64>,
+}
+
+/// The session in progress, at most one at a time.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Session {
+    /// The job following the session
+    pub job_id: String,
+    pub started_at: DateTime<Utc>,
+    pub ends_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub standing_height_mm: Option<u32>,
+    pub sitting_height_mm: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remind_at: Option<DateTime<Utc>>,
+    #[serde(skip)]
+    reminded: bool,
+}
+
+/// What the desk state reports about a running session.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema)]
+pub struct SessionView {
+    pub ends_at: DateTime<Utc>,
+    pub remaining_secs: u64,
+}
+
+impl Session {
+    pub fn view(&self) -> SessionView {
+        SessionView {
+            ends_at: self.ends_at,
+            remaining_secs: remaining(self.ends_at, Utc::now()).as_secs(),
+        }
+    }
+}
+
+/// Time left until `ends_at`, rounded up so a session only shows 0 once it's over.
+fn remaining(ends_at: DateTime<Utc>, now: DateTime<Utc>) -> Duration {
+    let left = (ends_at - now).to_std().unwrap_or_default();
+    Duration::from_secs(left.as_secs() + u64::from(left.subsec_nanos() > 0))
+}
+
+fn invalid(message: String) -> (StatusCode, Json<serde_json::Value>) {
+    (
+        StatusCode::UNPROCESSABLE_ENTITY,
+        Json(serde_json::json!(ErrorBody {
+            error: message,
+            code: Some("invalid_session".to_string()),
+        })),
+    )
+}
+
+fn validate(
+    app_state: &AppState,
+    request: &SessionRequest,
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    if request.duration_secs == 0 || request.duration_secs > MAX_DURATION_SECS {
+        return Err(invalid(format!(
+            "duration_secs must be between 1 and {}",
+            MAX_DURATION_SECS
+        )));
+    }
+    if let Some(before) = request.remind_before_secs
+        && before >= request.duration_secs
+    {
+        return Err(invalid(
+            "remind_before_secs must be shorter than the session".to_string(),
+        ));
+    }
+    for height_mm in [request.standing_height_mm, request.sitting_height_mm]
+        .into_iter()
+        .flatten()
+    {
+        validation::validate_command(&absolute(height_mm), &app_state.config())?;
+    }
+    Ok(())
+}
+
+fn absolute(height_mm: u32) -> DeskCommand {
+    DeskCommand {
+        command: SvenCommand::AbsoluteHeight,
+        value: height_mm,
+    }
+}
+
+/// Sends `command` the way `POST /api/sven/command` would, returning the error on failure.
+async fn move_desk(
+    app_state: &Arc<AppState>,
+    remote: ClientAddr,
+    headers: &HeaderMap,
+    command: DeskCommand,
+) -> Result<(), String> {
+    let request = CommandRequest {
+        command,
+        confirm: false,
+        timeout_ms: None,
+        override_protected_zones: false,
+        force: false,
+        ack: None,
+        dry_run: false,
+        soft_start: true,
+        ttl_ms: None,
+        label: None,
+        pulse: false,
+    };
+    let (status, Json(body)) =
+        crate::execute_command(app_state, remote, headers, None, request).await;
+    if status.is_success() {
+        Ok(())
+    } else {
+        Err(body["error"].as_str().unwrap_or_default().to_string())
+    }
+}
+
+/// Whether the session's job was ended from outside, by a stop or a cancel.
+async fn cancelled(app_state: &AppState, job_id: &str) -> bool {
+    app_state
+        .jobs
+        .lock()
+        .await
+        .get(job_id)
+        .is_none_or(|job| job.status.is_finished())
+}
+
+async fn finish(app_state: &AppState, job_id: &str, status: JobStatus, error: Option<String>) {
+    app_state.jobs.lock().await.finish(job_id, status, error);
+    *app_state.session.lock().await = None;
+    app_state.refresh_state_cache().await;
+    info!("Standing session {} ended: {:?}", job_id, status);
+}
+
+async fn run(
+    app_state: Arc<AppState>,
+    job_id: String,
+    standing: DeskCommand,
+    remote: ClientAddr,
+    headers: HeaderMap,
+) {
+    if let Err(e) = move_desk(&app_state, remote, &headers, standing).await {
+        let error = format!("Could not raise the desk: {}", e);
+        return finish(&app_state, &job_id, JobStatus::Failed, Some(error)).await;
+    }
+    app_state
+        .jobs
+        .lock()
+        .await
+        .set_status(&job_id, JobStatus::Moving);
+
+    let sitting_height_mm = loop {
+        tokio::time::sleep(TICK).await;
+        if cancelled(&app_state, &job_id).await {
+            *app_state.session.lock().await = None;
+            app_state.refresh_state_cache().await;
+            info!("Standing session {} was stopped", job_id);
+            return;
+        }
+        let now = Utc::now();
+        let mut current = app_state.session.lock().await;
+        let Some(session) = current.as_mut() else {
+            return;
+        };
+        if !session.reminded && session.remind_at.is_some_and(|at| at <= now) {
+            session.reminded = true;
+            let left = remaining(session.ends_at, now);
+            app_state.notifier.notify(
+                NotificationKind::Session {
+                    job_id: job_id.clone(),
+                },
+                format!(
+                    "Standing session ends in {} minutes",
+                    left.as_secs().div_ceil(60)
+                ),
+            );
+        }
+        if session.ends_at <= now {
+            break session.sitting_height_mm;
+        }
+        drop(current);
+        // The remaining time in the cached state goes down every tick
+        app_state.refresh_state_cache().await;
+    };
+
+    app_state.notifier.notify(
+        NotificationKind::Session {
+            job_id: job_id.clone(),
+        },
+        format!(
+            "Standing session over, lowering to {} mm",
+            sitting_height_mm
+        ),
+    );
+    match move_desk(&app_state, remote, &headers, absolute(sitting_height_mm)).await {
+        Ok(()) => finish(&app_state, &job_id, JobStatus::Completed, None).await,
+        Err(e) => {
+            let error = format!("Could not lower the desk: {}", e);
+            finish(&app_state, &job_id, JobStatus::Failed, Some(error)).await
+        }
+    }
+}
+
+/// Starts a standing session in the background.
+#[utoipa::path(
+    post,
+    path = "/api/sven/sessions",
+    tag = "macros",
+    request_body = SessionRequest,
+    responses(
+        (status = 202, description = "Session started, follow it at `/api/sven/jobs/{id}`", body = Session),
+        (status = 409, description = "A session is already running", body = ErrorBody),
+        (status = 422, description = "Invalid duration or height", body = ErrorBody),
+    ),
+)]
+pub async fn start_session(
+    ConnectInfo(remote): ConnectInfo<ClientAddr>,
+    headers: HeaderMap,
+    Extension(app_state): Extension<Arc<AppState>>,
+    Json(request): Json<SessionRequest>,
+) -> impl IntoResponse {
+    if let Err(rejection) = validate(&app_state, &request) {
+        return rejection;
+    }
+    let mut current = app_state.session.lock().await;
+    if let Some(session) = current.as_ref() {
+        return (
+            StatusCode::CONFLICT,
+            Json(serde_json::json!({
+                "error": "A standing session is already running",
+                "code": "session_active",
+                "session": session,
+            })),
+        );
+    }
+
+    let job = Job::new(JobKind::Session, &request_id::from_headers(&headers), None);
+    let job_id = app_state.jobs.lock().await.insert(job);
+    let started_at = Utc::now();
+    let ends_at = started_at + chrono::Duration::seconds(request.duration_secs as i64);
+    let session = Session {
+        job_id: job_id.clone(),
+        started_at,
+        ends_at,
+        standing_height_mm: request.standing_height_mm,
+        sitting_height_mm: request
+            .sitting_height_mm
+            .unwrap_or(app_state.sven_state.read().await.height_mm),
+        remind_at: request
+            .remind_before_secs
+            .map(|before| ends_at - chrono::Duration::seconds(before as i64)),
+        reminded: false,
+    };
+    *current = Some(session.clone());
+    drop(current);
+    app_state.refresh_state_cache().await;
+
+    let standing = match request.standing_height_mm {
+        Some(height_mm) => absolute(height_mm),
+        None => DeskCommand {
+            command: SvenCommand::Position,
+            value: SvenPosition::Standing as u32,
+        },
+    };
+    info!(
+        "Starting standing session {} for {} s",
+        job_id, request.duration_secs
+    );
+    tokio::spawn(run(app_state.clone(), job_id, standing, remote, headers));
+    (StatusCode::ACCEPTED, Json(serde_json::json!(session)))
+}
+
+/// The session in progress, with the time left.
+#[utoipa::path(
+    get,
+    path = "/api/sven/sessions",
+    tag = "macros",
+    responses(
+        (status = 200, body = Session),
+        (status = 404, description = "No session is running", body = ErrorBody),
+    ),
+)]
+pub async fn get_session(Extension(app_state): Extension<Arc<AppState>>) -> impl IntoResponse {
+    match app_state.session.lock().await.as_ref() {
+        Some(session) => {
+            let mut body = serde_json::json!(session);
+            body["remaining_secs"] = session.view().remaining_secs.into();
+            (StatusCode::OK, Json(body))
+        }
+        None => no_session(),
+    }
+}
+
+/// Ends the session now: the desk is lowered as if the time were up.
+#[utoipa::path(
+    delete,
+    path = "/api/sven/sessions",
+    tag = "macros",
+    responses(
+        (status = 200, description = "Session ending, the desk is being lowered", body = Session),
+        (status = 404, description = "No session is running", body = ErrorBody),
+    ),
+)]
+pub async fn end_session(Extension(app_state): Extension<Arc<AppState>>) -> impl IntoResponse {
+    let mut current = app_state.session.lock().await;
+    let Some(session) = current.as_mut() else {
+        return no_session();
+    };
+    warn!("Ending standing session {} early", session.job_id);
+    session.ends_at = session.ends_at.min(Utc::now());
+    (StatusCode::OK, Json(serde_json::json!(session)))
+}
+
+fn no_session() -> (StatusCode, Json<serde_json::Value>) {
+    (
+        StatusCode::NOT_FOUND,
+        Json(serde_json::json!({
+            "error": "No standing session is running",
+            "code": "no_session",
+        })),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remaining_time_rounds_up_to_the_second() {
+        let now = Utc::now();
+        let ends_at = now + chrono::Duration::milliseconds(1500);
+        assert_eq!(remaining(ends_at, now), Duration::from_secs(2));
+        assert_eq!(remaining(now, now), Duration::ZERO);
+        assert_eq!(
+            remaining(now - chrono::Duration::seconds(5), now),
+            Duration::ZERO
+        );
+    }
+}
@@ -0,0 +1,79 @@
+//! Shutdown coordination. Once a signal arrives, long-lived streams end so the
+//! HTTP server can finish its in-flight requests, new commands are refused and
+//! background automation stops issuing moves while queued publishes drain.
+
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio_stream::{Stream, StreamExt, wrappers::WatchStream};
+use tracing::info;
+
+pub struct Shutdown {
+    tx: watch::Sender<bool>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Shutdown {
+            tx: watch::Sender::new(false),
+        }
+    }
+
+    pub fn trigger(&self) {
+        self.tx.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.tx.borrow()
+    }
+
+    /// Resolves once shutdown has been triggered.
+    pub async fn wait(&self) {
+        let _ = self.tx.subscribe().wait_for(|triggered| *triggered).await;
+    }
+
+    /// Resolves `grace` after shutdown has been triggered.
+    pub async fn expired(&self, grace: Duration) {
+        self.wait().await;
+        tokio::time::sleep(grace).await;
+    }
+
+    /// Ends `stream` when shutdown is triggered, so SSE responses don't hold the
+    /// server open.
+    pub fn until<S: Stream>(&self, stream: S) -> impl Stream<Item = S::Item> + use<S> {
+        let stop = WatchStream::new(self.tx.subscribe())
+            .filter(|triggered| *triggered)
+            .map(|_| None);
+        stream
+            .map(Some)
+            .merge(stop)
+            .take_while(Option::is_some)
+            .map(Option::unwrap)
+    }
+}
+
+/// Waits for Ctrl+C or SIGTERM and triggers `shutdown`.
+pub async fn on_signal(shutdown: &Shutdown) {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    info!("Shutdown signal received");
+    shutdown.trigger();
+}
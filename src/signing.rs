@@ -0,0 +1,148 @@
+//! Signed MQTT payloads, for brokers other devices can publish on. With
+//! `SVEN_MQTT_SIGNING_SECRET`, shared with the firmware, every command on the
+//! command topics carries a random `nonce`, a `timestamp` in Unix milliseconds and
+//! a `signature`: the hex HMAC-SHA256 of the message without its `signature`,
+//! written as compact JSON with the keys of every object sorted. State reports,
+//! acks, faults, power and status messages have to be signed the same way, the
+//! plain-text ones as a message with the text in `payload`; ones that aren't,
+//! don't verify, are older than `SVEN_MQTT_SIGNATURE_MAX_AGE_SECS` or repeat a
+//! nonce are dropped, which includes a retained report from before a restart.
+//! Only a bare `offline` status, the firmware's last will, is taken unsigned.
+//! Home Assistant can't sign, so its commands are refused and discovery is off.
+
+use chrono::{DateTime, Utc};
+use ring::hmac;
+use std::collections::HashMap;
+
+fn key(secret: &str) -> hmac::Key {
+    hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// `value` as compact JSON with sorted object keys, the text a signature covers.
+pub fn canonical(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Object(fields) => {
+            let mut keys: Vec<&String> = fields.keys().collect();
+            keys.sort();
+            let fields: Vec<String> = keys
+                .into_iter()
+                .map(|key| {
+                    format!(
+                        "{}:{}",
+                        serde_json::Value::from(key.as_str()),
+                        canonical(&fields[key])
+                    )
+                })
+                .collect();
+            format!("{{{}}}", fields.join(","))
+        }
+        serde_json::Value::Array(items) => {
+            let items: Vec<String> = items.iter().map(canonical).collect();
+            format!("[{}]", items.join(","))
+        }
+        scalar => scalar.to_string(),
+    }
+}
+
+/// Adds a fresh `nonce`, the `timestamp` and the `signature` to a message object.
+pub fn sign(secret: &str, message: &mut serde_json::Value, now: DateTime<Utc>) {
+    message["nonce"] = uuid::Uuid::new_v4().simple().to_string().into();
+    message["timestamp"] = now.timestamp_millis().into();
+    let signature = hmac::sign(&key(secret), canonical(message).as_bytes());
+    message["signature"] = hex(signature.as_ref()).into();
+}
+
+/// Nonces of accepted messages, kept as long as their messages would be fresh.
+#[derive(Debug, Default)]
+pub struct NonceCache(HashMap<String, i64>);
+
+/// Checks a received message's signature, age and nonce, answering why it's
+/// refused. An accepted nonce is remembered so it can't be replayed.
+pub fn verify(
+    secret: &str,
+    payload: &[u8],
+    now: DateTime<Utc>,
+    max_age: chrono::Duration,
+    nonces: &mut NonceCache,
+) -> Result<(), String> {
+    let mut message: serde_json::Value =
+        serde_json::from_slice(payload).map_err(|_| "not a JSON message".to_string())?;
+    let signature = message
+        .as_object_mut()
+        .and_then(|fields| fields.remove("signature"))
+        .ok_or("no signature")?;
+    let signature = signature
+        .as_str()
+        .and_then(|signature| {
+            (0..signature.len())
+                .step_by(2)
+                .map(|at| u8::from_str_radix(signature.get(at..at + 2)?, 16).ok())
+                .collect::<Option<Vec<u8>>>()
+        })
+        .ok_or("the signature isn't hex")?;
+    hmac::verify(
+        &key(secret),
+        canonical(&message).as_bytes(),
+        signature.as_ref(),
+    )
+    .map_err(|_| "the signature doesn't match".to_string())?;
+
+    let timestamp = message["timestamp"].as_i64().ok_or("no timestamp")?;
+    let nonce = message["nonce"].as_str().ok_or("no nonce")?;
+    let now_ms = now.timestamp_millis();
+    let max_age_ms = max_age.num_milliseconds();
+    if (now_ms - timestamp).abs() > max_age_ms {
+        return Err(format!(
+            "the timestamp is {} ms off",
+            (now_ms - timestamp).abs()
+        ));
+    }
+    nonces.0.retain(|_, seen| now_ms - *seen <= max_age_ms);
+    if nonces.0.insert(nonce.to_string(), timestamp).is_some() {
+        return Err(format!("nonce {} was already used", nonce));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "shared-with-the-firmware";
+
+    #[test]
+    fn signed_messages_verify_once() {
+        let now = Utc::now();
+        let max_age = chrono::Duration::seconds(30);
+        let mut message = serde_json::json!({"position": "Custom", "height_mm": 900});
+        sign(SECRET, &mut message, now);
+        let payload = message.to_string();
+        let mut nonces = NonceCache::default();
+
+        assert_eq!(
+            verify(SECRET, payload.as_bytes(), now, max_age, &mut nonces),
+            Ok(())
+        );
+        assert!(verify(SECRET, payload.as_bytes(), now, max_age, &mut nonces).is_err());
+        let mut fresh = NonceCache::default();
+        assert!(verify("other", payload.as_bytes(), now, max_age, &mut fresh).is_err());
+        let later = now + chrono::Duration::seconds(31);
+        assert!(verify(SECRET, payload.as_bytes(), later, max_age, &mut fresh).is_err());
+
+        message["height_mm"] = 1200.into();
+        let tampered = message.to_string();
+        assert!(verify(SECRET, tampered.as_bytes(), now, max_age, &mut fresh).is_err());
+        let unsigned = br#"{"position": "Custom", "height_mm": 900}"#;
+        assert!(verify(SECRET, unsigned, now, max_age, &mut fresh).is_err());
+    }
+
+    #[test]
+    fn keys_are_sorted_at_every_level() {
+        let value = serde_json::json!({"b": [{"z": 1, "a": "x"}], "a": null});
+        assert_eq!(canonical(&value), r#"{"a":null,"b":[{"a":"x","z":1}]}"#);
+    }
+}
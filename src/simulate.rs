@@ -0,0 +1,202 @@
+//! Virtual desk for `--simulate` (or `SVEN_SIMULATE=true`). The MQTT client is
+//! wired to a channel drained here instead of a broker connection, so no broker
+//! or hardware is needed: commands published for the primary desk move a
+//! simulated desk at `SVEN_TRAVEL_SPEED_MM_S`, and its state is fed back as if
+//! the firmware had reported it. Motor settings are applied too, so a lower
+//! `SetSpeed` slows the simulated desk down.
+
+use rumqttc::Request;
+use serde::Deserialize;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tracing::{debug, info};
+
+use crate::ack::{Ack, AckStatus};
+use crate::motor::{AccelerationProfile, MotorSettings};
+use crate::power::PowerState;
+use crate::{AppState, DeskCommand, SvenCommand, SvenPosition, SvenState, apply_state_update};
+
+/// How often the simulated desk reports its height while moving.
+const TICK: Duration = Duration::from_millis(100);
+
+#[derive(Deserialize)]
+struct CommandPayload {
+    #[serde(flatten)]
+    command: DeskCommand,
+    id: Option<String>,
+}
+
+struct VirtualDesk {
+    height_mm: f64,
+    target_mm: Option<f64>,
+    motor: MotorSettings,
+}
+
+impl VirtualDesk {
+    /// Where `command` sends the desk, or `None` when it stops it.
+    async fn target(&self, app_state: &AppState, command: &DeskCommand) -> Option<f64> {
+        let config = &app_state.config();
+        let value = f64::from(command.value);
+        let duration_mm = value * f64::from(config.travel_speed_mm_per_s) / 1000.0;
+        let target_mm = match command.command {
+            SvenCommand::UpDuration => self.height_mm + duration_mm,
+            SvenCommand::DownDuration => self.height_mm - duration_mm,
+            SvenCommand::UpRelative => self.height_mm + value,
+            SvenCommand::DownRelative => self.height_mm - value,
+            SvenCommand::AbsoluteHeight => value,
+            SvenCommand::Position => {
+                let position = SvenPosition::from_index(command.value)?;
+                let heights = app_state.position_heights.lock().await;
+                f64::from(*heights.heights.get(&position)?)
+            }
+            // Calibration homes the desk to its lowest point
+            SvenCommand::Calibrate => f64::from(config.min_height_mm),
+            // Percentages are resolved to absolute moves before they are published
+            SvenCommand::UpPercent | SvenCommand::DownPercent | SvenCommand::Stop => return None,
+            // Published on the motor topic and don't change where the desk is going
+            SvenCommand::SetSpeed | SvenCommand::SetAcceleration | SvenCommand::SetSoftStart => {
+                return self.target_mm;
+            }
+        };
+        Some(target_mm.clamp(
+            f64::from(config.min_height_mm),
+            f64::from(config.max_height_mm),
+        ))
+    }
+
+    /// Moves one tick towards the target, returning whether the height changed.
+    fn step(&mut self, speed_mm_per_s: u32) -> bool {
+        let Some(target_mm) = self.target_mm else {
+            return false;
+        };
+        let speed_share = f64::from(self.motor.speed_percent.unwrap_or(100)) / 100.0;
+        let max_step = f64::from(speed_mm_per_s) * speed_share * TICK.as_secs_f64();
+        let remaining = target_mm - self.height_mm;
+        if remaining.abs() <= max_step {
+            self.height_mm = target_mm;
+            self.target_mm = None;
+        } else {
+            self.height_mm += max_step.copysign(remaining);
+        }
+        true
+    }
+}
+
+/// The state the firmware would report: the height, the saved position closest to
+/// it and the motor settings.
+async fn report(app_state: &AppState, desk: &VirtualDesk) -> SvenState {
+    let height_mm = desk.height_mm.round() as u32;
+    let position = app_state
+        .position_heights
+        .lock()
+        .await
+        .heights
+        .iter()
+        .min_by_key(|(_, position_mm)| position_mm.abs_diff(height_mm))
+        .map(|(position, _)| *position)
+        .unwrap_or(SvenPosition::Bottom);
+    SvenState {
+        height_mm,
+        position,
+        motor: Some(desk.motor),
+    }
+}
+
+/// Stands in for the MQTT eventloop: consumes the client's requests until it
+/// disconnects, and keeps the virtual desk moving in between.
+pub async fn run(app_state: Arc<AppState>, requests: flume::Receiver<Request>) {
+    info!("Simulating the desk, no MQTT broker is used");
+    let mut desk = VirtualDesk {
+        height_mm: f64::from(app_state.sven_state.read().await.height_mm),
+        target_mm: None,
+        motor: MotorSettings {
+            speed_percent: Some(100),
+            acceleration: Some(AccelerationProfile::Normal),
+            soft_start_ms: Some(0),
+        },
+    };
+    app_state.on_broker_connected().await;
+    app_state.power_tx.send_replace(PowerState::Active);
+    apply_state_update(&app_state, None, report(&app_state, &desk).await).await;
+
+    let mut ticker = tokio::time::interval(TICK);
+    loop {
+        tokio::select! {
+            request = requests.recv_async() => match request {
+                Ok(Request::Publish(publish)) => {
+                    let _ = app_state.pending_publishes.fetch_update(
+                        Ordering::SeqCst,
+                        Ordering::SeqCst,
+                        |pending| pending.checked_sub(1),
+                    );
+                    if publish.topic == app_state.config().command_topic {
+                        handle_command(&app_state, &mut desk, &publish.payload).await;
+                    } else if publish.topic == app_state.config().motor_topic {
+                        handle_motor_settings(&app_state, &mut desk, &publish.payload).await;
+                    } else if publish.topic == app_state.config().power_command_topic {
+                        app_state.power_tx.send_replace(PowerState::Active);
+                    } else {
+                        debug!("Simulated publish on {}", publish.topic);
+                    }
+                }
+                Ok(Request::Disconnect(_)) | Err(_) => {
+                    info!("Simulated desk stopped");
+                    return;
+                }
+                Ok(_) => {}
+            },
+            _ = ticker.tick() => {
+                if desk.step(app_state.config().travel_speed_mm_per_s) {
+                    let state = report(&app_state, &desk).await;
+                    apply_state_update(&app_state, None, state).await;
+                }
+            }
+        }
+    }
+}
+
+async fn handle_command(app_state: &AppState, desk: &mut VirtualDesk, payload: &[u8]) {
+    let Ok(message) = serde_json::from_slice::<CommandPayload>(payload) else {
+        debug!("Simulated desk ignored an unreadable command");
+        return;
+    };
+    desk.target_mm = desk.target(app_state, &message.command).await;
+    debug!(
+        "Simulated desk: {} {} from {:.0} mm",
+        message.command.command, message.command.value, desk.height_mm
+    );
+    if let Some(id) = message.id {
+        app_state
+            .ack_waiters
+            .resolve(Ack {
+                id,
+                status: AckStatus::Ok,
+                error: None,
+            })
+            .await;
+    }
+}
+
+async fn handle_motor_settings(app_state: &AppState, desk: &mut VirtualDesk, payload: &[u8]) {
+    let Ok(settings) = serde_json::from_slice::<MotorSettings>(payload) else {
+        debug!("Simulated desk ignored unreadable motor settings");
+        return;
+    };
+    desk.motor = desk.motor.merge(settings);
+    debug!("Simulated desk: motor settings {:?}", desk.motor);
+    let id = serde_json::from_slice::<serde_json::Value>(payload)
+        .ok()
+        .and_then(|payload| payload["id"].as_str().map(str::to_string));
+    if let Some(id) = id {
+        app_state
+            .ack_waiters
+            .resolve(Ack {
+                id,
+                status: AckStatus::Ok,
+                error: None,
+            })
+            .await;
+    }
+    apply_state_update(app_state, None, report(app_state, desk).await).await;
+}
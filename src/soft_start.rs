@@ -0,0 +1,79 @@
+//! A ramp for long timed moves, for motors that jolt when they start at full
+//! speed. With `SVEN_SOFT_START_PULSES_MS=200,200,200`, an UpDuration or
+//! DownDuration longer than `SVEN_SOFT_START_MIN_MS` is sent as those short
+//! pulses, `SVEN_SOFT_START_GAP_MS` apart, followed by the rest of the duration
+//! in one go. The pulses run as a sequence job, so the desk covers the same
+//! distance and a stop cancels the rest. A pulse waits its turn in the queue like
+//! any command, but gives it up once sent, so the gap is what separates them.
+//! `soft_start: false` on a command
+//! sends it unchanged.
+
+use crate::config::Config;
+use crate::sequences::SequenceStep;
+use crate::{DeskCommand, SvenCommand};
+
+/// The steps `command` is sent as, `None` when it goes out unchanged.
+pub fn ramp(config: &Config, command: &DeskCommand) -> Option<Vec<SequenceStep>> {
+    if !matches!(
+        command.command,
+        SvenCommand::UpDuration | SvenCommand::DownDuration
+    ) || config.soft_start_pulses_ms.is_empty()
+        || u64::from(command.value) <= config.soft_start_min_ms
+    {
+        return None;
+    }
+    let pulsed: u64 = config.soft_start_pulses_ms.iter().sum();
+    let rest = u64::from(command.value).checked_sub(pulsed)?;
+    if rest == 0 {
+        return None;
+    }
+    let step = |value: u64, pulse: bool, delay_ms: u64| SequenceStep {
+        command: Some(command.command),
+        value: value as u32,
+        wait: !pulse,
+        until_height_mm: None,
+        delay_ms,
+        pulse,
+    };
+    // A pulse isn't waited for, the desk barely moves; the pause covers it and the gap
+    let mut steps: Vec<SequenceStep> = config
+        .soft_start_pulses_ms
+        .iter()
+        .map(|&pulse| step(pulse, true, pulse + config.soft_start_gap_ms))
+        .collect();
+    steps.push(step(rest, false, 0));
+    Some(steps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_timed_moves_start_with_pulses() {
+        let mut config = Config::load_from(None).unwrap();
+        config.soft_start_pulses_ms = vec![200, 200, 200];
+        config.soft_start_min_ms = 1000;
+        config.soft_start_gap_ms = 100;
+        let up = |value: u32| DeskCommand {
+            command: SvenCommand::UpDuration,
+            value,
+        };
+
+        let steps = ramp(&config, &up(3000)).unwrap();
+        let values: Vec<u32> = steps.iter().map(|step| step.value).collect();
+        assert_eq!(values, [200, 200, 200, 2400]);
+        assert_eq!(steps[0].delay_ms, 300);
+        assert!(!steps[0].wait && steps[3].wait);
+        assert!(steps[0].pulse && !steps[3].pulse);
+
+        assert!(ramp(&config, &up(1000)).is_none());
+        let absolute = DeskCommand {
+            command: SvenCommand::AbsoluteHeight,
+            value: 3000,
+        };
+        assert!(ramp(&config, &absolute).is_none());
+        config.soft_start_pulses_ms.clear();
+        assert!(ramp(&config, &up(3000)).is_none());
+    }
+}
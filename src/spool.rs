@@ -0,0 +1,185 @@
+//! Moves kept while the broker is away, for automations that carry on through a
+//! broker restart. With `SVEN_SPOOL_COMMANDS`, a move that passes validation
+//! while the bridge isn't connected is answered with 202 and spooled, in the store
+//! when there is one so it survives a restart, instead of being refused with
+//! `broker_unavailable`. Once the broker is back the spool is sent like automated
+//! moves: commands past their TTL or older than `SVEN_SPOOL_MAX_AGE_SECS` are
+//! dropped, and an absolute move replaces every move spooled before it, so only
+//! the latest height is driven to. Percentage moves aren't spooled, they depend on
+//! the height when they are sent. `/healthz` and `/readyz` show what is waiting.
+
+use axum::{Json, http::StatusCode};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+use crate::persistence::Artifact;
+use crate::{AppState, DeskCommand, SvenCommand};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpooledCommand {
+    pub request_id: String,
+    pub command: DeskCommand,
+    pub spooled_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Spooled moves, oldest first.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Spool(pub Vec<SpooledCommand>);
+
+impl Artifact for Spool {
+    const KEY: &'static str = "spool";
+}
+
+impl Spool {
+    /// Adds a move; an absolute one replaces the moves spooled before it.
+    fn push(&mut self, entry: SpooledCommand) {
+        if entry.command.command.is_absolute() {
+            self.0.clear();
+        }
+        self.0.push(entry);
+    }
+
+    /// Empties the spool, answering the moves still worth sending at `now`.
+    fn take_due(&mut self, now: DateTime<Utc>, max_age: chrono::Duration) -> Vec<SpooledCommand> {
+        std::mem::take(&mut self.0)
+            .into_iter()
+            .filter(|entry| {
+                entry.expires_at.is_none_or(|expires_at| expires_at > now)
+                    && now - entry.spooled_at <= max_age
+            })
+            .collect()
+    }
+
+    /// What `/healthz` and `/readyz` show.
+    pub fn status(&self) -> serde_json::Value {
+        serde_json::json!({
+            "pending": self.0.len(),
+            "oldest_spooled_at": self.0.first().map(|entry| entry.spooled_at),
+        })
+    }
+}
+
+/// Whether a move arriving while the broker is unreachable can wait for it.
+pub fn accepts(command: &DeskCommand) -> bool {
+    command.command.is_movement()
+        && !matches!(
+            command.command,
+            SvenCommand::UpPercent | SvenCommand::DownPercent
+        )
+}
+
+fn persist(app_state: &AppState, spool: &Spool) {
+    if let Some(store) = &app_state.store
+        && let Err(e) = store.put(spool)
+    {
+        error!("Failed to persist the command spool: {}", e);
+    }
+}
+
+/// Spools `command` until the broker is back.
+pub async fn spool(
+    app_state: &AppState,
+    request_id: &str,
+    command: DeskCommand,
+    expires_at: Option<DateTime<Utc>>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let mut spool = app_state.spool.lock().await;
+    if !command.command.is_absolute() && spool.0.len() >= app_state.config().spool_max_commands {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(serde_json::json!({
+                "error": "Not connected to the MQTT broker and the command spool is full",
+                "code": "spool_full",
+            })),
+        );
+    }
+    info!(
+        "Spooling {} with value {} until the broker is back",
+        command.command, command.value
+    );
+    spool.push(SpooledCommand {
+        request_id: request_id.to_string(),
+        command,
+        spooled_at: Utc::now(),
+        expires_at,
+    });
+    persist(app_state, &spool);
+    (
+        StatusCode::ACCEPTED,
+        Json(serde_json::json!({
+            "status": "Not connected to the MQTT broker, the command is spooled until it is back",
+            "request_id": request_id,
+            "spool": spool.status(),
+        })),
+    )
+}
+
+/// Sends what was spooled while the broker was away.
+pub async fn flush(app_state: Arc<AppState>) {
+    let max_age = chrono::Duration::seconds(app_state.config().spool_max_age_secs as i64);
+    let mut spool = app_state.spool.lock().await;
+    if spool.0.is_empty() {
+        return;
+    }
+    let spooled = spool.0.len();
+    let due = spool.take_due(Utc::now(), max_age);
+    persist(&app_state, &spool);
+    drop(spool);
+    if due.len() < spooled {
+        warn!(
+            "Dropped {} spooled commands that expired while the broker was away",
+            spooled - due.len()
+        );
+    }
+    for entry in due {
+        info!(
+            "Sending spooled {} with value {} ({})",
+            entry.command.command, entry.command.value, entry.request_id
+        );
+        if let Err(e) = app_state.send_automated(entry.command).await {
+            warn!("Spooled command {} wasn't sent: {}", entry.request_id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(command: SvenCommand, value: u32, age_secs: i64) -> SpooledCommand {
+        SpooledCommand {
+            request_id: format!("{:?}-{}", command, value),
+            command: DeskCommand { command, value },
+            spooled_at: Utc::now() - chrono::Duration::seconds(age_secs),
+            expires_at: None,
+        }
+    }
+
+    #[test]
+    fn the_latest_absolute_move_wins() {
+        let mut spool = Spool::default();
+        spool.push(entry(SvenCommand::UpRelative, 50, 10));
+        spool.push(entry(SvenCommand::AbsoluteHeight, 900, 8));
+        spool.push(entry(SvenCommand::AbsoluteHeight, 1100, 6));
+        spool.push(entry(SvenCommand::DownRelative, 20, 4));
+        let mut stale = entry(SvenCommand::UpDuration, 500, 2);
+        stale.expires_at = Some(Utc::now() - chrono::Duration::seconds(1));
+        spool.push(stale);
+
+        let due = spool.take_due(Utc::now(), chrono::Duration::seconds(300));
+        let values: Vec<u32> = due.iter().map(|entry| entry.command.value).collect();
+        assert_eq!(values, [1100, 20]);
+        assert!(spool.0.is_empty());
+
+        spool.push(entry(SvenCommand::AbsoluteHeight, 900, 400));
+        assert!(
+            spool
+                .take_due(Utc::now(), chrono::Duration::seconds(300))
+                .is_empty()
+        );
+    }
+}
@@ -0,0 +1,99 @@
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Exponential backoff between connection attempts, from `INITIAL_BACKOFF` up to `MAX_BACKOFF`.
+pub struct Backoff {
+    next: Duration,
+}
+
+impl Backoff {
+    pub fn new() -> Self {
+        Backoff {
+            next: INITIAL_BACKOFF,
+        }
+    }
+
+    /// How long to wait before the next attempt; each call doubles the wait.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(MAX_BACKOFF);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.next = INITIAL_BACKOFF;
+    }
+}
+
+/// Tracks attempts to connect and subscribe before the bridge is first subscribed,
+/// so it can start before the broker is up. Retries use exponential backoff; with
+/// `strict` set, the bridge gives up after `max_attempts`.
+pub struct StartupRetry {
+    strict: bool,
+    max_attempts: u32,
+    attempts: u32,
+    backoff: Backoff,
+    subscribed: bool,
+}
+
+impl StartupRetry {
+    pub fn new(strict: bool, max_attempts: u32) -> Self {
+        StartupRetry {
+            strict,
+            max_attempts,
+            attempts: 0,
+            backoff: Backoff::new(),
+            subscribed: false,
+        }
+    }
+
+    pub fn is_subscribed(&self) -> bool {
+        self.subscribed
+    }
+
+    pub fn on_subscribed(&mut self) {
+        if !self.subscribed && self.attempts > 0 {
+            info!(
+                "Subscribed to MQTT topics after {} failed attempts",
+                self.attempts
+            );
+        }
+        self.subscribed = true;
+        self.attempts = 0;
+        self.backoff.reset();
+    }
+
+    /// Records a failed attempt and returns how long to wait before the next one,
+    /// or `None` when strict startup has run out of attempts.
+    pub fn on_failure(&mut self, reason: &str) -> Option<Duration> {
+        self.attempts += 1;
+        if self.strict && self.attempts >= self.max_attempts {
+            error!(
+                "MQTT startup failed after {} attempts: {}",
+                self.attempts, reason
+            );
+            return None;
+        }
+        let delay = self.backoff.next_delay();
+        if self.strict {
+            warn!(
+                "MQTT not ready (attempt {}/{}): {}, retrying in {} ms",
+                self.attempts,
+                self.max_attempts,
+                reason,
+                delay.as_millis()
+            );
+        } else {
+            warn!(
+                "MQTT not ready (attempt {}): {}, retrying in {} ms",
+                self.attempts,
+                reason,
+                delay.as_millis()
+            );
+        }
+        Some(delay)
+    }
+}
@@ -0,0 +1,145 @@
+use axum::{
+    body::Bytes,
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::time::Duration;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::SvenState;
+use crate::energy::PowerDraw;
+use crate::faults::FaultKind;
+use crate::movement::Direction;
+use crate::occupancy::Occupancy;
+use crate::power::PowerState;
+use crate::sessions::SessionView;
+use crate::status::DeskStatus;
+
+/// What `GET /api/sven/state` reports: the firmware state plus what the bridge knows about the desk.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct StateView {
+    #[serde(flatten)]
+    pub state: SvenState,
+    pub direction: Direction,
+    /// Whether the bridge is connected to the MQTT broker; commands can't reach the desk otherwise
+    pub broker_connected: bool,
+    pub power: PowerState,
+    /// What the presence sensor last reported, `unknown` without one
+    pub occupancy: Occupancy,
+    /// What the smart plug last measured, absent without one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub power_w: Option<f64>,
+    /// Whether that draw says the motor is running
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub power_draw: Option<PowerDraw>,
+    pub status: DeskStatus,
+    /// When the desk last reported its state, `null` if it hasn't since startup
+    pub last_updated: Option<chrono::DateTime<chrono::Utc>>,
+    /// Older than `SVEN_STALE_STATE_SECS`; never set when that is unconfigured
+    pub stale: bool,
+    /// The fault blocking movement until it's cleared, see `GET /api/sven/faults`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fault: Option<FaultKind>,
+    /// The standing session in progress and the time it has left
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session: Option<SessionView>,
+    /// The custom position label the desk is at, see `PUT /api/sven/positions/{position}`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+}
+
+/// `StateView` serialized once per change, so state reads only clone the bytes.
+#[derive(Clone)]
+pub struct CachedState {
+    body: Bytes,
+    etag: HeaderValue,
+}
+
+/// The longest a `?wait=true` request is held open.
+pub const MAX_WAIT: Duration = Duration::from_secs(60);
+const DEFAULT_WAIT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct WaitParams {
+    /// Hold the request until the state changes: from the `If-None-Match` ETag
+    /// when given, otherwise from now
+    #[serde(default)]
+    pub wait: bool,
+    /// How long to hold it, up to 60 s; answered with 304, or the unchanged state
+    /// without `If-None-Match`, once it runs out
+    pub timeout_secs: Option<u64>,
+}
+
+impl WaitParams {
+    pub fn timeout(&self) -> Duration {
+        self.timeout_secs
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_WAIT)
+            .min(MAX_WAIT)
+    }
+}
+
+impl CachedState {
+    pub fn new(view: &StateView) -> Self {
+        Self::from_body(Bytes::from(serde_json::to_vec(view).unwrap()))
+    }
+
+    /// A body built on demand, like one with heights in another unit, with its own ETag.
+    pub fn from_value(body: &serde_json::Value) -> Self {
+        Self::from_body(Bytes::from(serde_json::to_vec(body).unwrap()))
+    }
+
+    fn from_body(body: Bytes) -> Self {
+        let mut hasher = DefaultHasher::new();
+        body.hash(&mut hasher);
+        let etag = HeaderValue::from_str(&format!("\"{:016x}\"", hasher.finish())).unwrap();
+        CachedState { body, etag }
+    }
+
+    pub fn etag(&self) -> &HeaderValue {
+        &self.etag
+    }
+
+    /// Whether the request's `If-None-Match` names this body, so the client's
+    /// copy is still current.
+    pub fn is_fresh(&self, headers: &HeaderMap) -> bool {
+        let Some(if_none_match) = headers
+            .get(header::IF_NONE_MATCH)
+            .and_then(|value| value.to_str().ok())
+        else {
+            return false;
+        };
+        let etag = self.etag.to_str().unwrap_or_default();
+        if_none_match
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag)
+    }
+
+    pub fn not_modified(&self) -> Response {
+        (
+            StatusCode::NOT_MODIFIED,
+            [(header::ETAG, self.etag.clone())],
+        )
+            .into_response()
+    }
+}
+
+impl IntoResponse for CachedState {
+    fn into_response(self) -> Response {
+        (
+            StatusCode::OK,
+            [
+                (
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static("application/json"),
+                ),
+                (header::ETAG, self.etag),
+            ],
+            self.body,
+        )
+            .into_response()
+    }
+}
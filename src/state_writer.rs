@@ -0,0 +1,105 @@
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, warn};
+
+use crate::SvenState;
+use crate::history::HistoryDb;
+use crate::persistence::Store;
+
+/// Reports queued before the writer counts as behind and drops them
+const QUEUE_CAPACITY: usize = 1024;
+
+enum Write {
+    History {
+        desk_id: Option<String>,
+        state: SvenState,
+    },
+    Persist(SvenState),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Records state reports in the history database and the store on the blocking
+/// pool, in order, so disk I/O never holds up the task driving the MQTT event
+/// loop. Of the states queued while a write runs only the latest is persisted.
+pub struct StateWriter {
+    sender: mpsc::Sender<Write>,
+}
+
+impl StateWriter {
+    pub fn spawn(store: Option<Arc<dyn Store>>, history_db: Option<Arc<HistoryDb>>) -> Self {
+        let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        tokio::spawn(write(store, history_db, receiver));
+        StateWriter { sender }
+    }
+
+    pub fn record_history(&self, desk_id: Option<&str>, state: &SvenState) {
+        self.send(Write::History {
+            desk_id: desk_id.map(str::to_string),
+            state: *state,
+        });
+    }
+
+    pub fn persist(&self, state: &SvenState) {
+        self.send(Write::Persist(*state));
+    }
+
+    /// Waits until everything queued so far is written.
+    pub async fn flush(&self) {
+        let (done, written) = oneshot::channel();
+        if self.sender.send(Write::Flush(done)).await.is_ok() {
+            let _ = written.await;
+        }
+    }
+
+    fn send(&self, write: Write) {
+        if self.sender.try_send(write).is_err() {
+            warn!("State writer is behind, dropping a state report");
+        }
+    }
+}
+
+async fn write(
+    store: Option<Arc<dyn Store>>,
+    history_db: Option<Arc<HistoryDb>>,
+    mut receiver: mpsc::Receiver<Write>,
+) {
+    while let Some(first) = receiver.recv().await {
+        let mut batch = vec![first];
+        while let Ok(next) = receiver.try_recv() {
+            batch.push(next);
+        }
+        let (store, history_db) = (store.clone(), history_db.clone());
+        let written = tokio::task::spawn_blocking(move || {
+            let mut flushed = Vec::new();
+            let mut latest = None;
+            for write in batch {
+                match write {
+                    Write::History { desk_id, state } => {
+                        if let Some(history_db) = &history_db
+                            && let Err(e) = history_db.record_state(desk_id.as_deref(), &state)
+                        {
+                            error!("Failed to record state history: {}", e);
+                        }
+                    }
+                    Write::Persist(state) => latest = Some(state),
+                    Write::Flush(done) => flushed.push(done),
+                }
+            }
+            if let (Some(store), Some(state)) = (&store, latest)
+                && let Err(e) = store.put(&state)
+            {
+                error!("Failed to persist Sven state: {}", e);
+            }
+            flushed
+        })
+        .await;
+        match written {
+            Ok(flushed) => {
+                for done in flushed {
+                    let _ = done.send(());
+                }
+            }
+            Err(e) => error!("State writer failed: {}", e),
+        }
+    }
+}
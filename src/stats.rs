@@ -0,0 +1,457 @@
+use axum::{
+    Json,
+    extract::{Extension, Query},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use chrono::{DateTime, Local, NaiveDate, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info};
+use utoipa::IntoParams;
+
+use crate::history::CommandSender;
+use crate::notifications::NotificationKind;
+use crate::{AppState, energy};
+
+const DEFAULT_RANGE: &str = "7d";
+const MAX_RANGE_DAYS: i64 = 366;
+/// How often today's standing time is checked against the daily goal.
+const GOAL_TICK: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct StatsQuery {
+    /// How far back to look, in days or hours: `7d`, `24h`. Defaults to `7d`
+    pub range: Option<String>,
+    /// Desk to report on, the primary desk when unset
+    pub desk: Option<String>,
+}
+
+fn parse_range(range: &str) -> Option<chrono::Duration> {
+    let count = |digits: &str| digits.parse::<i64>().ok().filter(|count| *count > 0);
+    let duration = if let Some(days) = range.strip_suffix('d') {
+        chrono::Duration::days(count(days)?)
+    } else {
+        chrono::Duration::hours(count(range.strip_suffix('h')?)?)
+    };
+    (duration <= chrono::Duration::days(MAX_RANGE_DAYS)).then_some(duration)
+}
+
+/// Time at each height band, summed up for a day or the whole range.
+#[derive(Debug, Default, Clone, Copy)]
+struct Totals {
+    standing_ms: i64,
+    sitting_ms: i64,
+    transitions: u32,
+    /// Sum of height times milliseconds held, for the time-weighted average
+    height_mm_ms: f64,
+}
+
+impl Totals {
+    fn add(&mut self, other: &Totals) {
+        self.standing_ms += other.standing_ms;
+        self.sitting_ms += other.sitting_ms;
+        self.transitions += other.transitions;
+        self.height_mm_ms += other.height_mm_ms;
+    }
+
+    fn summary(&self) -> Summary {
+        let tracked_ms = self.standing_ms + self.sitting_ms;
+        Summary {
+            standing_minutes: (self.standing_ms as f64 / 60_000.0).round() as u64,
+            sitting_minutes: (self.sitting_ms as f64 / 60_000.0).round() as u64,
+            transitions: self.transitions,
+            average_height_mm: (tracked_ms > 0)
+                .then(|| (self.height_mm_ms / tracked_ms as f64).round() as u32),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Summary {
+    standing_minutes: u64,
+    sitting_minutes: u64,
+    /// Changes between sitting and standing
+    transitions: u32,
+    /// Time-weighted, `null` when no height was known
+    average_height_mm: Option<u32>,
+}
+
+/// Splits the time between `since` and `until` into days of `tz`. Each report's
+/// height counts until the next report; time before the first report isn't
+/// counted, as the height then is unknown.
+fn daily_totals<Tz: TimeZone>(
+    reports: &[(DateTime<Utc>, u32)],
+    since: DateTime<Utc>,
+    until: DateTime<Utc>,
+    standing_from_mm: u32,
+    tz: &Tz,
+) -> BTreeMap<NaiveDate, Totals> {
+    let local_date = |at: DateTime<Utc>| at.with_timezone(tz).date_naive();
+    let mut days = BTreeMap::new();
+    let mut date = local_date(since);
+    while date <= local_date(until) {
+        days.insert(date, Totals::default());
+        date = date.succ_opt().unwrap();
+    }
+
+    for (index, &(reported_at, height_mm)) in reports.iter().enumerate() {
+        let standing = height_mm >= standing_from_mm;
+        if index > 0 && reported_at >= since {
+            let was_standing = reports[index - 1].1 >= standing_from_mm;
+            if standing != was_standing {
+                days.entry(local_date(reported_at)).or_default().transitions += 1;
+            }
+        }
+
+        let end = reports
+            .get(index + 1)
+            .map_or(until, |(next_at, _)| *next_at)
+            .min(until);
+        let mut start = reported_at.max(since);
+        while start < end {
+            // Up to the next local midnight, so each day gets its own share
+            let next_midnight = local_date(start)
+                .succ_opt()
+                .and_then(|next| next.and_hms_opt(0, 0, 0))
+                .and_then(|midnight| tz.from_local_datetime(&midnight).earliest())
+                .map(|midnight| midnight.with_timezone(&Utc))
+                .unwrap_or(start + chrono::Duration::days(1));
+            let segment_end = next_midnight.min(end);
+            let ms = (segment_end - start).num_milliseconds();
+            let totals = days.entry(local_date(start)).or_default();
+            if standing {
+                totals.standing_ms += ms;
+            } else {
+                totals.sitting_ms += ms;
+            }
+            totals.height_mm_ms += f64::from(height_mm) * ms as f64;
+            start = segment_end;
+        }
+    }
+    days
+}
+
+/// Standing and sitting time per user over `since..until`. Time counts for the
+/// user who sent the last command before it, taken to be the one at the desk;
+/// time after commands without a user, or before any command, counts for no one.
+/// `commands` are send times and users, oldest first.
+fn user_totals(
+    reports: &[(DateTime<Utc>, u32)],
+    commands: &[CommandSender],
+    since: DateTime<Utc>,
+    until: DateTime<Utc>,
+    standing_from_mm: u32,
+) -> BTreeMap<String, Totals> {
+    let user_at = |at: DateTime<Utc>| {
+        commands
+            .iter()
+            .rev()
+            .find(|(sent_at, _)| *sent_at <= at)
+            .and_then(|(_, user)| user.clone())
+    };
+    let mut users: BTreeMap<String, Totals> = BTreeMap::new();
+    for (index, &(reported_at, height_mm)) in reports.iter().enumerate() {
+        let standing = height_mm >= standing_from_mm;
+        if index > 0
+            && reported_at >= since
+            && standing != (reports[index - 1].1 >= standing_from_mm)
+            && let Some(user) = user_at(reported_at)
+        {
+            users.entry(user).or_default().transitions += 1;
+        }
+
+        let end = reports
+            .get(index + 1)
+            .map_or(until, |(next_at, _)| *next_at)
+            .min(until);
+        let mut start = reported_at.max(since);
+        while start < end {
+            // Up to the next command, which may hand the desk to someone else
+            let segment_end = commands
+                .iter()
+                .map(|(sent_at, _)| *sent_at)
+                .find(|sent_at| *sent_at > start && *sent_at < end)
+                .unwrap_or(end);
+            if let Some(user) = user_at(start) {
+                let ms = (segment_end - start).num_milliseconds();
+                let totals = users.entry(user).or_default();
+                if standing {
+                    totals.standing_ms += ms;
+                } else {
+                    totals.sitting_ms += ms;
+                }
+                totals.height_mm_ms += f64::from(height_mm) * ms as f64;
+            }
+            start = segment_end;
+        }
+    }
+    users
+}
+
+/// Standing and sitting time, sit/stand changes and average height per day, from
+/// the state reports in the history database, and the same totals per user. The desk counts as standing from
+/// the sitting reminder's `sitting_below_mm` up. With a smart plug configured, each
+/// day and the total also carry the `energy_wh` the desk used.
+#[utoipa::path(
+    get,
+    path = "/api/sven/stats",
+    tag = "history",
+    params(StatsQuery),
+    responses(
+        (status = 200, description = "Daily and total statistics, oldest day first", body = serde_json::Value),
+        (status = 422, description = "Invalid range", body = crate::validation::ErrorBody),
+        (status = 503, description = "No history database is configured", body = crate::validation::ErrorBody),
+    ),
+)]
+pub async fn get_stats(
+    Query(query): Query<StatsQuery>,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    let Some(history_db) = &app_state.history_db else {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(serde_json::json!({
+                "error": "Statistics need the history database, set SVEN_HISTORY_DB",
+                "code": "history_unavailable",
+            })),
+        );
+    };
+    let range = query.range.as_deref().unwrap_or(DEFAULT_RANGE);
+    let Some(duration) = parse_range(range) else {
+        return (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(serde_json::json!({
+                "error": format!(
+                    "Invalid range '{}', expected days or hours such as 7d or 24h, at most {}d",
+                    range, MAX_RANGE_DAYS
+                ),
+            })),
+        );
+    };
+
+    let until = Utc::now();
+    let since = until - duration;
+    let config = app_state.config();
+    let desk = query.desk.as_deref().or(config.desk_id.as_deref());
+    let metered = config.power_meter_topic.is_some();
+    let history = history_db.state_reports(desk, since).and_then(|reports| {
+        let readings = if metered {
+            history_db.power_readings(desk, since)?
+        } else {
+            Vec::new()
+        };
+        Ok((reports, history_db.command_users(desk, since)?, readings))
+    });
+    let (reports, commands, readings) = match history {
+        Ok(history) => history,
+        Err(e) => {
+            error!("Failed to read state history: {}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({"error": "Failed to read history"})),
+            );
+        }
+    };
+    let standing_from_mm = app_state.reminders.lock().await.settings.sitting_below_mm;
+
+    let days = daily_totals(&reports, since, until, standing_from_mm, &Local);
+    let users: BTreeMap<_, _> = user_totals(&reports, &commands, since, until, standing_from_mm)
+        .into_iter()
+        .map(|(user, totals)| (user, totals.summary()))
+        .collect();
+    let energy = energy::daily_energy_wh(&readings, since, until, &Local);
+    let energy_wh = |wh: f64| (wh * 10.0).round() / 10.0;
+    let mut total = Totals::default();
+    let days: Vec<_> = days
+        .iter()
+        .map(|(date, totals)| {
+            total.add(totals);
+            let mut day = serde_json::json!(totals.summary());
+            day["date"] = serde_json::json!(date);
+            if metered {
+                day["energy_wh"] = energy_wh(energy.get(date).copied().unwrap_or_default()).into();
+            }
+            day
+        })
+        .collect();
+    let mut total = serde_json::json!(total.summary());
+    if metered {
+        total["energy_wh"] = energy_wh(energy.values().sum()).into();
+    }
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "range": range,
+            "since": since,
+            "until": until,
+            "standing_from_mm": standing_from_mm,
+            "days": days,
+            "total": total,
+            "users": users,
+        })),
+    )
+}
+
+/// Standing time on `until`'s day in `tz`, up to `until`.
+fn standing_minutes_today<Tz: TimeZone>(
+    reports: &[(DateTime<Utc>, u32)],
+    until: DateTime<Utc>,
+    standing_from_mm: u32,
+    tz: &Tz,
+) -> u64 {
+    let today = until.with_timezone(tz).date_naive();
+    let midnight = today
+        .and_hms_opt(0, 0, 0)
+        .and_then(|midnight| tz.from_local_datetime(&midnight).earliest())
+        .map_or(until, |midnight| midnight.with_timezone(&Utc));
+    daily_totals(reports, midnight, until, standing_from_mm, tz)
+        .get(&today)
+        .map_or(0, |totals| totals.summary().standing_minutes)
+}
+
+/// Notifies once a day when the primary desk's standing time since local midnight
+/// reaches the reminder settings' `standing_goal_minutes`, as counted by the stats.
+pub async fn run_standing_goal(app_state: Arc<AppState>) {
+    let Some(history_db) = app_state.history_db.clone() else {
+        return;
+    };
+    let mut met_on = None;
+    loop {
+        tokio::time::sleep(GOAL_TICK).await;
+        let (goal_minutes, standing_from_mm) = {
+            let settings = &app_state.reminders.lock().await.settings;
+            (settings.standing_goal_minutes, settings.sitting_below_mm)
+        };
+        let Some(goal_minutes) = goal_minutes.filter(|minutes| *minutes > 0) else {
+            continue;
+        };
+        let now = Utc::now();
+        let today = now.with_timezone(&Local).date_naive();
+        if met_on == Some(today) {
+            continue;
+        }
+
+        let history_db = history_db.clone();
+        let desk = app_state.config().desk_id.clone();
+        let since = now - chrono::Duration::days(1);
+        let reports =
+            tokio::task::spawn_blocking(move || history_db.state_reports(desk.as_deref(), since))
+                .await;
+        let reports = match reports {
+            Ok(Ok(reports)) => reports,
+            Ok(Err(e)) => {
+                error!("Failed to read state history: {}", e);
+                continue;
+            }
+            Err(e) => {
+                error!("Standing goal check failed: {}", e);
+                continue;
+            }
+        };
+        let standing_minutes = standing_minutes_today(&reports, now, standing_from_mm, &Local);
+        if standing_minutes < goal_minutes {
+            continue;
+        }
+        met_on = Some(today);
+        info!(
+            "Stood for {} minutes today, the goal of {} is met",
+            standing_minutes, goal_minutes
+        );
+        app_state.notifier.notify(
+            NotificationKind::GoalMet {
+                standing_minutes,
+                goal_minutes,
+            },
+            format!(
+                "Stood for {} minutes today, daily goal of {} minutes met",
+                standing_minutes, goal_minutes
+            ),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 3, 10, hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn parses_days_and_hours() {
+        assert_eq!(parse_range("7d"), Some(chrono::Duration::days(7)));
+        assert_eq!(parse_range("24h"), Some(chrono::Duration::hours(24)));
+        assert_eq!(parse_range("0d"), None);
+        assert_eq!(parse_range("3w"), None);
+        assert_eq!(parse_range("400d"), None);
+        assert_eq!(parse_range(""), None);
+        assert_eq!(parse_range("7é"), None);
+    }
+
+    #[test]
+    fn splits_time_at_midnight_and_counts_transitions() {
+        // Sitting from before the range, standing 22:00 to 01:00, then sitting again
+        let reports = [
+            (at(8, 0), 700),
+            (at(22, 0), 1100),
+            (at(23, 0) + chrono::Duration::hours(2), 700),
+        ];
+        let since = at(12, 0);
+        let until = at(12, 0) + chrono::Duration::days(1);
+        let days = daily_totals(&reports, since, until, 900, &Utc);
+
+        let first = days[&since.date_naive()].summary();
+        assert_eq!(first.sitting_minutes, 10 * 60);
+        assert_eq!(first.standing_minutes, 2 * 60);
+        assert_eq!(first.transitions, 1);
+        assert_eq!(first.average_height_mm, Some(767));
+
+        let second = days[&until.date_naive()].summary();
+        assert_eq!(second.standing_minutes, 60);
+        assert_eq!(second.sitting_minutes, 11 * 60);
+        assert_eq!(second.transitions, 1);
+    }
+
+    #[test]
+    fn time_counts_for_whoever_moved_the_desk_last() {
+        let reports = [(at(9, 0), 1100), (at(12, 0), 700), (at(12, 30), 1150)];
+        let commands = [
+            (at(8, 59), Some("alice".to_string())),
+            (at(11, 59), None),
+            (at(12, 29), Some("bob".to_string())),
+        ];
+        let users = user_totals(&reports, &commands, at(9, 0), at(14, 0), 900);
+
+        let alice = users["alice"].summary();
+        assert_eq!(alice.standing_minutes, 3 * 60 - 1);
+        assert_eq!(alice.sitting_minutes, 0);
+        let bob = users["bob"].summary();
+        assert_eq!((bob.sitting_minutes, bob.standing_minutes), (1, 90));
+        assert_eq!(bob.transitions, 1);
+        assert_eq!(users.len(), 2);
+    }
+
+    #[test]
+    fn standing_today_starts_at_midnight() {
+        let yesterday = at(20, 0) - chrono::Duration::days(1);
+        let reports = [(yesterday, 1100), (at(9, 0), 700), (at(10, 0), 1100)];
+        assert_eq!(
+            standing_minutes_today(&reports, at(10, 45), 900, &Utc),
+            9 * 60 + 45
+        );
+    }
+
+    #[test]
+    fn time_before_the_first_report_is_not_counted() {
+        let since = at(0, 0);
+        let days = daily_totals(&[(at(23, 0), 1000)], since, at(23, 30), 900, &Utc);
+        let summary = days[&since.date_naive()].summary();
+        assert_eq!(summary.standing_minutes, 30);
+        assert_eq!(summary.sitting_minutes, 0);
+        assert_eq!(summary.transitions, 0);
+    }
+}
@@ -0,0 +1,146 @@
+//! Status of the primary desk, derived from what the bridge sees happen: height
+//! reports and how they change, commands it publishes, the controller's status
+//! topic and the state going stale. It shows up as `status` in the desk state and
+//! as `status` events on `GET /api/sven/events`.
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::movement::Direction;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum DeskStatus {
+    Idle,
+    MovingUp,
+    MovingDown,
+    /// The controller reported an error; cleared once the desk moves again
+    Error,
+    /// The controller announced it went offline or its state went stale
+    Offline,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusEvent {
+    /// A height report, travelling in the direction it shows
+    Reported(Direction),
+    /// Reports stopped for long enough to consider the desk stopped
+    Settled,
+    /// A command was published that moves the desk this way
+    Commanded(Direction),
+    /// The controller published an error on its status topic
+    Failed,
+    /// The controller announced it is offline, or its state went stale
+    WentOffline,
+    /// The controller announced it is back online
+    CameOnline,
+    /// The faults that put the desk into `Error` were cleared
+    Cleared,
+}
+
+impl DeskStatus {
+    fn moving(direction: Direction) -> DeskStatus {
+        match direction {
+            Direction::Up => DeskStatus::MovingUp,
+            Direction::Down => DeskStatus::MovingDown,
+            Direction::Idle => DeskStatus::Idle,
+        }
+    }
+
+    fn is_moving(self) -> bool {
+        matches!(self, DeskStatus::MovingUp | DeskStatus::MovingDown)
+    }
+
+    /// The status after `event`.
+    pub fn next(self, event: StatusEvent) -> DeskStatus {
+        match event {
+            // An error stands until the desk is seen moving again
+            StatusEvent::Reported(Direction::Idle) if self == DeskStatus::Error => self,
+            StatusEvent::Reported(direction) => DeskStatus::moving(direction),
+            StatusEvent::Settled if self.is_moving() => DeskStatus::Idle,
+            StatusEvent::Settled => self,
+            // Without the controller nothing is going to move
+            StatusEvent::Commanded(_) if self == DeskStatus::Offline => self,
+            StatusEvent::Commanded(direction) => DeskStatus::moving(direction),
+            StatusEvent::Failed => DeskStatus::Error,
+            StatusEvent::WentOffline => DeskStatus::Offline,
+            StatusEvent::CameOnline if self == DeskStatus::Offline => DeskStatus::Idle,
+            StatusEvent::CameOnline => self,
+            StatusEvent::Cleared if self == DeskStatus::Error => DeskStatus::Idle,
+            StatusEvent::Cleared => self,
+        }
+    }
+}
+
+/// The message of an error the controller reports on its status topic, either
+/// `error` or `error: <message>` or a JSON object with an `error` field.
+pub fn error_message(payload: &str) -> Option<String> {
+    let payload = payload.trim();
+    if let Ok(serde_json::Value::Object(object)) = serde_json::from_str(payload) {
+        return match object.get("error")? {
+            serde_json::Value::String(message) => Some(message.clone()),
+            serde_json::Value::Null | serde_json::Value::Bool(false) => None,
+            other => Some(other.to_string()),
+        };
+    }
+    let rest = payload
+        .get(..5)
+        .filter(|prefix| prefix.eq_ignore_ascii_case("error"))
+        .map(|_| &payload[5..])?;
+    if !rest.is_empty() && !rest.starts_with([':', ' ']) {
+        return None;
+    }
+    Some(rest.trim_start_matches([':', ' ']).trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_follows_desk_events() {
+        let status = [
+            StatusEvent::CameOnline,
+            StatusEvent::Commanded(Direction::Up),
+        ]
+        .into_iter()
+        .fold(DeskStatus::Offline, DeskStatus::next);
+        assert_eq!(status, DeskStatus::MovingUp);
+        assert_eq!(
+            status.next(StatusEvent::Reported(Direction::Down)),
+            DeskStatus::MovingDown
+        );
+        assert_eq!(status.next(StatusEvent::Settled), DeskStatus::Idle);
+
+        let failed = status.next(StatusEvent::Failed);
+        assert_eq!(failed.next(StatusEvent::Reported(Direction::Idle)), failed);
+        assert_eq!(failed.next(StatusEvent::Settled), failed);
+        assert_eq!(
+            failed.next(StatusEvent::Reported(Direction::Up)),
+            DeskStatus::MovingUp
+        );
+
+        let offline = status.next(StatusEvent::WentOffline);
+        assert_eq!(offline.next(StatusEvent::Commanded(Direction::Up)), offline);
+        assert_eq!(
+            offline.next(StatusEvent::Reported(Direction::Idle)),
+            DeskStatus::Idle
+        );
+    }
+
+    #[test]
+    fn error_payloads_carry_their_message() {
+        assert_eq!(
+            error_message("ERROR: motor overcurrent").as_deref(),
+            Some("motor overcurrent")
+        );
+        assert_eq!(error_message("error").as_deref(), Some(""));
+        assert_eq!(
+            error_message(r#"{"error": "E07", "code": 7}"#).as_deref(),
+            Some("E07")
+        );
+        assert_eq!(error_message(r#"{"error": null}"#), None);
+        assert_eq!(error_message("online"), None);
+        assert_eq!(error_message("offline"), None);
+        assert_eq!(error_message("errored"), None);
+    }
+}
@@ -0,0 +1,179 @@
+use rumqttc::QoS;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use crate::config::Config;
+use crate::topics::TopicPattern;
+use crate::transport::QosLevel;
+
+/// What the eventloop does with a message on a subscribed topic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TopicHandler {
+    /// Desk state reports, routed per desk by the `+` level
+    State,
+    /// Controller online/offline status
+    Status,
+    /// Motor controller power state
+    Power,
+    /// Firmware faults, see `faults`
+    Fault,
+    /// Presence sensor reports, see `occupancy`
+    Occupancy,
+    /// Smart plug power readings, see `energy`
+    PowerMeter,
+    /// Firmware acknowledgements of published commands
+    Ack,
+    /// Home Assistant command topics, see `homeassistant`
+    HomeAssistant,
+    /// Logged and otherwise ignored, for topics that have no handler yet
+    Log,
+}
+
+impl FromStr for TopicHandler {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "state" => Ok(TopicHandler::State),
+            "status" => Ok(TopicHandler::Status),
+            "power" => Ok(TopicHandler::Power),
+            "fault" => Ok(TopicHandler::Fault),
+            "occupancy" => Ok(TopicHandler::Occupancy),
+            "power_meter" => Ok(TopicHandler::PowerMeter),
+            "ack" => Ok(TopicHandler::Ack),
+            "homeassistant" => Ok(TopicHandler::HomeAssistant),
+            "log" => Ok(TopicHandler::Log),
+            other => Err(format!("unknown topic handler '{}'", other)),
+        }
+    }
+}
+
+/// Handler and QoS for one configured topic, written `handler` or `handler:qos`.
+#[derive(Debug, Clone, Copy)]
+pub struct SubscriptionSpec {
+    pub handler: TopicHandler,
+    pub qos: QoS,
+}
+
+impl FromStr for SubscriptionSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (handler, qos) = match s.split_once(':') {
+            Some((handler, qos)) => (handler, qos.trim()),
+            None => (s, "1"),
+        };
+        let QosLevel(qos) = qos.parse()?;
+        Ok(SubscriptionSpec {
+            handler: handler.trim().parse()?,
+            qos,
+        })
+    }
+}
+
+struct Route {
+    pattern: TopicPattern,
+    spec: SubscriptionSpec,
+}
+
+/// Subscribed topics and the handler each one dispatches to. Routes are tried in
+/// order, so configured topics take precedence over the built-in ones and the
+/// catch-all under the topic prefix comes last.
+pub struct TopicRouter {
+    routes: Vec<Route>,
+}
+
+impl TopicRouter {
+    pub fn from_config(config: &Config) -> Result<Self, String> {
+        let mut configured: Vec<(&String, &SubscriptionSpec)> =
+            config.subscriptions.iter().collect();
+        configured.sort_by_key(|(filter, _)| *filter);
+
+        let defaults = [
+            (config.state_topic_pattern.as_str(), TopicHandler::State),
+            (config.status_topic.as_str(), TopicHandler::Status),
+            (config.power_topic.as_str(), TopicHandler::Power),
+            (config.error_topic.as_str(), TopicHandler::Fault),
+            (config.ack_topic.as_str(), TopicHandler::Ack),
+        ];
+        let occupancy = config
+            .occupancy_topic
+            .as_deref()
+            .map(|topic| (topic, TopicHandler::Occupancy));
+        let power_meter = config
+            .power_meter_topic
+            .as_deref()
+            .map(|topic| (topic, TopicHandler::PowerMeter));
+
+        // Registry desks report on their own topics, with the id as the `+` level
+        let desk_state_filter = config.desk_state_topic.replace("{id}", "+");
+        let desk_states =
+            (!config.desks.is_empty()).then_some((desk_state_filter.as_str(), TopicHandler::State));
+        let ha_filter = format!("{}/#", config.ha_topic_prefix);
+        let ha_commands = config
+            .ha_discovery
+            .then_some((ha_filter.as_str(), TopicHandler::HomeAssistant));
+
+        let mut routes = Vec::new();
+        for (filter, spec) in configured {
+            routes.push(Route {
+                pattern: TopicPattern::parse(filter)?,
+                spec: *spec,
+            });
+        }
+        let catch_all_filter = format!("{}/#", config.topic_prefix);
+        let catch_all = (catch_all_filter.as_str(), TopicHandler::Log);
+        let builtin = defaults
+            .into_iter()
+            .chain(occupancy)
+            .chain(power_meter)
+            .chain(desk_states)
+            .chain(ha_commands)
+            .chain([catch_all]);
+        for (filter, handler) in builtin {
+            if config.subscriptions.contains_key(filter) {
+                continue;
+            }
+            routes.push(Route {
+                pattern: TopicPattern::parse(filter)?,
+                spec: SubscriptionSpec {
+                    handler,
+                    qos: QoS::AtLeastOnce,
+                },
+            });
+        }
+        Ok(TopicRouter { routes })
+    }
+
+    /// Filters to subscribe to after connecting. Filters already covered by a
+    /// `prefix/#` route are left out so the broker doesn't deliver messages twice.
+    pub fn subscriptions(&self) -> Vec<(&str, QoS)> {
+        let mut filters: HashMap<&str, QoS> = HashMap::new();
+        for route in &self.routes {
+            let filter = route.pattern.filter();
+            let covered = self.routes.iter().any(|other| {
+                let other = other.pattern.filter();
+                other != filter
+                    && other
+                        .strip_suffix('#')
+                        .is_some_and(|prefix| filter.starts_with(prefix))
+            });
+            if !covered {
+                filters.entry(filter).or_insert(route.spec.qos);
+            }
+        }
+        let mut filters: Vec<_> = filters.into_iter().collect();
+        filters.sort_by_key(|(filter, _)| *filter);
+        filters
+    }
+
+    /// The handler for `topic` and the desk id its pattern extracted, if any.
+    pub fn route(&self, topic: &str) -> Option<(TopicHandler, Option<String>)> {
+        self.routes.iter().find_map(|route| {
+            route
+                .pattern
+                .match_topic(topic)
+                .map(|desk_id| (route.spec.handler, desk_id))
+        })
+    }
+}
@@ -0,0 +1,169 @@
+//! HTTPS for the HTTP API, with rustls. The certificate comes from `SVEN_TLS_CERT`
+//! and `SVEN_TLS_KEY`, or is generated self-signed with `SVEN_TLS_SELF_SIGNED` for
+//! a desk on the LAN without a real certificate. A generated certificate is written
+//! to the configured paths so clients only have to trust it once; without paths it
+//! only lives until the next restart.
+
+use axum::extract::connect_info::Connected;
+use axum::serve::{IncomingStream, Listener};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::server::TlsStream;
+use tracing::{debug, info, warn};
+
+use crate::config::Config;
+use crate::listen::ClientAddr;
+
+/// Clients that don't finish the handshake in this time are dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The TLS settings to serve with, or `None` when HTTPS isn't configured.
+pub fn server_config(config: &Config) -> Result<Option<Arc<ServerConfig>>, String> {
+    let (chain, key) = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert_path), Some(key_path)) => {
+            if config.tls_self_signed && !cert_path.exists() && !key_path.exists() {
+                let (cert_pem, key_pem) = self_signed(config)?;
+                write_pem(cert_path, &cert_pem, 0o644)?;
+                write_pem(key_path, &key_pem, 0o600)?;
+                info!(
+                    "Generated a self-signed certificate at {}",
+                    cert_path.display()
+                );
+            }
+            (
+                CertificateDer::pem_file_iter(cert_path)
+                    .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                    .map_err(|e| format!("failed to read {}: {}", cert_path.display(), e))?,
+                PrivateKeyDer::from_pem_file(key_path)
+                    .map_err(|e| format!("failed to read {}: {}", key_path.display(), e))?,
+            )
+        }
+        _ if config.tls_self_signed => {
+            warn!(
+                "Serving a self-signed certificate that changes on every restart, set SVEN_TLS_CERT and SVEN_TLS_KEY to keep it"
+            );
+            let (cert_pem, key_pem) = self_signed(config)?;
+            (
+                vec![
+                    CertificateDer::from_pem_slice(cert_pem.as_bytes())
+                        .map_err(|e| e.to_string())?,
+                ],
+                PrivateKeyDer::from_pem_slice(key_pem.as_bytes()).map_err(|e| e.to_string())?,
+            )
+        }
+        _ => return Ok(None),
+    };
+
+    let mut server_config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_no_client_auth()
+        .with_single_cert(chain, key)
+        .map_err(|e| format!("invalid certificate or key: {}", e))?;
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Some(Arc::new(server_config)))
+}
+
+/// A certificate and key for `localhost`, `SVEN_TLS_HOSTNAMES` and the bind
+/// address when it is a specific one, PEM encoded.
+fn self_signed(config: &Config) -> Result<(String, String), String> {
+    let mut names = vec!["localhost".to_string()];
+    names.extend(config.tls_hostnames.iter().cloned());
+    if !matches!(config.http_host.as_str(), "0.0.0.0" | "::" | "localhost") {
+        names.push(config.http_host.clone());
+    }
+    let generated = rcgen::generate_simple_self_signed(names)
+        .map_err(|e| format!("failed to generate a certificate: {}", e))?;
+    Ok((generated.cert.pem(), generated.signing_key.serialize_pem()))
+}
+
+fn write_pem(path: &Path, pem: &str, mode: u32) -> Result<(), String> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(path)
+        .and_then(|mut file| file.write_all(pem.as_bytes()))
+        .map_err(|e| format!("failed to write {}: {}", path.display(), e))
+}
+
+/// A TCP listener that hands out connections once their TLS handshake is done.
+/// Handshakes run in their own tasks, so a slow client doesn't hold up the rest.
+pub struct TlsListener {
+    connections: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl TlsListener {
+    pub fn new(listener: TcpListener, config: Arc<ServerConfig>) -> std::io::Result<TlsListener> {
+        let local_addr = listener.local_addr()?;
+        let acceptor = TlsAcceptor::from(config);
+        let (connections_tx, connections) = mpsc::channel(16);
+        tokio::spawn(async move {
+            loop {
+                let accepted = tokio::select! {
+                    accepted = listener.accept() => accepted,
+                    _ = connections_tx.closed() => return,
+                };
+                let (stream, addr) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        // Typically out of file descriptors, give connections time to close
+                        warn!("Failed to accept a connection: {}", e);
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+                let acceptor = acceptor.clone();
+                let connections_tx = connections_tx.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            let _ = connections_tx.send((stream, addr)).await;
+                        }
+                        Ok(Err(e)) => debug!("TLS handshake with {} failed: {}", addr, e),
+                        Err(_) => debug!("TLS handshake with {} timed out", addr),
+                    }
+                });
+            }
+        });
+        Ok(TlsListener {
+            connections,
+            local_addr,
+        })
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        self.connections
+            .recv()
+            .await
+            .expect("the accept task runs as long as the listener")
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+impl Connected<IncomingStream<'_, TlsListener>> for ClientAddr {
+    fn connect_info(stream: IncomingStream<'_, TlsListener>) -> Self {
+        ClientAddr::Tcp(*stream.remote_addr())
+    }
+}
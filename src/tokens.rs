@@ -0,0 +1,352 @@
+//! API tokens scoped to desks, for sharing one bridge between the people at
+//! different desks. `POST /api/admin/tokens` issues a token with scopes written
+//! `desk:<desk id>:<read|control>`: `read` allows GET and HEAD, `control` every
+//! method, and `*` stands for every desk. A token only reaches the routes of its
+//! desks, `/api/desks/{desk_id}/…` and `/api/sven/{desk_id}/state`; the bridge's
+//! other routes act on the primary desk and need a `desk:*` scope. Only a digest
+//! of each token is kept, so it is shown once, when it's issued. Issuing a token
+//! requires one on every route, also when no `SVEN_API_KEYS` are set. The gRPC
+//! API only takes the static keys.
+
+use axum::{
+    Json,
+    extract::{Extension, Path},
+    http::{HeaderMap, Method, StatusCode},
+    response::IntoResponse,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::{error, info};
+use utoipa::ToSchema;
+
+use crate::persistence::Artifact;
+use crate::validation::ErrorBody;
+use crate::{AppState, admin, auth, login};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScopeAccess {
+    Read,
+    Control,
+}
+
+/// One `desk:<desk id>:<read|control>` scope.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Scope {
+    /// `*` for every desk
+    pub desk: String,
+    pub access: ScopeAccess,
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        let (Some("desk"), Some(desk), Some(access), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(format!("scope '{}' isn't desk:<desk id>:<read|control>", s));
+        };
+        if desk.is_empty() {
+            return Err(format!("scope '{}' has no desk", s));
+        }
+        let access = match access {
+            "read" => ScopeAccess::Read,
+            "control" => ScopeAccess::Control,
+            other => return Err(format!("unknown access '{}'", other)),
+        };
+        Ok(Scope {
+            desk: desk.to_string(),
+            access,
+        })
+    }
+}
+
+impl TryFrom<String> for Scope {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let access = match self.access {
+            ScopeAccess::Read => "read",
+            ScopeAccess::Control => "control",
+        };
+        write!(f, "desk:{}:{}", self.desk, access)
+    }
+}
+
+impl From<Scope> for String {
+    fn from(scope: Scope) -> String {
+        scope.to_string()
+    }
+}
+
+impl Scope {
+    /// Whether the scope covers `desk`, the primary desk being `None`.
+    fn covers(&self, desk: Option<&str>) -> bool {
+        self.desk == "*" || desk == Some(self.desk.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ScopedToken {
+    pub name: String,
+    #[schema(value_type = Vec<String>, example = json!(["desk:office-1:control"]))]
+    pub scopes: Vec<Scope>,
+    /// Hex SHA-256 of the token
+    #[schema(ignore)]
+    pub token_sha256: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl ScopedToken {
+    /// The token as listed, without its digest.
+    fn public(&self) -> serde_json::Value {
+        let mut value = serde_json::json!(self);
+        if let Some(fields) = value.as_object_mut() {
+            fields.remove("token_sha256");
+        }
+        value
+    }
+
+    /// Whether the token may call `method` on `path`.
+    pub fn permits(&self, method: &Method, path: &str) -> bool {
+        let reads = matches!(*method, Method::GET | Method::HEAD);
+        let desk = desk_of(path);
+        self.scopes
+            .iter()
+            .any(|scope| scope.covers(desk) && (reads || scope.access == ScopeAccess::Control))
+    }
+}
+
+/// The desk a route acts on: the id in `/api/desks/{desk_id}/…` and
+/// `/api/sven/{desk_id}/state`, `None` for the primary desk's and the bridge's
+/// other routes.
+fn desk_of(path: &str) -> Option<&str> {
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    match segments.as_slice() {
+        ["api", "desks", desk, _, ..] => Some(desk),
+        ["api", "sven", desk, "state"] => Some(desk),
+        _ => None,
+    }
+}
+
+fn digest(token: &str) -> String {
+    ring::digest::digest(&ring::digest::SHA256, token.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Issued tokens by name.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Tokens(pub BTreeMap<String, ScopedToken>);
+
+impl Artifact for Tokens {
+    const KEY: &'static str = "tokens";
+}
+
+impl Tokens {
+    /// The issued token `token` is, if any.
+    pub fn find(&self, token: &str) -> Option<&ScopedToken> {
+        let digest = digest(token);
+        self.0
+            .values()
+            .find(|issued| auth::secrets_match(&digest, &issued.token_sha256))
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct TokenRequest {
+    /// Identifies the token when listing and revoking it
+    pub name: String,
+    #[schema(example = json!(["desk:office-1:control", "desk:*:read"]))]
+    pub scopes: Vec<String>,
+}
+
+fn invalid(message: String) -> (StatusCode, Json<serde_json::Value>) {
+    (
+        StatusCode::UNPROCESSABLE_ENTITY,
+        Json(serde_json::json!(ErrorBody {
+            error: message,
+            code: Some("invalid_token".to_string()),
+        })),
+    )
+}
+
+fn persist(app_state: &AppState, tokens: &Tokens) {
+    if let Some(store) = &app_state.store
+        && let Err(e) = store.put(tokens)
+    {
+        error!("Failed to persist API tokens: {}", e);
+    }
+}
+
+/// The issued tokens, without the tokens themselves.
+#[utoipa::path(
+    get,
+    path = "/api/admin/tokens",
+    tag = "admin",
+    responses(
+        (status = 200, body = Vec<ScopedToken>),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+    ),
+)]
+pub async fn list_tokens(
+    headers: HeaderMap,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    if let Err(rejection) = admin::require_admin(&headers, &app_state.config()) {
+        return rejection;
+    }
+    let tokens = app_state.tokens.lock().await;
+    (
+        StatusCode::OK,
+        Json(serde_json::Value::Array(
+            tokens.0.values().map(ScopedToken::public).collect(),
+        )),
+    )
+}
+
+/// Issues a token, answering with it; this is the only time it is shown.
+#[utoipa::path(
+    post,
+    path = "/api/admin/tokens",
+    tag = "admin",
+    request_body = TokenRequest,
+    responses(
+        (status = 201, description = "The token's name, scopes and `token`", body = serde_json::Value),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+        (status = 409, description = "A token with the name exists", body = ErrorBody),
+        (status = 422, description = "Invalid name or scopes", body = ErrorBody),
+    ),
+)]
+pub async fn create_token(
+    headers: HeaderMap,
+    Extension(app_state): Extension<Arc<AppState>>,
+    Json(request): Json<TokenRequest>,
+) -> impl IntoResponse {
+    if let Err(rejection) = admin::require_admin(&headers, &app_state.config()) {
+        return rejection;
+    }
+    let name = request.name.trim().to_string();
+    if name.is_empty() {
+        return invalid("The name must not be empty".to_string());
+    }
+    if name == "admin" || app_state.config().api_keys.contains_key(&name) {
+        return invalid(format!("'{}' is the name of an API key", name));
+    }
+    if request.scopes.is_empty() {
+        return invalid("A token needs at least one scope".to_string());
+    }
+    let scopes = match request
+        .scopes
+        .iter()
+        .map(|scope| scope.parse())
+        .collect::<Result<Vec<Scope>, String>>()
+    {
+        Ok(scopes) => scopes,
+        Err(e) => return invalid(e),
+    };
+
+    let mut tokens = app_state.tokens.lock().await;
+    if tokens.0.contains_key(&name) {
+        return (
+            StatusCode::CONFLICT,
+            Json(serde_json::json!({
+                "error": format!("A token named '{}' exists, delete it first", name),
+                "code": "token_exists",
+            })),
+        );
+    }
+    let token = login::random_secret();
+    let issued = ScopedToken {
+        name: name.clone(),
+        scopes,
+        token_sha256: digest(&token),
+        created_at: Utc::now(),
+    };
+    let mut body = issued.public();
+    body["token"] = token.into();
+    tokens.0.insert(name.clone(), issued);
+    persist(&app_state, &tokens);
+    info!("Issued API token '{}'", name);
+    (StatusCode::CREATED, Json(body))
+}
+
+/// Revokes a token.
+#[utoipa::path(
+    delete,
+    path = "/api/admin/tokens/{name}",
+    tag = "admin",
+    params(("name" = String, Path)),
+    responses(
+        (status = 200, body = serde_json::Value),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    ),
+)]
+pub async fn delete_token(
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    if let Err(rejection) = admin::require_admin(&headers, &app_state.config()) {
+        return rejection;
+    }
+    let mut tokens = app_state.tokens.lock().await;
+    if tokens.0.remove(&name).is_none() {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({"error": format!("Unknown token '{}'", name)})),
+        );
+    }
+    persist(&app_state, &tokens);
+    info!("Revoked API token '{}'", name);
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({"status": "Token revoked", "name": name})),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scopes_cover_their_desks() {
+        let token = ScopedToken {
+            name: "ada".to_string(),
+            scopes: vec![
+                "desk:office-1:control".parse().unwrap(),
+                "desk:*:read".parse().unwrap(),
+            ],
+            token_sha256: digest("secret"),
+            created_at: Utc::now(),
+        };
+        assert!(token.permits(&Method::POST, "/api/desks/office-1/command"));
+        assert!(!token.permits(&Method::POST, "/api/desks/office-2/command"));
+        assert!(token.permits(&Method::GET, "/api/desks/office-2/state"));
+        assert!(token.permits(&Method::GET, "/api/sven/office-2/state"));
+        assert!(token.permits(&Method::GET, "/api/sven/state"));
+        assert!(!token.permits(&Method::POST, "/api/sven/command"));
+
+        assert!("desk:office-1".parse::<Scope>().is_err());
+        assert!("room:office-1:read".parse::<Scope>().is_err());
+        assert!("desk:office-1:write".parse::<Scope>().is_err());
+        let tokens = Tokens(BTreeMap::from([("ada".to_string(), token)]));
+        assert!(tokens.find("secret").is_some());
+        assert!(tokens.find("other").is_none());
+    }
+}
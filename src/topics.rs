@@ -0,0 +1,93 @@
+/// A state topic pattern, optionally with a wildcard that identifies the desk.
+///
+/// Patterns use MQTT filter syntax. Extraction rules:
+/// - a `+` level matches exactly one topic level and its value is taken as the desk id;
+///   at most one `+` is allowed,
+/// - a trailing `#` matches any remaining levels, which are ignored,
+/// - every other level must match literally.
+///
+/// So `sven/+/state` matches `sven/office/state` with desk id `office`, while
+/// the plain `sven/state` matches only itself and yields no desk id.
+#[derive(Debug, Clone)]
+pub struct TopicPattern {
+    pattern: String,
+    levels: Vec<Level>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Level {
+    Literal(String),
+    DeskId,
+    Rest,
+}
+
+impl TopicPattern {
+    pub fn parse(pattern: &str) -> Result<Self, String> {
+        if pattern.is_empty() {
+            return Err("topic pattern must not be empty".to_string());
+        }
+
+        let parts: Vec<&str> = pattern.split('/').collect();
+        let mut levels = Vec::with_capacity(parts.len());
+        for (i, part) in parts.iter().enumerate() {
+            let level = match *part {
+                "+" => Level::DeskId,
+                "#" if i == parts.len() - 1 => Level::Rest,
+                "#" => return Err(format!("'#' must be the last level in '{}'", pattern)),
+                _ if part.contains(['+', '#']) => {
+                    return Err(format!(
+                        "wildcards must occupy a whole level in '{}'",
+                        pattern
+                    ));
+                }
+                _ => Level::Literal(part.to_string()),
+            };
+            levels.push(level);
+        }
+
+        if levels.iter().filter(|l| **l == Level::DeskId).count() > 1 {
+            return Err(format!(
+                "at most one '+' (the desk id) is allowed in '{}'",
+                pattern
+            ));
+        }
+
+        Ok(TopicPattern {
+            pattern: pattern.to_string(),
+            levels,
+        })
+    }
+
+    /// The MQTT subscription filter for this pattern.
+    pub fn filter(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Returns `Some(desk_id)` when `topic` matches; the desk id is `None`
+    /// for patterns without a `+` level.
+    pub fn match_topic(&self, topic: &str) -> Option<Option<String>> {
+        let mut desk_id = None;
+        let mut parts = topic.split('/');
+        for level in &self.levels {
+            match level {
+                Level::Rest => return Some(desk_id),
+                Level::Literal(literal) => {
+                    if parts.next()? != literal {
+                        return None;
+                    }
+                }
+                Level::DeskId => {
+                    let part = parts.next()?;
+                    if part.is_empty() {
+                        return None;
+                    }
+                    desk_id = Some(part.to_string());
+                }
+            }
+        }
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(desk_id)
+    }
+}
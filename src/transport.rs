@@ -0,0 +1,494 @@
+//! How messages reach the desk and how its reports come back, picked with
+//! `SVEN_TRANSPORT`:
+//! - `mqtt` (default): straight to the broker,
+//! - `zigbee2mqtt`: the desk is a Zigbee2MQTT device. Commands are sent as its
+//!   `height` and `state` attributes on `<base>/<device>/set`, and the reports it
+//!   publishes on `<base>/<device>` are read as the primary desk's state,
+//! - `http`: every message is POSTed to `SVEN_TRANSPORT_URL` as
+//!   `{topic, payload, retain, qos}`, and the bridge on the other end reports back
+//!   through `POST /api/sven/transport/messages` with `{topic, payload}`.
+//!
+//! The rest of the service keeps speaking in topics and payloads, so the topic
+//! router and its handlers are the same whatever the transport.
+
+use axum::{Json, extract::Extension, http::StatusCode, response::IntoResponse};
+use rumqttc::{AsyncClient, QoS, SubscribeFilter};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::info;
+
+use crate::config::Config;
+use crate::{AppState, DeskCommand, SvenCommand};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportKind {
+    Mqtt,
+    Zigbee2Mqtt,
+    Http,
+}
+
+impl FromStr for TransportKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "mqtt" => Ok(TransportKind::Mqtt),
+            "zigbee2mqtt" | "z2m" => Ok(TransportKind::Zigbee2Mqtt),
+            "http" => Ok(TransportKind::Http),
+            other => Err(format!("unknown transport '{}'", other)),
+        }
+    }
+}
+
+/// A QoS level as settings write it: `0`, `1` or `2`.
+#[derive(Debug, Clone, Copy)]
+pub struct QosLevel(pub QoS);
+
+impl FromStr for QosLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "0" => Ok(QosLevel(QoS::AtMostOnce)),
+            "1" => Ok(QosLevel(QoS::AtLeastOnce)),
+            "2" => Ok(QosLevel(QoS::ExactlyOnce)),
+            other => Err(format!("QoS must be 0, 1 or 2, got '{}'", other)),
+        }
+    }
+}
+
+/// The kinds of message the API publishes, each with its own QoS and retain flag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageClass {
+    /// Desk commands other than stop, motor settings, wake, maintenance and OTA messages
+    Command,
+    Stop,
+    /// Home Assistant discovery configs
+    Config,
+    /// The API's availability, also its last will
+    Status,
+}
+
+impl MessageClass {
+    pub const ALL: [MessageClass; 4] = [
+        MessageClass::Command,
+        MessageClass::Stop,
+        MessageClass::Config,
+        MessageClass::Status,
+    ];
+
+    /// How the class is named in `SVEN_PUBLISH_<CLASS>_QOS` and `_RETAIN`.
+    pub fn setting_name(self) -> &'static str {
+        match self {
+            MessageClass::Command => "COMMAND",
+            MessageClass::Stop => "STOP",
+            MessageClass::Config => "CONFIG",
+            MessageClass::Status => "STATUS",
+        }
+    }
+
+    /// QoS 1 for all of them; discovery configs and availability are retained so
+    /// late subscribers still see them.
+    pub fn default_options(self) -> PublishOptions {
+        PublishOptions {
+            qos: QoS::AtLeastOnce,
+            retain: matches!(self, MessageClass::Config | MessageClass::Status),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PublishOptions {
+    pub qos: QoS,
+    pub retain: bool,
+}
+
+/// The options each class is published with.
+#[derive(Debug, Clone, Default)]
+pub struct PublishClasses(HashMap<MessageClass, PublishOptions>);
+
+impl PublishClasses {
+    pub fn options(&self, class: MessageClass) -> PublishOptions {
+        self.0
+            .get(&class)
+            .copied()
+            .unwrap_or_else(|| class.default_options())
+    }
+
+    pub fn set(&mut self, class: MessageClass, options: PublishOptions) {
+        self.0.insert(class, options);
+    }
+}
+
+/// Why a message didn't get out, which decides what the HTTP client is told.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportErrorKind {
+    /// The broker, or the bridge behind the HTTP transport, can't be reached.
+    Unreachable,
+    /// The message wasn't taken within `SVEN_PUBLISH_TIMEOUT_MS`.
+    Timeout,
+    /// The message reached the other end and was refused or couldn't be sent.
+    Rejected,
+}
+
+impl TransportErrorKind {
+    pub const ALL: [TransportErrorKind; 3] = [
+        TransportErrorKind::Unreachable,
+        TransportErrorKind::Timeout,
+        TransportErrorKind::Rejected,
+    ];
+
+    /// The machine-readable code HTTP error bodies and the failure metric carry.
+    pub fn code(self) -> &'static str {
+        match self {
+            TransportErrorKind::Unreachable => "broker_unreachable",
+            TransportErrorKind::Timeout => "publish_timeout",
+            TransportErrorKind::Rejected => "publish_rejected",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TransportError {
+    pub kind: TransportErrorKind,
+    message: String,
+}
+
+impl TransportError {
+    pub fn new(kind: TransportErrorKind, message: impl Into<String>) -> Self {
+        TransportError {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    pub fn timeout(after: Duration) -> Self {
+        TransportError::new(
+            TransportErrorKind::Timeout,
+            format!("publish timed out after {} ms", after.as_millis()),
+        )
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self.kind {
+            TransportErrorKind::Unreachable => StatusCode::SERVICE_UNAVAILABLE,
+            TransportErrorKind::Timeout | TransportErrorKind::Rejected => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    /// The response for a request whose message couldn't be published, with
+    /// `error` saying what wasn't sent.
+    pub fn response(
+        &self,
+        error: &str,
+        request_id: Option<&str>,
+    ) -> (StatusCode, Json<serde_json::Value>) {
+        let mut body = serde_json::json!({
+            "error": error,
+            "code": self.kind.code(),
+            "detail": self.message,
+        });
+        if let Some(request_id) = request_id {
+            body["request_id"] = request_id.into();
+        }
+        (self.status(), Json(body))
+    }
+}
+
+impl std::fmt::Display for TransportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// The client only fails once its eventloop is gone, so there is no broker to
+/// hand messages to.
+impl From<rumqttc::ClientError> for TransportError {
+    fn from(e: rumqttc::ClientError) -> Self {
+        TransportError::new(TransportErrorKind::Unreachable, e.to_string())
+    }
+}
+
+impl From<reqwest::Error> for TransportError {
+    fn from(e: reqwest::Error) -> Self {
+        let kind = if e.is_timeout() {
+            TransportErrorKind::Timeout
+        } else if e.is_status() {
+            TransportErrorKind::Rejected
+        } else {
+            TransportErrorKind::Unreachable
+        };
+        TransportError::new(kind, e.to_string())
+    }
+}
+
+pub type TransportFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<(), TransportError>> + Send + 'a>>;
+
+pub trait DeskTransport: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn publish<'a>(
+        &'a self,
+        topic: &'a str,
+        payload: Vec<u8>,
+        options: PublishOptions,
+    ) -> TransportFuture<'a>;
+
+    /// Whether `publish` only queues the message, for the MQTT eventloop to send
+    /// and count as sent.
+    fn queues_publishes(&self) -> bool {
+        true
+    }
+
+    /// Queues subscriptions without waiting, as it is called from the eventloop
+    /// that sends them.
+    fn subscribe(&self, filters: Vec<(String, QoS)>) -> Result<(), TransportError>;
+
+    fn disconnect(&self) -> TransportFuture<'_>;
+
+    /// The topic and payload the router should see for a received message.
+    fn incoming(&self, topic: String, payload: Vec<u8>) -> (String, Vec<u8>) {
+        (topic, payload)
+    }
+}
+
+/// The transport `SVEN_TRANSPORT` selects. `client` is only used by the MQTT based ones.
+pub fn from_config(config: &Config, client: AsyncClient) -> Box<dyn DeskTransport> {
+    let mqtt = MqttTransport { client };
+    match config.transport {
+        TransportKind::Mqtt => Box::new(mqtt),
+        TransportKind::Zigbee2Mqtt => Box::new(Zigbee2MqttTransport {
+            mqtt,
+            device_topic: format!("{}/{}", config.z2m_base_topic, config.z2m_device),
+            command_topic: config.command_topic.clone(),
+            state_topic: config.primary_state_topic(),
+        }),
+        TransportKind::Http => Box::new(HttpTransport {
+            client: reqwest::Client::new(),
+            url: config.transport_url.clone().unwrap_or_default(),
+        }),
+    }
+}
+
+pub struct MqttTransport {
+    client: AsyncClient,
+}
+
+impl DeskTransport for MqttTransport {
+    fn name(&self) -> &'static str {
+        "mqtt"
+    }
+
+    fn publish<'a>(
+        &'a self,
+        topic: &'a str,
+        payload: Vec<u8>,
+        options: PublishOptions,
+    ) -> TransportFuture<'a> {
+        Box::pin(async move {
+            self.client
+                .publish(topic, options.qos, options.retain, payload)
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn subscribe(&self, filters: Vec<(String, QoS)>) -> Result<(), TransportError> {
+        let filters = filters
+            .into_iter()
+            .map(|(filter, qos)| SubscribeFilter::new(filter, qos));
+        self.client.try_subscribe_many(filters)?;
+        Ok(())
+    }
+
+    fn disconnect(&self) -> TransportFuture<'_> {
+        Box::pin(async move {
+            self.client.disconnect().await?;
+            Ok(())
+        })
+    }
+}
+
+pub struct Zigbee2MqttTransport {
+    mqtt: MqttTransport,
+    /// `<base>/<device>`, where the device reports and under which it takes commands
+    device_topic: String,
+    command_topic: String,
+    state_topic: String,
+}
+
+#[derive(Deserialize)]
+struct Zigbee2MqttReport {
+    height: f64,
+}
+
+impl Zigbee2MqttTransport {
+    /// The device attributes a desk command sets. Only absolute moves and stops
+    /// map onto a Zigbee2MQTT desk; calibrated positions and percentages arrive
+    /// here resolved to absolute moves already.
+    fn attributes(payload: &[u8]) -> Result<serde_json::Value, TransportError> {
+        let command: DeskCommand = serde_json::from_slice(payload).map_err(|e| {
+            TransportError::new(
+                TransportErrorKind::Rejected,
+                format!("unreadable command: {}", e),
+            )
+        })?;
+        match command.command {
+            SvenCommand::AbsoluteHeight => Ok(serde_json::json!({"height": command.value})),
+            SvenCommand::Stop => Ok(serde_json::json!({"state": "STOP"})),
+            other => Err(TransportError::new(
+                TransportErrorKind::Rejected,
+                format!("{} can't be sent to a Zigbee2MQTT desk", other),
+            )),
+        }
+    }
+}
+
+impl DeskTransport for Zigbee2MqttTransport {
+    fn name(&self) -> &'static str {
+        "zigbee2mqtt"
+    }
+
+    fn publish<'a>(
+        &'a self,
+        topic: &'a str,
+        payload: Vec<u8>,
+        options: PublishOptions,
+    ) -> TransportFuture<'a> {
+        if topic != self.command_topic {
+            return self.mqtt.publish(topic, payload, options);
+        }
+        Box::pin(async move {
+            let attributes = Self::attributes(&payload)?;
+            let set_topic = format!("{}/set", self.device_topic);
+            self.mqtt
+                .publish(&set_topic, attributes.to_string().into_bytes(), options)
+                .await
+        })
+    }
+
+    fn subscribe(&self, mut filters: Vec<(String, QoS)>) -> Result<(), TransportError> {
+        filters.push((self.device_topic.clone(), QoS::AtLeastOnce));
+        self.mqtt.subscribe(filters)
+    }
+
+    fn disconnect(&self) -> TransportFuture<'_> {
+        self.mqtt.disconnect()
+    }
+
+    fn incoming(&self, topic: String, payload: Vec<u8>) -> (String, Vec<u8>) {
+        if topic != self.device_topic {
+            return (topic, payload);
+        }
+        match serde_json::from_slice::<Zigbee2MqttReport>(&payload) {
+            // Zigbee2MQTT knows nothing of saved positions
+            Ok(report) => {
+                let state = serde_json::json!({
+                    "height_mm": report.height.round() as u32,
+                    "position": "Custom",
+                });
+                (self.state_topic.clone(), state.to_string().into_bytes())
+            }
+            Err(_) => (topic, payload),
+        }
+    }
+}
+
+pub struct HttpTransport {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl DeskTransport for HttpTransport {
+    fn name(&self) -> &'static str {
+        "http"
+    }
+
+    fn publish<'a>(
+        &'a self,
+        topic: &'a str,
+        payload: Vec<u8>,
+        options: PublishOptions,
+    ) -> TransportFuture<'a> {
+        Box::pin(async move {
+            // JSON payloads are sent as JSON, anything else as a string
+            let payload =
+                serde_json::from_slice::<serde_json::Value>(&payload).unwrap_or_else(|_| {
+                    serde_json::Value::String(String::from_utf8_lossy(&payload).into_owned())
+                });
+            self.client
+                .post(&self.url)
+                .json(&serde_json::json!({
+                    "topic": topic,
+                    "payload": payload,
+                    "retain": options.retain,
+                    "qos": options.qos as u8,
+                }))
+                .timeout(Duration::from_secs(10))
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())?;
+            Ok(())
+        })
+    }
+
+    fn queues_publishes(&self) -> bool {
+        false
+    }
+
+    fn subscribe(&self, _filters: Vec<(String, QoS)>) -> Result<(), TransportError> {
+        Ok(())
+    }
+
+    fn disconnect(&self) -> TransportFuture<'_> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Stands in for the MQTT eventloop of the HTTP transport, which has no connection
+/// to keep: the desk counts as reachable from the start.
+pub fn start_http(app_state: Arc<AppState>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        app_state.announce_availability(true).await;
+        info!(
+            "Sending desk messages to {}",
+            app_state
+                .config()
+                .transport_url
+                .as_deref()
+                .unwrap_or_default()
+        );
+        app_state.on_broker_connected().await;
+    })
+}
+
+/// Body of `POST /api/sven/transport/messages`.
+#[derive(Debug, Deserialize)]
+pub struct TransportMessage {
+    pub topic: String,
+    /// A string is taken as the raw payload, anything else as JSON
+    pub payload: serde_json::Value,
+}
+
+/// A message from the HTTP transport's bridge, handled as if it had arrived over MQTT.
+pub async fn handle_message(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Json(message): Json<TransportMessage>,
+) -> impl IntoResponse {
+    let payload = match message.payload {
+        serde_json::Value::String(raw) => raw.into_bytes(),
+        other => other.to_string().into_bytes(),
+    };
+    crate::handle_incoming(&app_state, message.topic, payload, false).await;
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({"status": "Message handled"})),
+    )
+}
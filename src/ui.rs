@@ -0,0 +1,46 @@
+//! The built-in control page at `/`: buttons for the presets, a height slider and
+//! the live state. The assets under `ui/` are compiled into the binary, so there
+//! is nothing to deploy next to it. `SVEN_UI=false` leaves `/` unserved. With
+//! `SVEN_ACCOUNTS` configured, `/login` is where the page sends you to log in.
+
+use axum::{
+    extract::Path,
+    http::{HeaderValue, StatusCode, header},
+    response::{Html, IntoResponse, Response},
+};
+
+const INDEX: &str = include_str!("../ui/index.html");
+const LOGIN: &str = include_str!("../ui/login.html");
+
+/// The assets the page loads, by name under `/ui/`.
+const ASSETS: &[(&str, &str, &str)] = &[
+    (
+        "app.js",
+        "text/javascript; charset=utf-8",
+        include_str!("../ui/app.js"),
+    ),
+    (
+        "style.css",
+        "text/css; charset=utf-8",
+        include_str!("../ui/style.css"),
+    ),
+];
+
+pub async fn get_index() -> impl IntoResponse {
+    Html(INDEX)
+}
+
+pub async fn get_login() -> impl IntoResponse {
+    Html(LOGIN)
+}
+
+pub async fn get_asset(Path(name): Path<String>) -> Response {
+    match ASSETS.iter().find(|(asset, _, _)| *asset == name) {
+        Some((_, content_type, body)) => (
+            [(header::CONTENT_TYPE, HeaderValue::from_static(content_type))],
+            *body,
+        )
+            .into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
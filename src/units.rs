@@ -0,0 +1,223 @@
+//! Length units clients can use instead of millimetres. The `unit` query parameter,
+//! or else the `Accept-Units` header, picks `mm`, `cm` or `in` for a request: the
+//! `value` of height commands is read in that unit, where it may be a decimal, and
+//! rounded to the nearest millimetre, and every `*_mm` field of the response gets a converted sibling
+//! without the suffix, rounded to a tenth of the unit, next to a `unit` field.
+//! Millimetre fields stay as they are, so clients reading them keep working.
+
+use axum::{
+    Json,
+    http::{HeaderMap, StatusCode},
+};
+use serde::Deserialize;
+use std::str::FromStr;
+use utoipa::IntoParams;
+
+use crate::CommandRequest;
+
+type Rejection = (StatusCode, Json<serde_json::Value>);
+
+pub const HEADER: &str = "accept-units";
+
+const MM_PER_INCH: f64 = 25.4;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Unit {
+    #[default]
+    Mm,
+    Cm,
+    In,
+}
+
+impl FromStr for Unit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "mm" | "millimetre" | "millimetres" | "millimeter" | "millimeters" => Ok(Unit::Mm),
+            "cm" | "centimetre" | "centimetres" | "centimeter" | "centimeters" => Ok(Unit::Cm),
+            "in" | "inch" | "inches" => Ok(Unit::In),
+            other => Err(format!("unknown unit '{}', use mm, cm or in", other)),
+        }
+    }
+}
+
+impl Unit {
+    fn name(self) -> &'static str {
+        match self {
+            Unit::Mm => "mm",
+            Unit::Cm => "cm",
+            Unit::In => "in",
+        }
+    }
+
+    fn mm_per_unit(self) -> f64 {
+        match self {
+            Unit::Mm => 1.0,
+            Unit::Cm => 10.0,
+            Unit::In => MM_PER_INCH,
+        }
+    }
+
+    /// `value` in this unit, to the nearest millimetre.
+    pub fn to_mm(self, value: f64) -> u32 {
+        (value * self.mm_per_unit()).round() as u32
+    }
+
+    /// `mm` in this unit, to a tenth of it.
+    pub fn of_mm(self, mm: u64) -> f64 {
+        (mm as f64 / self.mm_per_unit() * 10.0).round() / 10.0
+    }
+
+    /// Reads a command body in this unit. A command that takes millimetres gets its
+    /// `value` converted from this unit, any other keeps the whole number it was sent.
+    pub fn read_command(self, body: CommandBody) -> Result<CommandRequest, Rejection> {
+        let CommandBody { value, mut request } = body;
+        let Some(value) = value else {
+            return Ok(request);
+        };
+        let invalid = |message: String| {
+            Err((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(serde_json::json!({"error": message, "code": "invalid_command"})),
+            ))
+        };
+        let in_mm = request.command.command.value_unit() == "mm";
+        if !value.is_finite() || value < 0.0 {
+            return invalid(format!("value must not be negative, got {}", value));
+        }
+        if in_mm && self != Unit::Mm {
+            request.command.value = self.to_mm(value);
+        } else if value.fract() == 0.0 && value < f64::from(u32::MAX) {
+            request.command.value = value as u32;
+        } else {
+            return invalid(format!("value must be a whole number, got {}", value));
+        }
+        Ok(request)
+    }
+
+    /// Adds this unit's version of every `*_mm` field in `body`, and `unit`.
+    pub fn annotate(self, body: &mut serde_json::Value) {
+        if self == Unit::Mm {
+            return;
+        }
+        self.add_converted(body);
+        if let Some(object) = body.as_object_mut() {
+            object.insert("unit".to_string(), self.name().into());
+        }
+    }
+
+    fn add_converted(self, value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::Object(object) => {
+                let converted: Vec<(String, f64)> = object
+                    .iter()
+                    .filter_map(|(key, value)| {
+                        let name = key.strip_suffix("_mm")?;
+                        Some((name.to_string(), self.of_mm(value.as_u64()?)))
+                    })
+                    .filter(|(name, _)| !object.contains_key(name))
+                    .collect();
+                for value in object.values_mut() {
+                    self.add_converted(value);
+                }
+                for (name, value) in converted {
+                    object.insert(name, value.into());
+                }
+            }
+            serde_json::Value::Array(values) => {
+                for value in values {
+                    self.add_converted(value);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Body of the command endpoints, a `CommandRequest` whose `value` is left for
+/// `Unit::read_command` to read in the request's unit.
+#[derive(Debug, Deserialize)]
+pub struct CommandBody {
+    value: Option<f64>,
+    #[serde(flatten)]
+    request: CommandRequest,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct UnitParams {
+    /// `mm`, `cm` or `in` for heights in the request and response, overrides `Accept-Units`
+    pub unit: Option<String>,
+}
+
+/// The unit a request asked for, millimetres unless it says otherwise.
+pub fn negotiate(
+    headers: &HeaderMap,
+    params: &UnitParams,
+) -> Result<Unit, (StatusCode, Json<serde_json::Value>)> {
+    let requested = match &params.unit {
+        Some(unit) => Some(unit.as_str()),
+        None => headers.get(HEADER).and_then(|value| value.to_str().ok()),
+    };
+    let Some(requested) = requested else {
+        return Ok(Unit::Mm);
+    };
+    requested.parse().map_err(|e: String| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({"error": e, "code": "unknown_unit"})),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DeskCommand;
+
+    fn read(unit: Unit, body: serde_json::Value) -> Result<DeskCommand, StatusCode> {
+        let body: CommandBody = serde_json::from_value(body).unwrap();
+        unit.read_command(body)
+            .map(|request| request.command)
+            .map_err(|(status, _)| status)
+    }
+
+    #[test]
+    fn heights_convert_both_ways() {
+        let height = serde_json::json!({"command": "AbsoluteHeight", "value": 45});
+        assert_eq!(read(Unit::In, height).unwrap().value, 1143);
+        let fractional = serde_json::json!({"command": "AbsoluteHeight", "value": 29.5});
+        assert_eq!(read(Unit::In, fractional).unwrap().value, 749);
+        let duration = serde_json::json!({"command": "UpDuration", "value": 45});
+        assert_eq!(read(Unit::In, duration).unwrap().value, 45);
+        let fractional_mm = serde_json::json!({"command": "AbsoluteHeight", "value": 749.5});
+        assert_eq!(
+            read(Unit::Mm, fractional_mm),
+            Err(StatusCode::UNPROCESSABLE_ENTITY)
+        );
+        let negative = serde_json::json!({"command": "UpRelative", "value": -2.5});
+        assert_eq!(
+            read(Unit::Cm, negative),
+            Err(StatusCode::UNPROCESSABLE_ENTITY)
+        );
+        let label = serde_json::json!({"command": "Position", "label": "standing"});
+        assert_eq!(
+            read(Unit::In, label).unwrap().value,
+            DeskCommand::MISSING_VALUE
+        );
+
+        let mut body = serde_json::json!({
+            "height_mm": 1143,
+            "limits": {"max_height_mm": 1200},
+            "target_mm": null,
+        });
+        Unit::Cm.annotate(&mut body);
+        assert_eq!(body["height"], 114.3);
+        assert_eq!(body["limits"]["max_height"], 120.0);
+        assert_eq!(body["unit"], "cm");
+        assert!(body.get("target").is_none());
+
+        assert_eq!(Unit::In.of_mm(1000), 39.4);
+        assert_eq!("Inches".parse::<Unit>(), Ok(Unit::In));
+    }
+}
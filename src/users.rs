@@ -0,0 +1,393 @@
+//! People sharing the desk. Users come from `SVEN_USERS` or register through
+//! `POST /api/sven/users`, and a request acts as one when it authenticates with the
+//! API key of the same name, or, while no keys are configured, names the user in
+//! an `X-Sven-User` header. Users can keep their own heights for presets, which
+//! take the place of the shared preset of that name when they apply it, and the
+//! commands they send record their name in the history.
+
+use axum::{
+    Json,
+    extract::{Extension, Path, Query},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tracing::{error, info};
+use utoipa::ToSchema;
+
+use crate::config::Config;
+use crate::listing::{Collection, ListQuery};
+use crate::persistence::Artifact;
+use crate::presets::{self, Preset};
+use crate::validation::ErrorBody;
+use crate::{AppState, admin, auth};
+
+/// Names the acting user when the API runs without keys.
+pub const HEADER: &str = "x-sven-user";
+
+const MAX_NAME_LEN: usize = 32;
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct User {
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    /// The user's own preset heights, by preset name
+    #[serde(default)]
+    pub presets: BTreeMap<String, Preset>,
+}
+
+impl User {
+    fn new(name: &str) -> User {
+        User {
+            name: name.to_string(),
+            created_at: Utc::now(),
+            presets: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Users(pub BTreeMap<String, User>);
+
+impl Artifact for Users {
+    const KEY: &'static str = "users";
+}
+
+impl Users {
+    /// The persisted users plus any configured ones that haven't been seen before.
+    pub fn with_configured(mut self, config: &Config) -> Users {
+        for name in &config.users {
+            self.0
+                .entry(name.clone())
+                .or_insert_with(|| User::new(name));
+        }
+        self
+    }
+
+    /// The preset `name` as `user` sees it: their own height if they saved one,
+    /// otherwise the shared preset.
+    pub fn preset_for(
+        &self,
+        user: Option<&str>,
+        name: &str,
+        shared: &presets::Presets,
+    ) -> Option<Preset> {
+        user.and_then(|user| self.0.get(user))
+            .and_then(|user| user.presets.get(name))
+            .or_else(|| shared.0.get(name))
+            .cloned()
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"-_.".contains(&byte))
+}
+
+/// The user a request acts as. With API keys or accounts configured that is the
+/// user named like the key or the logged in account, if any; without, the user named in `X-Sven-User`, which has to
+/// exist.
+pub fn identify(
+    headers: &HeaderMap,
+    config: &Config,
+    users: &Users,
+) -> Result<Option<String>, (StatusCode, Json<serde_json::Value>)> {
+    if auth::protected(config) {
+        return Ok(
+            auth::presented_key_name(headers, config).filter(|name| users.0.contains_key(name))
+        );
+    }
+    let Some(name) = headers.get(HEADER).and_then(|value| value.to_str().ok()) else {
+        return Ok(None);
+    };
+    if !users.0.contains_key(name) {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(serde_json::json!({
+                "error": format!("Unknown user '{}'", name),
+                "code": "unknown_user",
+            })),
+        ));
+    }
+    Ok(Some(name.to_string()))
+}
+
+/// Like `identify`, for calls that must not fail on an unknown user, such as stops.
+pub async fn acting_user(app_state: &AppState, headers: &HeaderMap) -> Option<String> {
+    identify(headers, &app_state.config(), &*app_state.users.lock().await)
+        .ok()
+        .flatten()
+}
+
+/// Refuses changes to `name`'s presets by anyone but that user or the admin,
+/// once API keys tell users apart.
+fn require_self_or_admin(
+    headers: &HeaderMap,
+    config: &Config,
+    name: &str,
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    if !auth::protected(config)
+        || auth::presented_key_name(headers, config).as_deref() == Some(name)
+        || admin::require_admin(headers, config).is_ok()
+    {
+        return Ok(());
+    }
+    Err((
+        StatusCode::FORBIDDEN,
+        Json(serde_json::json!({
+            "error": format!("Only {} can change their presets", name),
+            "code": "not_your_user",
+        })),
+    ))
+}
+
+fn unknown_user(name: &str) -> (StatusCode, Json<serde_json::Value>) {
+    (
+        StatusCode::NOT_FOUND,
+        Json(serde_json::json!({"error": format!("Unknown user '{}'", name)})),
+    )
+}
+
+fn persist(app_state: &AppState, users: &Users) {
+    if let Some(store) = &app_state.store
+        && let Err(e) = store.put(users)
+    {
+        error!("Failed to persist users: {}", e);
+    }
+}
+
+const USERS: Collection = Collection {
+    fields: &["name", "created_at"],
+    timestamp: Some("created_at"),
+};
+
+#[utoipa::path(
+    get,
+    path = "/api/sven/users",
+    tag = "users",
+    params(ListQuery),
+    responses(
+        (status = 200, body = Vec<User>),
+        (status = 400, description = "Invalid query", body = ErrorBody),
+    ),
+)]
+pub async fn list_users(
+    Query(params): Query<BTreeMap<String, String>>,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    let users = app_state.users.lock().await;
+    USERS.respond(users.0.values(), &params, serde_json::Value::Array)
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct RegisterRequest {
+    pub name: String,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/sven/users",
+    tag = "users",
+    request_body = RegisterRequest,
+    responses(
+        (status = 201, body = User),
+        (status = 409, description = "The name is taken", body = ErrorBody),
+        (status = 422, description = "Invalid name", body = ErrorBody),
+    ),
+)]
+pub async fn register_user(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Json(request): Json<RegisterRequest>,
+) -> impl IntoResponse {
+    let name = request.name.trim();
+    if !is_valid_name(name) {
+        return (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(serde_json::json!({
+                "error": format!(
+                    "User names must be 1 to {} letters, digits, '-', '_' or '.'",
+                    MAX_NAME_LEN
+                )
+            })),
+        );
+    }
+    let mut users = app_state.users.lock().await;
+    if users.0.contains_key(name) {
+        return (
+            StatusCode::CONFLICT,
+            Json(serde_json::json!({"error": format!("User '{}' already exists", name)})),
+        );
+    }
+    let user = User::new(name);
+    users.0.insert(name.to_string(), user.clone());
+    persist(&app_state, &users);
+    info!("Registered user {}", name);
+    (StatusCode::CREATED, Json(serde_json::json!(user)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/sven/users/{name}",
+    tag = "users",
+    params(("name" = String, Path)),
+    responses(
+        (status = 200, body = serde_json::Value),
+        (status = 404, body = ErrorBody),
+        (status = 409, description = "Configured in SVEN_USERS", body = ErrorBody),
+    ),
+)]
+pub async fn delete_user(
+    Path(name): Path<String>,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    if app_state.config().users.contains(&name) {
+        return (
+            StatusCode::CONFLICT,
+            Json(serde_json::json!({
+                "error": format!("User '{}' is configured in SVEN_USERS", name)
+            })),
+        );
+    }
+    let mut users = app_state.users.lock().await;
+    if users.0.remove(&name).is_none() {
+        return unknown_user(&name);
+    }
+    persist(&app_state, &users);
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({"status": "User deleted", "name": name})),
+    )
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct UserPresetRequest {
+    pub height_mm: u32,
+}
+
+/// Saves the user's own height for a preset, used instead of the shared one
+/// whenever they apply it. The shared preset doesn't have to exist.
+#[utoipa::path(
+    put,
+    path = "/api/sven/users/{name}/presets/{preset}",
+    tag = "users",
+    params(("name" = String, Path), ("preset" = String, Path)),
+    request_body = UserPresetRequest,
+    responses(
+        (status = 200, body = Preset),
+        (status = 403, description = "Another user's presets", body = ErrorBody),
+        (status = 404, body = ErrorBody),
+        (status = 422, description = "Invalid request", body = ErrorBody),
+    ),
+)]
+pub async fn save_user_preset(
+    Path((name, preset)): Path<(String, String)>,
+    headers: HeaderMap,
+    Extension(app_state): Extension<Arc<AppState>>,
+    Json(request): Json<UserPresetRequest>,
+) -> impl IntoResponse {
+    if let Err(rejection) = require_self_or_admin(&headers, &app_state.config(), &name) {
+        return rejection;
+    }
+    let preset = match presets::check_preset(&app_state.config(), &preset, request.height_mm) {
+        Ok(preset) => preset,
+        Err(rejection) => return rejection,
+    };
+    let mut users = app_state.users.lock().await;
+    let Some(user) = users.0.get_mut(&name) else {
+        return unknown_user(&name);
+    };
+    let preset = Preset {
+        name: preset,
+        height_mm: request.height_mm,
+        created_at: Utc::now(),
+        user: Some(name.clone()),
+    };
+    user.presets.insert(preset.name.clone(), preset.clone());
+    persist(&app_state, &users);
+    info!(
+        "Saved preset {} for {} at {} mm",
+        preset.name, name, preset.height_mm
+    );
+    (StatusCode::OK, Json(serde_json::json!(preset)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/sven/users/{name}/presets/{preset}",
+    tag = "users",
+    params(("name" = String, Path), ("preset" = String, Path)),
+    responses(
+        (status = 200, body = serde_json::Value),
+        (status = 403, description = "Another user's presets", body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    ),
+)]
+pub async fn delete_user_preset(
+    Path((name, preset)): Path<(String, String)>,
+    headers: HeaderMap,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    if let Err(rejection) = require_self_or_admin(&headers, &app_state.config(), &name) {
+        return rejection;
+    }
+    let mut users = app_state.users.lock().await;
+    let Some(user) = users.0.get_mut(&name) else {
+        return unknown_user(&name);
+    };
+    if user.presets.remove(&preset).is_none() {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({
+                "error": format!("{} has no preset '{}'", name, preset)
+            })),
+        );
+    }
+    persist(&app_state, &users);
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({"status": "Preset deleted", "name": preset, "user": name})),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn own_preset_takes_the_place_of_the_shared_one() {
+        let preset = |height_mm| Preset {
+            name: "standing".to_string(),
+            height_mm,
+            created_at: Utc::now(),
+            user: None,
+        };
+        let mut shared = presets::Presets::default();
+        shared.0.insert("standing".to_string(), preset(1100));
+        let mut users = Users::default();
+        let mut alice = User::new("alice");
+        alice.presets.insert("standing".to_string(), preset(1180));
+        users.0.insert("alice".to_string(), alice);
+        users.0.insert("bob".to_string(), User::new("bob"));
+
+        let height = |user| {
+            users
+                .preset_for(user, "standing", &shared)
+                .map(|preset| preset.height_mm)
+        };
+        assert_eq!(height(Some("alice")), Some(1180));
+        assert_eq!(height(Some("bob")), Some(1100));
+        assert_eq!(height(None), Some(1100));
+        assert_eq!(
+            users
+                .preset_for(None, "sitting", &shared)
+                .map(|p| p.height_mm),
+            None
+        );
+    }
+}
@@ -0,0 +1,117 @@
+use axum::{Json, extract::rejection::JsonRejection, http::StatusCode};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::config::Config;
+use crate::motor::{self, AccelerationProfile};
+use crate::{DeskCommand, SvenCommand, SvenPosition};
+
+type Rejection = (StatusCode, Json<serde_json::Value>);
+
+/// Body of error responses. Validation errors carry a machine readable `code`.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorBody {
+    pub error: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+}
+
+fn error(status: StatusCode, code: &str, message: String) -> Rejection {
+    let body = ErrorBody {
+        error: message,
+        code: Some(code.to_string()),
+    };
+    (status, Json(serde_json::json!(body)))
+}
+
+/// Turns an unreadable request body into a JSON error: 400 for malformed JSON,
+/// 422 for JSON that doesn't describe a command.
+pub fn body_rejection(rejection: JsonRejection) -> Rejection {
+    let code = match rejection.status() {
+        StatusCode::BAD_REQUEST => "malformed_body",
+        StatusCode::UNSUPPORTED_MEDIA_TYPE => "unsupported_content_type",
+        StatusCode::PAYLOAD_TOO_LARGE => "payload_too_large",
+        _ => "invalid_command",
+    };
+    error(rejection.status(), code, rejection.body_text())
+}
+
+/// Checks that a command's value makes sense for its variant before it is sent
+/// to the desk.
+pub fn validate_command(command: &DeskCommand, config: &Config) -> Result<(), Rejection> {
+    let value = command.value;
+    let max_travel_mm = config.max_height_mm - config.min_height_mm;
+    let invalid =
+        |code: &str, message: String| Err(error(StatusCode::UNPROCESSABLE_ENTITY, code, message));
+
+    match command.command {
+        SvenCommand::UpDuration | SvenCommand::DownDuration
+            if value == 0 || u64::from(value) > config.max_duration_ms =>
+        {
+            invalid(
+                "duration_out_of_range",
+                format!(
+                    "Duration must be between 1 and {} ms, got {}",
+                    config.max_duration_ms, value
+                ),
+            )
+        }
+        SvenCommand::UpRelative | SvenCommand::DownRelative
+            if value == 0 || value > max_travel_mm =>
+        {
+            invalid(
+                "distance_out_of_range",
+                format!(
+                    "Distance must be between 1 and {} mm, got {}",
+                    max_travel_mm, value
+                ),
+            )
+        }
+        SvenCommand::AbsoluteHeight if !config.height_in_bounds(value) => invalid(
+            "height_out_of_range",
+            format!(
+                "Height must be between {} and {} mm, got {}",
+                config.min_height_mm, config.max_height_mm, value
+            ),
+        ),
+        SvenCommand::UpPercent | SvenCommand::DownPercent if value == 0 || value > 100 => invalid(
+            "percent_out_of_range",
+            format!("Percentage must be between 1 and 100, got {}", value),
+        ),
+        SvenCommand::Position if SvenPosition::from_index(value).is_none() => invalid(
+            "unknown_position",
+            format!(
+                "Unknown position {}, expected 0 to {}",
+                value,
+                SvenPosition::ALL.len() - 1
+            ),
+        ),
+        SvenCommand::SetSpeed if !(motor::MIN_SPEED_PERCENT..=100).contains(&value) => invalid(
+            "speed_out_of_range",
+            format!(
+                "Speed must be between {} and 100%, got {}",
+                motor::MIN_SPEED_PERCENT,
+                value
+            ),
+        ),
+        SvenCommand::SetAcceleration if AccelerationProfile::from_index(value).is_none() => {
+            invalid(
+                "unknown_acceleration_profile",
+                format!(
+                    "Unknown acceleration profile {}, expected 0 to {}",
+                    value,
+                    AccelerationProfile::ALL.len() - 1
+                ),
+            )
+        }
+        SvenCommand::SetSoftStart if value > motor::MAX_SOFT_START_MS => invalid(
+            "soft_start_out_of_range",
+            format!(
+                "Soft start must be at most {} ms, got {}",
+                motor::MAX_SOFT_START_MS,
+                value
+            ),
+        ),
+        _ => Ok(()),
+    }
+}
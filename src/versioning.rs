@@ -0,0 +1,251 @@
+//! API versions. Every `/api/...` route is also served as `/api/v1/...`, and the
+//! unversioned paths stay on the current version. Clients may instead ask for a
+//! version with an `Api-Version: 1` header or `Accept: application/vnd.sven.v1+json`.
+//! Responses name their version in an `Api-Version` header and, for JSON objects,
+//! an `api_version` field.
+//!
+//! Handlers only deal with the current schema. When a later version changes
+//! `DeskCommand` or `SvenState`, the older versions get translated here: request
+//! bodies up to the current schema, response bodies back down to theirs.
+
+use axum::{
+    Json,
+    body::{Body, Bytes},
+    extract::Request,
+    http::{HeaderMap, HeaderValue, StatusCode, Uri, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde_json::Value;
+use tracing::error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiVersion {
+    V1,
+}
+
+/// Paths whose responses aren't versioned resources, so their bodies are left
+/// alone: the GraphQL envelope and the API description.
+const UNVERSIONED_BODIES: &[&str] = &["/api/graphql", "/api/openapi.json", "/api/docs"];
+
+impl ApiVersion {
+    pub const CURRENT: ApiVersion = ApiVersion::V1;
+    const SUPPORTED: &[ApiVersion] = &[ApiVersion::V1];
+
+    pub fn number(self) -> u32 {
+        match self {
+            ApiVersion::V1 => 1,
+        }
+    }
+
+    fn from_number(number: &str) -> Option<ApiVersion> {
+        let number = number.parse::<u32>().ok()?;
+        Self::SUPPORTED
+            .iter()
+            .copied()
+            .find(|version| version.number() == number)
+    }
+
+    /// Rewrites a JSON request body of this version to the current schema.
+    fn upgrade_request(self, _body: &mut Value) {
+        match self {
+            ApiVersion::V1 => {}
+        }
+    }
+
+    /// Rewrites a JSON response body of the current schema to this version's.
+    fn downgrade_response(self, _body: &mut Value) {
+        match self {
+            ApiVersion::V1 => {}
+        }
+    }
+}
+
+/// The version segment of an `/api/v<n>/...` path and the path without it.
+fn split_version(path: &str) -> Option<(&str, String)> {
+    let rest = path.strip_prefix("/api/v")?;
+    let (number, rest) = rest.split_once('/').unwrap_or((rest, ""));
+    if number.is_empty() || !number.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    Some((number, format!("/api/{}", rest)))
+}
+
+/// The version asked for in the headers: `Api-Version`, then the `Accept` media type.
+fn requested_version(headers: &HeaderMap) -> Option<&str> {
+    if let Some(version) = headers.get("api-version") {
+        return version.to_str().ok();
+    }
+    headers
+        .get(header::ACCEPT)?
+        .to_str()
+        .ok()?
+        .split(',')
+        .find_map(|media_type| {
+            media_type
+                .trim()
+                .strip_prefix("application/vnd.sven.v")?
+                .strip_suffix("+json")
+        })
+}
+
+fn unsupported(status: StatusCode, requested: &str) -> Response {
+    let supported: Vec<u32> = ApiVersion::SUPPORTED
+        .iter()
+        .map(|version| version.number())
+        .collect();
+    (
+        status,
+        Json(serde_json::json!({
+            "error": format!("API version '{}' is not supported", requested),
+            "code": "unsupported_api_version",
+            "supported_versions": supported,
+        })),
+    )
+        .into_response()
+}
+
+/// Routes `/api/v<n>/...` to the unversioned routes and translates bodies of
+/// versions other than the current one. Layered outside the router, as it
+/// changes the path that gets routed.
+pub async fn negotiate(mut request: Request, next: Next) -> Response {
+    let version = match split_version(request.uri().path()) {
+        Some((number, path)) => {
+            let Some(version) = ApiVersion::from_number(number) else {
+                return unsupported(StatusCode::NOT_FOUND, number);
+            };
+            let path_and_query = match request.uri().query() {
+                Some(query) => format!("{}?{}", path, query),
+                None => path,
+            };
+            let mut parts = request.uri().clone().into_parts();
+            parts.path_and_query = path_and_query.parse().ok();
+            match Uri::from_parts(parts) {
+                Ok(uri) => *request.uri_mut() = uri,
+                Err(e) => {
+                    error!("Failed to strip the API version from the path: {}", e);
+                    return StatusCode::BAD_REQUEST.into_response();
+                }
+            }
+            version
+        }
+        None => match requested_version(request.headers()) {
+            Some(number) => match ApiVersion::from_number(number) {
+                Some(version) => version,
+                None => return unsupported(StatusCode::NOT_ACCEPTABLE, number),
+            },
+            None => ApiVersion::CURRENT,
+        },
+    };
+
+    let versioned_body = request.uri().path().starts_with("/api/")
+        && !UNVERSIONED_BODIES.contains(&request.uri().path());
+    if version != ApiVersion::CURRENT && versioned_body {
+        request = map_json(request, |body| version.upgrade_request(body)).await;
+    }
+    request.extensions_mut().insert(version);
+
+    let mut response = next.run(request).await;
+    response
+        .headers_mut()
+        .insert("api-version", HeaderValue::from(version.number()));
+    if !versioned_body || !is_json(response.headers()) {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let Ok(bytes) = axum::body::to_bytes(body, usize::MAX).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    let bytes = if version == ApiVersion::CURRENT {
+        with_version_field(bytes, version)
+    } else {
+        match serde_json::from_slice::<Value>(&bytes) {
+            Ok(mut body) => {
+                version.downgrade_response(&mut body);
+                if let Some(fields) = body.as_object_mut() {
+                    fields.insert("api_version".to_string(), version.number().into());
+                }
+                Bytes::from(body.to_string())
+            }
+            Err(_) => bytes,
+        }
+    };
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(bytes))
+}
+
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"))
+}
+
+/// Adds the `api_version` field to a JSON object without parsing it, so the
+/// cached state body stays cheap to serve. Other JSON is returned as is.
+fn with_version_field(bytes: Bytes, version: ApiVersion) -> Bytes {
+    let Some(rest) = bytes.trim_ascii_start().strip_prefix(b"{") else {
+        return bytes;
+    };
+    let field = format!("{{\"api_version\":{}", version.number());
+    let separator = if rest.trim_ascii_start().starts_with(b"}") {
+        ""
+    } else {
+        ","
+    };
+    let mut body = Vec::with_capacity(bytes.len() + field.len() + 1);
+    body.extend_from_slice(field.as_bytes());
+    body.extend_from_slice(separator.as_bytes());
+    body.extend_from_slice(rest);
+    Bytes::from(body)
+}
+
+/// Applies `upgrade` to a JSON request body, leaving other bodies untouched.
+async fn map_json(request: Request, upgrade: impl FnOnce(&mut Value)) -> Request {
+    if !is_json(request.headers()) {
+        return request;
+    }
+    let (mut parts, body) = request.into_parts();
+    let bytes = axum::body::to_bytes(body, usize::MAX)
+        .await
+        .unwrap_or_default();
+    let bytes = match serde_json::from_slice::<Value>(&bytes) {
+        Ok(mut body) => {
+            upgrade(&mut body);
+            parts.headers.remove(header::CONTENT_LENGTH);
+            Bytes::from(body.to_string())
+        }
+        // Left for the handler to reject
+        Err(_) => bytes,
+    };
+    Request::from_parts(parts, Body::from(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_the_version_off_the_path() {
+        assert_eq!(
+            split_version("/api/v1/sven/state"),
+            Some(("1", "/api/sven/state".to_string()))
+        );
+        assert_eq!(
+            split_version("/api/v12/desks"),
+            Some(("12", "/api/desks".to_string()))
+        );
+        assert_eq!(split_version("/api/sven/state"), None);
+        assert_eq!(split_version("/api/vx/sven"), None);
+        assert_eq!(split_version("/healthz"), None);
+    }
+
+    #[test]
+    fn version_field_is_added_to_objects_only() {
+        let add = |body: &'static str| with_version_field(Bytes::from(body), ApiVersion::V1);
+        assert_eq!(add(r#"{"a":1}"#), r#"{"api_version":1,"a":1}"#);
+        assert_eq!(add("{ }"), r#"{"api_version":1 }"#);
+        assert_eq!(add(r#""online""#), r#""online""#);
+        assert_eq!(add("[1]"), "[1]");
+    }
+}
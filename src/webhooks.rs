@@ -0,0 +1,445 @@
+//! Webhook subscriptions: callback URLs registered with `POST /api/sven/webhooks`
+//! that are sent the desk events they ask for, `state_changed`,
+//! `position_reached`, `fault` and `offline`. Each delivery is a JSON POST signed
+//! with the subscription's secret, `X-Sven-Signature: sha256=<hex HMAC of the
+//! body>`, and is retried with exponential backoff until the receiver answers
+//! with a 2xx. Every attempt is logged at `GET /api/sven/webhooks/{id}/deliveries`.
+
+use axum::{
+    Json,
+    extract::{Extension, Path},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use chrono::{DateTime, Utc};
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
+use utoipa::ToSchema;
+
+use crate::jobs::JobStatus;
+use crate::login;
+use crate::persistence::Artifact;
+use crate::validation::ErrorBody;
+use crate::{AppState, DeskStatus};
+
+pub const SIGNATURE_HEADER: &str = "X-Sven-Signature";
+
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+/// Attempts per delivery, the first one included.
+const MAX_ATTEMPTS: u32 = 5;
+/// The wait before the first retry, doubled for each one after it.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+/// Attempts kept in each subscription's delivery log.
+const LOGGED_DELIVERIES: usize = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    /// The desk reported a different height, position or motor settings
+    StateChanged,
+    /// A movement arrived at its destination
+    PositionReached,
+    /// The controller reported an error
+    Fault,
+    /// The controller went offline or stopped reporting
+    Offline,
+}
+
+impl WebhookEvent {
+    pub fn name(self) -> &'static str {
+        match self {
+            WebhookEvent::StateChanged => "state_changed",
+            WebhookEvent::PositionReached => "position_reached",
+            WebhookEvent::Fault => "fault",
+            WebhookEvent::Offline => "offline",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Webhook {
+    pub id: String,
+    pub url: String,
+    pub events: Vec<WebhookEvent>,
+    /// Signs the deliveries; only shown when the webhook is registered
+    pub secret: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Webhook {
+    /// The webhook as listed, without its secret.
+    fn public(&self) -> serde_json::Value {
+        let mut value = serde_json::json!(self);
+        if let Some(fields) = value.as_object_mut() {
+            fields.remove("secret");
+        }
+        value
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Webhooks(pub BTreeMap<String, Webhook>);
+
+impl Artifact for Webhooks {
+    const KEY: &'static str = "webhooks";
+}
+
+/// One attempt at delivering an event.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Delivery {
+    /// Shared by the attempts at the same event, sent as `X-Sven-Delivery`
+    pub delivery_id: String,
+    pub event: WebhookEvent,
+    pub attempt: u32,
+    pub delivered: bool,
+    /// The receiver's status code, absent when it couldn't be reached
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status_code: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub attempted_at: DateTime<Utc>,
+}
+
+/// The latest delivery attempts of each webhook, oldest first.
+#[derive(Debug, Default)]
+pub struct Deliveries(HashMap<String, VecDeque<Delivery>>);
+
+impl Deliveries {
+    fn record(&mut self, webhook_id: &str, delivery: Delivery) {
+        let log = self.0.entry(webhook_id.to_string()).or_default();
+        if log.len() == LOGGED_DELIVERIES {
+            log.pop_front();
+        }
+        log.push_back(delivery);
+    }
+}
+
+/// `sha256=` and the hex HMAC-SHA256 of `body` keyed with `secret`, as sent in
+/// `X-Sven-Signature`.
+pub fn signature(secret: &str, body: &[u8]) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let tag = hmac::sign(&key, body);
+    let hex: String = tag
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("sha256={}", hex)
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct WebhookRequest {
+    /// An `http` or `https` URL the events are posted to
+    pub url: String,
+    pub events: Vec<WebhookEvent>,
+    /// Signs the deliveries; a random one is made when absent
+    #[serde(default)]
+    pub secret: Option<String>,
+}
+
+fn validate(request: &WebhookRequest) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    let invalid = |message: &str| {
+        Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(serde_json::json!(ErrorBody {
+                error: message.to_string(),
+                code: Some("invalid_webhook".to_string()),
+            })),
+        ))
+    };
+    match reqwest::Url::parse(request.url.trim()) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+        _ => return invalid("The url must be an http or https URL"),
+    }
+    if request.events.is_empty() {
+        return invalid("A webhook needs at least one event");
+    }
+    if request
+        .secret
+        .as_ref()
+        .is_some_and(|secret| secret.is_empty())
+    {
+        return invalid("The secret must not be empty");
+    }
+    Ok(())
+}
+
+fn persist(app_state: &AppState, webhooks: &Webhooks) {
+    if let Some(store) = &app_state.store
+        && let Err(e) = store.put(webhooks)
+    {
+        error!("Failed to persist webhooks: {}", e);
+    }
+}
+
+fn unknown(id: &str) -> (StatusCode, Json<serde_json::Value>) {
+    (
+        StatusCode::NOT_FOUND,
+        Json(serde_json::json!({"error": format!("Unknown webhook '{}'", id)})),
+    )
+}
+
+/// The registered webhooks, without their secrets.
+#[utoipa::path(
+    get,
+    path = "/api/sven/webhooks",
+    tag = "webhooks",
+    responses((status = 200, description = "Webhooks without their `secret`", body = Vec<Webhook>)),
+)]
+pub async fn list_webhooks(Extension(app_state): Extension<Arc<AppState>>) -> impl IntoResponse {
+    let webhooks = app_state.webhooks.lock().await;
+    Json(serde_json::Value::Array(
+        webhooks.0.values().map(Webhook::public).collect(),
+    ))
+}
+
+/// Registers a webhook, answering with the secret its deliveries are signed with.
+#[utoipa::path(
+    post,
+    path = "/api/sven/webhooks",
+    tag = "webhooks",
+    request_body = WebhookRequest,
+    responses(
+        (status = 201, body = Webhook),
+        (status = 422, description = "Invalid URL, events or secret", body = ErrorBody),
+    ),
+)]
+pub async fn create_webhook(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Json(request): Json<WebhookRequest>,
+) -> impl IntoResponse {
+    if let Err(rejection) = validate(&request) {
+        return rejection;
+    }
+    let mut events = Vec::new();
+    for event in request.events {
+        if !events.contains(&event) {
+            events.push(event);
+        }
+    }
+    let webhook = Webhook {
+        id: uuid::Uuid::new_v4().to_string(),
+        url: request.url.trim().to_string(),
+        events,
+        secret: request.secret.unwrap_or_else(login::random_secret),
+        created_at: Utc::now(),
+    };
+    let mut webhooks = app_state.webhooks.lock().await;
+    webhooks.0.insert(webhook.id.clone(), webhook.clone());
+    persist(&app_state, &webhooks);
+    info!("Added webhook {} for {:?}", webhook.url, webhook.events);
+    (StatusCode::CREATED, Json(serde_json::json!(webhook)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/sven/webhooks/{id}",
+    tag = "webhooks",
+    params(("id" = String, Path)),
+    responses((status = 200, body = serde_json::Value), (status = 404, body = ErrorBody)),
+)]
+pub async fn delete_webhook(
+    Path(id): Path<String>,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    let mut webhooks = app_state.webhooks.lock().await;
+    if webhooks.0.remove(&id).is_none() {
+        return unknown(&id);
+    }
+    persist(&app_state, &webhooks);
+    drop(webhooks);
+    app_state.webhook_deliveries.lock().await.0.remove(&id);
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({"status": "Webhook deleted", "id": id})),
+    )
+}
+
+/// The latest delivery attempts to a webhook, newest first.
+#[utoipa::path(
+    get,
+    path = "/api/sven/webhooks/{id}/deliveries",
+    tag = "webhooks",
+    params(("id" = String, Path)),
+    responses((status = 200, body = Vec<Delivery>), (status = 404, body = ErrorBody)),
+)]
+pub async fn list_deliveries(
+    Path(id): Path<String>,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    if !app_state.webhooks.lock().await.0.contains_key(&id) {
+        return unknown(&id);
+    }
+    let deliveries = app_state.webhook_deliveries.lock().await;
+    let log: Vec<&Delivery> = deliveries
+        .0
+        .get(&id)
+        .map(|log| log.iter().rev().collect())
+        .unwrap_or_default();
+    (StatusCode::OK, Json(serde_json::json!(log)))
+}
+
+/// Posts `body` to the webhook until it's accepted, the attempts run out or the
+/// webhook is deleted.
+async fn deliver(
+    app_state: Arc<AppState>,
+    webhook: Webhook,
+    delivery_id: String,
+    event: WebhookEvent,
+    body: Vec<u8>,
+) {
+    let signature = signature(&webhook.secret, &body);
+    let mut backoff = INITIAL_BACKOFF;
+    for attempt in 1..=MAX_ATTEMPTS {
+        let sent = app_state
+            .http_client
+            .post(&webhook.url)
+            .timeout(SEND_TIMEOUT)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header("X-Sven-Event", event.name())
+            .header("X-Sven-Delivery", &delivery_id)
+            .header(SIGNATURE_HEADER, &signature)
+            .body(body.clone())
+            .send()
+            .await;
+        let (status_code, error) = match sent {
+            Ok(response) if response.status().is_success() => {
+                (Some(response.status().as_u16()), None)
+            }
+            Ok(response) => (
+                Some(response.status().as_u16()),
+                Some(format!("The receiver answered {}", response.status())),
+            ),
+            Err(e) => (None, Some(e.to_string())),
+        };
+        let delivered = error.is_none();
+        app_state.webhook_deliveries.lock().await.record(
+            &webhook.id,
+            Delivery {
+                delivery_id: delivery_id.clone(),
+                event,
+                attempt,
+                delivered,
+                status_code,
+                error,
+                attempted_at: Utc::now(),
+            },
+        );
+        if delivered {
+            debug!("Delivered {} to webhook {}", event.name(), webhook.url);
+            return;
+        }
+        if attempt == MAX_ATTEMPTS {
+            break;
+        }
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+        if !app_state.webhooks.lock().await.0.contains_key(&webhook.id) {
+            return;
+        }
+    }
+    warn!(
+        "Gave up delivering {} to webhook {} after {} attempts",
+        event.name(),
+        webhook.url,
+        MAX_ATTEMPTS
+    );
+}
+
+/// Sends `event` to every webhook subscribed to it.
+async fn dispatch(app_state: &Arc<AppState>, event: WebhookEvent, data: serde_json::Value) {
+    let webhooks: Vec<Webhook> = app_state
+        .webhooks
+        .lock()
+        .await
+        .0
+        .values()
+        .filter(|webhook| webhook.events.contains(&event))
+        .cloned()
+        .collect();
+    if webhooks.is_empty() {
+        return;
+    }
+    let delivery_id = uuid::Uuid::new_v4().to_string();
+    let body = serde_json::to_vec(&serde_json::json!({
+        "id": delivery_id,
+        "event": event,
+        "timestamp": Utc::now(),
+        "data": data,
+    }))
+    .unwrap();
+    for webhook in webhooks {
+        tokio::spawn(deliver(
+            app_state.clone(),
+            webhook,
+            delivery_id.clone(),
+            event,
+            body.clone(),
+        ));
+    }
+}
+
+/// Watches the desk for the events webhooks can subscribe to.
+pub async fn run_webhooks(app_state: Arc<AppState>) {
+    let mut state_rx = app_state.state_tx.subscribe();
+    let mut status_rx = app_state.status_tx.subscribe();
+    let mut progress_rx = app_state.progress_tx.subscribe();
+    let mut last_state = *state_rx.borrow_and_update();
+    loop {
+        let (event, data) = tokio::select! {
+            Ok(()) = state_rx.changed() => {
+                // Every report is sent on, most repeat the state
+                let state = *state_rx.borrow_and_update();
+                if state == last_state {
+                    continue;
+                }
+                last_state = state;
+                (WebhookEvent::StateChanged, serde_json::json!(state))
+            }
+            Ok(()) = status_rx.changed() => {
+                let status = *status_rx.borrow_and_update();
+                match status {
+                    DeskStatus::Offline => (
+                        WebhookEvent::Offline,
+                        serde_json::json!({"state": last_state}),
+                    ),
+                    DeskStatus::Error => {
+                        let fault = app_state.faults.lock().await.active().cloned();
+                        (WebhookEvent::Fault, serde_json::json!({"fault": fault}))
+                    }
+                    _ => continue,
+                }
+            }
+            Ok(()) = progress_rx.changed() => {
+                let job = progress_rx.borrow_and_update().clone();
+                match job {
+                    Some(job) if job.status == JobStatus::Completed => (
+                        WebhookEvent::PositionReached,
+                        serde_json::json!({"height_mm": job.height_mm, "job": job}),
+                    ),
+                    _ => continue,
+                }
+            }
+            else => return,
+        };
+        dispatch(&app_state, event, data).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deliveries_are_signed_with_the_secret() {
+        // The HMAC-SHA256 test vector from RFC 4231, case 2
+        assert_eq!(
+            signature("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}
@@ -0,0 +1,50 @@
+use serde::Serialize;
+use std::str::FromStr;
+
+/// A height band the desk must not move into, e.g. where an open drawer sits.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProtectedZone {
+    pub name: String,
+    pub min_mm: u32,
+    pub max_mm: u32,
+}
+
+/// A `min-max` range in mm as written in the config, e.g. `700-760`.
+#[derive(Debug, Clone, Copy)]
+pub struct HeightRange {
+    pub min_mm: u32,
+    pub max_mm: u32,
+}
+
+impl FromStr for HeightRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (min, max) = s
+            .split_once('-')
+            .ok_or_else(|| format!("expected min-max, got '{}'", s))?;
+        let min_mm: u32 = min.trim().parse().map_err(|e| format!("{}", e))?;
+        let max_mm: u32 = max.trim().parse().map_err(|e| format!("{}", e))?;
+        if min_mm > max_mm {
+            return Err(format!("range {} is reversed", s));
+        }
+        Ok(HeightRange { min_mm, max_mm })
+    }
+}
+
+/// Finds the first zone that a move from `from_mm` to `to_mm` would pass through.
+/// Every height along the way counts, not just the target, but the starting height
+/// doesn't: a desk already inside a zone may still be moved.
+pub fn crossed_zone(zones: &[ProtectedZone], from_mm: u32, to_mm: u32) -> Option<&ProtectedZone> {
+    if from_mm == to_mm {
+        return None;
+    }
+    let (low, high) = if to_mm > from_mm {
+        (from_mm + 1, to_mm)
+    } else {
+        (to_mm, from_mm - 1)
+    };
+    zones
+        .iter()
+        .find(|zone| zone.min_mm <= high && low <= zone.max_mm)
+}
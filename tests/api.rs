@@ -0,0 +1,2736 @@
+//! Drives the HTTP API end to end with the MQTT client wired to a channel, so the
+//! tests can see exactly what would have been published to the broker.
+
+use rumqttc::{AsyncClient, Publish, QoS, Request};
+use serde_json::{Value, json};
+use std::time::Duration;
+
+use sven_api::config::Config;
+use sven_api::listen::ClientAddr;
+use sven_api::transport::{MessageClass, PublishOptions, TransportKind};
+use sven_api::{ClientError, DeskStatus, SvenClient};
+
+struct Harness {
+    url: String,
+    requests: flume::Receiver<Request>,
+    client: reqwest::Client,
+    app_state: std::sync::Arc<sven_api::AppState>,
+}
+
+impl Harness {
+    /// Serves the app on an ephemeral port. With `connected` the MQTT client is
+    /// treated as connected to a broker, as after a ConnAck.
+    async fn start(connected: bool) -> Harness {
+        Harness::start_with(connected, default_config()).await
+    }
+
+    async fn start_with(connected: bool, config: Config) -> Harness {
+        let (requests_tx, requests) = flume::bounded(10);
+        let app_state = sven_api::build_state(
+            config,
+            AsyncClient::from_senders(requests_tx),
+            "sven-test".to_string(),
+        )
+        .unwrap();
+        if connected {
+            app_state.on_broker_connected().await;
+        }
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let app = sven_api::build_app(app_state.clone())
+            .into_make_service_with_connect_info::<ClientAddr>();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        Harness {
+            url,
+            requests,
+            client: reqwest::Client::new(),
+            app_state,
+        }
+    }
+
+    /// Serves the gRPC API on another ephemeral port and connects to it.
+    #[cfg(feature = "grpc")]
+    async fn grpc_client(
+        &self,
+    ) -> sven_api::grpc::proto::desk_client::DeskClient<tonic::transport::Channel> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(sven_api::grpc::serve(self.app_state.clone(), listener));
+        sven_api::grpc::proto::desk_client::DeskClient::connect(url)
+            .await
+            .unwrap()
+    }
+
+    async fn post(&self, path: &str, body: Value) -> (u16, Value) {
+        let response = self
+            .client
+            .post(format!("{}{}", self.url, path))
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        let status = response.status().as_u16();
+        (status, response.json().await.unwrap())
+    }
+
+    async fn get(&self, path: &str) -> (u16, Value) {
+        let response = self
+            .client
+            .get(format!("{}{}", self.url, path))
+            .send()
+            .await
+            .unwrap();
+        let status = response.status().as_u16();
+        (status, response.json().await.unwrap())
+    }
+
+    /// The next message the client handed over for publishing.
+    async fn next_publish(&self) -> Publish {
+        loop {
+            let request = tokio::time::timeout(Duration::from_secs(1), self.requests.recv_async())
+                .await
+                .expect("nothing was published")
+                .unwrap();
+            if let Request::Publish(publish) = request {
+                return publish;
+            }
+        }
+    }
+
+    /// Drops the end of the channel the eventloop would read, as if it had shut down.
+    fn lose_eventloop(&mut self) {
+        self.requests = flume::bounded(1).1;
+    }
+
+    fn nothing_published(&self) -> bool {
+        !self
+            .requests
+            .drain()
+            .any(|request| matches!(request, Request::Publish(_)))
+    }
+}
+
+fn default_config() -> Config {
+    Config::load().expect("default configuration")
+}
+
+fn payload(publish: &Publish) -> Value {
+    serde_json::from_slice(&publish.payload).unwrap()
+}
+
+#[tokio::test]
+async fn command_is_published_on_the_command_topic() {
+    let harness = Harness::start(true).await;
+    let (status, body) = harness
+        .post(
+            "/api/sven/command",
+            json!({"command": "AbsoluteHeight", "value": 900}),
+        )
+        .await;
+    assert_eq!(status, 200, "{}", body);
+
+    let publish = harness.next_publish().await;
+    assert_eq!(publish.topic, "sven/command");
+    assert_eq!(
+        payload(&publish),
+        json!({"command": "AbsoluteHeight", "value": 900, "id": body["request_id"]})
+    );
+}
+
+#[tokio::test]
+async fn command_topic_follows_the_configured_layout() {
+    let mut config = default_config();
+    config.command_topic = "home/office/desk/command".to_string();
+    let harness = Harness::start_with(true, config).await;
+    let (status, body) = harness
+        .post(
+            "/api/sven/command",
+            json!({"command": "AbsoluteHeight", "value": 900}),
+        )
+        .await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(
+        harness.next_publish().await.topic,
+        "home/office/desk/command"
+    );
+}
+
+#[tokio::test]
+async fn zigbee2mqtt_transport_sets_the_device_height() {
+    let mut config = default_config();
+    config.transport = TransportKind::Zigbee2Mqtt;
+    let harness = Harness::start_with(true, config).await;
+    let (status, body) = harness
+        .post(
+            "/api/sven/command",
+            json!({"command": "AbsoluteHeight", "value": 900}),
+        )
+        .await;
+    assert_eq!(status, 200, "{}", body);
+    let publish = harness.next_publish().await;
+    assert_eq!(publish.topic, "zigbee2mqtt/desk/set");
+    assert_eq!(payload(&publish), json!({"height": 900}));
+}
+
+#[tokio::test]
+async fn request_id_tags_the_published_command() {
+    let harness = Harness::start(true).await;
+    let response = harness
+        .client
+        .post(format!("{}/api/sven/command", harness.url))
+        .header("x-request-id", "trace-me-42")
+        .json(&json!({"command": "AbsoluteHeight", "value": 900}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["x-request-id"], "trace-me-42");
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["request_id"], "trace-me-42");
+    assert_eq!(payload(&harness.next_publish().await)["id"], "trace-me-42");
+
+    let (_, history) = harness.get("/api/sven/history").await;
+    assert_eq!(
+        history["entries"][0]["request_id"], "trace-me-42",
+        "{}",
+        history
+    );
+}
+
+#[tokio::test]
+async fn retried_command_is_answered_without_publishing_again() {
+    let harness = Harness::start(true).await;
+    let send = |value: u32| {
+        harness
+            .client
+            .post(format!("{}/api/sven/command", harness.url))
+            .header("idempotency-key", "retry-1")
+            .json(&json!({"command": "AbsoluteHeight", "value": value}))
+            .send()
+    };
+    let first: Value = send(900).await.unwrap().json().await.unwrap();
+    harness.next_publish().await;
+
+    let retry = send(900).await.unwrap();
+    assert_eq!(retry.status(), 200);
+    let retry: Value = retry.json().await.unwrap();
+    assert_eq!(retry["job_id"], first["job_id"]);
+    assert_eq!(retry["replayed"], true);
+    assert!(harness.nothing_published());
+
+    assert_eq!(send(1000).await.unwrap().status(), 422);
+}
+
+#[tokio::test]
+async fn heights_in_inches_are_converted_to_millimetres() {
+    let harness = Harness::start(true).await;
+    let response = harness
+        .client
+        .post(format!("{}/api/sven/command", harness.url))
+        .header("accept-units", "in")
+        .json(&json!({"command": "AbsoluteHeight", "value": 40}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["target_mm"], 1016);
+    assert_eq!(body["target"], 40.0);
+    assert_eq!(body["unit"], "in");
+    assert_eq!(payload(&harness.next_publish().await)["value"], 1016);
+
+    let (status, state) = harness.get("/api/sven/state?unit=cm").await;
+    assert_eq!(status, 200);
+    assert_eq!(state["height"], state["height_mm"].as_f64().unwrap() / 10.0);
+    let (status, body) = harness.get("/api/sven/state?unit=furlong").await;
+    assert_eq!(status, 400);
+    assert_eq!(body["code"], "unknown_unit");
+}
+
+#[tokio::test]
+async fn fractional_heights_are_rounded_once() {
+    let harness = Harness::start(true).await;
+    let (status, body) = harness
+        .post(
+            "/api/sven/command?unit=in&dry_run=1",
+            json!({"command": "AbsoluteHeight", "value": 29.5}),
+        )
+        .await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(body["command"]["value"], 749);
+    assert_eq!(body["unit"], "in");
+
+    let (status, body) = harness
+        .post(
+            "/api/sven/command?dry_run=1",
+            json!({"command": "AbsoluteHeight", "value": 749.3}),
+        )
+        .await;
+    assert_eq!(status, 422);
+    assert_eq!(body["code"], "invalid_command");
+    assert!(harness.nothing_published());
+}
+
+#[tokio::test]
+async fn group_command_reports_each_desk() {
+    let mut config = default_config();
+    config.desks = vec!["row-1".to_string()];
+    config
+        .desk_groups
+        .insert("row".to_string(), "row-1 row-9".parse().unwrap());
+    let harness = Harness::start_with(true, config).await;
+    let command = json!({"command": "AbsoluteHeight", "value": 1100});
+
+    let (status, body) = harness
+        .post("/api/groups/row/command", command.clone())
+        .await;
+    assert_eq!(status, 207, "{}", body);
+    assert_eq!(body["succeeded"], 0);
+    assert_eq!(body["failed"], 2);
+    // Configured but silent desks can't be moved yet, unknown ones not at all
+    assert_eq!(body["results"]["row-1"]["status"], 409);
+    assert_eq!(body["results"]["row-9"]["status"], 404);
+    assert!(harness.nothing_published());
+
+    let (status, _) = harness.post("/api/groups/hall/command", command).await;
+    assert_eq!(status, 404);
+    let (_, groups) = harness.get("/api/groups").await;
+    assert_eq!(groups["row"], json!(["row-1", "row-9"]));
+}
+
+#[tokio::test]
+async fn scoped_tokens_only_reach_their_desks() {
+    let mut config = default_config();
+    config.admin_token = Some("secret".to_string());
+    config.desks = vec!["office-1".to_string(), "office-2".to_string()];
+    let harness = Harness::start_with(true, config).await;
+    let response = harness
+        .client
+        .post(format!("{}/api/admin/tokens", harness.url))
+        .bearer_auth("secret")
+        .json(&json!({"name": "ada", "scopes": ["desk:office-1:control"]}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 201);
+    let body: Value = response.json().await.unwrap();
+    let token = body["token"].as_str().unwrap().to_string();
+    assert_eq!(body["scopes"], json!(["desk:office-1:control"]));
+
+    let command = |desk: &str| {
+        harness
+            .client
+            .post(format!("{}/api/desks/{}/command", harness.url, desk))
+            .json(&json!({"command": "AbsoluteHeight", "value": 1100}))
+    };
+    // Through authentication, but the desk hasn't reported yet
+    let response = command("office-1")
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 409);
+    let response = command("office-2")
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 403);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["code"], "out_of_scope");
+    let response = harness
+        .client
+        .get(format!("{}/api/sven/state", harness.url))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 403);
+    // Once a token is issued, every route needs one
+    let (status, body) = harness.get("/api/sven/state").await;
+    assert_eq!(status, 401);
+    assert_eq!(body["code"], "missing_api_key");
+
+    let response = harness
+        .client
+        .post(format!("{}/api/admin/tokens", harness.url))
+        .bearer_auth("secret")
+        .json(&json!({"name": "reception", "scopes": ["desk:*:read"]}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 201);
+    let response = harness
+        .client
+        .delete(format!("{}/api/admin/tokens/ada", harness.url))
+        .bearer_auth("secret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let response = command("office-1")
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 401);
+    assert!(harness.nothing_published());
+}
+
+#[tokio::test]
+async fn a_moving_job_reports_its_progress() {
+    let mut config = default_config();
+    config.travel_speed_mm_per_s = 2000;
+    let (requests_tx, requests) = flume::bounded(10);
+    let app_state = sven_api::build_state(
+        config,
+        AsyncClient::from_senders(requests_tx),
+        "sven-test".to_string(),
+    )
+    .unwrap();
+    tokio::spawn(sven_api::simulate::run(app_state.clone(), requests));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let app = sven_api::build_app(app_state).into_make_service_with_connect_info::<ClientAddr>();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    let client = reqwest::Client::new();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let mut events = client
+        .get(format!("{}/api/sven/events", url))
+        .send()
+        .await
+        .unwrap();
+    let body: Value = client
+        .post(format!("{}/api/sven/command", url))
+        .json(&json!({"command": "AbsoluteHeight", "value": 1200}))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    // Progress events carry the job on its way, ending with it done at 100 %
+    let mut seen = Vec::new();
+    let job = tokio::time::timeout(Duration::from_secs(5), async {
+        let mut buffer = String::new();
+        loop {
+            buffer.push_str(&String::from_utf8_lossy(
+                &events.chunk().await.unwrap().unwrap(),
+            ));
+            while let Some(end) = buffer.find("\n\n") {
+                let event: String = buffer.drain(..end + 2).collect();
+                let Some(data) = event.strip_prefix("event: progress\ndata: ").map(str::trim)
+                else {
+                    continue;
+                };
+                let job: Value = serde_json::from_str(data).unwrap();
+                assert_eq!(job["id"], body["job_id"]);
+                seen.push(job["progress_percent"].as_u64().unwrap());
+                if job["status"] == "completed" {
+                    return job;
+                }
+            }
+        }
+    })
+    .await
+    .expect("the job should finish");
+    assert_eq!(job["progress_percent"], 100);
+    assert_eq!(job["height_mm"], 1200);
+    assert!(job["start_mm"].as_u64().unwrap() < 1200);
+    assert!(seen.len() > 2, "{:?}", seen);
+    assert!(seen.windows(2).all(|pair| pair[0] <= pair[1]), "{:?}", seen);
+}
+
+#[tokio::test]
+async fn webhooks_get_signed_events_retried_until_accepted() {
+    use axum::http::HeaderMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // A receiver that fails its first delivery
+    let (received_tx, received) = flume::unbounded::<(HeaderMap, String)>();
+    let calls = std::sync::Arc::new(AtomicUsize::new(0));
+    let receiver = axum::Router::new().route(
+        "/hook",
+        axum::routing::post(move |headers: HeaderMap, body: String| {
+            let calls = calls.clone();
+            let received_tx = received_tx.clone();
+            async move {
+                received_tx.send((headers, body)).unwrap();
+                if calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                    axum::http::StatusCode::INTERNAL_SERVER_ERROR
+                } else {
+                    axum::http::StatusCode::NO_CONTENT
+                }
+            }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let hook_url = format!("http://{}/hook", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, receiver).await.unwrap() });
+
+    let mut config = default_config();
+    config.travel_speed_mm_per_s = 2000;
+    let (requests_tx, requests) = flume::bounded(10);
+    let app_state = sven_api::build_state(
+        config,
+        AsyncClient::from_senders(requests_tx),
+        "sven-test".to_string(),
+    )
+    .unwrap();
+    tokio::spawn(sven_api::simulate::run(app_state.clone(), requests));
+    tokio::spawn(sven_api::webhooks::run_webhooks(app_state.clone()));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let app = sven_api::build_app(app_state).into_make_service_with_connect_info::<ClientAddr>();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    let client = reqwest::Client::new();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let response = client
+        .post(format!("{}/api/sven/webhooks", url))
+        .json(&json!({"url": "ftp://example.com", "events": ["fault"]}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 422);
+    let response = client
+        .post(format!("{}/api/sven/webhooks", url))
+        .json(&json!({"url": hook_url, "events": ["position_reached"], "secret": "s3cret"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 201);
+    let webhook: Value = response.json().await.unwrap();
+    assert_eq!(webhook["secret"], "s3cret");
+    let id = webhook["id"].as_str().unwrap();
+    let webhooks: Value = client
+        .get(format!("{}/api/sven/webhooks", url))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(webhooks[0]["id"], id);
+    assert!(webhooks[0].get("secret").is_none());
+
+    client
+        .post(format!("{}/api/sven/command", url))
+        .json(&json!({"command": "AbsoluteHeight", "value": 1200}))
+        .send()
+        .await
+        .unwrap();
+
+    let next = || async {
+        tokio::time::timeout(Duration::from_secs(5), received.recv_async())
+            .await
+            .expect("the webhook should be called")
+            .unwrap()
+    };
+    let (headers, body) = next().await;
+    let (retried_headers, retried_body) = next().await;
+    assert_eq!(retried_body, body);
+    assert_eq!(
+        retried_headers["X-Sven-Delivery"],
+        headers["X-Sven-Delivery"]
+    );
+    assert_eq!(headers["X-Sven-Event"], "position_reached");
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, b"s3cret");
+    let tag = ring::hmac::sign(&key, body.as_bytes());
+    let hex: String = tag.as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+    assert_eq!(
+        headers["X-Sven-Signature"].to_str().unwrap(),
+        format!("sha256={}", hex)
+    );
+    let event: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(event["event"], "position_reached");
+    assert_eq!(event["data"]["height_mm"], 1200);
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let deliveries: Value = client
+        .get(format!("{}/api/sven/webhooks/{}/deliveries", url, id))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(deliveries[0]["attempt"], 2);
+    assert_eq!(deliveries[0]["delivered"], true);
+    assert_eq!(deliveries[1]["attempt"], 1);
+    assert_eq!(deliveries[1]["status_code"], 500);
+
+    let response = client
+        .delete(format!("{}/api/sven/webhooks/{}", url, id))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+}
+
+#[tokio::test]
+async fn heights_are_estimated_from_timed_moves() {
+    let mut config = default_config();
+    config.height_estimation = true;
+    config.travel_speed_mm_per_s = 1000;
+    config.default_height_mm = Some(800);
+    let harness = Harness::start_with(true, config).await;
+    tokio::spawn(sven_api::estimation::run_estimation(
+        harness.app_state.clone(),
+    ));
+
+    let (status, body) = harness
+        .post(
+            "/api/sven/command",
+            json!({"command": "AbsoluteHeight", "value": 1000}),
+        )
+        .await;
+    assert_eq!(status, 200, "{}", body);
+    let publish = payload(&harness.next_publish().await);
+    assert_eq!(publish["command"], "UpDuration");
+    assert_eq!(publish["value"], 200);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let (_, state) = harness.get("/api/sven/state").await;
+    assert_eq!(state["height_mm"], 1000);
+
+    let (status, _) = harness
+        .post(
+            "/api/sven/command",
+            json!({"command": "DownRelative", "value": 100}),
+        )
+        .await;
+    assert_eq!(status, 200);
+    let publish = payload(&harness.next_publish().await);
+    assert_eq!(publish["command"], "DownDuration");
+    assert_eq!(publish["value"], 100);
+}
+
+#[tokio::test]
+async fn long_timed_moves_start_with_soft_start_pulses() {
+    let mut config = default_config();
+    config.soft_start_pulses_ms = vec![200, 200];
+    config.soft_start_min_ms = 1000;
+    config.soft_start_gap_ms = 50;
+    let harness = Harness::start_with(true, config).await;
+
+    let (status, body) = harness
+        .post(
+            "/api/sven/command",
+            json!({"command": "UpDuration", "value": 3000}),
+        )
+        .await;
+    assert_eq!(status, 202, "{}", body);
+    let mut values = Vec::new();
+    let mut sent_at = Vec::new();
+    for _ in 0..3 {
+        let command = payload(&harness.next_publish().await);
+        sent_at.push(std::time::Instant::now());
+        assert_eq!(command["command"], "UpDuration");
+        values.push(command["value"].as_u64().unwrap());
+    }
+    assert_eq!(values, [200, 200, 2600]);
+    // Each pulse is followed by its length and the gap, not the ack timeout
+    for pair in sent_at.windows(2) {
+        let gap = pair[1] - pair[0];
+        assert!(
+            gap >= Duration::from_millis(200) && gap < Duration::from_millis(600),
+            "{:?} between pulses",
+            gap
+        );
+    }
+    let (_, job) = harness
+        .get(&format!(
+            "/api/sven/jobs/{}",
+            body["job_id"].as_str().unwrap()
+        ))
+        .await;
+    assert_eq!(job["kind"], "sequence");
+
+    // The last part holds the queue until the desk settles, so a fresh desk
+    let mut config = default_config();
+    config.soft_start_pulses_ms = vec![200, 200];
+    config.soft_start_min_ms = 1000;
+    let harness = Harness::start_with(true, config).await;
+    let (status, body) = harness
+        .post(
+            "/api/sven/command",
+            json!({"command": "DownDuration", "value": 3000, "soft_start": false}),
+        )
+        .await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(payload(&harness.next_publish().await)["value"], 3000);
+    assert!(harness.nothing_published());
+}
+
+#[tokio::test]
+async fn the_quick_action_follows_the_time_of_day() {
+    let harness = Harness::start(true).await;
+    let (status, body) = harness.post("/api/sven/quick", json!({})).await;
+    assert_eq!(status, 409, "{}", body);
+    assert_eq!(body["code"], "no_quick_action");
+
+    let now = chrono::Local::now().time();
+    let window = |from: i64, to: i64| {
+        format!(
+            "{}-{}",
+            (now + chrono::Duration::hours(from)).format("%H:%M"),
+            (now + chrono::Duration::hours(to)).format("%H:%M")
+        )
+    };
+    let rules = json!({
+        "rules": [
+            {"window": window(2, 3), "action": {"position": "Armrest"}},
+            {"window": window(-1, 1), "action": {"height_mm": 1050}},
+        ],
+        "default": {"position": "Standing"},
+    });
+    let response = harness
+        .client
+        .put(format!("{}/api/sven/quick", harness.url))
+        .json(&rules)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let (_, quick) = harness.get("/api/sven/quick").await;
+    assert_eq!(quick["current"]["rule"], 1, "{}", quick);
+
+    let (status, body) = harness.post("/api/sven/quick", json!({})).await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(body["quick_action"]["action"]["height_mm"], 1050);
+    let command = payload(&harness.next_publish().await);
+    assert_eq!(command["command"], "AbsoluteHeight");
+    assert_eq!(command["value"], 1050);
+
+    let response = harness
+        .client
+        .put(format!("{}/api/sven/quick", harness.url))
+        .json(&json!({"rules": [{"window": "9am", "action": {"height_mm": 900}}]}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 422);
+}
+
+#[tokio::test]
+async fn quiet_hours_refuse_moves_unless_forced() {
+    let mut config = default_config();
+    let now = chrono::Local::now().time();
+    let window = format!(
+        "{}-{}",
+        (now - chrono::Duration::hours(1)).format("%H:%M"),
+        (now + chrono::Duration::hours(1)).format("%H:%M")
+    );
+    config.quiet_hours = vec![window.parse().unwrap()];
+    let harness = Harness::start_with(true, config).await;
+
+    let (status, body) = harness
+        .post(
+            "/api/sven/command",
+            json!({"command": "AbsoluteHeight", "value": 900}),
+        )
+        .await;
+    assert_eq!(status, 409, "{}", body);
+    assert_eq!(body["code"], "quiet_hours");
+    assert!(harness.nothing_published());
+
+    let (status, body) = harness.post("/api/sven/stop", json!({})).await;
+    assert_eq!(status, 200, "{}", body);
+    harness.next_publish().await;
+
+    let (status, body) = harness
+        .post(
+            "/api/sven/command",
+            json!({"command": "AbsoluteHeight", "value": 900, "force": true}),
+        )
+        .await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(payload(&harness.next_publish().await)["value"], 900);
+}
+
+/// The hex HMAC-SHA256 a signed MQTT message carries: over the message without
+/// its signature, as compact JSON with sorted keys.
+fn mqtt_signature(secret: &str, message: &Value) -> String {
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, secret.as_bytes());
+    let tag = ring::hmac::sign(&key, message.to_string().as_bytes());
+    tag.as_ref().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[tokio::test]
+async fn mqtt_commands_are_signed_and_unsigned_state_is_dropped() {
+    let mut config = default_config();
+    config.mqtt_signing_secret = Some("firmware-secret".to_string());
+    let harness = Harness::start_with(true, config).await;
+    let (status, body) = harness
+        .post(
+            "/api/sven/command",
+            json!({"command": "AbsoluteHeight", "value": 900}),
+        )
+        .await;
+    assert_eq!(status, 200, "{}", body);
+    let mut command = payload(&harness.next_publish().await);
+    let signature = command
+        .as_object_mut()
+        .unwrap()
+        .remove("signature")
+        .unwrap();
+    assert_eq!(signature, mqtt_signature("firmware-secret", &command));
+    assert!(command["nonce"].is_string());
+    assert!(command["timestamp"].is_i64());
+
+    let mut config = default_config();
+    config.transport = TransportKind::Http;
+    config.transport_url = Some("http://127.0.0.1:9".to_string());
+    config.mqtt_signing_secret = Some("firmware-secret".to_string());
+    let harness = Harness::start_with(true, config).await;
+    let report = |state: Value| {
+        harness.post(
+            "/api/sven/transport/messages",
+            json!({"topic": "sven/state", "payload": state}),
+        )
+    };
+    let height = || async { harness.get("/api/sven/state").await.1["height_mm"].clone() };
+    let before = height().await;
+
+    report(json!({"height_mm": 1111, "position": "Custom"})).await;
+    assert_eq!(height().await, before);
+
+    let mut signed = json!({
+        "height_mm": 1111,
+        "position": "Custom",
+        "nonce": "n1",
+        "timestamp": chrono::Utc::now().timestamp_millis(),
+    });
+    let mut forged = signed.clone();
+    signed["signature"] = mqtt_signature("firmware-secret", &signed).into();
+    forged["signature"] = mqtt_signature("guessed", &forged).into();
+    report(forged).await;
+    assert_eq!(height().await, before);
+    report(signed.clone()).await;
+    assert_eq!(height().await, 1111);
+
+    // A replay of the same report is dropped
+    let mut lower = json!({
+        "height_mm": 800,
+        "position": "Custom",
+        "nonce": "n2",
+        "timestamp": chrono::Utc::now().timestamp_millis(),
+    });
+    lower["signature"] = mqtt_signature("firmware-secret", &lower).into();
+    report(lower).await;
+    assert_eq!(height().await, 800);
+    report(signed).await;
+    assert_eq!(height().await, 800);
+}
+
+#[tokio::test]
+async fn unsigned_home_assistant_commands_are_dropped_while_signing() {
+    // The desk end of the HTTP transport, collecting what's published to it
+    let (published_tx, published) = flume::unbounded::<Value>();
+    let desk = axum::Router::new().route(
+        "/publish",
+        axum::routing::post(move |axum::Json(message): axum::Json<Value>| {
+            let published_tx = published_tx.clone();
+            async move { published_tx.send(message).unwrap() }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let desk_url = format!("http://{}/publish", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, desk).await.unwrap() });
+    let next_command = || async {
+        loop {
+            let message = tokio::time::timeout(Duration::from_secs(1), published.recv_async())
+                .await
+                .ok()?
+                .unwrap();
+            if message["topic"] == "sven/command" {
+                return Some(message);
+            }
+        }
+    };
+
+    let home_assistant = |secret: Option<&str>| {
+        let mut config = default_config();
+        config.transport = TransportKind::Http;
+        config.transport_url = Some(desk_url.clone());
+        config.ha_discovery = true;
+        config.mqtt_signing_secret = secret.map(str::to_string);
+        Harness::start_with(true, config)
+    };
+    let set_position = json!({"topic": "sven/ha/cover/position/set", "payload": "50"});
+
+    let harness = home_assistant(None).await;
+    let (status, body) = harness
+        .post("/api/sven/transport/messages", set_position.clone())
+        .await;
+    assert_eq!(status, 200, "{}", body);
+    assert!(next_command().await.is_some());
+
+    let harness = home_assistant(Some("firmware-secret")).await;
+    let (status, body) = harness
+        .post("/api/sven/transport/messages", set_position)
+        .await;
+    assert_eq!(status, 200, "{}", body);
+    assert!(next_command().await.is_none());
+
+    // Power reports have to be signed too, with the text in `payload`
+    let power = |payload: Value| {
+        harness.post(
+            "/api/sven/transport/messages",
+            json!({"topic": "sven/power", "payload": payload}),
+        )
+    };
+    let power_state = || async { harness.get("/api/sven/state").await.1["power"].clone() };
+    let before = power_state().await;
+    power(json!("standby")).await;
+    assert_eq!(power_state().await, before);
+    let mut signed = json!({
+        "payload": "standby",
+        "nonce": "p1",
+        "timestamp": chrono::Utc::now().timestamp_millis(),
+    });
+    signed["signature"] = mqtt_signature("firmware-secret", &signed).into();
+    power(signed).await;
+    assert_ne!(power_state().await, before);
+}
+
+#[tokio::test]
+async fn smart_plug_readings_show_in_the_state_and_stats() {
+    let history_db = std::env::temp_dir().join(format!("sven-energy-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&history_db);
+    let mut config = default_config();
+    // Messages are delivered over HTTP, so the test can play the plug
+    config.transport = TransportKind::Http;
+    config.transport_url = Some("http://127.0.0.1:9".to_string());
+    config.history_db = Some(history_db.clone());
+    config.power_meter_topic = Some("tele/desk-plug/SENSOR".to_string());
+    let harness = Harness::start_with(true, config).await;
+    let reading = |watts: f64| {
+        harness.post(
+            "/api/sven/transport/messages",
+            json!({
+                "topic": "tele/desk-plug/SENSOR",
+                "payload": {"Time": "2026-10-14T09:00:00", "ENERGY": {"Power": watts}},
+            }),
+        )
+    };
+
+    let (status, body) = reading(1.5).await;
+    assert_eq!(status, 200, "{}", body);
+    let (_, state) = harness.get("/api/sven/state").await;
+    assert_eq!(state["power_w"], 1.5);
+    assert_eq!(state["power_draw"], "idle");
+
+    reading(85.0).await;
+    let (_, state) = harness.get("/api/sven/state").await;
+    assert_eq!(state["power_w"], 85.0);
+    assert_eq!(state["power_draw"], "moving");
+
+    let (_, history) = harness.get("/api/sven/history?kind=power").await;
+    assert_eq!(
+        history["entries"].as_array().unwrap().len(),
+        2,
+        "{}",
+        history
+    );
+    let (status, stats) = harness.get("/api/sven/stats?range=24h").await;
+    assert_eq!(status, 200, "{}", stats);
+    assert!(stats["total"]["energy_wh"].is_number(), "{}", stats);
+    let _ = std::fs::remove_file(&history_db);
+}
+
+#[tokio::test]
+async fn command_job_is_cancelled_by_a_stop() {
+    let harness = Harness::start(true).await;
+    let (status, body) = harness
+        .post(
+            "/api/sven/command",
+            json!({"command": "AbsoluteHeight", "value": 900}),
+        )
+        .await;
+    assert_eq!(status, 200, "{}", body);
+    harness.next_publish().await;
+    let path = format!("/api/sven/jobs/{}", body["job_id"].as_str().unwrap());
+    let (_, job) = harness.get(&path).await;
+    assert_eq!(job["status"], "pending", "{}", job);
+    assert_eq!(job["target_mm"], 900);
+
+    let response = harness
+        .client
+        .delete(format!("{}{}", harness.url, path))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let job: Value = response.json().await.unwrap();
+    assert_eq!(job["status"], "cancelled", "{}", job);
+    assert_eq!(payload(&harness.next_publish().await)["command"], "Stop");
+
+    let response = harness
+        .client
+        .delete(format!("{}{}", harness.url, path))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 409);
+}
+
+#[tokio::test]
+async fn sequence_runs_its_steps_in_order() {
+    // Nothing reports the desk arriving, so each move holds the desk until it times out
+    let mut config = default_config();
+    config.ack_timeout_ms = 100;
+    let harness = Harness::start_with(true, config).await;
+    let (status, body) = harness
+        .post(
+            "/api/sven/sequence",
+            json!({"steps": [
+                {"command": "AbsoluteHeight", "value": 1100, "wait": false, "delay_ms": 50},
+                {"command": "AbsoluteHeight", "value": 5000},
+            ]}),
+        )
+        .await;
+    assert_eq!(status, 422);
+    assert!(
+        body["error"].as_str().unwrap().starts_with("Step 2"),
+        "{}",
+        body
+    );
+    assert!(harness.nothing_published());
+
+    let (status, body) = harness
+        .post(
+            "/api/sven/sequence",
+            json!({"steps": [
+                {"command": "AbsoluteHeight", "value": 1100, "wait": false, "delay_ms": 50},
+                {"command": "AbsoluteHeight", "value": 720, "wait": false},
+            ]}),
+        )
+        .await;
+    assert_eq!(status, 202, "{}", body);
+    let id = body["id"].as_str().unwrap().to_string();
+    let first = payload(&harness.next_publish().await);
+    let second = payload(&harness.next_publish().await);
+    assert_eq!(
+        (first["value"].clone(), second["value"].clone()),
+        (json!(1100), json!(720))
+    );
+    assert_eq!(first["id"], format!("{}.1", id));
+
+    let path = format!("/api/sven/sequence/{}", id);
+    let mut job = Value::Null;
+    for _ in 0..20 {
+        job = harness.get(&path).await.1;
+        if job["status"] != "running" {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(job["status"], "completed", "{}", job);
+    assert_eq!(job["completed_steps"], 2);
+}
+
+#[tokio::test]
+async fn presets_resolve_to_the_users_own_height() {
+    let mut config = default_config();
+    config.users = vec!["alice".to_string()];
+    let harness = Harness::start_with(true, config).await;
+    let (status, _) = harness
+        .post("/api/sven/users", json!({"name": "bob"}))
+        .await;
+    assert_eq!(status, 201);
+    let (status, _) = harness
+        .post(
+            "/api/sven/presets",
+            json!({"name": "standing", "height_mm": 1100}),
+        )
+        .await;
+    assert_eq!(status, 201);
+    let response = harness
+        .client
+        .put(format!(
+            "{}/api/sven/users/bob/presets/standing",
+            harness.url
+        ))
+        .json(&json!({"height_mm": 1180}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let apply_as = |user: &'static str| {
+        harness
+            .client
+            .post(format!("{}/api/sven/presets/standing/apply", harness.url))
+            .header("x-sven-user", user)
+            .send()
+    };
+    assert_eq!(apply_as("bob").await.unwrap().status(), 200);
+    assert_eq!(payload(&harness.next_publish().await)["value"], 1180);
+    let (_, history) = harness.get("/api/sven/history").await;
+    assert_eq!(history["entries"][0]["user"], "bob", "{}", history);
+
+    assert_eq!(apply_as("carol").await.unwrap().status(), 422);
+}
+
+#[tokio::test]
+async fn collections_filter_sort_and_page_the_same_way() {
+    let harness = Harness::start(true).await;
+    for (name, height_mm) in [("sitting", 720), ("standing", 1100), ("perching", 900)] {
+        let (status, _) = harness
+            .post(
+                "/api/sven/presets",
+                json!({"name": name, "height_mm": height_mm}),
+            )
+            .await;
+        assert_eq!(status, 201);
+    }
+    let response = harness
+        .client
+        .get(format!(
+            "{}/api/sven/presets?sort=-height_mm&limit=2&offset=1",
+            harness.url
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["x-total-count"], "3");
+    let presets: Value = response.json().await.unwrap();
+    assert_eq!(presets[0]["name"], "perching");
+    assert_eq!(presets[1]["name"], "sitting");
+
+    let (_, presets) = harness.get("/api/sven/presets?height_mm=1100").await;
+    assert_eq!(presets.as_array().unwrap().len(), 1);
+    let (status, body) = harness.get("/api/sven/presets?colour=red").await;
+    assert_eq!(status, 400);
+    assert_eq!(body["code"], "invalid_query");
+
+    let (status, _) = harness
+        .post(
+            "/api/sven/command",
+            json!({"command": "Position", "value": 2}),
+        )
+        .await;
+    assert_eq!(status, 200);
+    let (_, history) = harness.get("/api/sven/history?command=Position").await;
+    assert_eq!(history["entries"].as_array().unwrap().len(), 1);
+    let (_, history) = harness
+        .get("/api/sven/history?command=AbsoluteHeight")
+        .await;
+    assert!(history["entries"].as_array().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn state_revalidates_and_long_polls_with_its_etag() {
+    let harness = Harness::start(true).await;
+    let state_url = format!("{}/api/sven/state", harness.url);
+    let response = harness.client.get(&state_url).send().await.unwrap();
+    let etag = response.headers()["etag"].clone();
+    let response = harness
+        .client
+        .get(&state_url)
+        .header("if-none-match", etag.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 304);
+
+    let started = std::time::Instant::now();
+    let response = harness
+        .client
+        .get(format!("{}?wait=true&timeout_secs=1", state_url))
+        .header("if-none-match", etag.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 304);
+    assert!(started.elapsed() >= Duration::from_secs(1));
+
+    let poll = harness
+        .client
+        .get(format!("{}?wait=true&timeout_secs=10", state_url))
+        .header("if-none-match", etag.clone())
+        .send();
+    let session = async {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        harness
+            .post("/api/sven/sessions", json!({"duration_secs": 600}))
+            .await
+    };
+    let (response, (status, _)) = tokio::join!(poll, session);
+    assert_eq!(status, 202);
+    let response = response.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_ne!(response.headers()["etag"], etag);
+    let state: Value = response.json().await.unwrap();
+    assert!(state["session"]["remaining_secs"].is_u64());
+}
+
+#[tokio::test]
+async fn deadband_needs_a_reported_height() {
+    let mut config = default_config();
+    config.deadband_mm = 5000;
+    let harness = Harness::start_with(true, config).await;
+    // Nothing has reported, so the placeholder height can't rule the move out
+    let (status, body) = harness
+        .post(
+            "/api/sven/command",
+            json!({"command": "AbsoluteHeight", "value": 1000}),
+        )
+        .await;
+    assert_eq!(status, 200, "{}", body);
+    assert!(body.get("skipped").is_none());
+    assert_eq!(payload(&harness.next_publish().await)["value"], 1000);
+}
+
+#[tokio::test]
+async fn an_uncleared_fault_blocks_moves_across_restarts() {
+    let store_path = std::env::temp_dir().join(format!("sven-faults-{}", std::process::id()));
+    std::fs::create_dir_all(&store_path).unwrap();
+    std::fs::write(
+        store_path.join("faults.json"),
+        json!({
+            "recent": [{
+                "id": 1,
+                "kind": "collision",
+                "message": "collision: left leg",
+                "received_at": "2026-01-01T10:00:00Z",
+                "cleared_at": null,
+            }],
+            "next_id": 1,
+        })
+        .to_string(),
+    )
+    .unwrap();
+    let mut config = default_config();
+    config.store_path = Some(store_path.clone());
+    let harness = Harness::start_with(true, config).await;
+
+    let move_up = json!({"command": "AbsoluteHeight", "value": 1000});
+    let (status, body) = harness.post("/api/sven/command", move_up.clone()).await;
+    assert_eq!(status, 409);
+    assert_eq!(body["code"], "desk_fault");
+    let (status, _) = harness
+        .post("/api/sven/command", json!({"command": "Stop", "value": 0}))
+        .await;
+    assert_eq!(status, 200);
+    harness.next_publish().await;
+    let (_, faults) = harness.get("/api/sven/faults").await;
+    assert_eq!(faults["active"]["kind"], "collision");
+    let (_, state) = harness.get("/api/sven/state").await;
+    assert_eq!(state["fault"], "collision");
+
+    let (status, body) = harness.post("/api/sven/faults/clear", json!({})).await;
+    assert_eq!(status, 200);
+    assert_eq!(body["cleared"], 1);
+    let (status, _) = harness.post("/api/sven/command", move_up).await;
+    assert_eq!(status, 200);
+    assert_eq!(payload(&harness.next_publish().await)["value"], 1000);
+    std::fs::remove_dir_all(store_path).unwrap();
+}
+
+#[tokio::test]
+async fn a_backup_restores_onto_another_instance() {
+    let mut config = default_config();
+    config.admin_token = Some("secret".to_string());
+    let old = Harness::start_with(true, config.clone()).await;
+    let (status, _) = old
+        .post(
+            "/api/sven/presets",
+            json!({"name": "standing", "height_mm": 1100}),
+        )
+        .await;
+    assert_eq!(status, 201);
+    let response = old
+        .client
+        .get(format!("{}/api/admin/backup", old.url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 401);
+    let response = old
+        .client
+        .get(format!("{}/api/admin/backup", old.url))
+        .bearer_auth("secret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let backup: Value = response.json().await.unwrap();
+    assert_eq!(backup["format"], 1);
+    assert_eq!(
+        backup["artifacts"]["presets"]["standing"]["height_mm"],
+        1100
+    );
+
+    let store_path = std::env::temp_dir().join(format!("sven-restore-{}", std::process::id()));
+    config.store_path = Some(store_path.clone());
+    let new = Harness::start_with(true, config).await;
+    let restore = |body: Value| {
+        new.client
+            .post(format!("{}/api/admin/restore", new.url))
+            .bearer_auth("secret")
+            .json(&body)
+            .send()
+    };
+    let mut broken = backup.clone();
+    broken["artifacts"]["presets"] = json!("standing");
+    let response = restore(broken).await.unwrap();
+    assert_eq!(response.status(), 422);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["code"], "invalid_backup");
+    let (_, presets) = new.get("/api/sven/presets").await;
+    assert_eq!(presets, json!([]));
+
+    let response = restore(backup).await.unwrap();
+    assert_eq!(response.status(), 200);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["restart_required"], false);
+    assert!(
+        body["restored"]
+            .as_array()
+            .unwrap()
+            .contains(&json!("presets"))
+    );
+    let (_, presets) = new.get("/api/sven/presets").await;
+    assert_eq!(presets[0]["height_mm"], 1100);
+    assert!(store_path.join("presets.json").exists());
+    std::fs::remove_dir_all(store_path).unwrap();
+}
+
+#[tokio::test]
+async fn reloading_the_config_file_applies_limits_and_reports_the_rest() {
+    let path = std::env::temp_dir().join(format!("sven-reload-{}.toml", std::process::id()));
+    std::fs::write(&path, "admin_token = \"secret\"\nmax_height_mm = 1250\n").unwrap();
+    let config = Config::load_from(Some(path.clone())).unwrap();
+    let harness = Harness::start_with(true, config).await;
+    let move_to = json!({"command": "AbsoluteHeight", "value": 1200});
+    let (status, _) = harness.post("/api/sven/command", move_to.clone()).await;
+    assert_eq!(status, 200);
+    harness.next_publish().await;
+
+    std::fs::write(
+        &path,
+        "admin_token = \"secret\"\nmax_height_mm = 1100\n[http]\nport = 4000\n",
+    )
+    .unwrap();
+    let response = harness
+        .client
+        .post(format!("{}/api/admin/config/reload", harness.url))
+        .bearer_auth("secret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["applied"], json!(["SVEN_MAX_HEIGHT_MM"]));
+    assert_eq!(body["restart_required"], json!(["SVEN_HTTP_PORT"]));
+    let (status, _) = harness.post("/api/sven/command", move_to).await;
+    assert_eq!(status, 422);
+
+    std::fs::write(&path, "max_height_mm = [").unwrap();
+    let response = harness
+        .client
+        .post(format!("{}/api/admin/config/reload", harness.url))
+        .bearer_auth("secret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 422);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn invalid_subscription_patterns_are_a_config_error() {
+    let path = std::env::temp_dir().join(format!("sven-subscriptions-{}.toml", std::process::id()));
+    std::fs::write(&path, "subscriptions = \"sven/#/raw=log\"\n").unwrap();
+    let error = Config::load_from(Some(path.clone())).unwrap_err();
+    assert!(error.contains("'#' must be the last level"), "{}", error);
+
+    std::fs::write(&path, "subscriptions = \"sven/raw/#=log\"\n").unwrap();
+    assert!(Config::load_from(Some(path.clone())).is_ok());
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn motor_settings_are_published_on_the_motor_topic() {
+    let harness = Harness::start(true).await;
+    let response = harness
+        .client
+        .put(format!("{}/api/sven/settings", harness.url))
+        .json(&json!({"speed_percent": 40, "acceleration": "gentle"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let speed = harness.next_publish().await;
+    assert_eq!(speed.topic, "sven/config");
+    assert_eq!(payload(&speed)["speed_percent"], 40);
+    assert_eq!(
+        payload(&harness.next_publish().await)["acceleration"],
+        "gentle"
+    );
+    let (_, settings) = harness.get("/api/sven/settings").await;
+    assert_eq!(settings["requested"]["speed_percent"], 40);
+
+    let (status, body) = harness
+        .post(
+            "/api/sven/command",
+            json!({"command": "SetSpeed", "value": 5}),
+        )
+        .await;
+    assert_eq!(status, 422);
+    assert_eq!(body["code"], "speed_out_of_range");
+    let (status, _) = harness
+        .post(
+            "/api/sven/command",
+            json!({"command": "SetSoftStart", "value": 500}),
+        )
+        .await;
+    assert_eq!(status, 200);
+    assert_eq!(payload(&harness.next_publish().await)["soft_start_ms"], 500);
+}
+
+#[tokio::test]
+async fn audit_export_names_who_sent_what() {
+    let mut config = default_config();
+    config
+        .api_keys
+        .insert("kitchen".to_string(), "k1tchen".parse().unwrap());
+    let harness = Harness::start_with(true, config).await;
+    let send = |token: &str| {
+        harness
+            .client
+            .post(format!("{}/api/sven/command", harness.url))
+            .bearer_auth(token)
+            .json(&json!({"command": "AbsoluteHeight", "value": 900}))
+            .send()
+    };
+    assert_eq!(send("k1tchen").await.unwrap().status(), 200);
+    assert_eq!(send("guess").await.unwrap().status(), 401);
+
+    let export = |format: &str| {
+        harness
+            .client
+            .get(format!(
+                "{}/api/sven/audit/export?format={}",
+                harness.url, format
+            ))
+            .bearer_auth("k1tchen")
+            .send()
+    };
+    let entries: Value = export("json").await.unwrap().json().await.unwrap();
+    assert_eq!(entries.as_array().unwrap().len(), 2, "{}", entries);
+    assert_eq!(entries[0]["key"], "kitchen");
+    assert_eq!(entries[0]["client"], "127.0.0.1");
+    assert_eq!(entries[0]["status"], 200);
+    assert!(entries[0]["detail"].as_str().unwrap().contains("900"));
+    assert_eq!(entries[1]["key"], Value::Null);
+    assert_eq!(entries[1]["status"], 401);
+
+    let csv = export("csv").await.unwrap();
+    assert!(
+        csv.headers()["content-type"]
+            .to_str()
+            .unwrap()
+            .starts_with("text/csv")
+    );
+    let csv = csv.text().await.unwrap();
+    assert_eq!(csv.lines().count(), 3);
+    assert!(
+        csv.lines()
+            .nth(1)
+            .unwrap()
+            .contains(",kitchen,,127.0.0.1,POST,/api/sven/command,200,")
+    );
+}
+
+#[tokio::test]
+async fn percent_command_is_published_as_an_absolute_move() {
+    let harness = Harness::start(true).await;
+    // The desk starts at its lowest position, 650 mm
+    let (status, body) = harness
+        .post(
+            "/api/sven/command",
+            json!({"command": "UpPercent", "value": 10}),
+        )
+        .await;
+    assert_eq!(status, 200, "{}", body);
+
+    let publish = harness.next_publish().await;
+    assert_eq!(payload(&publish)["command"], "AbsoluteHeight");
+    assert_eq!(payload(&publish)["value"], 715);
+}
+
+#[tokio::test]
+async fn percent_of_a_huge_reported_height_doesnt_overflow() {
+    let mut config = default_config();
+    // Room above the report, so the resolved target isn't clamped to the bounds
+    config.max_height_mm = 4_000_000_000;
+    config.transport = TransportKind::Http;
+    config.transport_url = Some("http://127.0.0.1:9".to_string());
+    let harness = Harness::start_with(true, config).await;
+    harness
+        .post(
+            "/api/sven/transport/messages",
+            json!({"topic": "sven/state", "payload": {"height_mm": 4_000_000_000u32, "position": "Custom"}}),
+        )
+        .await;
+    let (status, body) = harness
+        .post(
+            "/api/sven/command",
+            json!({"command": "DownPercent", "value": 50, "dry_run": true}),
+        )
+        .await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(body["command"]["command"], "AbsoluteHeight");
+    assert_eq!(body["command"]["value"], 2_000_000_000u32);
+    let publish: Value =
+        serde_json::from_str(body["publishes"][0]["payload"].as_str().unwrap()).unwrap();
+    assert_eq!(publish["value"], 2_000_000_000u32);
+}
+
+#[tokio::test]
+async fn state_reports_are_persisted_by_the_writer_and_flushed_on_shutdown() {
+    let store_path = std::env::temp_dir().join(format!("sven-state-writer-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&store_path);
+    let mut config = default_config();
+    config.transport = TransportKind::Http;
+    config.transport_url = Some("http://127.0.0.1:9".to_string());
+    config.store_path = Some(store_path.clone());
+    let harness = Harness::start_with(true, config.clone()).await;
+    for height_mm in [800, 900, 1000] {
+        let (status, body) = harness
+            .post(
+                "/api/sven/transport/messages",
+                json!({"topic": "sven/state", "payload": {"height_mm": height_mm, "position": "Custom"}}),
+            )
+            .await;
+        assert_eq!(status, 200, "{}", body);
+    }
+    sven_api::finish_shutdown(&harness.app_state, tokio::spawn(async {})).await;
+    let restored = Harness::start_with(true, config.clone()).await;
+    assert_eq!(restored.get("/api/sven/state").await.1["height_mm"], 1000);
+
+    // A store that can't be opened is an error, not a panic
+    let file = store_path.join("not-a-directory");
+    std::fs::write(&file, b"").unwrap();
+    config.store_path = Some(file);
+    let error = sven_api::build_state(
+        config,
+        AsyncClient::from_senders(flume::bounded(1).0),
+        "sven-test".to_string(),
+    )
+    .err()
+    .unwrap();
+    assert!(error.contains("Failed to open"), "{}", error);
+    std::fs::remove_dir_all(store_path).unwrap();
+}
+
+#[tokio::test]
+async fn invalid_command_is_rejected_without_publishing() {
+    let harness = Harness::start(true).await;
+    let (status, body) = harness
+        .post(
+            "/api/sven/command",
+            json!({"command": "AbsoluteHeight", "value": 5000}),
+        )
+        .await;
+    assert_eq!(status, 422);
+    assert_eq!(body["code"], "height_out_of_range");
+    assert!(harness.nothing_published());
+}
+
+#[tokio::test]
+async fn command_without_broker_connection_is_rejected() {
+    let harness = Harness::start(false).await;
+    let (status, body) = harness
+        .post(
+            "/api/sven/command",
+            json!({"command": "UpRelative", "value": 50}),
+        )
+        .await;
+    assert_eq!(status, 503);
+    assert_eq!(body["code"], "broker_unavailable");
+    assert!(harness.nothing_published());
+}
+
+#[tokio::test]
+async fn moves_are_spooled_until_the_broker_is_back() {
+    let mut config = default_config();
+    config.spool_commands = true;
+    let harness = Harness::start_with(false, config).await;
+    for value in [900, 1100] {
+        let (status, _) = harness
+            .post(
+                "/api/sven/command",
+                json!({"command": "AbsoluteHeight", "value": value}),
+            )
+            .await;
+        assert_eq!(status, 202);
+    }
+    let (status, body) = harness
+        .post(
+            "/api/sven/command",
+            json!({"command": "UpPercent", "value": 10}),
+        )
+        .await;
+    assert_eq!(status, 503);
+    assert_eq!(body["code"], "broker_unavailable");
+
+    let (status, body) = harness.get("/readyz").await;
+    assert_eq!(status, 503);
+    assert_eq!(body["spool"]["pending"], 1);
+    assert!(harness.nothing_published());
+
+    harness.app_state.on_broker_connected().await;
+    let publish = harness.next_publish().await;
+    assert_eq!(payload(&publish)["value"], 1100);
+    assert!(harness.nothing_published());
+    let (_, body) = harness.get("/healthz").await;
+    assert_eq!(body["spool"]["pending"], 0);
+}
+
+#[tokio::test]
+async fn relative_move_needs_a_reported_height_to_be_resolved() {
+    let mut config = default_config();
+    config.resolve_relative_moves = true;
+    let harness = Harness::start_with(true, config).await;
+    let (status, body) = harness
+        .post(
+            "/api/sven/command",
+            json!({"command": "UpRelative", "value": 50}),
+        )
+        .await;
+    assert_eq!(status, 409);
+    assert_eq!(body["code"], "state_unknown");
+    assert!(harness.nothing_published());
+}
+
+#[tokio::test]
+async fn stale_state_is_flagged_and_can_be_unavailable() {
+    let mut config = default_config();
+    config.stale_state_max_age = Some(Duration::from_millis(1));
+    config.stale_state_unavailable = true;
+    let harness = Harness::start_with(true, config).await;
+    tokio::time::sleep(Duration::from_millis(5)).await;
+
+    let (status, body) = harness.get("/api/sven/state").await;
+    assert_eq!(status, 503);
+    assert_eq!(body["stale"], true);
+    assert_eq!(body["last_updated"], Value::Null);
+    assert_eq!(body["status"], "Offline");
+}
+
+#[tokio::test]
+async fn rate_limited_client_can_still_stop() {
+    let mut config = default_config();
+    config.rate_limit_per_minute = 1;
+    let harness = Harness::start_with(true, config).await;
+    let command = json!({"command": "UpRelative", "value": 50});
+
+    let (status, _) = harness.post("/api/sven/command", command.clone()).await;
+    assert_eq!(status, 200);
+    let (status, body) = harness.post("/api/sven/command", command).await;
+    assert_eq!(status, 429);
+    assert_eq!(body["code"], "rate_limited");
+
+    let (status, _) = harness.post("/api/sven/stop", json!({})).await;
+    assert_eq!(status, 200);
+    let (status, _) = harness
+        .post("/api/sven/command", json!({"command": "Stop", "value": 0}))
+        .await;
+    assert_eq!(status, 200);
+}
+
+#[tokio::test]
+async fn rotating_origins_share_the_address_limit() {
+    let mut config = default_config();
+    config.rate_limit_per_minute = 1;
+    config.rate_limit_overrides = [("https://dashboard.lan".to_string(), 3)].into();
+    // Moves behind the first are refused rather than waiting for it to arrive
+    config.command_queue_policy = "reject".parse().unwrap();
+    let harness = Harness::start_with(true, config).await;
+    let command = |origin: String| {
+        harness
+            .client
+            .post(format!("{}/api/sven/command", harness.url))
+            .header("origin", origin)
+            .json(&json!({"command": "UpRelative", "value": 50}))
+            .send()
+    };
+
+    let statuses = [
+        command("https://a.example".to_string())
+            .await
+            .unwrap()
+            .status(),
+        command("https://b.example".to_string())
+            .await
+            .unwrap()
+            .status(),
+        command("https://c.example".to_string())
+            .await
+            .unwrap()
+            .status(),
+    ];
+    assert_eq!(statuses.map(|status| status.as_u16()), [200, 429, 429]);
+
+    // A configured origin gets its own limit
+    for _ in 0..3 {
+        let response = command("https://dashboard.lan".to_string()).await.unwrap();
+        assert_ne!(response.status(), 429);
+    }
+    let response = command("https://dashboard.lan".to_string()).await.unwrap();
+    assert_eq!(response.status(), 429);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["limit_per_minute"], 3);
+}
+
+#[tokio::test]
+async fn cors_only_allows_configured_origins() {
+    let mut config = default_config();
+    config.cors_origins = vec!["https://desk.example.com".to_string()];
+    config.cors_origin_patterns = vec![r"https://.*\.lan".parse().unwrap()];
+    let harness = Harness::start_with(true, config).await;
+
+    for (origin, allowed) in [
+        ("https://desk.example.com", true),
+        ("https://office.lan", true),
+        ("https://evil.example.com", false),
+        ("https://office.lan.evil.com", false),
+    ] {
+        let response = harness
+            .client
+            .request(
+                reqwest::Method::OPTIONS,
+                format!("{}/api/sven/command", harness.url),
+            )
+            .header("origin", origin)
+            .header("access-control-request-method", "POST")
+            .header("access-control-request-headers", "authorization")
+            .send()
+            .await
+            .unwrap();
+        let allowed_origin = response.headers().get("access-control-allow-origin");
+        assert_eq!(allowed_origin.is_some(), allowed, "{}", origin);
+    }
+}
+
+#[tokio::test]
+async fn calibrated_position_is_published_as_its_height() {
+    let harness = Harness::start(true).await;
+    let (status, body) = harness
+        .post(
+            "/api/sven/calibrate",
+            json!({"position": "Standing", "height_mm": 1100}),
+        )
+        .await;
+    assert_eq!(status, 200, "{}", body);
+
+    let (status, body) = harness
+        .post(
+            "/api/sven/command",
+            json!({"command": "Position", "value": 4}),
+        )
+        .await;
+    assert_eq!(status, 200, "{}", body);
+    let publish = harness.next_publish().await;
+    assert_eq!(
+        payload(&publish),
+        json!({"command": "AbsoluteHeight", "value": 1100, "id": body["request_id"]})
+    );
+}
+
+#[tokio::test]
+async fn custom_labels_are_moved_to_and_reported() {
+    let harness = Harness::start(true).await;
+    let response = harness
+        .client
+        .put(format!("{}/api/sven/positions/Zeichnen", harness.url))
+        .json(&json!({"height_mm": 950}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let (status, body) = harness
+        .post(
+            "/api/sven/command",
+            json!({"command": "Position", "label": "zeichnen"}),
+        )
+        .await;
+    assert_eq!(status, 200, "{}", body);
+    let publish = harness.next_publish().await;
+    assert_eq!(
+        payload(&publish),
+        json!({"command": "AbsoluteHeight", "value": 950, "id": body["request_id"]})
+    );
+    let (status, body) = harness
+        .post(
+            "/api/sven/command",
+            json!({"command": "Position", "label": "Lounge"}),
+        )
+        .await;
+    assert_eq!(status, 422);
+    assert_eq!(body["code"], "unknown_label");
+    let (status, body) = harness
+        .post("/api/sven/command", json!({"command": "Position"}))
+        .await;
+    assert_eq!(status, 422);
+    assert_eq!(body["code"], "missing_value");
+    let (_, positions) = harness.get("/api/sven/positions").await;
+    assert!(
+        positions
+            .as_array()
+            .unwrap()
+            .contains(&json!({"label": "Zeichnen", "height_mm": 950}))
+    );
+
+    let mut config = default_config();
+    config.transport = TransportKind::Http;
+    config.transport_url = Some("http://127.0.0.1:9".to_string());
+    let harness = Harness::start_with(true, config).await;
+    let (status, _) = harness
+        .post(
+            "/api/sven/calibrate",
+            json!({"position": "Kinder", "height_mm": 700}),
+        )
+        .await;
+    assert_eq!(status, 200);
+    harness
+        .post(
+            "/api/sven/transport/messages",
+            json!({"topic": "sven/state", "payload": {"height_mm": 703, "position": "Custom"}}),
+        )
+        .await;
+    let (_, state) = harness.get("/api/sven/state").await;
+    assert_eq!(state["label"], "Kinder");
+}
+
+#[tokio::test]
+async fn calibrating_at_the_current_height_needs_a_report() {
+    let harness = Harness::start(true).await;
+    let (status, body) = harness
+        .post("/api/sven/calibrate", json!({"position": "Armrest"}))
+        .await;
+    assert_eq!(status, 409);
+    assert_eq!(body["code"], "state_unknown");
+}
+
+#[tokio::test]
+async fn dry_run_reports_the_publish_without_sending_it() {
+    let harness = Harness::start(true).await;
+    for (path, body) in [
+        (
+            "/api/sven/command",
+            json!({"command": "UpPercent", "value": 10, "dry_run": true}),
+        ),
+        (
+            "/api/sven/command?dry_run=1",
+            json!({"command": "UpPercent", "value": 10}),
+        ),
+    ] {
+        let (status, body) = harness.post(path, body).await;
+        assert_eq!(status, 200, "{}", body);
+        assert_eq!(body["dry_run"], true);
+        assert_eq!(body["command"]["command"], "AbsoluteHeight");
+        let publishes = body["publishes"].as_array().unwrap();
+        assert_eq!(publishes.len(), 1);
+        assert_eq!(publishes[0]["topic"], "sven/command");
+        let payload: Value =
+            serde_json::from_str(publishes[0]["payload"].as_str().unwrap()).unwrap();
+        assert_eq!(
+            payload,
+            json!({"command": "AbsoluteHeight", "value": 715, "id": body["request_id"]})
+        );
+    }
+    assert!(harness.nothing_published());
+}
+
+#[tokio::test]
+async fn versioned_routes_name_their_version() {
+    let harness = Harness::start(true).await;
+    let (status, body) = harness.get("/api/v1/sven/state").await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(body["api_version"], 1);
+    assert_eq!(body["height_mm"], 650);
+
+    let (status, body) = harness
+        .post(
+            "/api/v1/sven/command?dry_run=1",
+            json!({"command": "AbsoluteHeight", "value": 900}),
+        )
+        .await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(body["dry_run"], true);
+
+    let (status, body) = harness.get("/api/v2/sven/state").await;
+    assert_eq!(status, 404);
+    assert_eq!(body["code"], "unsupported_api_version");
+
+    let response = harness
+        .client
+        .get(format!("{}/api/sven/state", harness.url))
+        .header("accept", "application/vnd.sven.v3+json")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 406);
+    let response = harness
+        .client
+        .get(format!("{}/api/sven/limits", harness.url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["api-version"], "1");
+}
+
+#[cfg(feature = "graphql")]
+#[tokio::test]
+async fn graphql_mutation_goes_through_the_command_path() {
+    let harness = Harness::start(true).await;
+    let (status, body) = harness
+        .post(
+            "/api/graphql",
+            json!({"query": r#"mutation { sendCommand(command: "AbsoluteHeight", value: 900) }"#}),
+        )
+        .await;
+    assert_eq!(status, 200, "{}", body);
+    let request_id = &body["data"]["sendCommand"]["request_id"];
+    let publish = harness.next_publish().await;
+    assert_eq!(
+        payload(&publish),
+        json!({"command": "AbsoluteHeight", "value": 900, "id": request_id})
+    );
+
+    let (_, body) = harness
+        .post(
+            "/api/graphql",
+            json!({"query": r#"mutation { sendCommand(command: "AbsoluteHeight", value: 5000) }"#}),
+        )
+        .await;
+    assert_eq!(body["errors"][0]["extensions"]["status"], 422);
+    assert_eq!(
+        body["errors"][0]["extensions"]["code"],
+        "height_out_of_range"
+    );
+
+    let (_, body) = harness
+        .post(
+            "/api/graphql",
+            json!({"query": "{ state { heightMm brokerConnected } presets { name } }"}),
+        )
+        .await;
+    assert_eq!(
+        body["data"],
+        json!({"state": {"heightMm": 650, "brokerConnected": true}, "presets": []})
+    );
+}
+
+#[cfg(feature = "grpc")]
+#[tokio::test]
+async fn grpc_command_goes_through_the_command_path() {
+    use sven_api::grpc::proto;
+
+    let harness = Harness::start(true).await;
+    let mut client = harness.grpc_client().await;
+    let mut states = client
+        .stream_state(proto::StreamStateRequest {})
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(states.message().await.unwrap().unwrap().height_mm, 650);
+
+    let reply = client
+        .send_command(proto::CommandRequest {
+            command: "AbsoluteHeight".to_string(),
+            value: 900,
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    let publish = harness.next_publish().await;
+    assert_eq!(
+        payload(&publish),
+        json!({"command": "AbsoluteHeight", "value": 900, "id": reply.request_id})
+    );
+
+    let failure = client
+        .send_command(proto::CommandRequest {
+            command: "AbsoluteHeight".to_string(),
+            value: 5000,
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(failure.code(), tonic::Code::InvalidArgument);
+    assert_eq!(
+        failure.metadata().get("x-error-code").unwrap(),
+        "height_out_of_range"
+    );
+
+    let state = client
+        .get_state(proto::GetStateRequest {})
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(state.broker_connected);
+}
+
+#[test]
+fn broker_marks_the_api_offline_when_it_dies() {
+    let options =
+        sven_api::broker::mqtt_options(&default_config(), "sven-test".to_string()).unwrap();
+    let will = options.last_will().unwrap();
+    assert_eq!(will.topic, "sven-api/status");
+    assert_eq!(&will.message[..], b"offline");
+    assert!(will.retain);
+}
+
+#[tokio::test]
+async fn stops_and_commands_use_their_own_qos_and_retain_flag() {
+    let mut config = default_config();
+    config.publish_classes.set(
+        MessageClass::Stop,
+        PublishOptions {
+            qos: QoS::ExactlyOnce,
+            retain: false,
+        },
+    );
+    config.publish_classes.set(
+        MessageClass::Command,
+        PublishOptions {
+            qos: QoS::AtMostOnce,
+            retain: true,
+        },
+    );
+    let harness = Harness::start_with(true, config).await;
+
+    harness
+        .post(
+            "/api/sven/command",
+            json!({"command": "AbsoluteHeight", "value": 900}),
+        )
+        .await;
+    let command = harness.next_publish().await;
+    assert_eq!(command.qos, QoS::AtMostOnce);
+    assert!(command.retain);
+
+    harness.post("/api/sven/stop", json!({})).await;
+    let stop = harness.next_publish().await;
+    assert_eq!(payload(&stop)["command"], "Stop");
+    assert_eq!(stop.qos, QoS::ExactlyOnce);
+    assert!(!stop.retain);
+}
+
+#[tokio::test]
+async fn firmware_upload_is_announced_on_the_ota_topic() {
+    let mut config = default_config();
+    config.admin_token = Some("secret".to_string());
+    config.ota_chunk_bytes = 2048;
+    let harness = Harness::start_with(true, config).await;
+    let upload = |token: &str| {
+        harness
+            .client
+            .post(format!("{}/api/sven/firmware?version=1.4.0", harness.url))
+            .bearer_auth(token)
+            .body(vec![0xa5; 5000])
+            .send()
+    };
+    assert_eq!(upload("wrong").await.unwrap().status(), 401);
+
+    let response = upload("secret").await.unwrap();
+    assert_eq!(response.status(), 202);
+    let update: Value = response.json().await.unwrap();
+    assert_eq!(update["chunks"], 3);
+
+    let begin = harness.next_publish().await;
+    assert_eq!(begin.topic, "sven/ota");
+    let begin = payload(&begin);
+    assert_eq!(begin["action"], "begin");
+    assert_eq!(begin["update"], update["id"]);
+    assert_eq!(begin["size"], 5000);
+    assert_eq!(begin["sha256"], update["sha256"]);
+    assert_eq!(begin["version"], "1.4.0");
+
+    let status = |token: &str| {
+        harness
+            .client
+            .get(format!("{}/api/sven/firmware", harness.url))
+            .bearer_auth(token)
+            .send()
+    };
+    assert_eq!(status("wrong").await.unwrap().status(), 401);
+    let status: Value = status("secret").await.unwrap().json().await.unwrap();
+    assert_eq!(status["status"], "uploading");
+    assert_eq!(status["acked_chunks"], 0);
+    assert_eq!(upload("secret").await.unwrap().status(), 409);
+}
+
+#[tokio::test]
+async fn the_typed_client_reads_the_state_and_sends_commands() {
+    let harness = Harness::start(true).await;
+    let client = SvenClient::new(&harness.url);
+    let state = client.state().await.unwrap();
+    assert_eq!(state.status, DeskStatus::Offline);
+
+    client.move_to(1000).await.unwrap();
+    let publish = harness.next_publish().await;
+    assert_eq!(payload(&publish)["value"], 1000);
+
+    let Err(ClientError::Api { status, .. }) = client.apply_preset("nowhere").await else {
+        panic!("unknown preset applied");
+    };
+    assert_eq!(status, 404);
+}
+
+#[tokio::test]
+async fn the_cli_client_sends_commands_to_a_running_instance() {
+    let harness = Harness::start(true).await;
+    let client = |args: &[&str]| {
+        tokio::process::Command::new(env!("CARGO_BIN_EXE_sven-api"))
+            .arg("client")
+            .arg("--url")
+            .arg(&harness.url)
+            .args(args)
+            .output()
+    };
+    let output = client(&["move", "--height", "1100"]).await.unwrap();
+    assert!(output.status.success());
+    let body: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert!(body["request_id"].is_string());
+    let publish = harness.next_publish().await;
+    assert_eq!(payload(&publish)["command"], "AbsoluteHeight");
+    assert_eq!(payload(&publish)["value"], 1100);
+
+    let output = client(&["preset", "nowhere"]).await.unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let output = client(&["move", "--sideways", "10"]).await.unwrap();
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[tokio::test]
+async fn oversized_bodies_are_refused_with_413() {
+    let mut config = default_config();
+    config.admin_token = Some("secret".to_string());
+    config.max_command_body_bytes = 256;
+    config.ota_max_bytes = 1024;
+    let harness = Harness::start_with(true, config).await;
+    let padding = "x".repeat(512);
+    let response = harness
+        .client
+        .post(format!("{}/api/sven/command", harness.url))
+        .json(&json!({"command": "AbsoluteHeight", "value": 900, "padding": padding}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 413);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["code"], "payload_too_large");
+
+    let response = harness
+        .client
+        .post(format!("{}/api/sven/firmware", harness.url))
+        .bearer_auth("secret")
+        .body(vec![0xa5; 4096])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 413);
+
+    let (status, _) = harness
+        .post(
+            "/api/sven/command",
+            json!({"command": "AbsoluteHeight", "value": 900}),
+        )
+        .await;
+    assert_eq!(status, 200);
+}
+
+#[tokio::test]
+async fn large_responses_are_compressed_when_accepted() {
+    let history_db =
+        std::env::temp_dir().join(format!("sven-compression-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&history_db);
+    let mut config = default_config();
+    config.history_db = Some(history_db.clone());
+    let harness = Harness::start_with(true, config).await;
+    // Something in the history, an empty one is too short to bother
+    let (status, body) = harness
+        .post(
+            "/api/sven/command",
+            json!({"command": "AbsoluteHeight", "value": 900}),
+        )
+        .await;
+    assert_eq!(status, 200, "{}", body);
+    harness.next_publish().await;
+    let content_encoding = |path: &'static str, accept: Option<&'static str>| {
+        let mut request = harness.client.get(format!("{}{}", harness.url, path));
+        if let Some(accept) = accept {
+            request = request.header("accept-encoding", accept);
+        }
+        async move {
+            let response = request.send().await.unwrap();
+            assert_eq!(response.status(), 200, "{}", path);
+            response
+                .headers()
+                .get("content-encoding")
+                .map(|encoding| encoding.to_str().unwrap().to_string())
+        }
+    };
+
+    for path in ["/api/openapi.json", "/api/sven/history", "/api/sven/stats"] {
+        assert_eq!(content_encoding(path, None).await, None, "{}", path);
+        assert_eq!(
+            content_encoding(path, Some("gzip")).await.as_deref(),
+            Some("gzip"),
+            "{}",
+            path
+        );
+        assert_eq!(
+            content_encoding(path, Some("br")).await.as_deref(),
+            Some("br"),
+            "{}",
+            path
+        );
+    }
+    // Small live reads aren't worth it
+    assert_eq!(
+        content_encoding("/api/sven/state", Some("gzip")).await,
+        None
+    );
+    let _ = std::fs::remove_file(&history_db);
+}
+
+#[tokio::test]
+async fn self_signed_certificate_serves_https() {
+    let mut config = default_config();
+    config.tls_self_signed = true;
+    let tls_config = sven_api::tls::server_config(&config).unwrap().unwrap();
+    let (requests_tx, _requests) = flume::bounded(10);
+    let app_state = sven_api::build_state(
+        config,
+        AsyncClient::from_senders(requests_tx),
+        "sven-test".to_string(),
+    )
+    .unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let listener = sven_api::tls::TlsListener::new(listener, tls_config).unwrap();
+    let app = sven_api::build_app(app_state).into_make_service_with_connect_info::<ClientAddr>();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap();
+    let response = client
+        .get(format!("https://localhost:{}/healthz", port))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert!(
+        reqwest::get(format!("http://localhost:{}/healthz", port))
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn stop_is_published() {
+    let harness = Harness::start(true).await;
+    let (status, body) = harness.post("/api/sven/stop", json!({})).await;
+    assert_eq!(status, 200, "{}", body);
+
+    let publish = harness.next_publish().await;
+    assert_eq!(publish.topic, "sven/command");
+    assert_eq!(payload(&publish)["command"], "Stop");
+}
+
+#[tokio::test]
+async fn command_the_broker_never_gets_is_an_error() {
+    let mut harness = Harness::start(true).await;
+    harness.lose_eventloop();
+    let (status, body) = harness
+        .post(
+            "/api/sven/command",
+            json!({"command": "AbsoluteHeight", "value": 900}),
+        )
+        .await;
+    assert_eq!(status, 503, "{}", body);
+    assert_eq!(body["code"], "broker_unreachable");
+
+    let (_, job) = harness
+        .get(&format!(
+            "/api/sven/jobs/{}",
+            body["job_id"].as_str().unwrap()
+        ))
+        .await;
+    assert_eq!(job["status"], "failed");
+    let (_, metrics) = harness.get("/api/sven/metrics.json").await;
+    assert_eq!(metrics["publish_failures"]["broker_unreachable"], 1);
+    assert_eq!(metrics["publish_failures_total"], 1);
+}
+
+#[tokio::test]
+async fn command_that_waits_past_its_ttl_is_dropped() {
+    let harness = Harness::start(true).await;
+    let (status, body) = harness
+        .post(
+            "/api/sven/command",
+            json!({"command": "AbsoluteHeight", "value": 900, "ttl_ms": 5000}),
+        )
+        .await;
+    assert_eq!(status, 200, "{}", body);
+    let expires_at = payload(&harness.next_publish().await)["expires_at"]
+        .as_i64()
+        .unwrap();
+    let left_ms = expires_at - chrono::Utc::now().timestamp_millis();
+    assert!((0..=5000).contains(&left_ms), "{}", left_ms);
+
+    // The first command holds the queue until the desk reports arriving
+    let (status, body) = harness
+        .post(
+            "/api/sven/command",
+            json!({"command": "AbsoluteHeight", "value": 1000, "ttl_ms": 100}),
+        )
+        .await;
+    assert_eq!(status, 409, "{}", body);
+    assert_eq!(body["code"], "command_expired");
+    assert!(harness.nothing_published());
+}
+
+#[tokio::test]
+async fn standing_session_raises_and_lowers_the_desk() {
+    let mut config = default_config();
+    // Nothing reports arriving, so each move only holds the queue this long
+    config.ack_timeout_ms = 100;
+    let harness = Harness::start_with(true, config).await;
+    let (status, session) = harness
+        .post(
+            "/api/sven/sessions",
+            json!({"duration_secs": 600, "standing_height_mm": 1100, "sitting_height_mm": 800}),
+        )
+        .await;
+    assert_eq!(status, 202, "{}", session);
+    assert_eq!(payload(&harness.next_publish().await)["value"], 1100);
+
+    let (_, state) = harness.get("/api/sven/state").await;
+    assert!(state["session"]["remaining_secs"].as_u64().unwrap() > 590);
+    let (status, body) = harness
+        .post("/api/sven/sessions", json!({"duration_secs": 60}))
+        .await;
+    assert_eq!(status, 409);
+    assert_eq!(body["code"], "session_active");
+
+    let response = harness
+        .client
+        .delete(format!("{}/api/sven/sessions", harness.url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    tokio::time::sleep(Duration::from_millis(1200)).await;
+    assert_eq!(payload(&harness.next_publish().await)["value"], 800);
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let (_, job) = harness
+        .get(&format!(
+            "/api/sven/jobs/{}",
+            session["job_id"].as_str().unwrap()
+        ))
+        .await;
+    assert_eq!(job["kind"], "session");
+    assert_eq!(job["status"], "completed");
+    let (status, _) = harness.get("/api/sven/sessions").await;
+    assert_eq!(status, 404);
+}
+
+#[tokio::test]
+async fn webhook_rule_runs_its_actions_when_the_conditions_hold() {
+    let harness = Harness::start(true).await;
+    let (status, rule) = harness
+        .post(
+            "/api/sven/rules",
+            json!({
+                "name": "stand up",
+                "trigger": {"type": "webhook"},
+                "conditions": [{"type": "height_between", "min_mm": 0, "max_mm": 2000}],
+                "actions": [
+                    {"type": "command", "command": "AbsoluteHeight", "value": 1000},
+                    {"type": "notify", "message": "Standing up"},
+                ],
+            }),
+        )
+        .await;
+    assert_eq!(status, 201, "{}", rule);
+    let id = rule["id"].as_str().unwrap();
+
+    let (status, body) = harness
+        .post(&format!("/api/sven/rules/{}/trigger", id), json!({}))
+        .await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(body["fired"], true);
+    assert_eq!(payload(&harness.next_publish().await)["value"], 1000);
+    let (_, rules) = harness.get("/api/sven/rules").await;
+    assert!(rules[0]["last_triggered"].is_string());
+
+    let (status, night) = harness
+        .post(
+            "/api/sven/rules",
+            json!({
+                "name": "night",
+                "trigger": {"type": "status", "status": "Idle"},
+                "actions": [{"type": "preset", "name": "sitting"}],
+            }),
+        )
+        .await;
+    assert_eq!(status, 201);
+    let (status, body) = harness
+        .post(
+            &format!("/api/sven/rules/{}/trigger", night["id"].as_str().unwrap()),
+            json!({}),
+        )
+        .await;
+    assert_eq!(status, 409);
+    assert_eq!(body["code"], "not_a_webhook_rule");
+
+    let (status, body) = harness
+        .post(
+            "/api/sven/rules",
+            json!({
+                "name": "broken",
+                "trigger": {"type": "time", "days": "someday", "time": "09:00"},
+                "actions": [{"type": "notify", "message": "never"}],
+            }),
+        )
+        .await;
+    assert_eq!(status, 422);
+    assert_eq!(body["code"], "invalid_rule");
+}
+
+#[tokio::test]
+async fn publish_that_waits_too_long_times_out() {
+    let mut config = default_config();
+    config.publish_timeout_ms = 100;
+    let harness = Harness::start_with(true, config).await;
+    // Nothing drains the client's queue of 10, so the next stop has to wait
+    for _ in 0..10 {
+        let (status, body) = harness.post("/api/sven/stop", json!({})).await;
+        assert_eq!(status, 200, "{}", body);
+    }
+    let (status, body) = harness.post("/api/sven/stop", json!({})).await;
+    assert_eq!(status, 502, "{}", body);
+    assert_eq!(body["code"], "publish_timeout");
+}
+
+#[tokio::test]
+async fn control_page_loads_without_an_api_key() {
+    let mut config = default_config();
+    config
+        .api_keys
+        .insert("kitchen".to_string(), "k1tchen".parse().unwrap());
+    let harness = Harness::start_with(true, config).await;
+
+    let page = reqwest::get(format!("{}/", harness.url)).await.unwrap();
+    assert_eq!(page.status(), 200);
+    assert!(page.text().await.unwrap().contains("/ui/app.js"));
+    let script = reqwest::get(format!("{}/ui/app.js", harness.url))
+        .await
+        .unwrap();
+    assert_eq!(script.status(), 200);
+    assert_eq!(
+        script.headers()["content-type"],
+        "text/javascript; charset=utf-8"
+    );
+    // The data the page shows still needs the key
+    let (status, _) = harness.get("/api/sven/state").await;
+    assert_eq!(status, 401);
+}
+
+#[tokio::test]
+async fn logins_get_a_session_cookie_limited_by_role() {
+    let mut config = default_config();
+    for (name, role) in [("vic", "viewer"), ("ana", "admin")] {
+        let hash = sven_api::login::hash_password("hunter2");
+        config.accounts.insert(
+            name.to_string(),
+            format!("{}:{}", role, hash).parse().unwrap(),
+        );
+    }
+    let harness = Harness::start_with(true, config).await;
+    let login = |name: &str, password: &str| {
+        harness
+            .client
+            .post(format!("{}/api/login", harness.url))
+            .json(&json!({"name": name, "password": password}))
+            .send()
+    };
+
+    // The page sends whoever isn't logged in to the login form, the API refuses them
+    let page = reqwest::get(format!("{}/", harness.url)).await.unwrap();
+    assert!(page.url().path().ends_with("/login"));
+    assert!(page.text().await.unwrap().contains("/api/login"));
+    let (status, _) = harness.get("/api/sven/state").await;
+    assert_eq!(status, 401);
+
+    let refused = login("vic", "hunter3").await.unwrap();
+    assert_eq!(refused.status(), 401);
+    let cookie = |response: &reqwest::Response| {
+        let set_cookie = response.headers()["set-cookie"].to_str().unwrap();
+        assert!(set_cookie.contains("HttpOnly"));
+        set_cookie.split(';').next().unwrap().to_string()
+    };
+
+    let viewer = login("vic", "hunter2").await.unwrap();
+    assert_eq!(viewer.status(), 200);
+    let viewer = cookie(&viewer);
+    let state = harness
+        .client
+        .get(format!("{}/api/sven/state", harness.url))
+        .header("cookie", &viewer)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(state.status(), 200);
+    let command = harness
+        .client
+        .post(format!("{}/api/sven/command", harness.url))
+        .header("cookie", &viewer)
+        .json(&json!({"command": "AbsoluteHeight", "value": 1000}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(command.status(), 403);
+    let body: Value = command.json().await.unwrap();
+    assert_eq!(body["code"], "viewer_role");
+    let backup = harness
+        .client
+        .get(format!("{}/api/admin/backup", harness.url))
+        .header("cookie", &viewer)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(backup.status(), 401);
+
+    let admin = cookie(&login("ana", "hunter2").await.unwrap());
+    let backup = harness
+        .client
+        .get(format!("{}/api/admin/backup", harness.url))
+        .header("cookie", &admin)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(backup.status(), 200);
+    let session: Value = harness
+        .client
+        .get(format!("{}/api/login", harness.url))
+        .header("cookie", &admin)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(session["name"], "ana");
+    assert_eq!(session["role"], "admin");
+}
+
+#[tokio::test]
+async fn failed_logins_are_throttled() {
+    let mut config = default_config();
+    let hash = sven_api::login::hash_password("hunter2");
+    config.accounts.insert(
+        "vic".to_string(),
+        format!("viewer:{}", hash).parse().unwrap(),
+    );
+    config.login_failures_per_minute = 2;
+    let harness = Harness::start_with(true, config).await;
+    let login = |name: &str, password: &str| {
+        harness
+            .client
+            .post(format!("{}/api/login", harness.url))
+            .json(&json!({"name": name, "password": password}))
+            .send()
+    };
+
+    // Successful logins don't count
+    for _ in 0..3 {
+        assert_eq!(login("vic", "hunter2").await.unwrap().status(), 200);
+    }
+    assert_eq!(login("vic", "guess1").await.unwrap().status(), 401);
+    assert_eq!(login("eve", "guess2").await.unwrap().status(), 401);
+    // The client is out of attempts, also for the right password
+    let throttled = login("vic", "hunter2").await.unwrap();
+    assert_eq!(throttled.status(), 429);
+    assert!(throttled.headers().contains_key("retry-after"));
+    let body: Value = throttled.json().await.unwrap();
+    assert_eq!(body["code"], "too_many_logins");
+}
+
+#[tokio::test]
+async fn alexa_sets_the_desk_height_by_percentage() {
+    let mut config = default_config();
+    config.alexa_tokens = vec!["linked".to_string()];
+    let harness = Harness::start_with(true, config).await;
+    let directive = |namespace: &str, name: &str, token: &str, payload: Value| {
+        json!({"directive": {
+            "header": {
+                "namespace": namespace,
+                "name": name,
+                "messageId": "m1",
+                "correlationToken": "c1",
+                "payloadVersion": "3",
+            },
+            "endpoint": {
+                "scope": {"type": "BearerToken", "token": token},
+                "endpointId": "sven-desk",
+            },
+            "payload": payload,
+        }})
+    };
+
+    let (status, discovered) = harness
+        .post(
+            "/api/alexa",
+            json!({"directive": {
+                "header": {"namespace": "Alexa.Discovery", "name": "Discover", "messageId": "m0", "payloadVersion": "3"},
+                "payload": {"scope": {"type": "BearerToken", "token": "linked"}},
+            }}),
+        )
+        .await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        discovered["event"]["payload"]["endpoints"][0]["endpointId"],
+        "sven-desk"
+    );
+
+    let (_, response) = harness
+        .post(
+            "/api/alexa",
+            directive(
+                "Alexa.RangeController",
+                "SetRangeValue",
+                "linked",
+                json!({"rangeValue": 50}),
+            ),
+        )
+        .await;
+    assert_eq!(
+        response["event"]["header"]["name"], "Response",
+        "{}",
+        response
+    );
+    assert_eq!(response["event"]["header"]["correlationToken"], "c1");
+    let publish = harness.next_publish().await;
+    assert_eq!(payload(&publish)["command"], "AbsoluteHeight");
+    assert_eq!(payload(&publish)["value"], 950);
+
+    let (_, refused) = harness
+        .post(
+            "/api/alexa",
+            directive(
+                "Alexa.RangeController",
+                "SetRangeValue",
+                "stolen",
+                json!({"rangeValue": 50}),
+            ),
+        )
+        .await;
+    assert_eq!(refused["event"]["header"]["name"], "ErrorResponse");
+    assert_eq!(
+        refused["event"]["payload"]["type"],
+        "INVALID_AUTHORIZATION_CREDENTIAL"
+    );
+    assert!(harness.nothing_published());
+}
+
+#[tokio::test]
+async fn concurrent_reads_see_whole_state_reports() {
+    let mut config = default_config();
+    config.transport = TransportKind::Http;
+    config.transport_url = Some("http://127.0.0.1:9".to_string());
+    let harness = Harness::start_with(true, config).await;
+
+    let readers: Vec<_> = (0..16)
+        .map(|reader| {
+            let client = harness.client.clone();
+            let path = if reader % 2 == 0 {
+                "/api/sven/state"
+            } else {
+                "/api/sven/status"
+            };
+            let url = format!("{}{}", harness.url, path);
+            tokio::spawn(async move {
+                for _ in 0..25 {
+                    let response = client.get(&url).send().await.unwrap();
+                    assert_eq!(response.status(), 200);
+                    let body: Value = response.json().await.unwrap();
+                    if path == "/api/sven/state" {
+                        // Reports alternate between two whole states, never a mix of them
+                        match body["height_mm"].as_u64() {
+                            Some(800) => assert_eq!(body["position"], "Sitting"),
+                            Some(1100) => assert_eq!(body["position"], "Standing"),
+                            _ => {}
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    for round in 0..20 {
+        let report = if round % 2 == 0 {
+            json!({"height_mm": 800, "position": "Sitting"})
+        } else {
+            json!({"height_mm": 1100, "position": "Standing"})
+        };
+        let (status, _) = harness
+            .post(
+                "/api/sven/transport/messages",
+                json!({"topic": "sven/state", "payload": report}),
+            )
+            .await;
+        assert_eq!(status, 200);
+    }
+    for reader in readers {
+        reader.await.unwrap();
+    }
+    let (_, state) = harness.get("/api/sven/state").await;
+    assert_eq!(state["height_mm"], 1100);
+}
+
+#[tokio::test]
+async fn discovery_describes_the_bridge_without_a_key() {
+    let mut config = default_config();
+    config.desks = vec!["office-2".to_string(), "office-1".to_string()];
+    config.mdns_name = "Office desk".to_string();
+    config
+        .api_keys
+        .insert("kitchen".to_string(), "k1tchen".parse().unwrap());
+    let harness = Harness::start_with(true, config).await;
+
+    let (status, _) = harness.get("/api/sven/state").await;
+    assert_eq!(status, 401);
+    let (status, info) = harness.get("/api/discovery").await;
+    assert_eq!(status, 200, "{}", info);
+    assert_eq!(info["name"], "Office desk");
+    assert_eq!(info["service"], "_sven-api._tcp");
+    assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(info["scheme"], "http");
+    assert_eq!(info["auth_required"], true);
+    assert_eq!(info["desks"], json!(["office-1", "office-2"]));
+    let capabilities = info["capabilities"].as_array().unwrap();
+    assert!(capabilities.contains(&json!("websocket")));
+    assert_eq!(
+        capabilities.contains(&json!("graphql")),
+        cfg!(feature = "graphql")
+    );
+}
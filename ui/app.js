@@ -0,0 +1,183 @@
+// Control page served at `/`. Talks to the same API as any other client; the API
+// key, when one is needed, is kept in localStorage and sent as a bearer token.
+// Live state comes from the server-sent event stream, read through fetch so the
+// key can go along in a header, which EventSource doesn't allow. After a login at
+// `/login` the session cookie goes along with every call instead.
+
+const $ = (id) => document.getElementById(id);
+
+const keyInput = $("key");
+keyInput.value = localStorage.getItem("sven-api-key") || "";
+
+function headers(extra = {}) {
+  const key = localStorage.getItem("sven-api-key");
+  return key ? { ...extra, Authorization: `Bearer ${key}` } : extra;
+}
+
+function showError(message) {
+  $("error").textContent = message;
+  $("error").hidden = !message;
+}
+
+async function api(method, path, body) {
+  const response = await fetch(path, {
+    method,
+    headers: headers(body ? { "Content-Type": "application/json" } : {}),
+    body: body ? JSON.stringify(body) : undefined,
+  });
+  const data = await response.json().catch(() => ({}));
+  if (!response.ok) {
+    throw new Error(data.error || `${method} ${path} failed with ${response.status}`);
+  }
+  return data;
+}
+
+async function send(method, path, body) {
+  try {
+    await api(method, path, body);
+    showError("");
+  } catch (e) {
+    showError(e.message);
+  }
+}
+
+function showState(state) {
+  $("height").textContent = state.height_mm;
+  if (state.status) {
+    showStatus(state.status);
+  }
+  if (document.activeElement !== $("slider")) {
+    $("slider").value = state.height_mm;
+    $("target").textContent = `${state.height_mm} mm`;
+  }
+}
+
+function showStatus(status) {
+  $("status").textContent = status.replace(/([a-z])([A-Z])/g, "$1 $2").toLowerCase();
+}
+
+async function loadLimits() {
+  const limits = await api("GET", "/api/sven/limits");
+  const soft = limits.soft_limits || {};
+  $("slider").min = soft.min_height_mm ?? limits.min_height_mm;
+  $("slider").max = soft.max_height_mm ?? limits.max_height_mm;
+}
+
+async function loadPresets() {
+  const presets = await api("GET", "/api/sven/presets");
+  const container = $("presets");
+  container.replaceChildren();
+  if (presets.length === 0) {
+    container.textContent = "No presets yet.";
+  }
+  for (const preset of presets) {
+    const button = document.createElement("button");
+    button.textContent = `${preset.name} (${preset.height_mm} mm)`;
+    button.onclick = () => send("POST", `/api/sven/presets/${encodeURIComponent(preset.name)}/apply`);
+    container.append(button);
+  }
+}
+
+function setConnected(connected) {
+  $("connection").textContent = connected ? "live" : "reconnecting";
+  $("connection").classList.toggle("online", connected);
+}
+
+// Reads `event:`/`data:` records off the stream until it ends, then reconnects.
+async function follow() {
+  for (;;) {
+    try {
+      const response = await fetch("/api/sven/events", { headers: headers() });
+      if (!response.ok) {
+        throw new Error(`Live updates failed with ${response.status}`);
+      }
+      setConnected(true);
+      const reader = response.body.pipeThrough(new TextDecoderStream()).getReader();
+      let buffer = "";
+      for (;;) {
+        const { value, done } = await reader.read();
+        if (done) {
+          break;
+        }
+        buffer += value;
+        let end;
+        while ((end = buffer.indexOf("\n\n")) >= 0) {
+          handleEvent(buffer.slice(0, end));
+          buffer = buffer.slice(end + 2);
+        }
+      }
+    } catch (e) {
+      showError(e.message);
+    }
+    setConnected(false);
+    await new Promise((resolve) => setTimeout(resolve, 2000));
+  }
+}
+
+function handleEvent(record) {
+  let event = "message";
+  let data = "";
+  for (const line of record.split("\n")) {
+    if (line.startsWith("event:")) {
+      event = line.slice(6).trim();
+    } else if (line.startsWith("data:")) {
+      data += line.slice(5).trim();
+    }
+  }
+  if (!data) {
+    return;
+  }
+  if (event === "state") {
+    showState(JSON.parse(data));
+  } else if (event === "status") {
+    showStatus(JSON.parse(data));
+  } else if (event === "progress") {
+    showProgress(JSON.parse(data));
+  }
+}
+
+function showProgress(job) {
+  const bar = $("progress");
+  bar.hidden = job.progress_percent == null || job.status !== "moving";
+  bar.value = job.progress_percent ?? 0;
+}
+
+$("slider").oninput = () => {
+  $("target").textContent = `${$("slider").value} mm`;
+};
+$("move").onclick = () =>
+  send("POST", "/api/sven/command", { command: "AbsoluteHeight", value: Number($("slider").value) });
+$("stop").onclick = () => send("POST", "/api/sven/stop", {});
+$("key-form").onsubmit = (e) => {
+  e.preventDefault();
+  localStorage.setItem("sven-api-key", keyInput.value.trim());
+  start();
+};
+
+$("logout").onclick = async () => {
+  await fetch("/api/logout", { method: "POST" });
+  location.href = "/login";
+};
+
+async function showAccount() {
+  const response = await fetch("/api/login");
+  if (response.ok) {
+    const session = await response.json();
+    $("account-name").textContent = `${session.name} (${session.role})`;
+    $("account").hidden = false;
+  }
+}
+
+async function start() {
+  try {
+    showState(await api("GET", "/api/sven/state"));
+    await Promise.all([loadLimits(), loadPresets()]);
+    showError("");
+  } catch (e) {
+    showError(e.message);
+  }
+}
+
+start();
+follow();
+showAccount();